@@ -0,0 +1,958 @@
+//! Creation and handling of adversaries
+//!
+//! The adversary consists of relays that are injected into the network and
+//! existing relays that are considered compromised. Simple adversaries can be
+//! configured directly (a number of identical guards, middles and exits), more complex
+//! ones are described by an adversary specification file (see [spec]).
+
+pub(crate) mod spec;
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use anyhow::{self, Context};
+use chrono::{DateTime, Duration, Utc};
+#[allow(unused_imports)]
+use log::{debug, info, trace, warn};
+use regex::RegexSet;
+
+use seeded_rand::RHashSet;
+use tordoc::{
+    consensus::CondensedExitPolicy, consensus::Flag, consensus::Relay, descriptor::OrAddress,
+    Consensus, Descriptor, Fingerprint,
+};
+
+use crate::config::AdversaryConfig;
+use crate::utils::parse_fingerprint;
+use spec::{AdversarySpec, RelaySpec};
+
+pub(crate) struct Adversary {
+    extra_relays: Vec<InjectedRelay>,
+    /// Fingerprints of the injected relays and the known compromised relays.
+    /// Relays matched by nickname are not included, as they depend on the
+    /// consensus (see `compromised_by_nickname`).
+    adversary_fingerprints: RHashSet<Fingerprint>,
+    /// Nicknames of compromised relays
+    compromised_nicknames: RegexSet,
+}
+
+/// A relay injected by the adversary, together with the times it is online
+struct InjectedRelay {
+    relay: Relay,
+    descriptor: Descriptor,
+    schedule: RelaySchedule,
+}
+
+/// The changes of an injected relay over time, keyed on the consensus `valid_after`
+#[derive(Debug, Default)]
+struct RelaySchedule {
+    /// The relay joins the network
+    online_from: Option<DateTime<Utc>>,
+    /// The relay leaves the network
+    online_until: Option<DateTime<Utc>>,
+    /// The relay gets the Guard flag (if it is configured to have it)
+    guard_from: Option<DateTime<Utc>>,
+    /// The relay loses the Guard flag
+    guard_until: Option<DateTime<Utc>>,
+    /// Initial weight and duration of the bandwidth ramp-up after joining
+    ramp_up: Option<(u64, Duration)>,
+}
+
+impl RelaySchedule {
+    fn is_static(&self) -> bool {
+        self.online_from.is_none()
+            && self.online_until.is_none()
+            && self.guard_from.is_none()
+            && self.guard_until.is_none()
+            && self.ramp_up.is_none()
+    }
+}
+
+/// Check if `time` lies within the (possibly open) interval
+fn in_window(
+    time: &DateTime<Utc>,
+    from: &Option<DateTime<Utc>>,
+    until: &Option<DateTime<Utc>>,
+) -> bool {
+    from.map_or(true, |from| *time >= from) && until.map_or(true, |until| *time <= until)
+}
+
+impl InjectedRelay {
+    /// Inject a relay into all consensuses
+    fn always((relay, descriptor): (Relay, Descriptor)) -> InjectedRelay {
+        InjectedRelay {
+            relay,
+            descriptor,
+            schedule: RelaySchedule::default(),
+        }
+    }
+
+    /// Get the consensus entry of the relay in the consensus that becomes
+    /// valid at `valid_after`, or `None` if it is not part of the network then.
+    fn relay_at(&self, valid_after: Option<&DateTime<Utc>>) -> Option<Relay> {
+        let schedule = &self.schedule;
+        let valid_after = match valid_after {
+            Some(valid_after) => valid_after,
+            None if schedule.is_static() => return Some(self.relay.clone()),
+            None => return None,
+        };
+
+        if !in_window(valid_after, &schedule.online_from, &schedule.online_until) {
+            return None;
+        }
+
+        let mut relay = self.relay.clone();
+
+        if !in_window(valid_after, &schedule.guard_from, &schedule.guard_until) {
+            if let Some(ref mut flags) = relay.flags {
+                flags.retain(|flag| flag != &Flag::Guard);
+            }
+        }
+
+        if let (Some((initial_weight, duration)), Some(online_from)) =
+            (schedule.ramp_up, schedule.online_from)
+        {
+            let elapsed = *valid_after - online_from;
+            if elapsed < duration {
+                let weight = relay.bandwidth_weight.unwrap_or(0);
+                let progress = elapsed.num_seconds() as f64 / duration.num_seconds().max(1) as f64;
+                relay.bandwidth_weight = Some(
+                    initial_weight
+                        + ((weight.saturating_sub(initial_weight)) as f64 * progress) as u64,
+                );
+            }
+        }
+
+        Some(relay)
+    }
+}
+
+impl Adversary {
+    /// Construct a new adversary object from the experiment configuration
+    pub fn new(config: &AdversaryConfig) -> anyhow::Result<Adversary> {
+        let mut extra_relays = Vec::new();
+
+        if let Some(adv_guards_num) = config.guards_num {
+            let adv_guards_bw = config.guards_bw.unwrap_or(0);
+
+            extra_relays.append(
+                &mut (1..=adv_guards_num)
+                    .into_iter()
+                    .map(|index| {
+                        Ok(InjectedRelay::always(make_adversarial_guard(
+                            index,
+                            adv_guards_bw,
+                        )?))
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            );
+        }
+
+        if let Some(adv_exits_num) = config.exits_num {
+            let adv_exits_bw = config.exits_bw.unwrap_or(0);
+
+            extra_relays.append(
+                &mut (1..=adv_exits_num)
+                    .into_iter()
+                    .map(|index| {
+                        Ok(InjectedRelay::always(make_adversarial_exit(
+                            index,
+                            config.guards_num.unwrap_or(0),
+                            adv_exits_bw,
+                        )?))
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            );
+        }
+
+        if let Some(adv_middles_num) = config.middles_num {
+            let adv_middles_bw = config.middles_bw.unwrap_or(0);
+
+            extra_relays.append(
+                &mut (1..=adv_middles_num)
+                    .into_iter()
+                    .map(|index| {
+                        Ok(InjectedRelay::always(make_adversarial_middle(
+                            index,
+                            config
+                                .guards_num
+                                .unwrap_or(0)
+                                .saturating_add(config.exits_num.unwrap_or(0)),
+                            adv_middles_bw,
+                        )?))
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            );
+        }
+
+        let spec = match config.spec {
+            Some(ref path) => AdversarySpec::from_file(path)?,
+            None => AdversarySpec::default(),
+        };
+        // the specified relays get their default addresses after the configured ones
+        let num_configured = config
+            .guards_num
+            .unwrap_or(0)
+            .saturating_add(config.exits_num.unwrap_or(0))
+            .saturating_add(config.middles_num.unwrap_or(0));
+        extra_relays.append(&mut make_specified_relays(&spec.relays, num_configured)?);
+
+        let mut adversary_fingerprints: RHashSet<Fingerprint> = extra_relays
+            .iter()
+            .map(|r| r.relay.fingerprint.as_ref().unwrap().clone())
+            .collect();
+        for fingerprint in spec.compromised.fingerprints.iter() {
+            adversary_fingerprints.insert(parse_fingerprint(fingerprint)?);
+        }
+
+        let compromised_nicknames = RegexSet::new(spec.compromised.nicknames.iter())
+            .context("Invalid nickname pattern for compromised relays")?;
+
+        info!(
+            "The adversary injects {} relays and controls {} existing relays (plus {} nickname patterns)",
+            extra_relays.len(),
+            spec.compromised.fingerprints.len(),
+            spec.compromised.nicknames.len()
+        );
+
+        Ok(Adversary {
+            extra_relays,
+            adversary_fingerprints,
+            compromised_nicknames,
+        })
+    }
+
+    /// The relays of a consensus that are compromised by their nickname.
+    ///
+    /// A relay only counts as compromised while it has a matching nickname,
+    /// so this is determined for every consensus separately.
+    pub fn compromised_by_nickname(&self, consensus: &Consensus) -> RHashSet<Fingerprint> {
+        if self.compromised_nicknames.is_empty() {
+            return RHashSet::default();
+        }
+        consensus
+            .relays
+            .iter()
+            .filter_map(|relay| match (&relay.nickname, &relay.fingerprint) {
+                (Some(nickname), Some(fingerprint))
+                    if self.compromised_nicknames.is_match(nickname) =>
+                {
+                    Some(fingerprint.clone())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Carry out modifications to the consensus, if necessary for the adversary
+    pub fn modify_consensus(&self, consensus: &mut Consensus, descriptors: &mut Vec<Descriptor>) {
+        let mut num_injected = 0;
+        for injected in self.extra_relays.iter() {
+            if let Some(relay) = injected.relay_at(consensus.valid_after.as_ref()) {
+                consensus.relays.push(relay);
+                descriptors.push(injected.descriptor.clone());
+                num_injected += 1;
+            }
+        }
+        debug!(
+            "Injected {} of {} adversarial relays into consensus {:?}",
+            num_injected,
+            self.extra_relays.len(),
+            consensus.valid_after
+        );
+
+        if num_injected > 0 {
+            bwweights::recompute_bw_weights(consensus);
+        }
+    }
+
+    /// Determine if a given fingerprint belongs to the adversary in every
+    /// consensus. Relays compromised by nickname are not considered here.
+    pub fn is_adversarial(&self, fingerprint: &Fingerprint) -> bool {
+        self.adversary_fingerprints.contains(fingerprint)
+    }
+}
+
+/// Generate the relays listed in an adversary specification.
+///
+/// Relays without configured addresses get them from `adversarial_addresses`,
+/// starting after the first `ip_offset` ones.
+fn make_specified_relays(
+    specs: &[RelaySpec],
+    ip_offset: u64,
+) -> anyhow::Result<Vec<InjectedRelay>> {
+    let mut relays = Vec::new();
+    // injected relays that declare a family, by family name
+    let mut families: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+
+    for spec in specs.iter() {
+        if spec.count > 1 && spec.fingerprint.is_some() {
+            anyhow::bail!("Cannot use the same fingerprint for multiple injected relays");
+        }
+
+        let flags = spec.parse_flags()?;
+        let exit_policy = match spec.exit_policy {
+            Some(ref policy) => CondensedExitPolicy::from_str(policy)
+                .map_err(|e| anyhow::anyhow!("Invalid exit policy {}: {:?}", policy, e))?,
+            None if flags.contains(&Flag::Exit) => CondensedExitPolicy::accept_all(),
+            None => CondensedExitPolicy::reject_all(),
+        };
+        let exit_policy6 = match spec.exit_policy6 {
+            Some(ref policy) => CondensedExitPolicy::from_str(policy)
+                .map_err(|e| anyhow::anyhow!("Invalid IPv6 exit policy {}: {:?}", policy, e))?,
+            None => exit_policy.clone(),
+        };
+        let addresses = spec.allocate_addresses()?;
+        let ipv6_addresses = spec.allocate_ipv6_addresses()?;
+        let online_from = spec.online_from.as_ref().map(|from| from.first_datetime());
+        let ramp_up = match spec.ramp_up_days {
+            Some(days) if online_from.is_none() => {
+                anyhow::bail!("A bandwidth ramp-up ({} days) requires online_from", days)
+            }
+            Some(days) => Some((
+                spec.initial_weight.unwrap_or(0),
+                Duration::seconds((days * 86400.0) as i64),
+            )),
+            None => None,
+        };
+
+        for i in 0..spec.count as usize {
+            // running index over all specified relays, used for default values
+            let index = relays.len() as u64 + 1;
+
+            let nickname = match spec.nickname {
+                Some(ref nickname) if spec.count > 1 => format!("{}{}", nickname, i + 1),
+                Some(ref nickname) => nickname.clone(),
+                None => format!("BadGuyRelay{}", index),
+            };
+            let fingerprint = match spec.fingerprint {
+                Some(ref fingerprint) => parse_fingerprint(fingerprint)?,
+                None => Fingerprint::from_str_hex(format!("{:A>40}", index)).unwrap(),
+            };
+            let (ip_address, ipv6_address) = match (&addresses, &ipv6_addresses) {
+                (Some(addresses), Some(ipv6_addresses)) => (addresses[i], ipv6_addresses[i]),
+                // give every other relay its own subnets
+                _ => {
+                    let (ip_address, ipv6_address) =
+                        adversarial_addresses(ip_offset.saturating_add(index))?;
+                    (
+                        addresses
+                            .as_ref()
+                            .map_or(ip_address, |addresses| addresses[i]),
+                        ipv6_addresses
+                            .as_ref()
+                            .map_or(ipv6_address, |addresses| addresses[i]),
+                    )
+                }
+            };
+
+            let relay = Relay {
+                nickname: Some(nickname.clone()),
+                fingerprint: Some(fingerprint.clone()),
+                digest: Some(fingerprint.clone()),
+                published: None,
+                address: Some(ip_address),
+                or_port: Some(spec.or_port),
+                dir_port: None,
+                flags: Some(flags.clone()),
+                version_line: None,
+                protocols: None,
+                exit_policy: Some(exit_policy.clone()),
+                bandwidth_weight: Some(spec.weight),
+            };
+
+            let descriptor = Descriptor {
+                nickname: Some(nickname.clone()),
+                fingerprint: Some(fingerprint.clone()),
+                digest: Some(fingerprint.clone()),
+                published: None,
+                or_addresses: Some(vec![
+                    OrAddress {
+                        ip: ip_address,
+                        port: spec.or_port,
+                    },
+                    OrAddress {
+                        ip: ipv6_address,
+                        port: spec.or_port,
+                    },
+                ]),
+                family_members: None,
+                bandwidth_avg: None,
+                bandwidth_burst: None,
+                bandwidth_observed: None,
+                exit_policy: None,
+                exit_policies_ipv6: Some(exit_policy6.clone()),
+            };
+
+            if let Some(ref family) = spec.family {
+                families
+                    .entry(family.clone())
+                    .or_default()
+                    .push(relays.len());
+            }
+
+            relays.push(InjectedRelay {
+                relay,
+                descriptor,
+                schedule: RelaySchedule {
+                    online_from,
+                    online_until: spec.online_until.as_ref().map(|x| x.last_datetime()),
+                    guard_from: spec.guard_from.as_ref().map(|x| x.first_datetime()),
+                    guard_until: spec.guard_until.as_ref().map(|x| x.last_datetime()),
+                    ramp_up,
+                },
+            });
+        }
+    }
+
+    // let the family members declare each other
+    for members in families.values() {
+        for &member in members.iter() {
+            let declared = members
+                .iter()
+                .filter(|&&other| other != member)
+                .map(|&other| format!("${}", relays[other].relay.fingerprint.as_ref().unwrap()))
+                .collect();
+            relays[member].descriptor.family_members = Some(declared);
+        }
+    }
+
+    Ok(relays)
+}
+
+/// Private and reserved IPv4 ranges the addresses of the configured
+/// adversarial relays are taken from
+const ADVERSARY_IPV4_RANGES: [(Ipv4Addr, u32); 2] = [
+    (Ipv4Addr::new(10, 0, 0, 0), 8),
+    (Ipv4Addr::new(240, 0, 0, 0), 4),
+];
+
+/// Get the IPv4 and IPv6 address of the `number`-th adversarial relay. The
+/// configured relays come first, followed by the specified ones without
+/// addresses of their own.
+///
+/// Every relay gets its own /16 (and IPv6 /32) subnet, so Tor does not prevent
+/// using several of them in the same circuit.
+fn adversarial_addresses(number: u64) -> anyhow::Result<(IpAddr, IpAddr)> {
+    let mut subnet = number;
+    for (network, prefix_len) in ADVERSARY_IPV4_RANGES {
+        let num_subnets = 1u64 << (16 - prefix_len);
+        if subnet < num_subnets {
+            let ip_address = Ipv4Addr::from(u32::from(network) | (subnet as u32) << 16 | 1);
+            // the second segment of the IPv6 /32 is the relay's number
+            let ipv6_subnet = u16::try_from(number)
+                .with_context(|| format!("No IPv6 subnet left for adversarial relay {}", number))?;
+            let ipv6_address = Ipv6Addr::new(0xfd00, ipv6_subnet, 0, 0, 0, 0, 0, 1);
+            return Ok((ip_address.into(), ipv6_address.into()));
+        }
+        subnet -= num_subnets;
+    }
+
+    anyhow::bail!(
+        "Too many adversarial relays (at most {} are supported)",
+        ADVERSARY_IPV4_RANGES
+            .iter()
+            .map(|(_, prefix_len)| 1u64 << (16 - prefix_len))
+            .sum::<u64>()
+            - 1
+    )
+}
+
+/// Generate a new (adversarial) guard relay and its descriptor
+fn make_adversarial_guard(index: u64, weight: u64) -> anyhow::Result<(Relay, Descriptor)> {
+    let nickname = format!("BadGuyGuard{}", index);
+    let fingerprint = Fingerprint::from_str_hex(format!("{:0>40}", index)).unwrap();
+    let (ip_address, ipv6_address) = adversarial_addresses(index)?;
+
+    let relay = Relay {
+        nickname: Some(nickname.clone()),
+        fingerprint: Some(fingerprint.clone()),
+        digest: Some(fingerprint.clone()),
+        published: None,
+        address: None,
+        or_port: None,
+        dir_port: None,
+        flags: Some(vec![
+            Flag::Fast,
+            Flag::Guard,
+            Flag::Running,
+            Flag::Stable,
+            Flag::Valid,
+        ]),
+        version_line: None,
+        protocols: None,
+        exit_policy: Some(CondensedExitPolicy::reject_all()),
+        bandwidth_weight: Some(weight),
+    };
+
+    let descriptor = Descriptor {
+        nickname: Some(nickname.clone()),
+        fingerprint: Some(fingerprint.clone()),
+        digest: Some(fingerprint.clone()),
+        published: None,
+        or_addresses: Some(vec![
+            OrAddress {
+                ip: ip_address,
+                port: 9001,
+            },
+            OrAddress {
+                ip: ipv6_address,
+                port: 9001,
+            },
+        ]),
+        family_members: None,
+        bandwidth_avg: None,
+        bandwidth_burst: None,
+        bandwidth_observed: None,
+        exit_policy: None,
+        exit_policies_ipv6: None,
+    };
+
+    Ok((relay, descriptor))
+}
+
+/// Generate a new (adversarial) exit relay and its descriptor
+fn make_adversarial_exit(
+    index: u64,
+    ip_offset: u64,
+    weight: u64,
+) -> anyhow::Result<(Relay, Descriptor)> {
+    let nickname = format!("BadGuyExit{}", index);
+    let fingerprint = Fingerprint::from_str_hex(format!("{:F>40}", index)).unwrap();
+    let (ip_address, ipv6_address) = adversarial_addresses(ip_offset.saturating_add(index))?;
+
+    let relay = Relay {
+        nickname: Some(nickname.clone()),
+        fingerprint: Some(fingerprint.clone()),
+        digest: Some(fingerprint.clone()),
+        published: None,
+        address: None,
+        or_port: None,
+        dir_port: None,
+        flags: Some(vec![
+            Flag::Fast,
+            Flag::Exit,
+            Flag::Running,
+            Flag::Stable,
+            Flag::Valid,
+        ]),
+        version_line: None,
+        protocols: None,
+        exit_policy: Some(CondensedExitPolicy::accept_all()),
+        bandwidth_weight: Some(weight),
+    };
+
+    let descriptor = Descriptor {
+        nickname: Some(nickname.clone()),
+        fingerprint: Some(fingerprint.clone()),
+        digest: Some(fingerprint.clone()),
+        published: None,
+        or_addresses: Some(vec![
+            OrAddress {
+                ip: ip_address,
+                port: 9001,
+            },
+            OrAddress {
+                ip: ipv6_address,
+                port: 9001,
+            },
+        ]),
+        family_members: None,
+        bandwidth_avg: None,
+        bandwidth_burst: None,
+        bandwidth_observed: None,
+        exit_policy: None,
+        exit_policies_ipv6: Some(CondensedExitPolicy::accept_all()),
+    };
+
+    Ok((relay, descriptor))
+}
+
+/// Generate a new (adversarial) middle relay and its descriptor.
+///
+/// The relay has neither the Guard nor the Exit flag and rejects all exit
+/// traffic, so it is only used in the middle position.
+fn make_adversarial_middle(
+    index: u64,
+    ip_offset: u64,
+    weight: u64,
+) -> anyhow::Result<(Relay, Descriptor)> {
+    let nickname = format!("BadGuyMiddle{}", index);
+    let fingerprint = Fingerprint::from_str_hex(format!("{:E>40}", index)).unwrap();
+    let (ip_address, ipv6_address) = adversarial_addresses(ip_offset.saturating_add(index))?;
+
+    let relay = Relay {
+        nickname: Some(nickname.clone()),
+        fingerprint: Some(fingerprint.clone()),
+        digest: Some(fingerprint.clone()),
+        published: None,
+        address: None,
+        or_port: None,
+        dir_port: None,
+        flags: Some(vec![Flag::Fast, Flag::Running, Flag::Stable, Flag::Valid]),
+        version_line: None,
+        protocols: None,
+        exit_policy: Some(CondensedExitPolicy::reject_all()),
+        bandwidth_weight: Some(weight),
+    };
+
+    let descriptor = Descriptor {
+        nickname: Some(nickname.clone()),
+        fingerprint: Some(fingerprint.clone()),
+        digest: Some(fingerprint.clone()),
+        published: None,
+        or_addresses: Some(vec![
+            OrAddress {
+                ip: ip_address,
+                port: 9001,
+            },
+            OrAddress {
+                ip: ipv6_address,
+                port: 9001,
+            },
+        ]),
+        family_members: None,
+        bandwidth_avg: None,
+        bandwidth_burst: None,
+        bandwidth_observed: None,
+        exit_policy: None,
+        exit_policies_ipv6: None,
+    };
+
+    Ok((relay, descriptor))
+}
+
+mod bwweights {
+    use std::cmp::{max, min};
+    use std::collections::BTreeMap;
+
+    use tordoc::consensus::Flag;
+    use tordoc::Consensus;
+
+    #[allow(non_snake_case)]
+    pub fn recompute_bw_weights(consensus: &mut Consensus) {
+        let mut Wmd: i64;
+        let mut Wed: i64;
+        let mut Wgd: i64;
+        let mut Wme: i64;
+        let mut Wee: i64;
+        let mut Wmg: i64;
+        let mut Wgg: i64;
+        // First, collect the total bandwidth values
+        let mut E = 1i64;
+        let mut G = 1i64;
+        let mut D = 1i64;
+        let mut M = 1i64;
+        for relay in consensus.relays.iter() {
+            let flags = relay.flags.as_ref().expect("relay missing flags");
+            let bandwidth_weight = relay
+                .bandwidth_weight
+                .expect("relay missing bandwidth weight") as i64;
+
+            let is_exit = flags.contains(&Flag::Exit) && !flags.contains(&Flag::BadExit);
+            if is_exit && flags.contains(&Flag::Guard) {
+                D += bandwidth_weight;
+            } else if is_exit {
+                E += bandwidth_weight;
+            } else if flags.contains(&Flag::Guard) {
+                G += bandwidth_weight;
+            } else {
+                M += bandwidth_weight;
+            }
+        }
+        let T = E + G + D + M;
+        let weightscale = 10000;
+
+        if 3 * E >= T && 3 * G >= T {
+            // Case 1: Neither are scarce
+            // casename = "Case 1 (Wgd=Wmd=Wed)"
+            Wmd = weightscale / 3;
+            Wed = weightscale / 3;
+            Wgd = weightscale / 3;
+            Wee = (weightscale * (E + G + M)) / (3 * E);
+            Wme = weightscale - Wee;
+            Wmg = (weightscale * (2 * G - E - M)) / (3 * G);
+            Wgg = weightscale - Wmg
+        } else if 3 * E < T && 3 * G < T {
+            // Case 2: Both Guards and Exits are scarce
+            // Balance D between E and G, depending upon D capacity and
+            // scarcity
+            let R = min(E, G);
+            let S = max(E, G);
+            if R + D < S {
+                // subcase a
+                Wgg = weightscale;
+                Wee = weightscale;
+                Wmd = 0;
+                Wme = 0;
+                Wmg = 0;
+                if E < G {
+                    // casename = "Case 2a (E scarce)"
+                    Wed = weightscale;
+                    Wgd = 0;
+                } else {
+                    // casename = "Case 2a (G scarce)"
+                    Wed = 0;
+                    Wgd = weightscale;
+                }
+            } else {
+                // subcase b R+D >= S
+                // casename = "Case 2b1 (Wgg=weightscale, Wmd=Wgd)"
+                Wee = (weightscale * (E - G + M)) / E;
+                Wed = (weightscale * (D - 2 * E + 4 * G - 2 * M)) / (3 * D);
+                Wme = (weightscale * (G - M)) / E;
+                Wmg = 0;
+                Wgg = weightscale;
+                Wgd = (weightscale - Wed) / 2;
+                Wmd = (weightscale - Wed) / 2;
+
+                if let Some(_) = check_weights_errors(
+                    Wgg,
+                    Wgd,
+                    Wmg,
+                    Wme,
+                    Wmd,
+                    Wee,
+                    Wed,
+                    weightscale,
+                    G,
+                    M,
+                    E,
+                    D,
+                    T,
+                    10,
+                    true,
+                ) {
+                    // casename = 'Case 2b2 (Wgg=weightscale, Wee=weightscale)'
+                    Wee = weightscale;
+                    Wgg = weightscale;
+                    Wed = (weightscale * (D - 2 * E + G + M)) / (3 * D);
+                    Wmd = (weightscale * (D - 2 * M + G + E)) / (3 * D);
+                    Wmg = 0;
+                    Wme = 0;
+                    if Wmd < 0 {
+                        // Too much bandwidth at middle position
+                        // casename = 'case 2b3 (Wmd=0)'
+                        Wmd = 0;
+                    }
+                    Wgd = weightscale - Wed - Wmd;
+                }
+
+                match check_weights_errors(
+                    Wgg,
+                    Wgd,
+                    Wmg,
+                    Wme,
+                    Wmd,
+                    Wee,
+                    Wed,
+                    weightscale,
+                    G,
+                    M,
+                    E,
+                    D,
+                    T,
+                    10,
+                    true,
+                ) {
+                    None | Some(BwwError::BalanceMid) => {}
+                    _ => {
+                        panic!("bw weight error");
+                    }
+                }
+            }
+        } else {
+            // if (E < T/3 or G < T/3)
+            // Case 3: Guard or Exit is scarce
+            let S = min(E, G);
+
+            if 3 * (S + D) < T {
+                // subcase a: S+D < T/3
+                if G < E {
+                    // casename = 'Case 3a (G scarce)'
+                    Wgd = weightscale;
+                    Wgg = weightscale;
+                    Wmg = 0;
+                    Wed = 0;
+                    Wmd = 0;
+
+                    if E < M {
+                        Wme = 0;
+                    } else {
+                        Wme = (weightscale * (E - M)) / (2 * E);
+                    }
+                    Wee = weightscale - Wme;
+                } else {
+                    // G >= E
+                    // casename = "Case 3a (E scarce)"
+                    Wed = weightscale;
+                    Wee = weightscale;
+                    Wme = 0;
+                    Wgd = 0;
+                    Wmd = 0;
+                    if G < M {
+                        Wmg = 0;
+                    } else {
+                        Wmg = (weightscale * (G - M)) / (2 * G);
+                    }
+                    Wgg = weightscale - Wmg;
+                }
+            } else {
+                // subcase S+D >= T/3
+                if G < E {
+                    // casename = 'Case 3bg (G scarce, Wgg=weightscale, Wmd == Wed'
+                    Wgg = weightscale;
+                    Wgd = (weightscale * (D - 2 * G + E + M)) / (3 * D);
+                    Wmg = 0;
+                    Wee = (weightscale * (E + M)) / (2 * E);
+                    Wme = weightscale - Wee;
+                    Wed = (weightscale - Wgd) / 2;
+                    Wmd = (weightscale - Wgd) / 2;
+                } else {
+                    // G >= E
+                    // casename = 'Case 3be (E scarce, Wee=weightscale, Wmd == Wgd'
+                    Wee = weightscale;
+                    Wed = (weightscale * (D - 2 * E + G + M)) / (3 * D);
+                    Wme = 0;
+                    Wgg = (weightscale * (G + M)) / (2 * G);
+                    Wmg = weightscale - Wgg;
+                    Wgd = (weightscale - Wed) / 2;
+                    Wmd = (weightscale - Wed) / 2;
+                }
+            }
+        }
+
+        consensus.weights = Some(BTreeMap::from_iter(
+            [
+                ("Wbd", Wmd),
+                ("Wbe", Wme),
+                ("Wbg", Wmg),
+                ("Wbm", weightscale),
+                ("Wdb", weightscale),
+                ("Web", weightscale),
+                ("Wed", Wed),
+                ("Wee", Wee),
+                ("Weg", Wed),
+                ("Wem", Wee),
+                ("Wgb", weightscale),
+                ("Wgd", Wgd),
+                ("Wgg", Wgg),
+                ("Wgm", Wgg),
+                ("Wmb", weightscale),
+                ("Wmd", Wmd),
+                ("Wme", Wme),
+                ("Wmg", Wmg),
+                ("Wmm", weightscale),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v as u64)),
+        ));
+        //    /*
+        //    * Provide Wgm=Wgg, Wmm=weight_scale, Wem=Wee, Weg=Wed. May later determine
+        //    * that middle nodes need different bandwidth weights for dirport traffic,
+        //    * or that weird exit policies need special weight, or that bridges
+        //    * need special weight.
+        //    *
+        //    * NOTE: This list is sorted.
+        //    */
+        //   smartlist_add_asprintf(chunks,
+        //     "bandwidth-weights Wbd=%d Wbe=%d Wbg=%d Wbm=%d "
+        //     "Wdb=%d "
+        //     "Web=%d Wed=%d Wee=%d Weg=%d Wem=%d "
+        //     "Wgb=%d Wgd=%d Wgg=%d Wgm=%d "
+        //     "Wmb=%d Wmd=%d Wme=%d Wmg=%d Wmm=%d\n",
+        //     (int)Wmd, (int)Wme, (int)Wmg, (int)weight_scale,
+        //     (int)weight_scale,
+        //     (int)weight_scale, (int)Wed, (int)Wee, (int)Wed, (int)Wee,
+        //     (int)weight_scale, (int)Wgd, (int)Wgg, (int)Wgg,
+        //     (int)weight_scale, (int)Wmd, (int)Wme, (int)Wmg, (int)weight_scale);
+    }
+
+    fn check_eq(a: i64, b: i64, margin: i64) -> bool {
+        if (a - b) >= 0 {
+            (a - b) <= margin
+        } else {
+            (b - a) <= margin
+        }
+    }
+    fn check_range(a: i64, b: i64, c: i64, d: i64, e: i64, f: i64, g: i64, mx: i64) -> bool {
+        a >= 0
+            && a <= mx
+            && b >= 0
+            && b <= mx
+            && c >= 0
+            && c <= mx
+            && d >= 0
+            && d <= mx
+            && e >= 0
+            && e <= mx
+            && f >= 0
+            && f <= mx
+            && g >= 0
+            && g <= mx
+    }
+
+    #[derive(Debug, PartialEq, Copy, Clone)]
+    enum BwwError {
+        SumD,
+        SumG,
+        SumE,
+        Range,
+        BalanceEg,
+        BalanceMid,
+    }
+
+    /// Verify that our weights satify the formulas from dir-spec.txt
+    #[allow(non_snake_case)]
+    fn check_weights_errors(
+        Wgg: i64,
+        Wgd: i64,
+        Wmg: i64,
+        Wme: i64,
+        Wmd: i64,
+        Wee: i64,
+        Wed: i64,
+        weightscale: i64,
+        G: i64,
+        M: i64,
+        E: i64,
+        D: i64,
+        T: i64,
+        margin: i64,
+        do_balance: bool,
+    ) -> Option<BwwError> {
+        // # Wed + Wmd + Wgd == weightscale
+        if !check_eq(Wed + Wmd + Wgd, weightscale, margin) {
+            return Some(BwwError::SumD);
+        }
+        // # Wmg + Wgg == weightscale
+        if !check_eq(Wmg + Wgg, weightscale, margin) {
+            return Some(BwwError::SumG);
+        }
+        // # Wme + Wee == weightscale
+        if !check_eq(Wme + Wee, weightscale, margin) {
+            return Some(BwwError::SumE);
+        }
+        // # Verify weights within range 0 -> weightscale
+        if !check_range(Wgg, Wgd, Wmg, Wme, Wmd, Wed, Wee, weightscale) {
+            return Some(BwwError::Range);
+        }
+        if do_balance {
+            // #Wgg*G + Wgd*D == Wee*E + Wed*D
+            if !check_eq(Wgg * G + Wgd * D, Wee * E + Wed * D, (margin * T) / 3) {
+                return Some(BwwError::BalanceEg);
+            }
+            // #Wgg*G+Wgd*D == M*weightscale + Wmd*D + Wme * E + Wmg*G
+            if !check_eq(
+                Wgg * G + Wgd * D,
+                M * weightscale + Wmd * D + Wme * E + Wmg * G,
+                (margin * T) / 3,
+            ) {
+                return Some(BwwError::BalanceMid);
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,239 @@
+//! File-based specification of the adversary
+//!
+//! Instead of a number of identical guards and exits, the adversary can be
+//! described by a TOML (or JSON) file that lists the injected relays
+//! individually, as well as existing relays that are considered compromised:
+//!
+//! ```toml
+//! [[relays]]
+//! count = 5
+//! nickname = "BadGuard"
+//! flags = ["Fast", "Guard", "Running", "Stable", "Valid"]
+//! weight = 20000
+//! address = "10.1.0.0/24"
+//! family = "guards"
+//! online_from = "2023-01-15"
+//! initial_weight = 20
+//! ramp_up_days = 14
+//! guard_from = "2023-01-23"
+//!
+//! [[relays]]
+//! fingerprint = "0123456789ABCDEF0123456789ABCDEF01234567"
+//! flags = ["Exit", "Fast", "Running", "Stable", "Valid"]
+//! weight = 50000
+//! exit_policy = "accept 80,443"
+//! exit_policy6 = "accept 443"
+//! address6 = "2001:db8::/64"
+//! guard_until = "2023-01-20"
+//! online_until = "2023-01-31"
+//!
+//! [compromised]
+//! fingerprints = ["89ABCDEF0123456789ABCDEF0123456789ABCDEF"]
+//! nicknames = ["^evil[0-9]+$"]
+//! ```
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::{self, Context};
+use serde::Deserialize;
+use tordoc::consensus::Flag;
+
+use crate::cli::SimulationRangeEdge;
+use crate::config::is_json;
+
+/// The complete adversary specification
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AdversarySpec {
+    /// Relays that are injected into the network
+    pub relays: Vec<RelaySpec>,
+    /// Existing relays that are controlled by the adversary
+    pub compromised: CompromisedSpec,
+}
+
+/// One (or more identical) injected relays
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RelaySpec {
+    /// Number of relays created from this entry
+    #[serde(default = "default_count")]
+    pub count: u64,
+    /// Nickname. If multiple relays are created, their index is appended.
+    pub nickname: Option<String>,
+    /// Fingerprint (hex). Only allowed for a single relay.
+    pub fingerprint: Option<String>,
+    /// Consensus flags, e.g. "Guard" or "Exit"
+    pub flags: Vec<String>,
+    /// Consensus weight (after the ramp-up, if any)
+    pub weight: u64,
+    /// Consensus weight when the relays join the network. The weight grows
+    /// linearly to `weight` over `ramp_up_days`, like the weight of new relays
+    /// that are measured by the bandwidth authorities. [default: 0]
+    pub initial_weight: Option<u64>,
+    /// Duration of the bandwidth ramp-up, in days. Requires `online_from`.
+    pub ramp_up_days: Option<f64>,
+    /// Exit policy summary as in the consensus, e.g. "accept 80,443". If
+    /// missing, everything is accepted for exits and rejected otherwise.
+    pub exit_policy: Option<String>,
+    /// IPv6 exit policy summary, e.g. "accept 443". If missing, the same as
+    /// `exit_policy`.
+    pub exit_policy6: Option<String>,
+    /// IP address or subnet (CIDR notation) to allocate the addresses from.
+    /// If missing, each relay gets an address in its own /16 of a private or
+    /// reserved range.
+    pub address: Option<String>,
+    /// IPv6 address or subnet to allocate additional addresses from. If
+    /// missing, each relay gets a unique local address in its own /32.
+    pub address6: Option<String>,
+    /// OR port
+    #[serde(default = "default_or_port")]
+    pub or_port: u16,
+    /// Name of the family. All injected relays with the same family name
+    /// declare each other as family members.
+    pub family: Option<String>,
+    /// The relays are part of consensuses that become valid from this time on
+    pub online_from: Option<SimulationRangeEdge>,
+    /// The relays are part of consensuses that become valid until this time
+    pub online_until: Option<SimulationRangeEdge>,
+    /// Consensuses that become valid from this time on assign the Guard flag,
+    /// if it is among `flags`
+    pub guard_from: Option<SimulationRangeEdge>,
+    /// Consensuses that become valid after this time do not assign the Guard flag
+    pub guard_until: Option<SimulationRangeEdge>,
+}
+
+/// Existing relays that count as adversarial
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CompromisedSpec {
+    /// Fingerprints (hex) of compromised relays
+    pub fingerprints: Vec<String>,
+    /// Regular expressions matching the nicknames of compromised relays. A
+    /// relay is compromised in the consensuses in which its nickname matches.
+    pub nicknames: Vec<String>,
+}
+
+fn default_count() -> u64 {
+    1
+}
+
+fn default_or_port() -> u16 {
+    9001
+}
+
+impl AdversarySpec {
+    /// Load an adversary specification from a TOML or JSON file
+    pub(crate) fn from_file(path: impl AsRef<Path>) -> anyhow::Result<AdversarySpec> {
+        let path = path.as_ref();
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read adversary file {}", path.display()))?;
+
+        let spec = if is_json(path) {
+            serde_json::from_str(&raw)?
+        } else {
+            toml::from_str(&raw)?
+        };
+
+        Ok(spec)
+    }
+}
+
+impl RelaySpec {
+    /// Parse the given flags
+    pub(crate) fn parse_flags(&self) -> anyhow::Result<Vec<Flag>> {
+        self.flags.iter().map(|name| parse_flag(name)).collect()
+    }
+
+    /// Get the addresses of the relays created from this entry
+    pub(crate) fn allocate_addresses(&self) -> anyhow::Result<Option<Vec<IpAddr>>> {
+        match self.address {
+            Some(ref address) => Ok(Some(allocate(address, self.count)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the additional IPv6 addresses of the relays created from this entry
+    pub(crate) fn allocate_ipv6_addresses(&self) -> anyhow::Result<Option<Vec<IpAddr>>> {
+        let address = match self.address6 {
+            Some(ref address) => address,
+            None => return Ok(None),
+        };
+        let addresses = allocate(address, self.count)?;
+        if addresses.iter().any(|ip| !ip.is_ipv6()) {
+            anyhow::bail!("Not an IPv6 relay address: {}", address);
+        }
+        Ok(Some(addresses))
+    }
+}
+
+/// Allocate addresses for `count` relays from an address or subnet
+fn allocate(address: &str, count: u64) -> anyhow::Result<Vec<IpAddr>> {
+    // a single address is shared by all relays
+    let (network, prefix_len) = match address.split_once('/') {
+        None => {
+            let ip: IpAddr = address
+                .parse()
+                .with_context(|| format!("Invalid relay address {}", address))?;
+            return Ok(vec![ip; count as usize]);
+        }
+        Some((network, prefix_len)) => (network, prefix_len),
+    };
+
+    let network: IpAddr = network
+        .parse()
+        .with_context(|| format!("Invalid relay subnet {}", address))?;
+    let prefix_len: u32 = prefix_len
+        .parse()
+        .with_context(|| format!("Invalid relay subnet {}", address))?;
+
+    let (network_bits, address_bits) = match network {
+        IpAddr::V4(net) => (u32::from(net) as u128, 32),
+        IpAddr::V6(net) => (u128::from(net), 128),
+    };
+    if prefix_len > address_bits {
+        anyhow::bail!("Invalid relay subnet {}", address);
+    }
+    let host_bits = address_bits - prefix_len;
+    let host_mask = u128::MAX.checked_shr(128 - host_bits).unwrap_or(0);
+    // leave out the network address itself
+    if count as u128 > host_mask {
+        anyhow::bail!("Subnet {} is too small for {} relays", address, count);
+    }
+
+    // the address may be given with host bits set (e.g. 10.0.0.200/24)
+    let base = network_bits & !host_mask;
+    (1..=count as u128)
+        .map(|i| {
+            let host = base
+                .checked_add(i)
+                .with_context(|| format!("Subnet {} is too small for {} relays", address, count))?;
+            Ok(match network {
+                IpAddr::V4(_) => IpAddr::V4(u32::try_from(host)?.into()),
+                IpAddr::V6(_) => IpAddr::V6(host.into()),
+            })
+        })
+        .collect()
+}
+
+/// Parse the name of a consensus flag
+fn parse_flag(name: &str) -> anyhow::Result<Flag> {
+    Ok(match name {
+        "Authority" => Flag::Authority,
+        "BadExit" => Flag::BadExit,
+        "Exit" => Flag::Exit,
+        "Fast" => Flag::Fast,
+        "Guard" => Flag::Guard,
+        "HSDir" => Flag::HSDir,
+        "MiddleOnly" => Flag::MiddleOnly,
+        "NoEdConsensus" => Flag::NoEdConsensus,
+        "Running" => Flag::Running,
+        "Stable" => Flag::Stable,
+        "StaleDesc" => Flag::StaleDesc,
+        "Sybil" => Flag::Sybil,
+        "V2Dir" => Flag::V2Dir,
+        "Valid" => Flag::Valid,
+        _ => anyhow::bail!("Unknown relay flag {}", name),
+    })
+}
@@ -0,0 +1,253 @@
+//! Clients that connect to the Tor network through bridges
+//!
+//! Instead of choosing guards from the public consensus, bridge users pick a
+//! few bridges from a configured bridge list and always use the first of them
+//! that is reachable as their first hop. Bridges are not part of the
+//! consensus, so their reachability is only learned from connection attempts
+//! (see `crate::reachability`). Unreachable bridges are retried with the
+//! schedule of primary guards.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use tordoc::Fingerprint;
+
+use crate::config::BridgeConfig;
+use crate::guard::{retry_interval, Reachability};
+use crate::observer::ClientObserver;
+use crate::reachability::ReachabilityModel;
+use crate::rng::get_rng;
+use crate::utils::{from_micros, parse_fingerprint, to_micros};
+
+/// A bridge from the configured bridge list
+#[derive(Debug, Clone)]
+pub(crate) struct Bridge {
+    pub fingerprint: Fingerprint,
+    /// The pluggable transport used to connect to this bridge, if any
+    pub transport: Option<String>,
+}
+
+/// All the bridges that clients can choose from
+#[derive(Debug, Default)]
+pub(crate) struct BridgeSet {
+    bridges: Vec<Bridge>,
+    /// Fraction of clients that use bridges
+    users_fraction: f64,
+    /// Number of bridges each bridge user configures
+    per_client: usize,
+}
+
+impl BridgeSet {
+    pub(crate) fn new(config: &BridgeConfig) -> anyhow::Result<BridgeSet> {
+        let bridges = config
+            .list
+            .iter()
+            .map(|bridge| -> anyhow::Result<_> {
+                Ok(Bridge {
+                    fingerprint: parse_fingerprint(&bridge.fingerprint)?,
+                    transport: bridge.transport.clone(),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        if config.users_fraction > 0.0 && bridges.is_empty() {
+            anyhow::bail!("Bridge users configured, but no bridges given (bridges.list)");
+        }
+        if !(0.0..=1.0).contains(&config.users_fraction) {
+            anyhow::bail!("Invalid fraction of bridge users {}", config.users_fraction);
+        }
+        if config.per_client == 0 {
+            anyhow::bail!("Bridge users need at least one bridge (bridges.per_client)");
+        }
+
+        Ok(BridgeSet {
+            bridges,
+            users_fraction: config.users_fraction,
+            per_client: config.per_client,
+        })
+    }
+
+    pub(crate) fn users_fraction(&self) -> f64 {
+        self.users_fraction
+    }
+
+    /// Choose the bridges a new bridge user configures
+    pub(crate) fn choose_for_client(&self) -> Vec<Bridge> {
+        self.bridges
+            .choose_multiple(&mut get_rng(), self.per_client)
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct ClientBridge {
+    fingerprint: Fingerprint,
+    is_reachable: Reachability,
+    /// When the bridge became unreachable
+    failing_since: Option<DateTime<Utc>>,
+    /// Last (failed) connection attempt
+    last_tried_connect: Option<DateTime<Utc>>,
+}
+
+impl ClientBridge {
+    fn set_unreachable(&mut self, now: &DateTime<Utc>) {
+        self.is_reachable = Reachability::No;
+        if self.failing_since.is_none() {
+            self.failing_since = Some(*now);
+        }
+        self.last_tried_connect = Some(*now);
+    }
+}
+
+/// The bridges configured by a single client, replacing its guards
+#[derive(Debug)]
+pub(crate) struct BridgeHandling {
+    bridges: Vec<ClientBridge>,
+    reachability: Arc<ReachabilityModel>,
+    /// This client's individual probability of failing to connect to a bridge
+    client_failure_probability: f64,
+}
+
+/// The bridges of a client, as stored in checkpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct BridgeHandlingState {
+    bridges: Vec<ClientBridgeState>,
+    client_failure_probability: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ClientBridgeState {
+    fingerprint: String,
+    is_reachable: Reachability,
+    failing_since: Option<i64>,
+    last_tried_connect: Option<i64>,
+}
+
+impl BridgeHandling {
+    pub fn new(bridges: &[Bridge], reachability: Arc<ReachabilityModel>) -> BridgeHandling {
+        BridgeHandling {
+            bridges: bridges
+                .iter()
+                .map(|bridge| ClientBridge {
+                    fingerprint: bridge.fingerprint.clone(),
+                    is_reachable: Reachability::Maybe,
+                    failing_since: None,
+                    last_tried_connect: None,
+                })
+                .collect(),
+            client_failure_probability: reachability.sample_client_failure_probability(),
+            reachability,
+        }
+    }
+
+    /// Export the state of the client's bridges
+    pub fn to_state(&self) -> BridgeHandlingState {
+        BridgeHandlingState {
+            bridges: self
+                .bridges
+                .iter()
+                .map(|bridge| ClientBridgeState {
+                    fingerprint: bridge.fingerprint.to_string(),
+                    is_reachable: bridge.is_reachable,
+                    failing_since: bridge.failing_since.as_ref().map(to_micros),
+                    last_tried_connect: bridge.last_tried_connect.as_ref().map(to_micros),
+                })
+                .collect(),
+            client_failure_probability: self.client_failure_probability,
+        }
+    }
+
+    /// Continue with the exported state of a client's bridges
+    pub fn from_state(
+        state: &BridgeHandlingState,
+        reachability: Arc<ReachabilityModel>,
+    ) -> anyhow::Result<BridgeHandling> {
+        Ok(BridgeHandling {
+            bridges: state
+                .bridges
+                .iter()
+                .map(|bridge| -> anyhow::Result<_> {
+                    Ok(ClientBridge {
+                        fingerprint: parse_fingerprint(&bridge.fingerprint)?,
+                        is_reachable: bridge.is_reachable,
+                        failing_since: bridge.failing_since.map(from_micros).transpose()?,
+                        last_tried_connect: bridge
+                            .last_tried_connect
+                            .map(from_micros)
+                            .transpose()?,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?,
+            client_failure_probability: state.client_failure_probability,
+            reachability,
+        })
+    }
+
+    /// Mark unreachable bridges for retrying, according to the retry schedule
+    pub fn timed_updates(&mut self, now: &DateTime<Utc>) {
+        for bridge in self.bridges.iter_mut() {
+            if let (Reachability::No, Some(failing_since), Some(last_tried)) = (
+                bridge.is_reachable,
+                bridge.failing_since,
+                bridge.last_tried_connect,
+            ) {
+                if *now - last_tried >= retry_interval(*now - failing_since, true) {
+                    bridge.is_reachable = Reachability::Maybe;
+                }
+            }
+        }
+    }
+
+    /// Select the bridge for a new circuit and connect to it.
+    ///
+    /// Bridges are tried in the configured order. Returns `None` if no
+    /// bridge is reachable.
+    pub fn get_bridge_for_circuit(
+        &mut self,
+        now: &DateTime<Utc>,
+        observer: &mut ClientObserver,
+    ) -> Option<Fingerprint> {
+        for bridge in self.bridges.iter_mut() {
+            if bridge.is_reachable == Reachability::No {
+                continue;
+            }
+
+            if self.reachability.try_connect(
+                &bridge.fingerprint,
+                now,
+                self.client_failure_probability,
+            ) {
+                bridge.is_reachable = Reachability::Yes;
+                bridge.failing_since = None;
+                bridge.last_tried_connect = None;
+                return Some(bridge.fingerprint.clone());
+            }
+
+            bridge.set_unreachable(now);
+            observer.notify_bridge_unreachable(now, &bridge.fingerprint);
+        }
+
+        None
+    }
+
+    /// Mark a bridge as unreachable after a circuit through it failed at the
+    /// first hop
+    pub fn mark_as_failed(
+        &mut self,
+        bridge: &Fingerprint,
+        now: &DateTime<Utc>,
+        observer: &mut ClientObserver,
+    ) {
+        if let Some(client_bridge) = self
+            .bridges
+            .iter_mut()
+            .find(|client_bridge| &client_bridge.fingerprint == bridge)
+        {
+            client_bridge.set_unreachable(now);
+            observer.notify_bridge_unreachable(now, bridge);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Checkpoints of the simulation state at epoch boundaries
+//!
+//! Long simulations periodically store everything needed to continue after
+//! the last handled epoch: the state of every client (random number
+//! generator, user model, circuits, needs, guards, trace IDs, and what its
+//! observer collected so far), the exit IDs of every scenario, and how far
+//! each trace had been written. When resuming, the traces are truncated to
+//! that size and continued.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::{self, Context};
+use chrono::{DateTime, Utc};
+#[allow(unused_imports)]
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::client::ClientState;
+use crate::observer::ExitIdsState;
+use crate::user::PrivcountUserState;
+use crate::utils::{from_micros, to_micros};
+
+/// Version of the on-disk format. Checkpoints with a different version cannot be resumed.
+const FORMAT_VERSION: u32 = 10;
+
+/// The complete state of a simulation after an epoch
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    format_version: u32,
+    pub seed: u64,
+    /// Start of the simulation, in microseconds since the UNIX epoch
+    start_time: i64,
+    /// End of the simulation
+    end_time: i64,
+    /// Publication time of the last consensus that was simulated
+    last_consensus: i64,
+    /// Time until which the clients have handled their requests
+    simulated_until: i64,
+    pub coverage: CoverageState,
+    pub scenarios: Vec<ScenarioCheckpoint>,
+}
+
+/// How the simulated time was covered by consensuses so far, in seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CoverageState {
+    pub fresh: i64,
+    pub stale: i64,
+    pub skipped: i64,
+    pub num_gaps: usize,
+}
+
+/// The state of a scenario, with all its trials
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ScenarioCheckpoint {
+    pub exit_ids: ExitIdsState,
+    pub trials: Vec<TrialCheckpoint>,
+}
+
+/// The state of a single trial
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TrialCheckpoint {
+    /// Size of the trace file (in bytes) when the checkpoint was written
+    pub trace_offset: u64,
+    /// The guard sets shared by the clients (guard sets policy only)
+    pub guard_sets: Option<Vec<Vec<String>>>,
+    pub clients: Vec<ClientState<PrivcountUserState>>,
+}
+
+impl Checkpoint {
+    pub(crate) fn new(
+        seed: u64,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+        last_consensus: &DateTime<Utc>,
+        simulated_until: &DateTime<Utc>,
+        coverage: CoverageState,
+        scenarios: Vec<ScenarioCheckpoint>,
+    ) -> Checkpoint {
+        Checkpoint {
+            format_version: FORMAT_VERSION,
+            seed,
+            start_time: to_micros(start_time),
+            end_time: to_micros(end_time),
+            last_consensus: to_micros(last_consensus),
+            simulated_until: to_micros(simulated_until),
+            coverage,
+            scenarios,
+        }
+    }
+
+    /// Publication time of the last consensus that was simulated. The
+    /// simulation continues with the consensus after it.
+    pub(crate) fn last_consensus(&self) -> anyhow::Result<DateTime<Utc>> {
+        from_micros(self.last_consensus)
+    }
+
+    /// Time until which the clients have handled their requests
+    pub(crate) fn simulated_until(&self) -> anyhow::Result<DateTime<Utc>> {
+        from_micros(self.simulated_until)
+    }
+
+    /// Make sure the checkpoint was written by a simulation of the same time range
+    pub(crate) fn check_time_range(
+        &self,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        if self.start_time != to_micros(start_time) || self.end_time != to_micros(end_time) {
+            anyhow::bail!(
+                "The checkpoint was written by a simulation from {} until {}, not from {} until {}",
+                from_micros(self.start_time)?,
+                from_micros(self.end_time)?,
+                start_time,
+                end_time
+            );
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read(path: impl AsRef<Path>) -> anyhow::Result<Checkpoint> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Cannot read checkpoint {}", path.display()))?;
+        let mut decoder = zstd::Decoder::new(BufReader::new(file))?;
+
+        let checkpoint: Checkpoint = bincode::deserialize_from(&mut decoder)
+            .with_context(|| format!("Broken checkpoint {}", path.display()))?;
+        if checkpoint.format_version != FORMAT_VERSION {
+            anyhow::bail!(
+                "Checkpoint {} has unsupported format version {}",
+                path.display(),
+                checkpoint.format_version
+            );
+        }
+
+        Ok(checkpoint)
+    }
+
+    pub(crate) fn write(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+
+        // write to a temporary file first so a crash never destroys the previous checkpoint
+        let tmp_path = path.with_extension("tmp");
+        {
+            let file = File::create(&tmp_path)
+                .with_context(|| format!("Cannot write checkpoint {}", tmp_path.display()))?;
+            let mut encoder = zstd::Encoder::new(BufWriter::new(file), 3)?;
+            bincode::serialize_into(&mut encoder, self)?;
+            encoder.finish()?;
+        }
+        fs::rename(tmp_path, path)?;
+
+        Ok(())
+    }
+}
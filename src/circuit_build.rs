@@ -0,0 +1,222 @@
+//! Simulated construction of circuits and the circuit build timeout
+//!
+//! A circuit is extended hop by hop. Each extension takes a log-normally
+//! distributed time and fails with a fixed probability. Like Tor's
+//! circuitstats.c, every client learns its circuit build timeout (CBT) from
+//! the build times it observes: they are fitted to a Pareto distribution,
+//! and the timeout is set to a quantile of it (see path-spec.txt, section
+//! 2.4). Circuits that take longer than the timeout are abandoned.
+//!
+//! Deviations from spec: Abandoned circuits are not kept open to measure
+//! their full build time. Instead, they count as taking as long as the
+//! slowest completed circuit. A circuit is available at the time it was
+//! requested; its build time only feeds the timeout estimation.
+
+use std::collections::VecDeque;
+
+use fxhash::FxHashMap;
+use lazy_static::lazy_static;
+use rand::Rng;
+use rand_distr::{Distribution, LogNormal};
+use serde::{Deserialize, Serialize};
+
+use crate::config::protocol_parameters;
+use crate::rng::get_rng;
+
+lazy_static! {
+    static ref ENABLED: bool = protocol_parameters().circuit_build.enabled;
+    static ref HOP_LATENCY: LogNormal<f64> = LogNormal::new(
+        protocol_parameters().circuit_build.hop_latency_median_ms.ln(),
+        protocol_parameters().circuit_build.hop_latency_sigma,
+    )
+    .unwrap(); // the parameters are checked when the configuration is loaded
+    static ref EXTEND_FAILURE_PROBABILITY: f64 =
+        protocol_parameters().circuit_build.extend_failure_probability;
+    static ref CBT_HISTORY: usize = protocol_parameters().circuit_build.cbt_history;
+    static ref CBT_MIN_CIRCUITS: usize = protocol_parameters().circuit_build.cbt_min_circuits;
+    static ref CBT_QUANTILE: f64 = protocol_parameters().circuit_build.cbt_quantile / 100.0;
+    static ref CBT_INITIAL_TIMEOUT: f64 = protocol_parameters().circuit_build.cbt_initial_timeout_ms;
+    static ref CBT_MIN_TIMEOUT: f64 = protocol_parameters().circuit_build.cbt_min_timeout_ms;
+    static ref CBT_NUM_MODES: usize = protocol_parameters().circuit_build.cbt_num_modes;
+    static ref CBT_RECENT_COUNT: usize = protocol_parameters().circuit_build.cbt_recent_count;
+    static ref CBT_MAX_TIMEOUTS: usize = protocol_parameters().circuit_build.cbt_max_timeouts;
+}
+
+/// CBT_BIN_WIDTH, in milliseconds
+const BIN_WIDTH: u32 = 10;
+
+/// Marks an abandoned circuit in the build time history (CBT_BUILD_ABANDONED)
+const ABANDONED: u32 = u32::MAX;
+
+/// Check if the construction of circuits is simulated at all. Otherwise,
+/// every circuit is built instantly and successfully.
+pub(crate) fn is_enabled() -> bool {
+    *ENABLED
+}
+
+/// The result of an attempt to build a circuit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BuildOutcome {
+    /// The circuit was completed, taking this long (in milliseconds)
+    Built(f64),
+    /// Extending the circuit to the hop with this index failed (0 is the first hop)
+    Failed(usize),
+    /// The circuit was abandoned while waiting for the hop with this index
+    TimedOut(usize),
+}
+
+/// Simulate building a circuit with the given number of hops, abandoning it
+/// after `timeout_ms` milliseconds
+pub(crate) fn simulate_build(num_hops: usize, timeout_ms: f64) -> BuildOutcome {
+    if !is_enabled() {
+        return BuildOutcome::Built(0.0);
+    }
+
+    let mut rng = get_rng();
+    let mut elapsed = 0.0;
+    for hop in 0..num_hops {
+        elapsed += HOP_LATENCY.sample(&mut rng);
+        // a late answer is not waited for, even if it reports a failure
+        if elapsed > timeout_ms {
+            return BuildOutcome::TimedOut(hop);
+        }
+        if rng.gen_bool(*EXTEND_FAILURE_PROBABILITY) {
+            return BuildOutcome::Failed(hop);
+        }
+    }
+
+    BuildOutcome::Built(elapsed)
+}
+
+/// The circuit build timeout of a client, learned from its recent circuits
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct BuildTimeEstimator {
+    /// Build times of the most recent circuits, in milliseconds, or
+    /// `ABANDONED` if they timed out
+    history: VecDeque<u32>,
+    /// Whether the most recent circuits timed out, to detect network changes
+    recent_timeouts: VecDeque<bool>,
+    /// The current timeout, in milliseconds
+    timeout_ms: f64,
+}
+
+impl BuildTimeEstimator {
+    pub fn new() -> BuildTimeEstimator {
+        BuildTimeEstimator {
+            history: VecDeque::new(),
+            recent_timeouts: VecDeque::new(),
+            timeout_ms: *CBT_INITIAL_TIMEOUT,
+        }
+    }
+
+    /// The current timeout, in milliseconds
+    pub fn timeout_ms(&self) -> f64 {
+        self.timeout_ms
+    }
+
+    /// Learn from a completed circuit.
+    ///
+    /// Returns `true` if the timeout changed.
+    pub fn record_built(&mut self, build_ms: f64) -> bool {
+        self.add(build_ms.round().min((ABANDONED - 1) as f64) as u32);
+        self.add_recent(false);
+        self.update_timeout()
+    }
+
+    /// Learn from an abandoned circuit.
+    ///
+    /// If almost all recent circuits timed out, the network has probably
+    /// changed. The history is then discarded, and the timeout is reset to
+    /// the initial one (if it was shorter).
+    ///
+    /// Returns `true` if the timeout changed.
+    pub fn record_timeout(&mut self) -> bool {
+        self.add(ABANDONED);
+        self.add_recent(true);
+
+        let num_timeouts = self.recent_timeouts.iter().filter(|t| **t).count();
+        if num_timeouts >= *CBT_MAX_TIMEOUTS && self.timeout_ms < *CBT_INITIAL_TIMEOUT {
+            self.history.clear();
+            self.recent_timeouts.clear();
+            self.timeout_ms = *CBT_INITIAL_TIMEOUT;
+            return true;
+        }
+
+        self.update_timeout()
+    }
+
+    fn add(&mut self, build_ms: u32) {
+        if self.history.len() >= *CBT_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(build_ms);
+    }
+
+    fn add_recent(&mut self, timed_out: bool) {
+        if self.recent_timeouts.len() >= *CBT_RECENT_COUNT {
+            self.recent_timeouts.pop_front();
+        }
+        self.recent_timeouts.push_back(timed_out);
+    }
+
+    /// Fit the build times to a Pareto distribution and set the timeout to
+    /// its quantile (circuit_build_times_set_timeout()).
+    ///
+    /// Returns `true` if the timeout changed.
+    fn update_timeout(&mut self) -> bool {
+        if self.history.len() < *CBT_MIN_CIRCUITS {
+            return false;
+        }
+        let xm = match self.mode() {
+            Some(xm) => xm,
+            None => return false,
+        };
+
+        // maximum likelihood estimate of the shape parameter, where abandoned
+        // circuits are censored at the slowest completed one
+        // (circuit_build_times_update_alpha())
+        let completed = self.history.iter().filter(|x| **x != ABANDONED);
+        let max_time = completed.clone().max().copied().unwrap_or(0) as f64;
+        let num_completed = completed.clone().count() as f64;
+        let num_abandoned = self.history.len() as f64 - num_completed;
+        let sum_log = completed.map(|x| (*x as f64).max(xm).ln()).sum::<f64>()
+            + num_abandoned * max_time.max(xm).ln();
+        let denominator = sum_log - num_completed * xm.ln();
+        if num_completed == 0.0 || denominator <= 0.0 {
+            return false;
+        }
+        let alpha = num_completed / denominator;
+
+        let timeout = (xm / (1.0 - *CBT_QUANTILE).powf(1.0 / alpha)).max(*CBT_MIN_TIMEOUT);
+        let changed = timeout != self.timeout_ms;
+        self.timeout_ms = timeout;
+        changed
+    }
+
+    /// The scale parameter Xm of the Pareto distribution: the average of the
+    /// most frequent build time bins, weighted by their counts
+    /// (circuit_build_times_get_xm())
+    fn mode(&self) -> Option<f64> {
+        let mut bins: FxHashMap<u32, u64> = FxHashMap::default();
+        for build_ms in self.history.iter().filter(|x| **x != ABANDONED) {
+            *bins.entry(build_ms / BIN_WIDTH).or_default() += 1;
+        }
+
+        // most frequent bins first, lower bins first among equally frequent ones
+        let mut bins: Vec<_> = bins.into_iter().collect();
+        bins.sort_unstable_by(|(bin_a, count_a), (bin_b, count_b)| {
+            count_b.cmp(count_a).then(bin_a.cmp(bin_b))
+        });
+
+        let modes = &bins[..bins.len().min(*CBT_NUM_MODES)];
+        let num: u64 = modes.iter().map(|(_, count)| count).sum();
+        if num == 0 {
+            return None;
+        }
+        let weighted: f64 = modes
+            .iter()
+            .map(|(bin, count)| (bin * BIN_WIDTH + BIN_WIDTH / 2) as f64 * *count as f64)
+            .sum();
+        Some(weighted / num as f64)
+    }
+}
@@ -1,41 +1,127 @@
 use std::path::PathBuf;
 
 use chrono::prelude::*;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::config::{ConsensusFlavor, GapPolicy, GuardPolicyKind, VanguardsMode};
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub(crate) struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Experiment configuration file (TOML or JSON). Command-line arguments
+    /// override the values given there.
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    pub config: Option<PathBuf>,
+
     /// Seed for the random number generators. If 0 or omitted, generate and print
     /// a random seed.
-    #[clap(long, default_value_t = 0)]
-    pub seed: u64,
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Number of independent Monte-Carlo trials, each with its own client
+    /// population and outputs [default: 1]
+    #[arg(long, value_name = "NUM")]
+    pub trials: Option<usize>,
+
+    /// Path to periodically write checkpoints to, for resuming an interrupted run
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    pub checkpoint: Option<PathBuf>,
+
+    /// Number of epochs (consensuses) between two checkpoints [default: 24]
+    #[arg(long, value_name = "NUM")]
+    pub checkpoint_every: Option<usize>,
+
+    /// Checkpoint of an interrupted run to resume, with the same configuration
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    pub resume: Option<PathBuf>,
 
     /// Location of consensus and descriptor files
     #[arg(long, value_name = "DIR", value_hint = clap::ValueHint::DirPath)]
-    pub tor_data: PathBuf,
+    pub tor_data: Option<PathBuf>,
+
+    /// Location of the binary consensus cache [default: <TOR_DATA>/torfs-cache]
+    #[arg(long, value_name = "DIR", value_hint = clap::ValueHint::DirPath)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Where to unpack the needed entries of compressed tarballs [default: system temp directory]
+    #[arg(long, value_name = "DIR", value_hint = clap::ValueHint::DirPath)]
+    pub unpack_dir: Option<PathBuf>,
+
+    /// Guard state file from an earlier run to start the clients with
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    pub guard_state: Option<PathBuf>,
+
+    /// Consensus flavor that clients use for path selection [default: full]
+    #[arg(long, value_enum)]
+    pub consensus_flavor: Option<ConsensusFlavor>,
 
     /// Begin of simulation timespan
     #[arg(long, value_name = "YYYY-MM[-DD[:HH[:mm]]]", value_parser = parse_simulation_range_edge)]
-    pub from: SimulationRangeEdge,
+    pub from: Option<SimulationRangeEdge>,
 
     /// End of simulation timespan
     #[arg(long, value_name = "YYYY-MM[-DD[:HH[:mm]]]", value_parser = parse_simulation_range_edge)]
-    pub to: SimulationRangeEdge,
+    pub to: Option<SimulationRangeEdge>,
+
+    /// What to do if consensuses are missing in the archive [default: reuse]
+    #[arg(long, value_enum)]
+    pub gap_policy: Option<GapPolicy>,
+
+    /// Number of consensuses that are loaded in advance, in addition to the next one
+    /// [default: 1]
+    #[arg(long, value_name = "NUM")]
+    pub prefetch_depth: Option<usize>,
+
+    /// Do not load consensuses in advance while using more than this amount of memory
+    #[arg(long, value_name = "MiB")]
+    pub prefetch_max_memory: Option<u64>,
 
     /// Number of clients. If omitted, use values from PrivCount measurements.
     #[arg(long)]
     pub clients: Option<u64>,
 
     /// Scale the load by number of users, relative to the PrivCount measurements
-    #[arg(
-        long,
-        value_name = "SCALE",
-        default_value_t = 1.0,
-        conflicts_with = "clients"
-    )]
-    pub load_scale: f64,
+    /// [default: 1.0]
+    #[arg(long, value_name = "SCALE", conflicts_with = "clients")]
+    pub load_scale: Option<f64>,
+
+    /// Fraction of requests that only resolve a hostname [default: 0]
+    #[arg(long, value_name = "FRACTION")]
+    pub resolve_fraction: Option<f64>,
+
+    /// Fraction of flows that go to an onion service [default: 0]
+    #[arg(long, value_name = "FRACTION")]
+    pub onion_fraction: Option<f64>,
+
+    /// Number of destination hosts the streams are spread over, 0 to not
+    /// model destinations [default: 0]
+    #[arg(long, value_name = "NUM")]
+    pub destinations: Option<u64>,
+
+    /// Popularity list of destination hosts (CSV lines `rank,host`, like the
+    /// Tranco list)
+    #[arg(long, value_name = "FILE")]
+    pub destination_list: Option<PathBuf>,
+
+    /// Fraction of destination hosts (without a known address) that also
+    /// have an IPv6 address [default: 0]
+    #[arg(long, value_name = "FRACTION")]
+    pub dual_stack_fraction: Option<f64>,
+
+    /// Probability that a stream to a dual-stack host uses IPv6
+    /// [default: 0]
+    #[arg(long, value_name = "PROBABILITY")]
+    pub ipv6_preference: Option<f64>,
+
+    /// Isolate the streams of each flow by their first destination, like
+    /// Tor Browser (requires destinations)
+    #[arg(long)]
+    pub first_party_isolation: bool,
 
     /// Number of adversarial guards
     #[arg(long, requires = "adv_guards_bw")]
@@ -53,17 +139,135 @@ pub(crate) struct Cli {
     #[arg(long, requires = "adv_exits_num")]
     pub adv_exits_bw: Option<u64>,
 
+    /// Number of adversarial middle relays
+    #[arg(long, requires = "adv_middles_bw")]
+    pub adv_middles_num: Option<u64>,
+
+    /// Consensus weight per adversarial middle relay
+    #[arg(long, requires = "adv_middles_num")]
+    pub adv_middles_bw: Option<u64>,
+
+    /// Adversary specification file (TOML or JSON) with individual injected
+    /// and compromised relays
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    pub adv_spec: Option<PathBuf>,
+
+    /// How clients choose their guards [default: tor]
+    #[arg(long, value_enum)]
+    pub guard_policy: Option<GuardPolicyKind>,
+
+    /// GUARD_LIFETIME, in days [default: 120]
+    #[arg(long, value_name = "DAYS")]
+    pub guard_lifetime: Option<i64>,
+
+    /// MIN_FILTERED_SAMPLE [default: 20]
+    #[arg(long, value_name = "NUM")]
+    pub min_filtered_sample: Option<usize>,
+
+    /// MAX_SAMPLE_SIZE [default: 60]
+    #[arg(long, value_name = "NUM")]
+    pub max_sample_size: Option<usize>,
+
+    /// N_PRIMARY_GUARDS [default: 3]
+    #[arg(long, value_name = "NUM")]
+    pub n_primary_guards: Option<usize>,
+
+    /// N_USABLE_PRIMARY_GUARDS [default: 1]
+    #[arg(long, value_name = "NUM")]
+    pub n_usable_primary_guards: Option<usize>,
+
+    /// Fraction of clients that use bridges (given in the config file) instead
+    /// of guards [default: 0]
+    #[arg(long, value_name = "FRACTION")]
+    pub bridge_users: Option<f64>,
+
+    /// Do not let streams to different destination hosts share circuits
+    /// (IsolateDestAddr)
+    #[arg(long)]
+    pub isolate_dest_addr: bool,
+
+    /// Do not let streams to different ports share circuits (IsolateDestPort)
+    #[arg(long)]
+    pub isolate_dest_port: bool,
+
+    /// Simulate the latency and failures of circuit construction, together
+    /// with the circuit build timeout learned by the clients
+    #[arg(long)]
+    pub circuit_build_model: bool,
+
+    /// Probability that extending a circuit to a relay fails, if circuit
+    /// construction is simulated [default: 0.01]
+    #[arg(long, value_name = "P")]
+    pub extend_failure_probability: Option<f64>,
+
+    /// Number of attempts to build a circuit before giving up [default: 5]
+    #[arg(long, value_name = "NUM")]
+    pub circuit_build_attempts: Option<usize>,
+
+    /// Vanguards used for internal circuits [default: lite]
+    #[arg(long, value_enum)]
+    pub vanguards: Option<VanguardsMode>,
+
+    /// Probability that a connection attempt to a relay fails [default: 0]
+    #[arg(long, value_name = "P")]
+    pub failure_probability: Option<f64>,
+
+    /// Upper bound of the clients' individual connection failure probabilities
+    /// [default: 0]
+    #[arg(long, value_name = "P")]
+    pub client_failure_max: Option<f64>,
+
     /// Path to the (prepared) stream model JSON file
     #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
-    pub stream_model: PathBuf,
+    pub stream_model: Option<PathBuf>,
 
     /// Path to the (prepared) packet model JSON file
     #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
-    pub packet_model: PathBuf,
+    pub packet_model: Option<PathBuf>,
 
     /// Path to the output message trace file
     #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
-    pub output_trace: PathBuf,
+    pub output_trace: Option<PathBuf>,
+
+    /// Path to the output CSV file with the compromise CDFs
+    /// [default: <OUTPUT_TRACE>.compromise.csv]
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    pub output_compromise: Option<PathBuf>,
+
+    /// Path to write the clients' guard state to at the end of the run
+    /// (JSON, zstd-compressed if ending with .zst)
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    pub output_guard_state: Option<PathBuf>,
+
+    /// Path to the output CSV file with the message IDs of bridge users' streams
+    /// [default: <OUTPUT_TRACE>.bridge-streams.csv]
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    pub output_bridge_streams: Option<PathBuf>,
+
+    /// Path to the output CSV file with the metrics aggregated over all trials
+    /// [default: <OUTPUT_TRACE>.trials.csv]
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    pub output_trials_summary: Option<PathBuf>,
+
+    /// Path to the output CSV file with the results of a parameter sweep
+    /// [default: <OUTPUT_TRACE>.sweep.csv]
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    pub output_sweep: Option<PathBuf>,
+}
+
+/// Alternative actions instead of running a simulation
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+    /// Parse the consensuses in the simulation timespan once and store them,
+    /// together with their descriptors, in the binary cache
+    Cache,
+    /// Simulate several adversary and load configurations side by side and
+    /// collect their results in one table
+    Sweep {
+        /// Sweep file (TOML or JSON) with a grid or list of configurations
+        #[arg(value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        file: PathBuf,
+    },
 }
 
 impl Cli {
@@ -133,6 +337,38 @@ impl SimulationRangeEdge {
     }
 }
 
+impl std::fmt::Display for SimulationRangeEdge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SimulationRangeEdge::MonthYear(x) => write!(f, "{:04}-{:02}", x.year, x.month),
+            SimulationRangeEdge::DayMonthYear(x) => {
+                write!(f, "{:04}-{:02}-{:02}", x.year, x.month, x.day)
+            }
+            SimulationRangeEdge::HourDayMonthYear(x) => {
+                write!(f, "{:04}-{:02}-{:02}:{:02}", x.year, x.month, x.day, x.hour)
+            }
+            SimulationRangeEdge::MinuteHourDayMonthYear(x) => write!(
+                f,
+                "{:04}-{:02}-{:02}:{:02}:{:02}",
+                x.year, x.month, x.day, x.hour, x.minute
+            ),
+        }
+    }
+}
+
+impl Serialize for SimulationRangeEdge {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SimulationRangeEdge {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        parse_simulation_range_edge(&raw).map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct MonthYear {
     pub year: u16,
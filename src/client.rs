@@ -1,52 +1,109 @@
 //! Implementation of simulated clients/users
 
-use std::iter::Peekable;
+use std::sync::Arc;
 
-use crate::guard::GuardHandling;
-use crate::needs::{NeedHandle, NeedsContainer};
-use crate::observer::{CircuitCloseReason, ClientObserver, ExitFingerprintSerializer};
+use crate::adversaries::Adversary;
+use crate::bridges::{Bridge, BridgeHandling, BridgeHandlingState};
+use crate::circuit_build::{self, simulate_build, BuildOutcome, BuildTimeEstimator};
+use crate::config::protocol_parameters;
+use crate::destinations::ExitPolicies;
+use crate::guard::GuardState;
+use crate::guard_policy::{GuardPolicy, GuardPolicyState};
+use crate::isolation::StreamIsolation;
+use crate::needs::{NeedHandle, NeedState, NeedTarget, NeedsContainer};
+use crate::observer::{
+    CircuitCloseReason, CircuitFailureReason, ClientObserver, ClientObserverState,
+    ExitFingerprintSerializer,
+};
+use crate::reachability::ReachabilityModel;
+use crate::rng::{with_circgen, with_rng, ClientRng};
 use crate::trace::MemoryCsvWriter;
-use crate::user::{Request, UserModel};
+use crate::user::{Request, RequestKind, RequestState, UserModel};
 use crate::utils::*;
+use crate::vanguards::{Vanguards, VanguardsState};
 
-use tor_circuit_generator::CircuitGenerator;
+use seeded_rand::RHashSet;
+use tor_circuit_generator::{CircuitGenerator, TorCircuit};
 use tordoc::{consensus::Flag, Fingerprint};
 
 use chrono::prelude::*;
 use chrono::Duration;
 #[allow(unused_imports)]
 use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
 
 use lazy_static::lazy_static;
 
 lazy_static! {
-    static ref MAX_CIRCUIT_DIRTINESS: Duration = Duration::minutes(10);
-    static ref CIRCUIT_IDLE_TIMEOUT: Duration = Duration::minutes(60);
-    static ref LONG_LIVED_PORTS: Vec<u16> =
-        [21, 22, 706, 1863, 5050, 5190, 5222, 5223, 6523, 6667, 6697, 8300]
-            .into_iter()
-            .collect();
+    static ref MAX_CIRCUIT_DIRTINESS: Duration =
+        Duration::seconds(protocol_parameters().circuits.max_dirtiness_secs);
+    static ref CIRCUIT_IDLE_TIMEOUT: Duration =
+        Duration::seconds(protocol_parameters().circuits.idle_timeout_secs);
+    static ref LONG_LIVED_PORTS: Vec<u16> = protocol_parameters().circuits.long_lived_ports.clone();
+    static ref MAX_BUILD_ATTEMPTS: usize = protocol_parameters().circuit_build.max_attempts;
 }
 
+/// Any exit can resolve hostnames. Like Tor (`rep_hist_note_used_resolve()`),
+/// we build and predict circuits for this port for them.
+const RESOLVE_PORT: u16 = 80;
+
 /// A simulated Tor client.
 ///
 /// This implements Tor's behavior of handling circuits, streams, etc. for
 /// handling the requests made by a user, as modelled by a given `UserModel`.
 pub(crate) struct Client<U: UserModel> {
     id: u64,
+    /// This client's own random number generator, so the client's behavior
+    /// does not depend on the other clients
+    rng: ClientRng,
     observer: ClientObserver,
-    user_model: Peekable<U>,
+    user_model: U,
+    /// The next request of the user model, if it has already been generated
+    /// but not handled yet
+    next_request: Option<Request>,
     circuit_manager: CircuitManager,
 }
 
+/// Everything a client needs to continue after a checkpoint
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ClientState<S> {
+    id: u64,
+    rng: ClientRng,
+    user: S,
+    next_request: Option<RequestState>,
+    circuits: CircuitManagerState,
+    observer: ClientObserverState,
+}
+
 impl<U: UserModel> Client<U> {
-    /// Construct a new Client
-    pub(crate) fn new(id: u64, user_model: U) -> Client<U> {
+    /// Construct a new Client.
+    ///
+    /// The user model and guard policy should have been created with the
+    /// client's random number generator, see `with_rng`.
+    pub(crate) fn new(
+        id: u64,
+        rng: ClientRng,
+        user_model: U,
+        adversary: Arc<Adversary>,
+        guards: Box<dyn GuardPolicy>,
+        reachability: Arc<ReachabilityModel>,
+        bridges: Option<Vec<Bridge>>,
+    ) -> Client<U> {
+        // bridge users are tagged by the transport of their first bridge
+        let bridge_transport = bridges.as_ref().map(|bridges| {
+            bridges
+                .first()
+                .and_then(|bridge| bridge.transport.clone())
+                .unwrap_or_else(|| "vanilla".to_string())
+        });
+
         Client {
             id,
-            observer: ClientObserver::new(id),
-            user_model: user_model.peekable(),
-            circuit_manager: CircuitManager::new(),
+            rng,
+            observer: ClientObserver::new(id, adversary, bridge_transport),
+            user_model,
+            next_request: None,
+            circuit_manager: CircuitManager::new(guards, reachability, bridges),
         }
     }
 
@@ -54,6 +111,30 @@ impl<U: UserModel> Client<U> {
     /// with a new consensus being available.
     /// TODO: Clarify what an epoch is
     pub(crate) fn handle_new_epoch(
+        &mut self,
+        epoch_start: &DateTime<Utc>,
+        epoch_end: &DateTime<Utc>,
+        circuit_generator: &CircuitGenerator,
+        exit_policies: &Arc<ExitPolicies>,
+        compromised_by_nickname: &Arc<RHashSet<Fingerprint>>,
+        csv_writer: &mut MemoryCsvWriter,
+        exit_ids: &ExitFingerprintSerializer,
+    ) -> anyhow::Result<()> {
+        self.circuit_manager.exit_policies = exit_policies.clone();
+        self.observer
+            .set_compromised_by_nickname(compromised_by_nickname.clone());
+        self.with_own_rng(|client| {
+            client.handle_epoch_requests(
+                epoch_start,
+                epoch_end,
+                circuit_generator,
+                csv_writer,
+                exit_ids,
+            )
+        })
+    }
+
+    fn handle_epoch_requests(
         &mut self,
         epoch_start: &DateTime<Utc>,
         epoch_end: &DateTime<Utc>,
@@ -75,18 +156,9 @@ impl<U: UserModel> Client<U> {
         )?;
 
         // construct all the circuits in this time frame
-        loop {
-            // Look at the next request, but do not consume it yet
-            let next = self.user_model.peek();
-
-            let request = match next {
-                Some(x) if (&x.time >= epoch_start && &x.time < epoch_end) => {
-                    // use/consume this request element and advance the user model
-                    self.user_model.next().unwrap() // cannot fail as peek() was Some(_)
-                }
-                _ => break,
-            };
-
+        while let Some(request) = self
+            .next_request_if(|request| &request.time >= epoch_start && &request.time < epoch_end)
+        {
             // Do time-based maintaining. TorPS does this once per minute but it
             // **should** be ok to do this only when actually needed.
             // TODO: Maybe this is not true anymore when we introduce need covering
@@ -108,12 +180,116 @@ impl<U: UserModel> Client<U> {
         Ok(())
     }
 
+    /// Drop all the requests of the user before a given time, without handling them.
+    ///
+    /// This is used if there is no consensus for some time, so the client
+    /// cannot be active.
+    pub(crate) fn skip_requests_until(&mut self, time: &DateTime<Utc>) {
+        let mut skipped = 0;
+        self.with_own_rng(|client| {
+            while client
+                .next_request_if(|request| &request.time < time)
+                .is_some()
+            {
+                skipped += 1;
+            }
+        });
+
+        if skipped > 0 {
+            trace!(
+                "Client {} skipped {} requests before {}",
+                self.id,
+                skipped,
+                time
+            );
+        }
+    }
+
+    /// Run `f` with this client's random number generator as the generator
+    /// of the current thread
+    fn with_own_rng<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let mut rng = self.rng.clone();
+        let result = with_rng(&mut rng, || f(self));
+        self.rng = rng;
+        result
+    }
+
+    /// Consume the next request of the user model if it matches a predicate,
+    /// advancing the user model if necessary
+    fn next_request_if(&mut self, predicate: impl FnOnce(&Request) -> bool) -> Option<Request> {
+        if self.next_request.is_none() {
+            self.next_request = self.user_model.next();
+        }
+        match self.next_request {
+            Some(ref request) if predicate(request) => self.next_request.take(),
+            _ => None,
+        }
+    }
+
     /// Get the client's ID
-    #[allow(unused)]
     pub(crate) fn get_id(&self) -> u64 {
         self.id
     }
 
+    /// Export the client's guard state, so a later run can continue with it.
+    ///
+    /// Returns `None` if the guard policy does not support this.
+    pub(crate) fn guard_state(&self) -> Option<GuardState> {
+        self.circuit_manager.guards.to_state().into_guard_state()
+    }
+
+    /// Start with a guard state from an earlier run instead of bootstrapping
+    pub(crate) fn restore_guard_state(&mut self, state: &GuardState) -> anyhow::Result<()> {
+        self.circuit_manager
+            .guards
+            .restore_state(&GuardPolicyState::Tor(state.clone()))
+    }
+
+    /// Export everything needed to continue with this client later.
+    ///
+    /// Fails if the guard policy does not support saving its state.
+    pub(crate) fn to_state(&self) -> ClientState<U::State> {
+        ClientState {
+            id: self.id,
+            rng: self.rng.clone(),
+            user: self.user_model.to_state(),
+            next_request: self.next_request.as_ref().map(|request| request.to_state()),
+            circuits: self.circuit_manager.to_state(),
+            observer: self.observer.to_state(),
+        }
+    }
+
+    /// Continue with an exported client state.
+    ///
+    /// The client must have been constructed with the same parameters as the
+    /// exported one. Its user model, circuits, guards, etc. are replaced.
+    pub(crate) fn restore_state(
+        &mut self,
+        state: &ClientState<U::State>,
+        adversary: Arc<Adversary>,
+        reachability: Arc<ReachabilityModel>,
+    ) -> anyhow::Result<()> {
+        if state.id != self.id {
+            anyhow::bail!(
+                "Cannot restore the state of client {} for client {}",
+                state.id,
+                self.id
+            );
+        }
+
+        self.rng = state.rng.clone();
+        self.user_model.restore_state(&state.user)?;
+        self.next_request = state
+            .next_request
+            .as_ref()
+            .map(Request::from_state)
+            .transpose()?;
+        self.circuit_manager
+            .restore_state(&state.circuits, reachability)?;
+        self.observer = ClientObserver::from_state(self.id, adversary, &state.observer)?;
+        Ok(())
+    }
+
     /// Finish this client and return its observer
     pub(crate) fn into_observer(self) -> ClientObserver {
         self.observer
@@ -128,15 +304,25 @@ impl<U: UserModel> Client<U> {
 #[derive(Debug)]
 pub(crate) struct ShallowCircuit {
     pub(crate) guard: Fingerprint,
-    pub(crate) middle: Fingerprint,
+    /// Is the first hop a bridge (instead of a guard from the consensus)?
+    pub(crate) via_bridge: bool,
+    /// The hops between the first and the last one (one for exit circuits,
+    /// two for internal circuits)
+    pub(crate) middle: Vec<Fingerprint>,
+    /// The last hop: the exit, or for internal circuits the relay that
+    /// serves as rendezvous point or introduction point
     pub(crate) exit: Fingerprint,
     // TODO: do we need to remember exit policy, ports, etc.?
     /// Time when this circuit was created
     pub(crate) time: DateTime<Utc>,
     /// Time the circuit became "dirty". If this is None, circuit is clean.
     pub(crate) dirty_time: Option<DateTime<Utc>>,
-    /// Is this circuit intended for name resolution and onion services only?
+    /// Is this circuit intended for onion services only?
     pub(crate) is_internal: bool,
+    /// For rendezvous circuits, the onion service they are joined with
+    pub(crate) onion_service: Option<u64>,
+    /// What the circuit is isolated to, once it carried a stream
+    pub(crate) isolation: Option<StreamIsolation>,
     /// Is this circuit expected to have only stable relays?
     pub(crate) is_stable: bool,
     /// Is this circuit expected to have only fast relays?
@@ -145,38 +331,102 @@ pub(crate) struct ShallowCircuit {
     pub(crate) covered_needs: Vec<NeedHandle>,
 }
 
+/// A circuit, as stored in checkpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CircuitState {
+    guard: String,
+    via_bridge: bool,
+    middle: Vec<String>,
+    exit: String,
+    time: i64,
+    dirty_time: Option<i64>,
+    is_internal: bool,
+    onion_service: Option<u64>,
+    isolation: Option<StreamIsolation>,
+    is_stable: bool,
+    is_fast: bool,
+    /// The needs covered by this circuit
+    covered_needs: Vec<NeedTarget>,
+}
+
 impl ShallowCircuit {
-    /// Construct from a circuit as generated by the CircuitGenerator
+    /// Construct from a circuit as generated by the CircuitGenerator (or by
+    /// `Vanguards::build_internal_circuit`)
     fn from_generated_circuit(
         circgen_circuit: tor_circuit_generator::TorCircuit,
+        internal: bool,
         stable: bool,
         fast: bool,
         time: DateTime<Utc>,
         dirty_time: Option<DateTime<Utc>>,
         covered_need: Option<NeedHandle>,
     ) -> ShallowCircuit {
-        if circgen_circuit.middle.len() != 1 {
-            panic!("We only support 3-hop circuits at the moment");
-        }
         ShallowCircuit {
             guard: circgen_circuit.guard.fingerprint.clone(),
-            middle: circgen_circuit.middle[0].fingerprint.clone(),
+            via_bridge: false,
+            middle: circgen_circuit
+                .middle
+                .iter()
+                .map(|relay| relay.fingerprint.clone())
+                .collect(),
             exit: circgen_circuit.exit.fingerprint.clone(),
             time,
             dirty_time,
-            is_internal: false,
+            is_internal: internal,
+            onion_service: None,
+            isolation: None,
             is_stable: stable,
             is_fast: fast,
             covered_needs: covered_need.into_iter().collect(),
         }
     }
 
+    /// Use the actual first hop the circuit was built through. This replaces
+    /// the placeholder guard chosen by the circuit generator for bridges.
+    fn with_first_hop(mut self, first_hop: FirstHop) -> ShallowCircuit {
+        if let FirstHop::Bridge(bridge) = first_hop {
+            self.guard = bridge;
+            self.via_bridge = true;
+        }
+        self
+    }
+
+    /// Check if this dirty circuit may still be used for a given stream
+    /// request, regardless of stream isolation
+    fn is_reusable_for(
+        &self,
+        request: &Request,
+        circgen: &CircuitGenerator,
+        exit_policies: &ExitPolicies,
+    ) -> bool {
+        match self.dirty_time {
+            Some(dirty_time) => {
+                request.time < dirty_time + *MAX_CIRCUIT_DIRTINESS
+                    && self.supports_stream(request, circgen, exit_policies)
+            }
+            None => false,
+        }
+    }
+
     /// Check if this circuit can accommodate a given stream request.
     ///
     /// # Panics
     ///
     /// _May_ panic if the circuit's relays aren't part of the consensus.
-    fn supports_stream(&self, request: &Request, circgen: &CircuitGenerator) -> bool {
+    fn supports_stream(
+        &self,
+        request: &Request,
+        circgen: &CircuitGenerator,
+        exit_policies: &ExitPolicies,
+    ) -> bool {
+        match request.kind {
+            // streams to onion services need a rendezvous circuit with the service
+            RequestKind::OnionService(service) => return self.onion_service == Some(service),
+            // all exits can resolve
+            RequestKind::Resolve => return !self.is_internal,
+            RequestKind::Exit => {}
+        }
+
         if self.is_internal {
             return false;
         }
@@ -190,10 +440,29 @@ impl ShallowCircuit {
             return false;
         }
 
+        // the full policy decides about the destination's address
+        if let Some(ref destination) = request.destination {
+            if !exit_policies.allows(&self.exit, destination, request.port) {
+                return false;
+            }
+        }
+
         true
     }
 }
 
+/// The circuits, needs and guards of a client, as stored in checkpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CircuitManagerState {
+    circuits: Vec<CircuitState>,
+    needs: Vec<NeedState>,
+    last_triggered: Option<i64>,
+    guards: GuardPolicyState,
+    vanguards: VanguardsState,
+    bridges: Option<BridgeHandlingState>,
+    build_times: BuildTimeEstimator,
+}
+
 /// A container for circuits currently maintained by the client
 struct CircuitManager {
     /// Circuits that have already been constructed (both, clean & dirty)
@@ -203,18 +472,322 @@ struct CircuitManager {
     /// Last time the time-based update was triggered
     last_triggered: Option<DateTime<Utc>>,
     /// Handler for this client's guard set
-    guards: GuardHandling,
+    guards: Box<dyn GuardPolicy>,
+    /// This client's layer-2 and layer-3 guards for internal circuits
+    vanguards: Vanguards,
+    /// For bridge users, the bridges that replace the guards
+    bridges: Option<BridgeHandling>,
+    /// The circuit build timeout learned from this client's circuits
+    build_times: BuildTimeEstimator,
+    /// The full exit policies of the current consensus' relays
+    exit_policies: Arc<ExitPolicies>,
+}
+
+/// The first hop of a new circuit
+enum FirstHop {
+    Guard(Fingerprint),
+    /// Bridges are not part of the consensus, so the circuit generator
+    /// cannot build circuits through them directly.
+    Bridge(Fingerprint),
+}
+
+impl FirstHop {
+    fn fingerprint(&self) -> &Fingerprint {
+        match self {
+            FirstHop::Guard(fingerprint) | FirstHop::Bridge(fingerprint) => fingerprint,
+        }
+    }
 }
 
 impl CircuitManager {
     /// Construct a new circuit manager from scratch for a new client
-    fn new() -> CircuitManager {
+    fn new(
+        guards: Box<dyn GuardPolicy>,
+        reachability: Arc<ReachabilityModel>,
+        bridges: Option<Vec<Bridge>>,
+    ) -> CircuitManager {
         CircuitManager {
             circuits: Vec::new(),
             port_needs: NeedsContainer::new(),
             last_triggered: None,
-            guards: GuardHandling::new(),
+            guards,
+            vanguards: Vanguards::new(),
+            bridges: bridges.map(|bridges| BridgeHandling::new(&bridges, reachability)),
+            build_times: BuildTimeEstimator::new(),
+            exit_policies: Arc::new(ExitPolicies::default()),
+        }
+    }
+
+    /// Export the circuits, needs and guards
+    fn to_state(&self) -> CircuitManagerState {
+        CircuitManagerState {
+            circuits: self
+                .circuits
+                .iter()
+                .map(|circuit| CircuitState {
+                    guard: circuit.guard.to_string(),
+                    via_bridge: circuit.via_bridge,
+                    middle: circuit
+                        .middle
+                        .iter()
+                        .map(|relay| relay.to_string())
+                        .collect(),
+                    exit: circuit.exit.to_string(),
+                    time: to_micros(&circuit.time),
+                    dirty_time: circuit.dirty_time.as_ref().map(to_micros),
+                    is_internal: circuit.is_internal,
+                    onion_service: circuit.onion_service,
+                    isolation: circuit.isolation,
+                    is_stable: circuit.is_stable,
+                    is_fast: circuit.is_fast,
+                    covered_needs: circuit
+                        .covered_needs
+                        .iter()
+                        .filter_map(|need| need.get_target())
+                        .collect(),
+                })
+                .collect(),
+            needs: self.port_needs.to_state(),
+            last_triggered: self.last_triggered.as_ref().map(to_micros),
+            guards: self.guards.to_state(),
+            vanguards: self.vanguards.to_state(),
+            bridges: self.bridges.as_ref().map(|bridges| bridges.to_state()),
+            build_times: self.build_times.clone(),
+        }
+    }
+
+    /// Continue with exported circuits, needs and guards
+    fn restore_state(
+        &mut self,
+        state: &CircuitManagerState,
+        reachability: Arc<ReachabilityModel>,
+    ) -> anyhow::Result<()> {
+        // the needs have to be restored first, so the circuits can cover them again
+        self.port_needs = NeedsContainer::from_state(&state.needs)?;
+
+        let mut circuits = Vec::with_capacity(state.circuits.len());
+        for circuit in state.circuits.iter() {
+            circuits.push(ShallowCircuit {
+                guard: parse_fingerprint(&circuit.guard)?,
+                via_bridge: circuit.via_bridge,
+                middle: circuit
+                    .middle
+                    .iter()
+                    .map(|relay| parse_fingerprint(relay))
+                    .collect::<anyhow::Result<_>>()?,
+                exit: parse_fingerprint(&circuit.exit)?,
+                time: from_micros(circuit.time)?,
+                dirty_time: circuit.dirty_time.map(from_micros).transpose()?,
+                is_internal: circuit.is_internal,
+                onion_service: circuit.onion_service,
+                isolation: circuit.isolation,
+                is_stable: circuit.is_stable,
+                is_fast: circuit.is_fast,
+                covered_needs: circuit
+                    .covered_needs
+                    .iter()
+                    .filter_map(|target| self.port_needs.handle_for(*target))
+                    .collect(),
+            });
+        }
+        self.circuits = circuits;
+
+        self.last_triggered = state.last_triggered.map(from_micros).transpose()?;
+        self.guards.restore_state(&state.guards)?;
+        self.vanguards = Vanguards::from_state(&state.vanguards)?;
+        self.bridges = state
+            .bridges
+            .as_ref()
+            .map(|bridges| BridgeHandling::from_state(bridges, reachability))
+            .transpose()?;
+        self.build_times = state.build_times.clone();
+        Ok(())
+    }
+
+    /// Select (and connect to) the first hop of a new circuit, which is a
+    /// bridge for bridge users and a guard otherwise.
+    ///
+    /// Returns `None` if no bridge or guard is reachable.
+    fn select_first_hop(
+        &mut self,
+        time: &DateTime<Utc>,
+        circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+    ) -> Option<FirstHop> {
+        match self.bridges {
+            Some(ref mut bridges) => bridges
+                .get_bridge_for_circuit(time, observer)
+                .map(FirstHop::Bridge),
+            None => self
+                .guards
+                .get_guard_for_circuit(time, circgen, observer)
+                .map(FirstHop::Guard),
+        }
+    }
+
+    /// Generate a new three-hop circuit through the given first hop.
+    ///
+    /// For bridges, the circuit's guard is only a placeholder that has to be
+    /// replaced (see `ShallowCircuit::with_first_hop`).
+    fn generate_circuit(
+        first_hop: &FirstHop,
+        port: u16,
+        need_fast: bool,
+        need_stable: bool,
+        circgen: &CircuitGenerator,
+    ) -> anyhow::Result<TorCircuit> {
+        let guard = match first_hop {
+            FirstHop::Guard(guard) => Some(guard),
+            FirstHop::Bridge(_) => None,
+        };
+        with_circgen(|| {
+            circgen.build_circuit_with_flags_and_guard(3, port, guard, need_fast, need_stable)
+        })
+        .map_err(|e| anyhow::anyhow!(format!("{:?}", e)))
+    }
+
+    /// Build a new three-hop circuit for the given port.
+    ///
+    /// Returns `None` if no guard or bridge is reachable, or if every
+    /// attempt failed (see `build_with_retries`).
+    fn build_circuit(
+        &mut self,
+        time: &DateTime<Utc>,
+        port: u16,
+        need_fast: bool,
+        need_stable: bool,
+        circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+    ) -> anyhow::Result<Option<(TorCircuit, FirstHop)>> {
+        self.build_with_retries(time, circgen, observer, |_, first_hop| {
+            CircuitManager::generate_circuit(first_hop, port, need_fast, need_stable, circgen)
+                .map(Some)
+        })
+    }
+
+    /// Build a new three-hop circuit for IPv6 streams to the given port.
+    ///
+    /// The circuit generator chooses exits by their IPv4 policy, so the exit
+    /// of the generated circuit is replaced by one whose IPv6 policy allows
+    /// the port (see `ExitPolicies::sample_ipv6_exit`). Returns `None` like
+    /// `build_circuit`.
+    fn build_ipv6_circuit(
+        &mut self,
+        time: &DateTime<Utc>,
+        port: u16,
+        need_fast: bool,
+        need_stable: bool,
+        circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+    ) -> anyhow::Result<Option<(TorCircuit, FirstHop)>> {
+        self.build_with_retries(time, circgen, observer, |manager, first_hop| {
+            let mut circuit =
+                CircuitManager::generate_circuit(first_hop, port, need_fast, need_stable, circgen)?;
+            let other_hops: Vec<_> = [first_hop.fingerprint(), &circuit.guard.fingerprint]
+                .into_iter()
+                .chain(circuit.middle.iter().map(|relay| &relay.fingerprint))
+                .collect();
+            let exit = manager
+                .exit_policies
+                .sample_ipv6_exit(port, need_fast, need_stable, &other_hops)
+                .and_then(|exit| circgen.lookup_relay(exit));
+            Ok(exit.map(|exit| {
+                circuit.exit = exit;
+                circuit
+            }))
+        })
+    }
+
+    /// Build a new circuit through a freshly selected first hop, with the
+    /// path chosen by `generate`.
+    ///
+    /// The construction of the circuit is simulated (see
+    /// `crate::circuit_build`). If it fails or times out, a new path is
+    /// chosen, up to the configured number of attempts. Returns `None` if no
+    /// guard or bridge is reachable, or if every attempt failed.
+    fn build_with_retries(
+        &mut self,
+        time: &DateTime<Utc>,
+        circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+        generate: impl Fn(&CircuitManager, &FirstHop) -> anyhow::Result<Option<TorCircuit>>,
+    ) -> anyhow::Result<Option<(TorCircuit, FirstHop)>> {
+        for _ in 0..*MAX_BUILD_ATTEMPTS {
+            let first_hop = match self.select_first_hop(time, circgen, observer) {
+                Some(first_hop) => first_hop,
+                None => return Ok(None),
+            };
+            let circuit = match generate(self, &first_hop)? {
+                Some(circuit) => circuit,
+                None => {
+                    observer.notify_no_path(time);
+                    continue;
+                }
+            };
+
+            if self.try_build(time, &circuit, &first_hop, observer) {
+                return Ok(Some((circuit, first_hop)));
+            }
+        }
+
+        observer.notify_circuit_given_up(time, *MAX_BUILD_ATTEMPTS);
+        Ok(None)
+    }
+
+    /// Simulate the construction of a circuit, and learn the circuit build
+    /// timeout from it.
+    ///
+    /// If the first hop was not completed, the guard (or bridge) is marked
+    /// as unreachable (see guard-spec, section 4.9). Returns `true` if the
+    /// circuit was built.
+    fn try_build(
+        &mut self,
+        time: &DateTime<Utc>,
+        circuit: &TorCircuit,
+        first_hop: &FirstHop,
+        observer: &mut ClientObserver,
+    ) -> bool {
+        if !circuit_build::is_enabled() {
+            return true;
+        }
+
+        let num_hops = circuit.middle.len() + 2;
+        let (hop, reason) = match simulate_build(num_hops, self.build_times.timeout_ms()) {
+            BuildOutcome::Built(build_ms) => {
+                if self.build_times.record_built(build_ms) {
+                    observer.notify_build_timeout(time, self.build_times.timeout_ms());
+                }
+                return true;
+            }
+            BuildOutcome::Failed(hop) => (hop, CircuitFailureReason::ExtendFailed),
+            BuildOutcome::TimedOut(hop) => {
+                if self.build_times.record_timeout() {
+                    observer.notify_build_timeout(time, self.build_times.timeout_ms());
+                }
+                (hop, CircuitFailureReason::Timeout)
+            }
+        };
+
+        let relay = match hop {
+            0 => first_hop.fingerprint(),
+            _ if hop <= circuit.middle.len() => &circuit.middle[hop - 1].fingerprint,
+            _ => &circuit.exit.fingerprint,
+        };
+        observer.notify_circuit_failed(time, relay, hop, reason);
+
+        if hop == 0 {
+            match first_hop {
+                FirstHop::Guard(guard) => self.guards.mark_as_failed(guard, time, observer),
+                FirstHop::Bridge(bridge) => {
+                    // bridge users always have their bridges
+                    if let Some(ref mut bridges) = self.bridges {
+                        bridges.mark_as_failed(bridge, time, observer);
+                    }
+                }
+            }
         }
+
+        false
     }
 
     /// When entering a new epoch, carry out the housekeeping of currently
@@ -227,7 +800,9 @@ impl CircuitManager {
     ) -> anyhow::Result<()> {
         // When being used for the first time, set an initial port need.
         if let None = self.last_triggered {
-            let need_string = self.port_needs.add_need(80, time, true, false);
+            let need_string = self
+                .port_needs
+                .add_need(NeedTarget::Port(80), time, true, false);
             observer.notify_new_need(time, need_string);
         };
         self.last_triggered = Some(time.clone());
@@ -267,7 +842,17 @@ impl CircuitManager {
         // remove circuits whose relays have gone missing
         self.circuits.retain_or_else(
             |circuit| {
-                for relay in [&circuit.guard, &circuit.middle, &circuit.exit] {
+                // bridges are not part of the consensus
+                let guard = if circuit.via_bridge {
+                    None
+                } else {
+                    Some(&circuit.guard)
+                };
+                for relay in guard
+                    .into_iter()
+                    .chain(circuit.middle.iter())
+                    .chain([&circuit.exit])
+                {
                     match circgen.lookup_relay(relay) {
                         None => {
                             return false;
@@ -288,41 +873,95 @@ impl CircuitManager {
         );
 
         // Trigger the guard handling
-        self.guards.timed_updates(time, circgen, observer);
+        match self.bridges {
+            Some(ref mut bridges) => bridges.timed_updates(time),
+            None => self.guards.timed_updates(time, circgen, observer),
+        }
+        self.vanguards.timed_updates(time, circgen, observer);
 
-        // Cover uncovered port needs
+        // Cover uncovered port needs. Should a new circuit not cover its need
+        // nevertheless, give up after too many such circuits and try again at
+        // the next update.
+        let mut num_not_covering = 0;
         while let Some(need_handle) = self.port_needs.get_uncovered_need() {
             // build a suitable circuit for this need
 
             // these unwraps never fail as we have just got an existing need
-            let port = need_handle.get_port().unwrap();
+            let target = need_handle.get_target().unwrap();
             let need_stable = need_handle.get_stable().unwrap();
             let need_fast = need_handle.get_fast().unwrap();
 
-            let guard = self.guards.get_guard_for_circuit(time, circgen);
-
-            let circuit = circgen
-                .build_circuit_with_flags_and_guard(3, port, Some(&guard), need_fast, need_stable)
-                .map_err(|e| anyhow::anyhow!(format!("{:?}", e)))?;
+            let built = match target {
+                NeedTarget::Port(port) => {
+                    self.build_circuit(time, port, need_fast, need_stable, circgen, observer)?
+                }
+                NeedTarget::Ipv6Port(port) => {
+                    self.build_ipv6_circuit(time, port, need_fast, need_stable, circgen, observer)?
+                }
+                NeedTarget::Internal => self.build_internal_circuit(time, circgen, observer)?,
+            };
+            let (circuit, first_hop) = match built {
+                Some(built) => built,
+                // no guard or bridge reachable or too many failures, try
+                // again at the next update
+                None => break,
+            };
+            let internal = target == NeedTarget::Internal;
+            let mut circuit = ShallowCircuit::from_generated_circuit(
+                circuit,
+                internal,
+                // the relays of internal circuits are always fast and stable
+                need_stable || internal,
+                need_fast || internal,
+                time.clone(),
+                None, // circuit is clean
+                None, // the need is added below, if the circuit covers it
+            )
+            .with_first_hop(first_hop);
             observer.notify_new_circuit(
                 time.clone(),
                 &circuit,
-                port,
-                format!("to cover need {}", need_handle.to_string()),
+                need_port(target),
+                format!("to cover need {}", need_handle),
             );
-            self.circuits.push(ShallowCircuit::from_generated_circuit(
-                circuit,
-                need_stable,
-                need_fast,
-                time.clone(),
-                None,              // circuit is clean
-                Some(need_handle), // this is to cover a port need
-            ));
+            if need_handle.can_be_covered_by_circuit(&circuit, circgen, &self.exit_policies) {
+                circuit.covered_needs.push(need_handle);
+            } else {
+                trace!(
+                    "Exit {} of the new circuit does not cover need {}",
+                    circuit.exit,
+                    need_handle
+                );
+                num_not_covering += 1;
+            }
+            self.circuits.push(circuit);
+            if num_not_covering >= *MAX_BUILD_ATTEMPTS {
+                break;
+            }
         }
 
         Ok(())
     }
 
+    /// Build an internal circuit (e.g. for onion services), using the vanguards.
+    ///
+    /// Returns `None` if no guard or bridge is reachable, or if every
+    /// attempt failed.
+    fn build_internal_circuit(
+        &mut self,
+        time: &DateTime<Utc>,
+        circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+    ) -> anyhow::Result<Option<(TorCircuit, FirstHop)>> {
+        self.build_with_retries(time, circgen, observer, |manager, first_hop| {
+            let guard = match first_hop {
+                FirstHop::Guard(guard) => Some(guard),
+                FirstHop::Bridge(_) => None,
+            };
+            Ok(manager.vanguards.build_internal_circuit(guard, circgen))
+        })
+    }
+
     /// Try to accommodate a stream request, using the existing circuits etc.
     fn handle_request(
         &mut self,
@@ -341,8 +980,16 @@ impl CircuitManager {
         // in a single function.
         let mut request = request;
 
+        // the compromised positions of the circuit the stream would have used
+        // if it was not isolated from it
+        let isolated_from = self
+            .get_isolating_circuit(&request, circgen)
+            .map(|circuit| observer.compromised_positions(circuit));
+
         // first check if a dirty circuit is usable
         let mut chosen_circ = self.get_suitable_dirty_circuit(&request, circgen);
+        // a dirty circuit has already been used for another stream
+        let first_use = chosen_circ.is_none();
 
         // check if a clean circuit is usable
         if chosen_circ.is_none() {
@@ -351,35 +998,19 @@ impl CircuitManager {
 
         // otherwise, make a new circuit
         if chosen_circ.is_none() {
-            let need_stable = LONG_LIVED_PORTS.contains(&request.port);
-            let need_fast = true;
-
-            let guard = self.guards.get_guard_for_circuit(&request.time, circgen);
-
-            let circuit = circgen
-                .build_circuit_with_flags_and_guard(
-                    3,
-                    request.port,
-                    Some(&guard),
-                    need_fast,
-                    need_stable,
-                )
-                .map_err(|e| anyhow::anyhow!(format!("{:?}", e)))?;
-            observer.notify_new_circuit(
-                request.time,
-                &circuit,
-                request.port,
-                format!("to fulfil stream request {:?}", &request),
-            );
-            self.circuits.push(ShallowCircuit::from_generated_circuit(
-                circuit,
-                need_stable,
-                need_fast,
-                request.time.clone(),
-                Some(request.time.clone()), // circuit is dirty
-                None,                       // this is not to cover a port need
-            ));
-            chosen_circ = self.circuits.last();
+            let index = match request.kind {
+                RequestKind::OnionService(service) => {
+                    self.connect_to_onion_service(service, &request, circgen, observer)?
+                }
+                RequestKind::Exit | RequestKind::Resolve => {
+                    self.build_exit_circuit(&request, circgen, observer)?
+                }
+            };
+            match index {
+                Some(index) => chosen_circ = Some(&self.circuits[index]),
+                // the observer has already been notified
+                None => return Ok(()),
+            }
         }
 
         // We now have a ready-to-use circuit to handle the request
@@ -394,6 +1025,8 @@ impl CircuitManager {
         let packet_timestamps = std::mem::take(&mut request.packet_timestamps);
         observer.notify_circuit_used(
             chosen_circ,
+            first_use,
+            isolated_from,
             &request,
             packet_timestamps,
             csv_writer,
@@ -406,18 +1039,35 @@ impl CircuitManager {
 
         // Now that we used a circuit to meet a stream request, remember the need for this port
         // so we build appropriate circuits in advance to future requests to the same port.
-        // (In TorPS, this is `stream_update_port_needs()`.)
+        // (In TorPS, this is `stream_update_port_needs()`.) After using an onion service,
+        // we keep internal circuits available instead.
         {
-            let port = request.port;
+            let (target, stable) = match request.kind {
+                RequestKind::OnionService(_) => (NeedTarget::Internal, false),
+                _ => {
+                    let port = exit_port(&request);
+                    let ipv6 = request.kind == RequestKind::Exit
+                        && request
+                            .destination
+                            .is_some_and(|destination| destination.address.is_ipv6());
+                    let target = if ipv6 {
+                        NeedTarget::Ipv6Port(port)
+                    } else {
+                        NeedTarget::Port(port)
+                    };
+                    (target, LONG_LIVED_PORTS.contains(&port))
+                }
+            };
             let fast = true;
-            let stable = LONG_LIVED_PORTS.contains(&request.port);
 
             // add need or update expiration
-            let need_string = self.port_needs.add_need(port, &request.time, fast, stable);
+            let need_string = self
+                .port_needs
+                .add_need(target, &request.time, fast, stable);
             observer.notify_new_need(&request.time, need_string);
 
             // check if this need can be covered by an existing, clean circuit
-            let need_handle = self.port_needs.cover_need_if_necessary(port);
+            let need_handle = self.port_needs.cover_need_if_necessary(target);
 
             if let Some(need_handle) = need_handle {
                 // the need is now covered because we have created a handle, but
@@ -427,16 +1077,15 @@ impl CircuitManager {
                 'circuit_loop: for circuit in
                     self.circuits.iter_mut().filter(|c| c.dirty_time.is_none())
                 {
-                    // ignore this circuit if it covers the port already
+                    // ignore this circuit if it covers the need already
                     for existing_cover in circuit.covered_needs.iter() {
-                        if let Some(existing_port) = existing_cover.get_port() {
-                            if existing_port == port {
-                                continue 'circuit_loop;
-                            }
+                        if existing_cover.get_target() == Some(target) {
+                            continue 'circuit_loop;
                         }
                     }
 
-                    if need_handle.can_be_covered_by_circuit(circuit, circgen) {
+                    if need_handle.can_be_covered_by_circuit(circuit, circgen, &self.exit_policies)
+                    {
                         // cover this need
                         circuit.covered_needs.push(need_handle);
                         break;
@@ -448,6 +1097,182 @@ impl CircuitManager {
         Ok(())
     }
 
+    /// Build a new (dirty) exit circuit for a request.
+    ///
+    /// Returns the circuit's index, or `None` if it could not be built.
+    fn build_exit_circuit(
+        &mut self,
+        request: &Request,
+        circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+    ) -> anyhow::Result<Option<usize>> {
+        let port = exit_port(request);
+        let need_stable = LONG_LIVED_PORTS.contains(&port);
+        let need_fast = true;
+
+        // The circuit generator chooses the exit by the port only. If the
+        // exit's full policy rejects the destination, the stream is tried on
+        // another circuit, like after END_STREAM_REASON_EXITPOLICY in Tor. The
+        // rejecting circuit stays available as a clean circuit. Exits for
+        // IPv6 destinations are chosen by their IPv6 policy right away.
+        let ipv6 = request
+            .destination
+            .is_some_and(|destination| destination.address.is_ipv6());
+        for _ in 0..*MAX_BUILD_ATTEMPTS {
+            let built = if ipv6 {
+                self.build_ipv6_circuit(
+                    &request.time,
+                    port,
+                    need_fast,
+                    need_stable,
+                    circgen,
+                    observer,
+                )?
+            } else {
+                self.build_circuit(
+                    &request.time,
+                    port,
+                    need_fast,
+                    need_stable,
+                    circgen,
+                    observer,
+                )?
+            };
+            let (circuit, first_hop) = match built {
+                Some(built) => built,
+                None => {
+                    observer.notify_request_failed(
+                        request,
+                        "no reachable guard or bridge, or too many circuit failures",
+                    );
+                    return Ok(None);
+                }
+            };
+            let mut circuit = ShallowCircuit::from_generated_circuit(
+                circuit,
+                false,
+                need_stable,
+                need_fast,
+                request.time,
+                None, // circuit is clean until the stream is attached
+                None, // this is not to cover a port need
+            )
+            .with_first_hop(first_hop);
+            observer.notify_new_circuit(
+                request.time,
+                &circuit,
+                port,
+                format!("to fulfil stream request {:?}", request),
+            );
+
+            let accepted = circuit.supports_stream(request, circgen, &self.exit_policies);
+            if accepted {
+                circuit.dirty_time = Some(request.time);
+                circuit.isolation = Some(StreamIsolation::of(request));
+            }
+            self.circuits.push(circuit);
+            if accepted {
+                return Ok(Some(self.circuits.len() - 1));
+            }
+            trace!(
+                "Exit {} rejects the destination of stream request {:?}",
+                self.circuits.last().unwrap().exit, // cannot fail as we just pushed it
+                request
+            );
+        }
+
+        observer.notify_request_failed(request, "no exit accepts the destination");
+        Ok(None)
+    }
+
+    /// Connect to an onion service (see rend-spec-v3.txt): introduce
+    /// ourselves to the service through one internal circuit, which is
+    /// closed afterwards, and meet the service at the end of another one,
+    /// the rendezvous circuit. Fetching the service's descriptor is not
+    /// modelled.
+    ///
+    /// Returns the index of the rendezvous circuit, or `None` if the
+    /// connection failed.
+    fn connect_to_onion_service(
+        &mut self,
+        service: u64,
+        request: &Request,
+        circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+    ) -> anyhow::Result<Option<usize>> {
+        let time = request.time;
+        let failed = |observer: &mut ClientObserver| {
+            observer.notify_request_failed(
+                request,
+                "no reachable guard or bridge, or too many circuit failures",
+            );
+            Ok(None)
+        };
+
+        let introduction =
+            match self.take_internal_circuit(&time, "to introduce", circgen, observer)? {
+                Some(index) => self.circuits.remove(index),
+                None => return failed(observer),
+            };
+        observer.notify_circuit_closed(&time, &introduction, CircuitCloseReason::Introduced);
+
+        let rendezvous =
+            match self.take_internal_circuit(&time, "to rendezvous", circgen, observer)? {
+                Some(index) => index,
+                None => return failed(observer),
+            };
+        self.circuits[rendezvous].onion_service = Some(service);
+        self.circuits[rendezvous].isolation = Some(StreamIsolation::of(request));
+        Ok(Some(rendezvous))
+    }
+
+    /// Take a clean internal circuit for connecting to an onion service, or
+    /// build a new one if there is none. The circuit is dirty afterwards.
+    ///
+    /// Returns the circuit's index, or `None` if it could not be built.
+    fn take_internal_circuit(
+        &mut self,
+        time: &DateTime<Utc>,
+        purpose: &str,
+        circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+    ) -> anyhow::Result<Option<usize>> {
+        if let Some(index) = self
+            .circuits
+            .iter()
+            .position(|circuit| circuit.is_internal && circuit.dirty_time.is_none())
+        {
+            let circuit = &mut self.circuits[index];
+            circuit.dirty_time = Some(*time);
+            // it does not cover the internal need anymore
+            circuit.covered_needs.clear();
+            return Ok(Some(index));
+        }
+
+        let (circuit, first_hop) = match self.build_internal_circuit(time, circgen, observer)? {
+            Some(built) => built,
+            None => return Ok(None),
+        };
+        let circuit = ShallowCircuit::from_generated_circuit(
+            circuit,
+            true,
+            true,
+            true,
+            *time,
+            Some(*time), // circuit is dirty
+            None,        // this is not to cover a need
+        )
+        .with_first_hop(first_hop);
+        observer.notify_new_circuit(
+            *time,
+            &circuit,
+            0,
+            format!("{} to an onion service", purpose),
+        );
+        self.circuits.push(circuit);
+        Ok(Some(self.circuits.len() - 1))
+    }
+
     /// Select an existing **dirty** circuit that is suitable for handling a given stream request
     fn get_suitable_dirty_circuit(
         &mut self,
@@ -455,17 +1280,33 @@ impl CircuitManager {
         circgen: &CircuitGenerator,
     ) -> Option<&ShallowCircuit> {
         for circ in self.circuits.iter_mut() {
-            if let Some(dirty_time) = circ.dirty_time {
-                if request.time < dirty_time + *MAX_CIRCUIT_DIRTINESS
-                    && circ.supports_stream(&request, circgen)
-                {
-                    return Some(circ);
-                }
+            if circ.is_reusable_for(request, circgen, &self.exit_policies)
+                && circ
+                    .isolation
+                    .is_none_or(|isolation| isolation.allows(request))
+            {
+                return Some(circ);
             }
         }
         None
     }
 
+    /// Find the dirty circuit a stream request would use if streams were not
+    /// isolated, if isolation keeps the request from using it
+    fn get_isolating_circuit(
+        &self,
+        request: &Request,
+        circgen: &CircuitGenerator,
+    ) -> Option<&ShallowCircuit> {
+        self.circuits
+            .iter()
+            .find(|circ| circ.is_reusable_for(request, circgen, &self.exit_policies))
+            .filter(|circ| {
+                circ.isolation
+                    .is_some_and(|isolation| !isolation.allows(request))
+            })
+    }
+
     /// Select an existing **clean** circuit that is suitable for handling a given stream request
     fn get_suitable_clean_circuit(
         &mut self,
@@ -474,12 +1315,13 @@ impl CircuitManager {
     ) -> Option<&ShallowCircuit> {
         for circ in self.circuits.iter_mut() {
             if circ.dirty_time.is_none() {
-                if circ.supports_stream(&request, circgen) {
+                if circ.supports_stream(request, circgen, &self.exit_policies) {
                     // TODO make sure we check somewhere else circuit_idle_timeout
                     // TODO Do we maybe have to reorder the circuits? TorPS uses .appendleft()
 
                     // make this circuit dirty
                     circ.dirty_time = Some(request.time.clone());
+                    circ.isolation = Some(StreamIsolation::of(request));
 
                     // As this circuit is now in use, it doesn't cover the port needs
                     // it may have covered before (not spare anymore). We thus
@@ -495,3 +1337,19 @@ impl CircuitManager {
         None
     }
 }
+
+/// The port to choose the exit for, for requests that go through an exit
+fn exit_port(request: &Request) -> u16 {
+    match request.kind {
+        RequestKind::Resolve => RESOLVE_PORT,
+        _ => request.port,
+    }
+}
+
+/// The port of a need, for logging (internal needs have none)
+fn need_port(target: NeedTarget) -> u16 {
+    match target {
+        NeedTarget::Port(port) | NeedTarget::Ipv6Port(port) => port,
+        NeedTarget::Internal => 0,
+    }
+}
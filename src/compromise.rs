@@ -0,0 +1,267 @@
+//! Analysis of client compromise, in the style of TorPS
+//!
+//! For every client, we record when it first used a circuit with an
+//! adversarial guard, an adversarial exit, and both at the same time
+//! (end-to-end compromise), as well as how many of its streams were
+//! compromised end-to-end. From this, we derive the distributions of the
+//! time to first compromise and of the fraction of compromised streams.
+//! Streams to onion services do not leave through an exit, so they can only
+//! contribute to guard compromise (see `CompromisedPositions`).
+//!
+//! Like in TorPS, the median time to first compromise is taken over all
+//! clients, counting clients that were never compromised as infinite. It is
+//! therefore only defined if at least half of the clients were compromised.
+
+use std::path::Path;
+
+use anyhow;
+use chrono::{DateTime, Utc};
+#[allow(unused_imports)]
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::observer::CompromisedPositions;
+use crate::trials::TrialMetrics;
+use crate::utils::{from_micros, to_micros};
+
+/// The compromise history of a single client
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClientCompromise {
+    first_guard: Option<DateTime<Utc>>,
+    first_exit: Option<DateTime<Utc>>,
+    first_end_to_end: Option<DateTime<Utc>>,
+    streams: u64,
+    compromised_streams: u64,
+}
+
+impl ClientCompromise {
+    /// Record a stream carried by a circuit with the given adversarial positions
+    pub(crate) fn record_stream(&mut self, time: &DateTime<Utc>, positions: CompromisedPositions) {
+        self.streams += 1;
+
+        if positions.guard && self.first_guard.is_none() {
+            self.first_guard = Some(*time);
+        }
+        if positions.exit && self.first_exit.is_none() {
+            self.first_exit = Some(*time);
+        }
+        if positions.guard && positions.exit {
+            self.compromised_streams += 1;
+            if self.first_end_to_end.is_none() {
+                self.first_end_to_end = Some(*time);
+            }
+        }
+    }
+
+    pub(crate) fn to_state(&self) -> ClientCompromiseState {
+        ClientCompromiseState {
+            first_guard: self.first_guard.as_ref().map(to_micros),
+            first_exit: self.first_exit.as_ref().map(to_micros),
+            first_end_to_end: self.first_end_to_end.as_ref().map(to_micros),
+            streams: self.streams,
+            compromised_streams: self.compromised_streams,
+        }
+    }
+
+    pub(crate) fn from_state(state: &ClientCompromiseState) -> anyhow::Result<ClientCompromise> {
+        Ok(ClientCompromise {
+            first_guard: state.first_guard.map(from_micros).transpose()?,
+            first_exit: state.first_exit.map(from_micros).transpose()?,
+            first_end_to_end: state.first_end_to_end.map(from_micros).transpose()?,
+            streams: state.streams,
+            compromised_streams: state.compromised_streams,
+        })
+    }
+}
+
+/// The compromise history of a client, as stored in checkpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ClientCompromiseState {
+    first_guard: Option<i64>,
+    first_exit: Option<i64>,
+    first_end_to_end: Option<i64>,
+    streams: u64,
+    compromised_streams: u64,
+}
+
+/// The compromise statistics of all clients
+pub(crate) struct CompromiseAnalysis {
+    /// Time to first guard compromise, in days (one entry per compromised client)
+    guard_days: Vec<f64>,
+    /// Time to first exit compromise, in days
+    exit_days: Vec<f64>,
+    /// Time to first end-to-end compromise, in days
+    end_to_end_days: Vec<f64>,
+    /// Fraction of compromised streams (one entry per client with any streams)
+    stream_fractions: Vec<f64>,
+    num_clients: usize,
+}
+
+impl CompromiseAnalysis {
+    /// Evaluate the clients' compromise histories, relative to the simulation start
+    pub(crate) fn new<'a>(
+        start_time: &DateTime<Utc>,
+        clients: impl IntoIterator<Item = &'a ClientCompromise>,
+    ) -> CompromiseAnalysis {
+        let days_since_start =
+            |time: &DateTime<Utc>| (*time - *start_time).num_seconds() as f64 / 86400.0;
+
+        let mut analysis = CompromiseAnalysis {
+            guard_days: Vec::new(),
+            exit_days: Vec::new(),
+            end_to_end_days: Vec::new(),
+            stream_fractions: Vec::new(),
+            num_clients: 0,
+        };
+
+        for client in clients {
+            analysis.num_clients += 1;
+            if let Some(ref time) = client.first_guard {
+                analysis.guard_days.push(days_since_start(time));
+            }
+            if let Some(ref time) = client.first_exit {
+                analysis.exit_days.push(days_since_start(time));
+            }
+            if let Some(ref time) = client.first_end_to_end {
+                analysis.end_to_end_days.push(days_since_start(time));
+            }
+            if client.streams > 0 {
+                analysis
+                    .stream_fractions
+                    .push(client.compromised_streams as f64 / client.streams as f64);
+            }
+        }
+
+        for values in [
+            &mut analysis.guard_days,
+            &mut analysis.exit_days,
+            &mut analysis.end_to_end_days,
+            &mut analysis.stream_fractions,
+        ] {
+            values.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+        }
+
+        analysis
+    }
+
+    pub(crate) fn print_summary(&self) {
+        let fraction = |n: usize| {
+            if self.num_clients == 0 {
+                0.0
+            } else {
+                n as f64 / self.num_clients as f64 * 100.0
+            }
+        };
+        for (name, values) in [
+            ("guard", &self.guard_days),
+            ("exit", &self.exit_days),
+            ("end-to-end", &self.end_to_end_days),
+        ] {
+            info!(
+                "Clients with {} compromise: {} of {} ({:.2} %), median time to first compromise: {}",
+                name,
+                values.len(),
+                self.num_clients,
+                fraction(values.len()),
+                match median(values, self.num_clients) {
+                    Some(days) => format!("{:.2} days", days),
+                    None => "-".to_string(),
+                }
+            );
+        }
+
+        if !self.stream_fractions.is_empty() {
+            info!(
+                "Fraction of compromised streams per client: mean {:.4}, median {:.4}",
+                self.stream_fractions.iter().sum::<f64>() / self.stream_fractions.len() as f64,
+                median(&self.stream_fractions, self.stream_fractions.len()).unwrap()
+            );
+        }
+    }
+
+    /// The metrics of this run that are compared across trials.
+    ///
+    /// Medians are only given if they are defined (see [median]), means only
+    /// if there is at least one value.
+    pub(crate) fn metrics(&self) -> TrialMetrics {
+        let mut metrics = Vec::new();
+
+        for (name, values) in [
+            ("guard", &self.guard_days),
+            ("exit", &self.exit_days),
+            ("end_to_end", &self.end_to_end_days),
+        ] {
+            if self.num_clients > 0 {
+                metrics.push((
+                    format!("clients_{}_compromised_fraction", name),
+                    values.len() as f64 / self.num_clients as f64,
+                ));
+            }
+            if let Some(days) = median(values, self.num_clients) {
+                metrics.push((format!("median_time_to_{}_compromise_days", name), days));
+            }
+        }
+
+        if !self.stream_fractions.is_empty() {
+            metrics.push((
+                "mean_compromised_stream_fraction".to_string(),
+                self.stream_fractions.iter().sum::<f64>() / self.stream_fractions.len() as f64,
+            ));
+        }
+
+        metrics
+    }
+
+    /// Write the empirical CDFs to a CSV file.
+    ///
+    /// Each row contains a metric, a value and the fraction of clients with at
+    /// most this value. For the times to first compromise, clients that were
+    /// never compromised are not listed, so these CDFs end at the fraction of
+    /// compromised clients.
+    pub(crate) fn write_csv(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record(["metric", "value", "cdf"])?;
+
+        let num_streaming_clients = self.stream_fractions.len();
+        for (metric, values, total) in [
+            (
+                "time_to_guard_compromise_days",
+                &self.guard_days,
+                self.num_clients,
+            ),
+            (
+                "time_to_exit_compromise_days",
+                &self.exit_days,
+                self.num_clients,
+            ),
+            (
+                "time_to_end_to_end_compromise_days",
+                &self.end_to_end_days,
+                self.num_clients,
+            ),
+            (
+                "compromised_stream_fraction",
+                &self.stream_fractions,
+                num_streaming_clients,
+            ),
+        ] {
+            for (i, value) in values.iter().enumerate() {
+                writer.write_record([
+                    metric.to_string(),
+                    value.to_string(),
+                    ((i + 1) as f64 / total as f64).to_string(),
+                ])?;
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Get the median of `total` values, of which the given sorted `values` are
+/// finite and the remaining ones are infinite. Returns `None` if the median
+/// is infinite (or there are no values at all).
+fn median(values: &[f64], total: usize) -> Option<f64> {
+    values.get(total / 2).copied()
+}
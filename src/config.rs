@@ -0,0 +1,1038 @@
+//! Declarative experiment configuration
+//!
+//! An experiment can be described by a TOML or JSON file that covers all the
+//! knobs of a simulation run. Command-line arguments take precedence over the
+//! values from the file. After merging both, the fully resolved configuration
+//! is written next to the output trace so a run can be reproduced exactly.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{self, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::{Cli, SimulationRangeEdge};
+
+/// The complete description of a simulation experiment
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ExperimentConfig {
+    /// Seed for the random number generators. If 0 or missing, a random seed is used.
+    pub seed: Option<u64>,
+    pub trials: TrialsConfig,
+    pub checkpoint: CheckpointConfig,
+    pub time: TimeConfig,
+    pub input: InputConfig,
+    pub prefetch: PrefetchConfig,
+    pub load: LoadConfig,
+    pub models: ModelsConfig,
+    pub adversary: AdversaryConfig,
+    pub guards: GuardConfig,
+    pub circuits: CircuitConfig,
+    pub circuit_build: CircuitBuildConfig,
+    pub vanguards: VanguardConfig,
+    pub reachability: ReachabilityConfig,
+    pub bridges: BridgeConfig,
+    pub output: OutputConfig,
+}
+
+/// Repeated simulation of the same time range with independent client populations
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TrialsConfig {
+    /// Number of Monte-Carlo trials. The consensuses are only loaded once
+    /// and shared by all trials. Every trial has its own seed, which is
+    /// logged, so a single trial can be reproduced by simulating one trial
+    /// with that seed.
+    pub num: usize,
+}
+
+impl Default for TrialsConfig {
+    fn default() -> Self {
+        TrialsConfig { num: 1 }
+    }
+}
+
+/// Periodic snapshots of the simulation state, to resume an interrupted run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CheckpointConfig {
+    /// Path to write checkpoints to (bincode, zstd-compressed). Each
+    /// checkpoint replaces the previous one. If missing, no checkpoints are written.
+    pub path: Option<PathBuf>,
+    /// Number of epochs (consensuses) between two checkpoints
+    pub every_epochs: usize,
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> Self {
+        CheckpointConfig {
+            path: None,
+            every_epochs: 24,
+        }
+    }
+}
+
+/// The simulated time range
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TimeConfig {
+    /// Begin of simulation timespan
+    pub from: Option<SimulationRangeEdge>,
+    /// End of simulation timespan
+    pub to: Option<SimulationRangeEdge>,
+    /// What to do if consensuses are missing in the archive, including at the
+    /// beginning and end of the simulation timespan
+    pub gap_policy: GapPolicy,
+}
+
+/// Handling of missing consensuses in the archive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum GapPolicy {
+    /// Keep using the previous consensus until it is not valid anymore,
+    /// then skip the remaining time without client activity
+    #[default]
+    Reuse,
+    /// Skip the time without a fresh consensus, without client activity
+    Skip,
+    /// Abort the simulation
+    Abort,
+}
+
+/// Location of the input data
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct InputConfig {
+    /// Location of consensus and descriptor files
+    pub tor_data: Option<PathBuf>,
+    /// Location of the binary consensus cache. If missing, a `torfs-cache`
+    /// directory within the Tor data location is used.
+    pub cache_dir: Option<PathBuf>,
+    /// Where to unpack the needed entries of compressed tarballs. If missing,
+    /// the system's temporary directory is used. This needs roughly as much
+    /// space as the uncompressed descriptor archives of the simulated months.
+    pub unpack_dir: Option<PathBuf>,
+    /// Which consensus flavor (and matching descriptors) clients use.
+    /// Microdescriptors do not contain full exit policies, so with modelled
+    /// destinations, exits are only checked against their port summaries.
+    pub flavor: ConsensusFlavor,
+    /// Guard state file from an earlier run to start the clients with.
+    /// Clients without a stored state bootstrap as usual.
+    pub guard_state: Option<PathBuf>,
+    /// Checkpoint of an interrupted run to resume. The run must use the same
+    /// configuration, and its outputs are continued.
+    pub resume: Option<PathBuf>,
+}
+
+/// The consensus flavor used for path selection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ConsensusFlavor {
+    /// The full (`ns`) consensus together with server descriptors
+    #[default]
+    Full,
+    /// The microdesc consensus together with microdescriptors, as used by
+    /// current Tor clients
+    Microdesc,
+}
+
+impl InputConfig {
+    /// Get the location of the binary consensus cache, if it can be determined
+    pub(crate) fn cache_dir(&self) -> Option<PathBuf> {
+        self.cache_dir
+            .clone()
+            .or_else(|| self.tor_data.as_ref().map(|dir| dir.join("torfs-cache")))
+    }
+
+    /// Get the directory to unpack tarball entries to
+    pub(crate) fn unpack_dir(&self) -> PathBuf {
+        self.unpack_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+}
+
+/// Background loading of upcoming consensuses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PrefetchConfig {
+    /// Number of epochs that are prepared in advance, in addition to the next one
+    pub depth: usize,
+    /// Do not prepare further epochs while the process uses more memory than
+    /// this (in MiB). Only supported on Linux.
+    pub max_memory_mb: Option<u64>,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        PrefetchConfig {
+            depth: 1,
+            max_memory_mb: None,
+        }
+    }
+}
+
+/// The number of clients and the load they generate
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LoadConfig {
+    /// Number of clients. If missing, use values from PrivCount measurements.
+    pub clients: Option<u64>,
+    /// Scale the load by number of users, relative to the PrivCount measurements
+    pub load_scale: f64,
+    /// Fraction of requests that only resolve a hostname (instead of opening
+    /// a stream)
+    pub resolve_fraction: f64,
+    /// Fraction of flows that go to an onion service instead of through an exit
+    pub onion_fraction: f64,
+    /// Number of onion services that the onion service flows are spread over
+    pub onion_services: u64,
+    /// Number of destination hosts the streams are spread over. With a
+    /// destination list, the number of most popular hosts taken from it (all
+    /// of them if zero). Without a list, zero means destinations are not
+    /// modelled.
+    pub destinations: u64,
+    /// A popularity list of destination hosts, as CSV lines `rank,host` (like
+    /// the Tranco list), optionally followed by the host's IPv4 and/or IPv6
+    /// addresses
+    pub destination_list: Option<PathBuf>,
+    /// Exponent of the Zipf distribution of the destinations' popularity by
+    /// their rank (0 for uniform)
+    pub destination_popularity: f64,
+    /// Fraction of the hosts without a known address that also have an IPv6
+    /// address (the others are IPv4-only)
+    pub dual_stack_fraction: f64,
+    /// Probability that a stream to a host with both an IPv4 and an IPv6
+    /// address uses IPv6
+    pub ipv6_preference: f64,
+    /// Open the streams of a flow with SOCKS credentials for the flow's
+    /// first destination, like Tor Browser's first-party isolation
+    pub first_party_isolation: bool,
+}
+
+impl Default for LoadConfig {
+    fn default() -> Self {
+        LoadConfig {
+            clients: None,
+            load_scale: 1.0,
+            resolve_fraction: 0.0,
+            onion_fraction: 0.0,
+            onion_services: 1000,
+            destinations: 0,
+            destination_list: None,
+            destination_popularity: 1.0,
+            dual_stack_fraction: 0.0,
+            ipv6_preference: 0.0,
+            first_party_isolation: false,
+        }
+    }
+}
+
+impl LoadConfig {
+    /// Whether streams go to modelled destination hosts
+    pub(crate) fn models_destinations(&self) -> bool {
+        self.destinations > 0 || self.destination_list.is_some()
+    }
+
+    /// Make sure the load is not given in two conflicting ways
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        if self.clients.is_some() && self.load_scale != 1.0 {
+            anyhow::bail!("Cannot give both a number of clients (load.clients) and a load scale (load.load_scale)");
+        }
+        Ok(())
+    }
+}
+
+/// The user behavior models
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ModelsConfig {
+    /// Path to the (prepared) stream model JSON file
+    pub stream_model: Option<PathBuf>,
+    /// Path to the (prepared) packet model JSON file
+    pub packet_model: Option<PathBuf>,
+}
+
+/// The adversarial relays injected into the network
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AdversaryConfig {
+    /// Number of adversarial guards
+    pub guards_num: Option<u64>,
+    /// Consensus weight per adversarial guard
+    pub guards_bw: Option<u64>,
+    /// Number of adversarial exits
+    pub exits_num: Option<u64>,
+    /// Consensus weight per adversarial exit
+    pub exits_bw: Option<u64>,
+    /// Number of adversarial middle relays
+    pub middles_num: Option<u64>,
+    /// Consensus weight per adversarial middle relay
+    pub middles_bw: Option<u64>,
+    /// Adversary specification file (TOML or JSON) that lists injected and
+    /// compromised relays individually
+    pub spec: Option<PathBuf>,
+}
+
+impl AdversaryConfig {
+    /// Make sure every number of injected relays comes with their weight, and
+    /// vice versa
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        for (kind, num, bw) in [
+            ("guards", self.guards_num, self.guards_bw),
+            ("exits", self.exits_num, self.exits_bw),
+            ("middles", self.middles_num, self.middles_bw),
+        ] {
+            if num.is_some() != bw.is_some() {
+                anyhow::bail!(
+                    "adversary.{}_num and adversary.{}_bw have to be given together",
+                    kind,
+                    kind
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parameters of the guard selection algorithm (see guard-spec.txt)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct GuardConfig {
+    /// How clients choose their guards
+    pub policy: GuardPolicyKind,
+    /// GUARD_LIFETIME, in days
+    pub lifetime_days: i64,
+    /// REMOVE_UNLISTED_GUARDS_AFTER, in days
+    pub remove_unlisted_after_days: i64,
+    /// GUARD_CONFIRMED_MIN_LIFETIME, in days
+    pub confirmed_min_lifetime_days: i64,
+    /// MIN_FILTERED_SAMPLE
+    pub min_filtered_sample: usize,
+    /// MAX_SAMPLE_SIZE
+    pub max_sample_size: usize,
+    /// MAX_SAMPLE_THRESHOLD
+    pub max_sample_threshold: f64,
+    /// N_PRIMARY_GUARDS
+    pub n_primary_guards: usize,
+    /// N_USABLE_PRIMARY_GUARDS
+    pub n_usable_primary_guards: usize,
+    /// Minimum consensus weight of guards (bandwidth policy only)
+    pub min_bandwidth: u64,
+    /// Number of guard sets that clients are distributed over (sets policy only)
+    pub num_sets: usize,
+    /// Minimum total consensus weight of a guard set (sets policy only)
+    pub set_min_bandwidth: u64,
+}
+
+/// The guard selection policies that can be compared
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum GuardPolicyKind {
+    /// Tor's current guard algorithm (guard-spec.txt)
+    #[default]
+    Tor,
+    /// Tor's algorithm, restricted to guards above a consensus weight threshold
+    Bandwidth,
+    /// A single guard that is only replaced when it leaves the network or expires
+    Single,
+    /// Clients share a guard set whose total weight is above a threshold
+    /// (Hayes and Danezis, "Guard Sets for Onion Routing")
+    Sets,
+}
+
+impl Default for GuardConfig {
+    fn default() -> Self {
+        GuardConfig {
+            policy: GuardPolicyKind::default(),
+            lifetime_days: 120,
+            remove_unlisted_after_days: 20,
+            confirmed_min_lifetime_days: 60,
+            min_filtered_sample: 20,
+            max_sample_size: 60,
+            max_sample_threshold: 0.2,
+            n_primary_guards: 3,
+            n_usable_primary_guards: 1,
+            min_bandwidth: 0,
+            num_sets: 100,
+            set_min_bandwidth: 40_000,
+        }
+    }
+}
+
+impl GuardConfig {
+    /// Make sure the guard parameters leave guards that can be used
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        if self.lifetime_days <= 0 || self.confirmed_min_lifetime_days <= 0 {
+            anyhow::bail!(
+                "Guard lifetimes must be positive (lifetime_days {}, confirmed_min_lifetime_days {})",
+                self.lifetime_days,
+                self.confirmed_min_lifetime_days
+            );
+        }
+        if self.remove_unlisted_after_days < 0 {
+            anyhow::bail!(
+                "Invalid time to remove unlisted guards after ({} days)",
+                self.remove_unlisted_after_days
+            );
+        }
+        if self.n_primary_guards == 0
+            || self.n_usable_primary_guards == 0
+            || self.n_usable_primary_guards > self.n_primary_guards
+        {
+            anyhow::bail!(
+                "Invalid number of primary guards ({} primary, {} usable)",
+                self.n_primary_guards,
+                self.n_usable_primary_guards
+            );
+        }
+        if !(self.max_sample_threshold > 0.0 && self.max_sample_threshold <= 1.0) {
+            anyhow::bail!(
+                "Invalid maximum sample threshold {}",
+                self.max_sample_threshold
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Parameters of the circuit handling
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CircuitConfig {
+    /// MaxCircuitDirtiness, in seconds
+    pub max_dirtiness_secs: i64,
+    /// Time after which unused (clean) circuits are closed, in seconds
+    pub idle_timeout_secs: i64,
+    /// LongLivedPorts, which need circuits with stable relays
+    pub long_lived_ports: Vec<u16>,
+    /// Number of clean circuits that should cover each port need
+    pub port_need_cover_num: usize,
+    /// Time after which a port need expires, in seconds
+    pub port_need_lifetime_secs: i64,
+    /// Number of clean internal circuits that should be available after
+    /// onion services were used
+    pub internal_need_cover_num: usize,
+    /// IsolateDestAddr: streams to different destination hosts do not share circuits
+    pub isolate_dest_addr: bool,
+    /// IsolateDestPort: streams to different ports do not share circuits
+    pub isolate_dest_port: bool,
+    /// IsolateSOCKSAuth: streams with different SOCKS credentials do not share circuits
+    pub isolate_socks_auth: bool,
+}
+
+impl Default for CircuitConfig {
+    fn default() -> Self {
+        CircuitConfig {
+            max_dirtiness_secs: 10 * 60,
+            idle_timeout_secs: 60 * 60,
+            long_lived_ports: vec![
+                21, 22, 706, 1863, 5050, 5190, 5222, 5223, 6523, 6667, 6697, 8300,
+            ],
+            // min coverage given with "#define MIN_CIRCUITS_HANDLING_STREAM 2" in or.h
+            port_need_cover_num: 2,
+            // #define PREDICTED_CIRCS_RELEVANCE_TIME 60*60" in rephist.c
+            port_need_lifetime_secs: 60 * 60,
+            // "#define SUFFICIENT_INTERNAL_HS_CLIENTS 3" in circuituse.c
+            internal_need_cover_num: 3,
+            // the default isolation flags of a SocksPort
+            isolate_dest_addr: false,
+            isolate_dest_port: false,
+            isolate_socks_auth: true,
+        }
+    }
+}
+
+/// Latency and failures of circuit construction, and the circuit build
+/// timeout that clients learn from it (see path-spec.txt, section 2.4)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CircuitBuildConfig {
+    /// Simulate the construction of circuits. If disabled, every circuit is
+    /// built instantly and successfully.
+    pub enabled: bool,
+    /// Median time to extend a circuit by one hop, in milliseconds
+    pub hop_latency_median_ms: f64,
+    /// Shape (sigma of the underlying normal distribution) of the log-normal
+    /// time to extend a circuit by one hop
+    pub hop_latency_sigma: f64,
+    /// Probability that extending a circuit to a relay fails
+    pub extend_failure_probability: f64,
+    /// Number of attempts to build a circuit before giving up
+    pub max_attempts: usize,
+    /// CBT_NCIRCUITS_TO_OBSERVE
+    pub cbt_history: usize,
+    /// cbtmincircs
+    pub cbt_min_circuits: usize,
+    /// cbtquantile, in percent
+    pub cbt_quantile: f64,
+    /// cbtinitialtimeout, in milliseconds
+    pub cbt_initial_timeout_ms: f64,
+    /// cbtmintimeout, in milliseconds
+    pub cbt_min_timeout_ms: f64,
+    /// cbtnummodes
+    pub cbt_num_modes: usize,
+    /// cbtrecentcount
+    pub cbt_recent_count: usize,
+    /// cbtmaxtimeouts
+    pub cbt_max_timeouts: usize,
+}
+
+impl Default for CircuitBuildConfig {
+    fn default() -> Self {
+        CircuitBuildConfig {
+            enabled: false,
+            hop_latency_median_ms: 200.0,
+            hop_latency_sigma: 0.7,
+            extend_failure_probability: 0.01,
+            // "#define MAX_CIRCUIT_FAILURES 5" in circuituse.c
+            max_attempts: 5,
+            // the following are the defaults from circuitstats.h
+            cbt_history: 1000,
+            cbt_min_circuits: 100,
+            cbt_quantile: 80.0,
+            cbt_initial_timeout_ms: 60.0 * 1000.0,
+            cbt_min_timeout_ms: 10.0,
+            cbt_num_modes: 10,
+            cbt_recent_count: 20,
+            cbt_max_timeouts: 18,
+        }
+    }
+}
+
+impl CircuitBuildConfig {
+    /// Make sure the parameters describe valid distributions
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        if self.hop_latency_median_ms <= 0.0 || self.hop_latency_sigma < 0.0 {
+            anyhow::bail!(
+                "Invalid hop latency distribution (median {} ms, sigma {})",
+                self.hop_latency_median_ms,
+                self.hop_latency_sigma
+            );
+        }
+        if !(0.0..=1.0).contains(&self.extend_failure_probability) {
+            anyhow::bail!(
+                "Invalid extend failure probability {}",
+                self.extend_failure_probability
+            );
+        }
+        if self.max_attempts == 0 {
+            anyhow::bail!("Circuits need at least one build attempt (circuit_build.max_attempts)");
+        }
+        if !(0.0..100.0).contains(&self.cbt_quantile) {
+            anyhow::bail!(
+                "Invalid circuit build timeout quantile {}",
+                self.cbt_quantile
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Pinned middle relays for internal circuits (see vanguards-spec.txt)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct VanguardConfig {
+    pub mode: VanguardsMode,
+    /// NUM_LAYER2_GUARDS
+    pub layer2_num: usize,
+    /// NUM_LAYER3_GUARDS (full vanguards only)
+    pub layer3_num: usize,
+    /// Lifetime of layer-2 guards is MAX(X, X) for X uniform in [min, max], in hours
+    pub layer2_lifetime_min_hours: i64,
+    pub layer2_lifetime_max_hours: i64,
+    /// Lifetime of layer-3 guards is MAX(X, X) for X uniform in [min, max], in hours
+    pub layer3_lifetime_min_hours: i64,
+    pub layer3_lifetime_max_hours: i64,
+}
+
+impl Default for VanguardConfig {
+    fn default() -> Self {
+        VanguardConfig {
+            mode: VanguardsMode::default(),
+            layer2_num: 4,
+            layer3_num: 6,
+            layer2_lifetime_min_hours: 24,
+            layer2_lifetime_max_hours: 12 * 24,
+            layer3_lifetime_min_hours: 1,
+            layer3_lifetime_max_hours: 48,
+        }
+    }
+}
+
+impl VanguardConfig {
+    /// Make sure the vanguard lifetimes describe valid ranges and that there
+    /// are vanguards to use
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        for (layer, min, max) in [
+            (
+                2,
+                self.layer2_lifetime_min_hours,
+                self.layer2_lifetime_max_hours,
+            ),
+            (
+                3,
+                self.layer3_lifetime_min_hours,
+                self.layer3_lifetime_max_hours,
+            ),
+        ] {
+            if min < 0 || min > max {
+                anyhow::bail!(
+                    "Invalid lifetime of layer-{} vanguards ({} to {} hours)",
+                    layer,
+                    min,
+                    max
+                );
+            }
+        }
+        if self.mode != VanguardsMode::None && self.layer2_num == 0 {
+            anyhow::bail!("Vanguards need at least one layer-2 guard (vanguards.layer2_num)");
+        }
+        Ok(())
+    }
+}
+
+/// Which vanguards are used for internal circuits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum VanguardsMode {
+    /// No vanguards, all middle hops are chosen freshly
+    None,
+    /// Layer-2 guards only, as used by current Tor clients
+    #[default]
+    Lite,
+    /// Layer-2 and layer-3 guards
+    Full,
+}
+
+/// Connection failures and outages of relays
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ReachabilityConfig {
+    /// Probability that a connection attempt to a relay fails
+    pub failure_probability: f64,
+    /// Every client additionally fails to connect with an individual
+    /// probability, drawn uniformly from [0, client_failure_max]
+    pub client_failure_max: f64,
+    /// Relays with an individual failure probability or outages
+    pub relays: Vec<RelayReachabilityConfig>,
+}
+
+impl ReachabilityConfig {
+    /// Make sure all failure probabilities are valid
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        if !(0.0..=1.0).contains(&self.failure_probability) {
+            anyhow::bail!(
+                "Invalid connection failure probability {}",
+                self.failure_probability
+            );
+        }
+        if !(0.0..=1.0).contains(&self.client_failure_max) {
+            anyhow::bail!(
+                "Invalid maximum client failure probability {}",
+                self.client_failure_max
+            );
+        }
+        for relay in self.relays.iter() {
+            if let Some(probability) = relay.failure_probability {
+                if !(0.0..=1.0).contains(&probability) {
+                    anyhow::bail!(
+                        "Invalid connection failure probability {} for relay {}",
+                        probability,
+                        relay.fingerprint
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reachability of a single relay
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RelayReachabilityConfig {
+    /// Fingerprint (hex) of the relay
+    pub fingerprint: String,
+    /// Probability that a connection attempt fails, instead of the global one
+    pub failure_probability: Option<f64>,
+    /// Times when the relay cannot be reached at all
+    #[serde(default)]
+    pub outages: Vec<OutageConfig>,
+}
+
+/// A time window when a relay cannot be reached
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OutageConfig {
+    pub from: SimulationRangeEdge,
+    pub until: SimulationRangeEdge,
+}
+
+/// Clients that use bridges instead of guards.
+///
+/// Failure probabilities and outages of individual bridges can be configured
+/// in the reachability section, like for relays.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BridgeConfig {
+    /// Fraction of clients that use bridges
+    pub users_fraction: f64,
+    /// Number of bridges each bridge user configures, chosen at random from the list
+    pub per_client: usize,
+    /// The bridges available to clients
+    pub list: Vec<BridgeSpecConfig>,
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
+        BridgeConfig {
+            users_fraction: 0.0,
+            per_client: 2,
+            list: Vec::new(),
+        }
+    }
+}
+
+/// A single bridge
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BridgeSpecConfig {
+    /// Fingerprint (hex) of the bridge
+    pub fingerprint: String,
+    /// Pluggable transport used to connect to the bridge (e.g. obfs4)
+    pub transport: Option<String>,
+}
+
+/// Where to put the results
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct OutputConfig {
+    /// Path to the output message trace file
+    pub trace: Option<PathBuf>,
+    /// Path to write the resolved configuration to. If missing, it is put
+    /// next to the trace file.
+    pub resolved_config: Option<PathBuf>,
+    /// Path to write the compromise CDFs (CSV) to. If missing, they are put
+    /// next to the trace file.
+    pub compromise: Option<PathBuf>,
+    /// Path to write the clients' guard state to at the end of the run
+    /// (JSON, zstd-compressed if ending with `.zst`)
+    pub guard_state: Option<PathBuf>,
+    /// Path to write the message IDs of the bridge users' streams (CSV)
+    /// to. If missing, they are put next to the trace file.
+    pub bridge_streams: Option<PathBuf>,
+    /// Path to write the metrics aggregated over all trials (CSV) to, if
+    /// there is more than one trial. If missing, they are put next to the
+    /// trace file.
+    pub trials_summary: Option<PathBuf>,
+    /// Path to write the results of a parameter sweep (CSV) to. If missing,
+    /// they are put next to the trace file.
+    pub sweep: Option<PathBuf>,
+}
+
+impl OutputConfig {
+    /// Get the path of a file that is put next to the trace, with the given
+    /// suffix appended to the trace's file name
+    fn next_to_trace(&self, suffix: &str) -> Option<PathBuf> {
+        self.trace.as_ref().map(|trace| {
+            let mut file_name = trace.file_name().unwrap_or_default().to_os_string();
+            file_name.push(suffix);
+            trace.with_file_name(file_name)
+        })
+    }
+
+    /// Get the path where the compromise CDFs are stored, if it can be determined
+    pub(crate) fn compromise_path(&self) -> Option<PathBuf> {
+        self.compromise
+            .clone()
+            .or_else(|| self.next_to_trace(".compromise.csv"))
+    }
+
+    /// Get the path where the bridge users' streams are stored, if it can be determined
+    pub(crate) fn bridge_streams_path(&self) -> Option<PathBuf> {
+        self.bridge_streams
+            .clone()
+            .or_else(|| self.next_to_trace(".bridge-streams.csv"))
+    }
+
+    /// Get the path where the metrics aggregated over all trials are stored,
+    /// if it can be determined
+    pub(crate) fn trials_summary_path(&self) -> Option<PathBuf> {
+        self.trials_summary
+            .clone()
+            .or_else(|| self.next_to_trace(".trials.csv"))
+    }
+
+    /// Get the path where the results of a parameter sweep are stored, if it
+    /// can be determined
+    pub(crate) fn sweep_path(&self) -> Option<PathBuf> {
+        self.sweep
+            .clone()
+            .or_else(|| self.next_to_trace(".sweep.csv"))
+    }
+
+    /// Get the outputs of a single trial. The trial number is inserted into
+    /// the file names of all per-trial outputs, e.g. `trace.trial-3.csv.zst`.
+    pub(crate) fn for_trial(&self, trial: usize) -> OutputConfig {
+        self.with_infix(&format!("trial-{}", trial))
+    }
+
+    /// Get the outputs of a single configuration of a parameter sweep, e.g.
+    /// `trace.config-3.csv.zst`
+    pub(crate) fn for_scenario(&self, scenario: usize) -> OutputConfig {
+        self.with_infix(&format!("config-{}", scenario))
+    }
+
+    /// Insert the infix into the file names of all outputs that are written
+    /// per trial, after the first part of the name
+    fn with_infix(&self, infix: &str) -> OutputConfig {
+        let with_infix = |path: &Option<PathBuf>| {
+            path.as_ref().map(|path| {
+                let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+                let file_name = match file_name.split_once('.') {
+                    Some((stem, extensions)) => format!("{}.{}.{}", stem, infix, extensions),
+                    None => format!("{}.{}", file_name, infix),
+                };
+                path.with_file_name(file_name)
+            })
+        };
+
+        OutputConfig {
+            trace: with_infix(&self.trace),
+            compromise: with_infix(&self.compromise),
+            guard_state: with_infix(&self.guard_state),
+            bridge_streams: with_infix(&self.bridge_streams),
+            // shared by all trials
+            resolved_config: self.resolved_config.clone(),
+            trials_summary: self.trials_summary.clone(),
+            sweep: self.sweep.clone(),
+        }
+    }
+}
+
+impl ExperimentConfig {
+    /// Load an experiment configuration from a TOML or JSON file.
+    ///
+    /// The format is determined by the file extension (`.json` for JSON,
+    /// TOML otherwise).
+    pub(crate) fn from_file(path: impl AsRef<Path>) -> anyhow::Result<ExperimentConfig> {
+        let path = path.as_ref();
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read config file {}", path.display()))?;
+
+        let config = if is_json(path) {
+            serde_json::from_str(&raw)?
+        } else {
+            toml::from_str(&raw)?
+        };
+
+        Ok(config)
+    }
+
+    /// Construct the configuration from the command-line arguments, using
+    /// the config file given there (if any) as a base.
+    pub(crate) fn from_cli(cli: &Cli) -> anyhow::Result<ExperimentConfig> {
+        let mut config = match cli.config {
+            Some(ref path) => ExperimentConfig::from_file(path)?,
+            None => ExperimentConfig::default(),
+        };
+        config.apply_cli(cli);
+
+        Ok(config)
+    }
+
+    /// Make sure the configuration is consistent, including the parts that the
+    /// command line checks for its own arguments
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        self.load.check()?;
+        self.adversary.check()?;
+        self.guards.check()?;
+        self.reachability.check()?;
+        self.circuit_build.check()?;
+        self.vanguards.check()?;
+        Ok(())
+    }
+
+    /// Override the configured values with the ones given on the command line
+    fn apply_cli(&mut self, cli: &Cli) {
+        // helper to override only if a value was given
+        fn set<T: Clone>(target: &mut Option<T>, value: &Option<T>) {
+            if value.is_some() {
+                *target = value.clone();
+            }
+        }
+
+        set(&mut self.seed, &cli.seed);
+        if let Some(num) = cli.trials {
+            self.trials.num = num;
+        }
+        set(&mut self.checkpoint.path, &cli.checkpoint);
+        if let Some(every_epochs) = cli.checkpoint_every {
+            self.checkpoint.every_epochs = every_epochs;
+        }
+        set(&mut self.time.from, &cli.from);
+        set(&mut self.time.to, &cli.to);
+        if let Some(gap_policy) = cli.gap_policy {
+            self.time.gap_policy = gap_policy;
+        }
+        set(&mut self.input.tor_data, &cli.tor_data);
+        set(&mut self.input.cache_dir, &cli.cache_dir);
+        set(&mut self.input.unpack_dir, &cli.unpack_dir);
+        set(&mut self.input.guard_state, &cli.guard_state);
+        set(&mut self.input.resume, &cli.resume);
+        if let Some(flavor) = cli.consensus_flavor {
+            self.input.flavor = flavor;
+        }
+        if let Some(depth) = cli.prefetch_depth {
+            self.prefetch.depth = depth;
+        }
+        set(&mut self.prefetch.max_memory_mb, &cli.prefetch_max_memory);
+        set(&mut self.load.clients, &cli.clients);
+        if let Some(load_scale) = cli.load_scale {
+            self.load.load_scale = load_scale;
+        }
+        if let Some(resolve_fraction) = cli.resolve_fraction {
+            self.load.resolve_fraction = resolve_fraction;
+        }
+        if let Some(onion_fraction) = cli.onion_fraction {
+            self.load.onion_fraction = onion_fraction;
+        }
+        if let Some(destinations) = cli.destinations {
+            self.load.destinations = destinations;
+        }
+        set(&mut self.load.destination_list, &cli.destination_list);
+        if let Some(dual_stack_fraction) = cli.dual_stack_fraction {
+            self.load.dual_stack_fraction = dual_stack_fraction;
+        }
+        if let Some(ipv6_preference) = cli.ipv6_preference {
+            self.load.ipv6_preference = ipv6_preference;
+        }
+        if cli.first_party_isolation {
+            self.load.first_party_isolation = true;
+        }
+        if cli.adv_guards_num.is_some() {
+            // clap makes sure both arguments are given
+            self.adversary.guards_num = cli.adv_guards_num;
+            self.adversary.guards_bw = cli.adv_guards_bw;
+        }
+        if cli.adv_exits_num.is_some() {
+            self.adversary.exits_num = cli.adv_exits_num;
+            self.adversary.exits_bw = cli.adv_exits_bw;
+        }
+        if cli.adv_middles_num.is_some() {
+            self.adversary.middles_num = cli.adv_middles_num;
+            self.adversary.middles_bw = cli.adv_middles_bw;
+        }
+        set(&mut self.adversary.spec, &cli.adv_spec);
+        if let Some(policy) = cli.guard_policy {
+            self.guards.policy = policy;
+        }
+        if let Some(lifetime_days) = cli.guard_lifetime {
+            self.guards.lifetime_days = lifetime_days;
+        }
+        if let Some(min_filtered_sample) = cli.min_filtered_sample {
+            self.guards.min_filtered_sample = min_filtered_sample;
+        }
+        if let Some(max_sample_size) = cli.max_sample_size {
+            self.guards.max_sample_size = max_sample_size;
+        }
+        if let Some(n_primary_guards) = cli.n_primary_guards {
+            self.guards.n_primary_guards = n_primary_guards;
+        }
+        if let Some(n_usable_primary_guards) = cli.n_usable_primary_guards {
+            self.guards.n_usable_primary_guards = n_usable_primary_guards;
+        }
+        if cli.isolate_dest_addr {
+            self.circuits.isolate_dest_addr = true;
+        }
+        if cli.isolate_dest_port {
+            self.circuits.isolate_dest_port = true;
+        }
+        if cli.circuit_build_model {
+            self.circuit_build.enabled = true;
+        }
+        if let Some(extend_failure_probability) = cli.extend_failure_probability {
+            self.circuit_build.extend_failure_probability = extend_failure_probability;
+        }
+        if let Some(max_attempts) = cli.circuit_build_attempts {
+            self.circuit_build.max_attempts = max_attempts;
+        }
+        if let Some(mode) = cli.vanguards {
+            self.vanguards.mode = mode;
+        }
+        if let Some(failure_probability) = cli.failure_probability {
+            self.reachability.failure_probability = failure_probability;
+        }
+        if let Some(client_failure_max) = cli.client_failure_max {
+            self.reachability.client_failure_max = client_failure_max;
+        }
+        if let Some(users_fraction) = cli.bridge_users {
+            self.bridges.users_fraction = users_fraction;
+        }
+        set(&mut self.models.stream_model, &cli.stream_model);
+        set(&mut self.models.packet_model, &cli.packet_model);
+        set(&mut self.output.trace, &cli.output_trace);
+        set(&mut self.output.compromise, &cli.output_compromise);
+        set(&mut self.output.guard_state, &cli.output_guard_state);
+        set(&mut self.output.bridge_streams, &cli.output_bridge_streams);
+        set(&mut self.output.trials_summary, &cli.output_trials_summary);
+        set(&mut self.output.sweep, &cli.output_sweep);
+    }
+
+    /// Get the path where the resolved configuration is stored, if it can be determined
+    pub(crate) fn resolved_config_path(&self) -> Option<PathBuf> {
+        if let Some(ref path) = self.output.resolved_config {
+            return Some(path.clone());
+        }
+
+        self.output.next_to_trace(".config.toml")
+    }
+
+    /// Write the (fully resolved) configuration to a TOML or JSON file
+    pub(crate) fn write(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let raw = if is_json(path) {
+            serde_json::to_string_pretty(self)?
+        } else {
+            toml::to_string_pretty(self)?
+        };
+
+        fs::write(path, raw)
+            .with_context(|| format!("Cannot write config file {}", path.display()))?;
+
+        Ok(())
+    }
+}
+
+/// Check whether a file should be read or written as JSON (instead of TOML)
+pub(crate) fn is_json(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "json")
+}
+
+/// The protocol parameters used by the simulated clients
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProtocolParameters {
+    pub guards: GuardConfig,
+    pub circuits: CircuitConfig,
+    pub circuit_build: CircuitBuildConfig,
+    pub vanguards: VanguardConfig,
+}
+
+static PROTOCOL_PARAMETERS: OnceLock<ProtocolParameters> = OnceLock::new();
+
+/// Set the protocol parameters for this run.
+///
+/// This has to happen before the simulation starts. The parameters cannot be
+/// changed afterwards.
+pub(crate) fn set_protocol_parameters(params: ProtocolParameters) -> anyhow::Result<()> {
+    PROTOCOL_PARAMETERS
+        .set(params)
+        .map_err(|_| anyhow::anyhow!("Protocol parameters have already been set"))
+}
+
+/// Get the protocol parameters for this run. If they have not been set,
+/// Tor's defaults are used.
+pub(crate) fn protocol_parameters() -> &'static ProtocolParameters {
+    PROTOCOL_PARAMETERS.get_or_init(ProtocolParameters::default)
+}
@@ -0,0 +1,463 @@
+//! Destinations of the users' streams, and the exit policies towards them
+//!
+//! Destination hosts are ranked by popularity, either from a list in the style
+//! of Alexa or Tranco (one `rank,host` line per host, most popular first,
+//! optionally followed by the host's addresses) or as a number of synthetic
+//! hosts. Streams choose their destination by a Zipf distribution over the
+//! ranks. A host that is not given by its addresses resolves to a public IPv4
+//! address derived from its name, and to a public IPv6 address for a
+//! configurable fraction of the hosts. Streams to a host with both kinds of
+//! addresses use IPv6 with a configurable probability.
+//!
+//! Whether an exit accepts a stream to an IPv4 address is decided by the full
+//! exit policy from its descriptor. Streams to IPv6 addresses are subject to
+//! the exit's IPv6 policy summary, which only few exits have. As the circuit
+//! generator chooses exits by their IPv4 policy, exits for IPv6 streams are
+//! chosen here, weighted like the circuit generator does for the exit
+//! position, but only from the relays whose IPv6 policy allows the port. Like
+//! the circuit generator, we skip exits in the family or subnet of another hop.
+
+use std::fs::File;
+use std::hash::Hash;
+use std::io::BufReader;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use anyhow::{self, Context};
+use fxhash::FxHashMap;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rand_distr::{Distribution, Zipf};
+use serde::{Deserialize, Serialize};
+use tordoc::consensus::{CondensedExitPolicy, Flag};
+use tordoc::descriptor::ExitPolicy;
+use tordoc::{Consensus, Descriptor, Fingerprint};
+
+use crate::config::LoadConfig;
+use crate::rng::get_rng;
+use crate::utils::parse_fingerprint;
+
+/// The destination host of a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Destination {
+    /// The host's popularity rank, starting at 0. It also identifies the host
+    /// in the trace.
+    pub id: u64,
+    /// The address the stream connects to
+    pub address: IpAddr,
+}
+
+/// The addresses of a destination host. At least one of them is known.
+#[derive(Debug, Clone, Copy, Default)]
+struct Host {
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+}
+
+impl Host {
+    /// Add an address, unless the host already has one of the same family
+    fn add(&mut self, address: IpAddr) {
+        match address {
+            IpAddr::V4(ipv4) => {
+                self.ipv4.get_or_insert(ipv4);
+            }
+            IpAddr::V6(ipv6) => {
+                self.ipv6.get_or_insert(ipv6);
+            }
+        }
+    }
+}
+
+/// The destination hosts the users choose from
+#[derive(Debug)]
+pub(crate) struct Destinations {
+    /// The hosts, by rank
+    hosts: Vec<Host>,
+    popularity: Zipf<f64>,
+    ipv6_preference: f64,
+}
+
+impl Destinations {
+    /// Set up the destinations of a load configuration, or `None` if
+    /// destinations are not modelled
+    pub(crate) fn new(config: &LoadConfig) -> anyhow::Result<Option<Destinations>> {
+        if !(0.0..=1.0).contains(&config.dual_stack_fraction) {
+            anyhow::bail!(
+                "Invalid fraction of dual-stack destinations {}",
+                config.dual_stack_fraction
+            );
+        }
+        if !(0.0..=1.0).contains(&config.ipv6_preference) {
+            anyhow::bail!("Invalid IPv6 preference {}", config.ipv6_preference);
+        }
+
+        let hosts = match config.destination_list {
+            Some(ref path) => read_list(path, config.destinations, config.dual_stack_fraction)?,
+            None if config.destinations > 0 => (0..config.destinations)
+                .map(|rank| derive_host(&rank, config.dual_stack_fraction))
+                .collect(),
+            None => return Ok(None),
+        };
+        if hosts.is_empty() {
+            anyhow::bail!("The list of destinations is empty");
+        }
+
+        let popularity =
+            Zipf::new(hosts.len() as u64, config.destination_popularity).map_err(|e| {
+                anyhow::anyhow!(
+                    "Invalid destination popularity {}: {}",
+                    config.destination_popularity,
+                    e
+                )
+            })?;
+
+        Ok(Some(Destinations {
+            hosts,
+            popularity,
+            ipv6_preference: config.ipv6_preference,
+        }))
+    }
+
+    /// Choose the destination of a stream
+    pub(crate) fn sample(&self) -> Destination {
+        let mut rng = get_rng();
+        // Zipf samples the rank, starting at 1
+        let rank = self.popularity.sample(&mut rng) as u64 - 1;
+        let host = &self.hosts[rank as usize];
+        let address = match (host.ipv4, host.ipv6) {
+            (Some(ipv4), Some(ipv6)) => {
+                let use_ipv6 = match self.ipv6_preference {
+                    p if p <= 0.0 => false,
+                    p if p >= 1.0 => true,
+                    p => rng.gen_bool(p),
+                };
+                if use_ipv6 {
+                    IpAddr::V6(ipv6)
+                } else {
+                    IpAddr::V4(ipv4)
+                }
+            }
+            (Some(ipv4), None) => IpAddr::V4(ipv4),
+            (None, Some(ipv6)) => IpAddr::V6(ipv6),
+            (None, None) => unreachable!("every host has an address"),
+        };
+        Destination { id: rank, address }
+    }
+}
+
+/// Read the addresses of the most popular `limit` hosts (or all of them, if
+/// `limit` is zero) from a popularity list
+fn read_list(path: &Path, limit: u64, dual_stack_fraction: f64) -> anyhow::Result<Vec<Host>> {
+    let file = File::open(path)
+        .with_context(|| format!("Cannot read destination list {}", path.display()))?;
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(BufReader::new(file));
+
+    let mut hosts = Vec::new();
+    for record in reader.records() {
+        if limit > 0 && hosts.len() as u64 >= limit {
+            break;
+        }
+        let record =
+            record.with_context(|| format!("Broken destination list {}", path.display()))?;
+        let host = record.get(1).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Missing host in line {} of destination list {}",
+                hosts.len() + 1,
+                path.display()
+            )
+        })?;
+
+        // the first IPv4 and the first IPv6 address given for the host count
+        let mut given = Host::default();
+        for address in record.iter().skip(2).filter(|address| !address.is_empty()) {
+            let address: IpAddr = address.parse().with_context(|| {
+                format!(
+                    "Invalid address of {} in destination list {}",
+                    host,
+                    path.display()
+                )
+            })?;
+            given.add(address);
+        }
+
+        let host = if given.ipv4.is_some() || given.ipv6.is_some() {
+            given
+        } else if let Ok(address) = host.parse() {
+            let mut given = Host::default();
+            given.add(address);
+            given
+        } else {
+            derive_host(host, dual_stack_fraction)
+        };
+        hosts.push(host);
+    }
+
+    Ok(hosts)
+}
+
+/// Public addresses for a host whose addresses are not known, derived from
+/// its name. It has an IPv6 address with probability `dual_stack_fraction`.
+fn derive_host<T: Hash + ?Sized>(name: &T, dual_stack_fraction: f64) -> Host {
+    let mut hash = fxhash::hash64(name);
+    let ipv4 = loop {
+        let address = Ipv4Addr::from(hash as u32);
+        if is_public(&address) {
+            break address;
+        }
+        hash = fxhash::hash64(&hash);
+    };
+
+    // decide by a hash as well, so the host is the same in every trial
+    let dual_stack = (fxhash::hash64(&(name, 6u8)) as f64 / u64::MAX as f64) < dual_stack_fraction;
+    let ipv6 = dual_stack.then(|| {
+        let high = fxhash::hash64(&(name, 1u8)) as u128;
+        let low = fxhash::hash64(&(name, 2u8)) as u128;
+        // global unicast addresses (2000::/3)
+        Ipv6Addr::from((high << 64 | low) >> 3 | 1 << 125)
+    });
+
+    Host {
+        ipv4: Some(ipv4),
+        ipv6,
+    }
+}
+
+fn is_public(address: &Ipv4Addr) -> bool {
+    !(address.is_unspecified()
+        || address.is_private()
+        || address.is_loopback()
+        || address.is_link_local()
+        || address.is_documentation()
+        || address.is_multicast()
+        || address.is_broadcast()
+        || address.octets()[0] == 0
+        || address.octets()[0] >= 240)
+}
+
+/// A relay that can be chosen as the exit of IPv6 streams
+struct Ipv6Exit {
+    fingerprint: Fingerprint,
+    fast: bool,
+    stable: bool,
+    /// The relay's bandwidth, weighted for the exit position
+    weight: u64,
+}
+
+/// What restricts using a relay in the same circuit with another one
+#[derive(Default)]
+struct PathRestrictions {
+    /// The declared family members, as far as given by their fingerprint
+    family: Vec<Fingerprint>,
+    addresses: Vec<IpAddr>,
+}
+
+/// The full exit policies of the relays in a consensus, and their IPv6
+/// policy summaries, from their descriptors
+#[derive(Default)]
+pub(crate) struct ExitPolicies {
+    policies: FxHashMap<Fingerprint, ExitPolicy>,
+    ipv6_policies: FxHashMap<Fingerprint, CondensedExitPolicy>,
+    /// The usable relays with an IPv6 policy, in consensus order
+    ipv6_exits: Vec<Ipv6Exit>,
+    /// The families and addresses of all relays, to choose IPv6 exits that
+    /// may be combined with the other hops
+    restrictions: FxHashMap<Fingerprint, PathRestrictions>,
+}
+
+impl ExitPolicies {
+    pub(crate) fn new(consensus: &Consensus, descriptors: &[Descriptor]) -> ExitPolicies {
+        let policies = descriptors
+            .iter()
+            .filter_map(|descriptor| {
+                Some((
+                    descriptor.fingerprint.clone()?,
+                    descriptor.exit_policy.clone()?,
+                ))
+            })
+            .collect();
+        let ipv6_policies = descriptors
+            .iter()
+            .filter_map(|descriptor| {
+                Some((
+                    descriptor.fingerprint.clone()?,
+                    descriptor.exit_policies_ipv6.clone()?,
+                ))
+            })
+            .collect::<FxHashMap<_, _>>();
+
+        // the bandwidth weights for the exit position (see dir-spec, section
+        // 3.8.3), by whether the relay also has the Guard or Exit flag
+        let position_weight = |name: &str| {
+            consensus
+                .weights
+                .as_ref()
+                .and_then(|weights| weights.get(name).copied())
+                .unwrap_or(10000)
+        };
+        let ipv6_exits = consensus
+            .relays
+            .iter()
+            .filter_map(|relay| {
+                let fingerprint = relay.fingerprint.as_ref()?;
+                let flags = relay.flags.as_ref()?;
+                if !ipv6_policies.contains_key(fingerprint)
+                    || !flags.contains(&Flag::Running)
+                    || !flags.contains(&Flag::Valid)
+                    || flags.contains(&Flag::BadExit)
+                {
+                    return None;
+                }
+                let position = match (flags.contains(&Flag::Guard), flags.contains(&Flag::Exit)) {
+                    (true, true) => "Wed",
+                    (false, true) => "Wee",
+                    (true, false) => "Weg",
+                    (false, false) => "Wem",
+                };
+                Some(Ipv6Exit {
+                    fingerprint: fingerprint.clone(),
+                    fast: flags.contains(&Flag::Fast),
+                    stable: flags.contains(&Flag::Stable),
+                    weight: relay.bandwidth_weight.unwrap_or(0) * position_weight(position),
+                })
+            })
+            .collect();
+
+        let mut restrictions: FxHashMap<Fingerprint, PathRestrictions> = descriptors
+            .iter()
+            .filter_map(|descriptor| {
+                let family = descriptor
+                    .family_members
+                    .iter()
+                    .flatten()
+                    .filter_map(|member| parse_family_member(member))
+                    .collect();
+                let addresses = descriptor
+                    .or_addresses
+                    .iter()
+                    .flatten()
+                    .map(|or_address| or_address.ip)
+                    .collect();
+                Some((
+                    descriptor.fingerprint.clone()?,
+                    PathRestrictions { family, addresses },
+                ))
+            })
+            .collect();
+        for relay in consensus.relays.iter() {
+            if let (Some(fingerprint), Some(address)) = (&relay.fingerprint, relay.address) {
+                let restriction = restrictions.entry(fingerprint.clone()).or_default();
+                if !restriction.addresses.contains(&address) {
+                    restriction.addresses.push(address);
+                }
+            }
+        }
+
+        ExitPolicies {
+            policies,
+            ipv6_policies,
+            ipv6_exits,
+            restrictions,
+        }
+    }
+
+    /// Check if an exit accepts streams to a destination and port.
+    ///
+    /// For IPv4 destinations, relays without a full exit policy (like the
+    /// ones injected by the adversary, or all relays when using
+    /// microdescriptors) accept everything here. They are still subject to the
+    /// condensed policy from the consensus, which only considers the port.
+    /// IPv6 destinations are checked against the IPv6 policy summary instead.
+    pub(crate) fn allows(&self, exit: &Fingerprint, destination: &Destination, port: u16) -> bool {
+        if destination.address.is_ipv6() {
+            return self.allows_ipv6_port(exit, port);
+        }
+        match self.policies.get(exit) {
+            Some(policy) => policy.allows(&destination.address, port),
+            None => true,
+        }
+    }
+
+    /// Check if an exit accepts streams to IPv6 addresses on a port. Like in
+    /// Tor, relays without an IPv6 policy do not exit to IPv6 at all.
+    pub(crate) fn allows_ipv6_port(&self, exit: &Fingerprint, port: u16) -> bool {
+        self.ipv6_policies
+            .get(exit)
+            .is_some_and(|policy| policy.allows_port(port))
+    }
+
+    /// Check if two relays must not be in the same circuit (see path-spec,
+    /// section 2.2): they are the same relay, they declare each other as
+    /// family members, or they share an IPv4 /16 or IPv6 /32 subnet.
+    fn are_related(&self, relay: &Fingerprint, other: &Fingerprint) -> bool {
+        if relay == other {
+            return true;
+        }
+        let (relay_restrictions, other_restrictions) =
+            match (self.restrictions.get(relay), self.restrictions.get(other)) {
+                (Some(relay_restrictions), Some(other_restrictions)) => {
+                    (relay_restrictions, other_restrictions)
+                }
+                _ => return false,
+            };
+
+        (relay_restrictions.family.contains(other) && other_restrictions.family.contains(relay))
+            || relay_restrictions.addresses.iter().any(|address| {
+                other_restrictions
+                    .addresses
+                    .iter()
+                    .any(|other_address| same_subnet(address, other_address))
+            })
+    }
+
+    /// Choose an exit for IPv6 streams to a port, weighted by bandwidth, from
+    /// the relays whose IPv6 policy allows the port. Relays that are related
+    /// to one in `exclude` (the other hops of the circuit) are not chosen.
+    ///
+    /// Returns `None` if there is no such exit.
+    pub(crate) fn sample_ipv6_exit(
+        &self,
+        port: u16,
+        need_fast: bool,
+        need_stable: bool,
+        exclude: &[&Fingerprint],
+    ) -> Option<&Fingerprint> {
+        let candidates: Vec<_> = self
+            .ipv6_exits
+            .iter()
+            .filter(|exit| {
+                (exit.fast || !need_fast)
+                    && (exit.stable || !need_stable)
+                    && self.allows_ipv6_port(&exit.fingerprint, port)
+                    && !exclude
+                        .iter()
+                        .any(|hop| self.are_related(&exit.fingerprint, hop))
+            })
+            .collect();
+        candidates
+            .choose_weighted(&mut get_rng(), |exit| exit.weight)
+            .ok()
+            .map(|exit| &exit.fingerprint)
+    }
+}
+
+/// Parse a family member given by its fingerprint (`$FINGERPRINT`, possibly
+/// followed by `~nickname` or `=nickname`). Members given by their nickname
+/// only are ignored.
+fn parse_family_member(member: &str) -> Option<Fingerprint> {
+    let fingerprint = member.strip_prefix('$')?.get(..40)?;
+    parse_fingerprint(fingerprint).ok()
+}
+
+/// Check if two addresses are in the same IPv4 /16 or IPv6 /32 subnet
+fn same_subnet(address: &IpAddr, other: &IpAddr) -> bool {
+    match (address, other) {
+        (IpAddr::V4(address), IpAddr::V4(other)) => address.octets()[..2] == other.octets()[..2],
+        (IpAddr::V6(address), IpAddr::V6(other)) => {
+            address.segments()[..2] == other.segments()[..2]
+        }
+        _ => false,
+    }
+}
@@ -2,53 +2,75 @@
 
 // Deviations from spec:
 //
-// We do not model reachability of relays. Relays are assumed to be
-// reachable if they are in the consensus and have the Running flag.
-// We wouln'd have a way to model reachability otherwise.
-// This also means FILTERED_GUARDS and USABLE_FILTERED_GUARDS are the
-// same for us. We therefore simply use FILTERED_GUARDS whenever
-// USABLE_FILTERED_GUARDS is required.
+// Reachability of relays is simulated by a failure model (see
+// `crate::reachability`). A connection attempt to a guard is made whenever a
+// circuit is built through it, and the result is known immediately. We
+// therefore do not model circuits that wait for a better guard ("pending"
+// guards and circuits that are "usable if no better guard").
 
 use std::borrow::Borrow;
 use std::cmp::min;
+use std::sync::Arc;
 
+use crate::config::{protocol_parameters, GuardPolicyKind};
 use crate::observer::ClientObserver;
+use crate::reachability::ReachabilityModel;
+use crate::rng::{get_rng, with_circgen};
+use crate::utils::parse_fingerprint;
 
 use chrono::prelude::*;
 use chrono::Duration;
 use lazy_static::lazy_static;
 use rand::Rng;
-use seeded_rand::{get_rng, RHashSet};
-use tor_circuit_generator::CircuitGenerator;
+use seeded_rand::RHashSet;
+use serde::{Deserialize, Serialize};
+use tor_circuit_generator::{CircuitGenerator, Relay};
 use tordoc::{consensus::Flag, Fingerprint};
 
 lazy_static! {
-    static ref GUARD_LIFETIME: Duration = Duration::days(120);
-    static ref REMOVE_UNLISTED_GUARDS_AFTER: Duration = Duration::days(20);
-    static ref GUARD_CONFIRMED_MIN_LIFETIME: Duration = Duration::days(60);
-    // static ref GUARD_LIFETIME: Duration = Duration::days(6);
-    // static ref REMOVE_UNLISTED_GUARDS_AFTER: Duration = Duration::days(1);
-    // static ref GUARD_CONFIRMED_MIN_LIFETIME: Duration = Duration::days(3);
-    static ref MIN_FILTERED_SAMPLE: usize = 20;
-    static ref MAX_SAMPLE_SIZE: usize = 60;
-    static ref MAX_SAMPLE_THRESHOLD: f64 = 0.2;
-    static ref N_PRIMARY_GUARDS: usize = 3;
-    static ref N_USABLE_PRIMARY_GUARDS: usize = 1;
+    pub(crate) static ref GUARD_LIFETIME: Duration =
+        Duration::days(protocol_parameters().guards.lifetime_days);
+    static ref REMOVE_UNLISTED_GUARDS_AFTER: Duration =
+        Duration::days(protocol_parameters().guards.remove_unlisted_after_days);
+    static ref GUARD_CONFIRMED_MIN_LIFETIME: Duration =
+        Duration::days(protocol_parameters().guards.confirmed_min_lifetime_days);
+    static ref MIN_FILTERED_SAMPLE: usize = protocol_parameters().guards.min_filtered_sample;
+    static ref MAX_SAMPLE_SIZE: usize = protocol_parameters().guards.max_sample_size;
+    static ref MAX_SAMPLE_THRESHOLD: f64 = protocol_parameters().guards.max_sample_threshold;
+    static ref N_PRIMARY_GUARDS: usize = protocol_parameters().guards.n_primary_guards;
+    static ref N_USABLE_PRIMARY_GUARDS: usize =
+        protocol_parameters().guards.n_usable_primary_guards;
+    /// Guards with a lower consensus weight are ignored (bandwidth threshold policy only)
+    static ref MIN_GUARD_BANDWIDTH: u64 = match protocol_parameters().guards.policy {
+        GuardPolicyKind::Bandwidth => protocol_parameters().guards.min_bandwidth,
+        _ => 0,
+    };
 }
 
+/// How often we try to sample a guard above the bandwidth threshold before giving up
+const MAX_SAMPLING_ATTEMPTS: usize = 100;
+
 #[derive(Debug)]
 pub(crate) struct GuardHandling {
     sampled_guards: Vec<SampledGuard>,
     confirmed_guards: Vec<ConfirmedGuard>,
     primary_guards: Vec<Fingerprint>,
+    /// The primary guards need to be recomputed before their next use
+    primary_guards_outdated: bool,
+    reachability: Arc<ReachabilityModel>,
+    /// This client's individual probability of failing to connect to a guard
+    client_failure_probability: f64,
 }
 
 impl GuardHandling {
-    pub fn new() -> GuardHandling {
+    pub fn new(reachability: Arc<ReachabilityModel>) -> GuardHandling {
         GuardHandling {
             sampled_guards: Vec::new(),
             confirmed_guards: Vec::new(),
             primary_guards: Vec::new(),
+            primary_guards_outdated: true,
+            client_failure_probability: reachability.sample_client_failure_probability(),
+            reachability,
         }
     }
 
@@ -61,11 +83,12 @@ impl GuardHandling {
         // update guard information
         for guard in self.sampled_guards.iter_mut() {
             match circgen.lookup_relay(&guard.fingerprint) {
-                Some(relay) if relay.flags.contains(&Flag::Running) => {
+                Some(relay) if is_listed_guard(&relay) => {
                     guard.first_unlisted_at = None;
                 }
                 _ => {
-                    // relay is either missing or not running
+                    // relay is either missing, not running, has lost its Guard flag
+                    // or is below the bandwidth threshold
                     if guard.is_listed() {
                         guard.set_unlisted(now);
                     }
@@ -73,6 +96,15 @@ impl GuardHandling {
             }
         }
 
+        // retry unreachable guards
+        for guard in self.sampled_guards.iter_mut() {
+            if guard.is_reachable == Reachability::No
+                && guard.retry_due(now, self.primary_guards.contains(&guard.fingerprint))
+            {
+                guard.is_reachable = Reachability::Maybe;
+            }
+        }
+
         // remove old guards
         {
             let mut guards_to_remove = RHashSet::default();
@@ -103,43 +135,72 @@ impl GuardHandling {
                 .retain(|guard| !guards_to_remove.contains(&guard.fingerprint));
         }
 
-        self.recompute_primary_guards();
+        self.recompute_primary_guards(now, circgen);
     }
 
-    fn recompute_primary_guards(&mut self) {
-        let mut primary_guards = Vec::new();
-
-        // filtered guards
-        let filtered_guards: Vec<_> = self
-            .sampled_guards
-            .iter()
-            .filter(|guard| guard.is_listed())
-            .map(|guard| guard.fingerprint.clone())
-            .collect();
+    /// Recompute the list of primary guards (see guard-spec, section 4.6).
+    ///
+    /// Primary guards are taken from the confirmed guards first (in the order
+    /// they were confirmed). Previous primary guards are kept until they are
+    /// replaced by confirmed ones. Remaining slots are filled from the filtered
+    /// guards, in the order they were sampled.
+    fn recompute_primary_guards(&mut self, now: &DateTime<Utc>, circgen: &CircuitGenerator) {
+        let mut primary_guards: Vec<Fingerprint> = Vec::new();
 
+        // confirmed guards that are also filtered
         for confirmed_guard in self.confirmed_guards.iter() {
-            // is this confirmed guard also in filtered_guards?
-            if filtered_guards
-                .iter()
-                .position(|x| x == &confirmed_guard.fingerprint)
-                .is_some()
-            {
+            if primary_guards.len() >= *N_PRIMARY_GUARDS {
+                break;
+            }
+            if self.is_filtered(&confirmed_guard.fingerprint) {
                 primary_guards.push(confirmed_guard.fingerprint.clone());
             }
+        }
 
-            if primary_guards.len() == *N_PRIMARY_GUARDS {
+        // keep the previous primary guards that are still filtered
+        for old_primary in self.primary_guards.iter() {
+            if primary_guards.len() >= *N_PRIMARY_GUARDS {
                 break;
             }
+            if self.is_filtered(old_primary) && !primary_guards.contains(old_primary) {
+                primary_guards.push(old_primary.clone());
+            }
         }
 
-        // if primary_guards.len() < *N_PRIMARY_GUARDS {
-        //     let usable_guards = self.usable_guards(now, circgen);
-        //     for i in primary_guards.len()..*N_PRIMARY_GUARDS {
-        //         primary_guards.push(usable_guards[i].clone());
-        //     }
-        // }
+        // fill up from the filtered guards, in sampled order
+        if primary_guards.len() < *N_PRIMARY_GUARDS {
+            for guard in self.usable_guards(now, circgen) {
+                if primary_guards.len() >= *N_PRIMARY_GUARDS {
+                    break;
+                }
+                if !primary_guards.contains(&guard) {
+                    primary_guards.push(guard);
+                }
+            }
+        }
 
         self.primary_guards = primary_guards;
+        self.primary_guards_outdated = false;
+    }
+
+    /// Check if a guard is part of FILTERED_GUARDS
+    fn is_filtered(&self, fingerprint: &Fingerprint) -> bool {
+        self.sampled_guards
+            .iter()
+            .any(|guard| &guard.fingerprint == fingerprint && guard.is_listed())
+    }
+
+    /// Check if a guard is part of USABLE_FILTERED_GUARDS
+    fn is_usable(&self, fingerprint: &Fingerprint) -> bool {
+        self.sampled_guards
+            .iter()
+            .any(|guard| &guard.fingerprint == fingerprint && guard.is_usable())
+    }
+
+    fn get_sampled_mut(&mut self, fingerprint: &Fingerprint) -> Option<&mut SampledGuard> {
+        self.sampled_guards
+            .iter_mut()
+            .find(|guard| &guard.fingerprint == fingerprint)
     }
 
     /// Get the time when a guard was confirmed, if it is confirmed
@@ -152,6 +213,8 @@ impl GuardHandling {
         None
     }
 
+    /// Get the usable filtered guards (in sampled order), expanding the
+    /// sample if there are too few of them (see guard-spec, section 4.7)
     fn usable_guards(
         &mut self,
         now: &DateTime<Utc>,
@@ -161,7 +224,7 @@ impl GuardHandling {
             let usable_guards: Vec<_> = self
                 .sampled_guards
                 .iter()
-                .filter(|guard| guard.is_listed())
+                .filter(|guard| guard.is_usable())
                 .collect();
 
             // Do we have enough sampled relays that are usable?
@@ -171,18 +234,19 @@ impl GuardHandling {
                 *MAX_SAMPLE_SIZE,
             );
 
-            if usable_guards.len() < *MIN_FILTERED_SAMPLE && usable_guards.len() < max_sampled {
+            if usable_guards.len() < *MIN_FILTERED_SAMPLE && self.sampled_guards.len() < max_sampled
+            {
                 // sample a new guard and add it to the sampled_guards list
-                self.sampled_guards.push(SampledGuard::new(
-                    now,
-                    circgen,
-                    &self
-                        .sampled_guards
-                        .iter()
-                        .map(|guard| &guard.fingerprint)
-                        .collect(),
-                ));
-                continue;
+                let existing_guards = self
+                    .sampled_guards
+                    .iter()
+                    .map(|guard| &guard.fingerprint)
+                    .collect();
+                // if there are no more guards to sample, go on with what we have
+                if let Some(new_guard) = SampledGuard::new(now, circgen, &existing_guards) {
+                    self.sampled_guards.push(new_guard);
+                    continue;
+                }
             }
 
             break usable_guards
@@ -206,7 +270,18 @@ impl GuardHandling {
     //     usable_guards[i].clone()
     // }
 
+    /// Move a guard to the confirmed guards after it was used successfully
+    /// for the first time (see guard-spec, section 4.3)
     pub fn mark_as_confirmed(&mut self, guard: &Fingerprint, now: &DateTime<Utc>) {
+        // only sampled guards can be confirmed
+        if !self
+            .sampled_guards
+            .iter()
+            .any(|sampled| &sampled.fingerprint == guard)
+        {
+            return;
+        }
+
         // if not already confirmed, confirm it now
         if self
             .confirmed_guards
@@ -217,24 +292,96 @@ impl GuardHandling {
             self.confirmed_guards
                 .push(ConfirmedGuard::new(guard.clone(), now));
 
-            self.recompute_primary_guards();
+            // the confirmed guard may replace a non-confirmed primary guard
+            self.primary_guards_outdated = true;
+        }
+    }
+
+    /// Mark a guard as unreachable after a circuit through it failed at the
+    /// first hop (see guard-spec, section 4.9)
+    pub fn mark_as_failed(
+        &mut self,
+        guard: &Fingerprint,
+        now: &DateTime<Utc>,
+        observer: &mut ClientObserver,
+    ) {
+        if let Some(sampled) = self.get_sampled_mut(guard) {
+            sampled.set_unreachable(now);
+            observer.notify_guard_unreachable(now, guard);
         }
     }
 
+    /// Select the guard for a new circuit (see guard-spec, section 4.9) and
+    /// connect to it.
+    ///
+    /// Guards that cannot be connected to are marked as unreachable, and the
+    /// next candidate is tried. Returns `None` if no guard is reachable.
     pub fn get_guard_for_circuit(
         &mut self,
         now: &DateTime<Utc>,
         circgen: &CircuitGenerator,
-    ) -> Fingerprint {
-        if self.primary_guards.len() >= *N_USABLE_PRIMARY_GUARDS {
-            let mut rng = get_rng();
-            let chosen_primary = rng.gen_range(0..*N_USABLE_PRIMARY_GUARDS);
-
-            self.primary_guards[chosen_primary].clone()
-        } else {
-            self.usable_guards(now, circgen).into_iter().next().unwrap()
+        observer: &mut ClientObserver,
+    ) -> Option<Fingerprint> {
+        loop {
+            if self.primary_guards_outdated {
+                self.recompute_primary_guards(now, circgen);
+            }
+
+            let candidate = self.next_guard_candidate(now, circgen)?;
+            let connected =
+                self.reachability
+                    .try_connect(&candidate, now, self.client_failure_probability);
+
+            // candidates are always sampled guards
+            let guard = self.get_sampled_mut(&candidate).unwrap();
+            if connected {
+                guard.set_reachable();
+                return Some(candidate);
+            }
+
+            guard.set_unreachable(now);
+            observer.notify_guard_unreachable(now, &candidate);
         }
     }
+
+    /// Choose the next guard to try to connect to
+    fn next_guard_candidate(
+        &mut self,
+        now: &DateTime<Utc>,
+        circgen: &CircuitGenerator,
+    ) -> Option<Fingerprint> {
+        let mut rng = get_rng();
+
+        // Use one of the first N_USABLE_PRIMARY_GUARDS reachable primary guards,
+        // at random. If a primary guard is unreachable, this falls through to
+        // the next one in order.
+        let reachable_primary_guards: Vec<_> = self
+            .primary_guards
+            .iter()
+            .filter(|guard| self.is_usable(guard))
+            .take(*N_USABLE_PRIMARY_GUARDS)
+            .collect();
+        if !reachable_primary_guards.is_empty() {
+            let chosen_primary = rng.gen_range(0..reachable_primary_guards.len());
+            return Some(reachable_primary_guards[chosen_primary].clone());
+        }
+
+        // Otherwise, use the first confirmed guard that is usable
+        if let Some(confirmed_guard) = self
+            .confirmed_guards
+            .iter()
+            .find(|confirmed| self.is_usable(&confirmed.fingerprint))
+        {
+            return Some(confirmed_guard.fingerprint.clone());
+        }
+
+        // Otherwise, use one of the usable filtered guards, at random
+        let usable_guards = self.usable_guards(now, circgen);
+        if usable_guards.is_empty() {
+            return None;
+        }
+        Some(usable_guards[rng.gen_range(0..usable_guards.len())].clone())
+    }
 }
 
 #[derive(Debug)]
@@ -252,11 +399,24 @@ impl ConfirmedGuard {
     }
 }
 
+/// Whether a guard (or bridge) is believed to be reachable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Reachability {
+    Yes,
+    No,
+    Maybe,
+}
+
 #[derive(Debug)]
 struct SampledGuard {
     fingerprint: Fingerprint,
     added_on: DateTime<Utc>,
     first_unlisted_at: Option<DateTime<Utc>>,
+    is_reachable: Reachability,
+    /// When the guard became unreachable
+    failing_since: Option<DateTime<Utc>>,
+    /// Last (failed) connection attempt
+    last_tried_connect: Option<DateTime<Utc>>,
 }
 
 impl SampledGuard {
@@ -264,29 +424,388 @@ impl SampledGuard {
         now: &DateTime<Utc>,
         circgen: &CircuitGenerator,
         existing_guards: &Vec<&Fingerprint>,
-    ) -> SampledGuard {
-        let new_guard = circgen.sample_new_guard(&existing_guards).unwrap();
+    ) -> Option<SampledGuard> {
+        // skip guards below the bandwidth threshold, if any
+        let mut rejected: Vec<Arc<Relay>> = Vec::new();
+        let new_guard = loop {
+            let mut excluded = existing_guards.clone();
+            excluded.extend(rejected.iter().map(|relay| &relay.fingerprint));
+            let new_guard = with_circgen(|| circgen.sample_new_guard(&excluded)).ok()?;
+            if meets_min_bandwidth(&new_guard) {
+                break new_guard;
+            }
+            if rejected.len() >= MAX_SAMPLING_ATTEMPTS {
+                return None;
+            }
+            rejected.push(new_guard);
+        };
 
-        SampledGuard {
+        Some(SampledGuard {
             fingerprint: new_guard.fingerprint.clone(),
             added_on: random_past(now, *GUARD_LIFETIME / 10),
             first_unlisted_at: None,
-        }
+            is_reachable: Reachability::Maybe,
+            failing_since: None,
+            last_tried_connect: None,
+        })
     }
 
     fn is_listed(&self) -> bool {
         self.first_unlisted_at.is_none()
     }
 
+    fn is_usable(&self) -> bool {
+        self.is_listed() && self.is_reachable != Reachability::No
+    }
+
+    fn set_reachable(&mut self) {
+        self.is_reachable = Reachability::Yes;
+        self.failing_since = None;
+        self.last_tried_connect = None;
+    }
+
+    fn set_unreachable(&mut self, now: &DateTime<Utc>) {
+        self.is_reachable = Reachability::No;
+        if self.failing_since.is_none() {
+            self.failing_since = Some(*now);
+        }
+        self.last_tried_connect = Some(*now);
+    }
+
+    /// Check if an unreachable guard should be retried
+    fn retry_due(&self, now: &DateTime<Utc>, is_primary: bool) -> bool {
+        match (self.failing_since, self.last_tried_connect) {
+            (Some(failing_since), Some(last_tried)) => {
+                *now - last_tried >= retry_interval(*now - failing_since, is_primary)
+            }
+            _ => true,
+        }
+    }
+
     /// Set this guard to unlisted and randomize the `unlisted` time.
     fn set_unlisted(&mut self, now: &DateTime<Utc>) {
         self.first_unlisted_at = Some(random_past(now, *REMOVE_UNLISTED_GUARDS_AFTER / 5))
     }
 }
 
-fn random_past(now: &DateTime<Utc>, range: impl Borrow<Duration>) -> DateTime<Utc> {
+/// Check if a guard is not excluded by the bandwidth threshold
+fn meets_min_bandwidth(relay: &Relay) -> bool {
+    relay.bandwidth_weight >= *MIN_GUARD_BANDWIDTH
+}
+
+/// Check if a relay is listed as a running guard (that meets the bandwidth
+/// threshold, if any)
+pub(crate) fn is_listed_guard(relay: &Relay) -> bool {
+    relay.flags.contains(&Flag::Running)
+        && relay.flags.contains(&Flag::Guard)
+        && meets_min_bandwidth(relay)
+}
+
+/// Time to wait before retrying an unreachable guard that has been failing for
+/// the given time (see guard-spec, section 4.7)
+pub(crate) fn retry_interval(failing_for: Duration, is_primary: bool) -> Duration {
+    // retry intervals for the first 6 hours, 4 days, 7 days and afterwards
+    let intervals = if is_primary {
+        [
+            Duration::minutes(10),
+            Duration::minutes(90),
+            Duration::hours(4),
+            Duration::hours(9),
+        ]
+    } else {
+        [
+            Duration::hours(1),
+            Duration::hours(4),
+            Duration::hours(18),
+            Duration::hours(36),
+        ]
+    };
+
+    if failing_for < Duration::hours(6) {
+        intervals[0]
+    } else if failing_for < Duration::days(4) {
+        intervals[1]
+    } else if failing_for < Duration::days(7) {
+        intervals[2]
+    } else {
+        intervals[3]
+    }
+}
+
+/// The persistable part of a client's guard handling, so a later simulation
+/// run can continue with it. Times are UNIX timestamps.
+///
+/// The reachability of guards is not stored, as Tor also forgets it on restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct GuardState {
+    sampled: Vec<SampledGuardState>,
+    confirmed: Vec<ConfirmedGuardState>,
+    primary: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SampledGuardState {
+    fingerprint: String,
+    added_on: i64,
+    first_unlisted_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ConfirmedGuardState {
+    fingerprint: String,
+    confirmed_on: i64,
+}
+
+impl GuardHandling {
+    /// Export the current guard state
+    pub fn to_state(&self) -> GuardState {
+        GuardState {
+            sampled: self
+                .sampled_guards
+                .iter()
+                .map(|guard| SampledGuardState {
+                    fingerprint: guard.fingerprint.to_string(),
+                    added_on: guard.added_on.timestamp(),
+                    first_unlisted_at: guard.first_unlisted_at.map(|time| time.timestamp()),
+                })
+                .collect(),
+            confirmed: self
+                .confirmed_guards
+                .iter()
+                .map(|guard| ConfirmedGuardState {
+                    fingerprint: guard.fingerprint.to_string(),
+                    confirmed_on: guard.confirmed_on.timestamp(),
+                })
+                .collect(),
+            primary: self
+                .primary_guards
+                .iter()
+                .map(|fingerprint| fingerprint.to_string())
+                .collect(),
+        }
+    }
+
+    /// Replace the guard lists with a previously exported state.
+    ///
+    /// The primary guards are recomputed before their next use, as the
+    /// network may have changed in the meantime.
+    pub fn restore_state(&mut self, state: &GuardState) -> anyhow::Result<()> {
+        self.sampled_guards = state
+            .sampled
+            .iter()
+            .map(|guard| -> anyhow::Result<_> {
+                Ok(SampledGuard {
+                    fingerprint: parse_fingerprint(&guard.fingerprint)?,
+                    added_on: from_timestamp(guard.added_on)?,
+                    first_unlisted_at: guard.first_unlisted_at.map(from_timestamp).transpose()?,
+                    is_reachable: Reachability::Maybe,
+                    failing_since: None,
+                    last_tried_connect: None,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        self.confirmed_guards = state
+            .confirmed
+            .iter()
+            .map(|guard| -> anyhow::Result<_> {
+                Ok(ConfirmedGuard {
+                    fingerprint: parse_fingerprint(&guard.fingerprint)?,
+                    confirmed_on: from_timestamp(guard.confirmed_on)?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        self.primary_guards = state
+            .primary
+            .iter()
+            .map(|fingerprint| parse_fingerprint(fingerprint))
+            .collect::<anyhow::Result<_>>()?;
+        self.primary_guards_outdated = true;
+
+        Ok(())
+    }
+}
+
+fn from_timestamp(timestamp: i64) -> anyhow::Result<DateTime<Utc>> {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .ok_or_else(|| anyhow::anyhow!("Invalid timestamp {}", timestamp))
+}
+
+pub(crate) fn random_past(now: &DateTime<Utc>, range: impl Borrow<Duration>) -> DateTime<Utc> {
     let mut rng = get_rng();
     let offset = Duration::milliseconds(rng.gen_range(0..range.borrow().num_milliseconds()));
 
     *now - offset
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adversaries::Adversary;
+    use crate::config::{AdversaryConfig, ReachabilityConfig};
+
+    /// A consensus without any relays. The test network is injected into it.
+    const EMPTY_CONSENSUS: &str = "\
+network-status-version 3
+vote-status consensus
+consensus-method 32
+valid-after 2023-01-01 00:00:00
+fresh-until 2023-01-01 01:00:00
+valid-until 2023-01-01 03:00:00
+voting-delay 300 300
+client-versions 0.4.7.13
+server-versions 0.4.7.13
+known-flags Exit Fast Guard Running Stable Valid
+directory-footer
+bandwidth-weights Wbd=0 Wbe=0 Wbg=0 Wbm=10000 Wdb=10000 Web=10000 Wed=10000 Wee=10000 Weg=10000 Wem=10000 Wgb=10000 Wgd=0 Wgg=10000 Wgm=10000 Wmb=10000 Wmd=0 Wme=0 Wmg=0 Wmm=10000
+";
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap()
+    }
+
+    /// A network of 100 guards and a few middles and exits
+    fn network() -> CircuitGenerator {
+        crate::rng::set_seed(1);
+
+        let adversary = Adversary::new(&AdversaryConfig {
+            guards_num: Some(100),
+            guards_bw: Some(1000),
+            exits_num: Some(10),
+            exits_bw: Some(1000),
+            middles_num: Some(10),
+            middles_bw: Some(1000),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut consensus = tordoc::Consensus::from_str(EMPTY_CONSENSUS).unwrap();
+        let mut descriptors = Vec::new();
+        adversary.modify_consensus(&mut consensus, &mut descriptors);
+
+        CircuitGenerator::new(&consensus, descriptors, vec![443]).unwrap()
+    }
+
+    /// Guard handling of a new client whose connections never fail, and its observer
+    fn new_client() -> (GuardHandling, ClientObserver) {
+        let reachability = ReachabilityModel::new(&ReachabilityConfig::default()).unwrap();
+        let adversary = Adversary::new(&AdversaryConfig::default()).unwrap();
+        (
+            GuardHandling::new(Arc::new(reachability)),
+            ClientObserver::new(0, Arc::new(adversary), None),
+        )
+    }
+
+    fn sampled(guards: &GuardHandling) -> Vec<Fingerprint> {
+        guards
+            .sampled_guards
+            .iter()
+            .map(|guard| guard.fingerprint.clone())
+            .collect()
+    }
+
+    #[test]
+    fn primary_guards_in_sampled_order() {
+        let circgen = network();
+        let (mut guards, _) = new_client();
+
+        guards.recompute_primary_guards(&now(), &circgen);
+
+        // the sample is expanded to MIN_FILTERED_SAMPLE guards at first
+        assert!(guards.sampled_guards.len() > *N_PRIMARY_GUARDS);
+        assert_eq!(
+            guards.primary_guards,
+            sampled(&guards)[..*N_PRIMARY_GUARDS].to_vec()
+        );
+    }
+
+    #[test]
+    fn primary_guards_backfilled_from_filtered_guards() {
+        let circgen = network();
+        let (mut guards, _) = new_client();
+        guards.recompute_primary_guards(&now(), &circgen);
+        let sampled = sampled(&guards);
+
+        // the second primary guard drops out of the consensus
+        guards
+            .get_sampled_mut(&sampled[1])
+            .unwrap()
+            .set_unlisted(&now());
+        guards.recompute_primary_guards(&now(), &circgen);
+
+        // the other primary guards keep their place, and the next filtered
+        // guard in sampled order takes the free one
+        let mut expected = vec![sampled[0].clone()];
+        expected.extend_from_slice(&sampled[2..=*N_PRIMARY_GUARDS]);
+        assert_eq!(guards.primary_guards, expected);
+    }
+
+    #[test]
+    fn unreachable_primary_guards_retried_in_order() {
+        let circgen = network();
+        let (mut guards, mut observer) = new_client();
+        guards.recompute_primary_guards(&now(), &circgen);
+        let primary_guards = guards.primary_guards.clone();
+
+        // by default, only the first usable primary guard is used
+        assert_eq!(
+            guards.get_guard_for_circuit(&now(), &circgen, &mut observer),
+            Some(primary_guards[0].clone())
+        );
+
+        // if it is unreachable, the next primary guard is used, and so on
+        guards.mark_as_failed(&primary_guards[0], &now(), &mut observer);
+        assert_eq!(
+            guards.get_guard_for_circuit(&now(), &circgen, &mut observer),
+            Some(primary_guards[1].clone())
+        );
+        guards.mark_as_failed(&primary_guards[1], &now(), &mut observer);
+        assert_eq!(
+            guards.get_guard_for_circuit(&now(), &circgen, &mut observer),
+            Some(primary_guards[2].clone())
+        );
+
+        // once the retry interval for primary guards is over, the first one
+        // is tried again
+        let later = now() + retry_interval(Duration::zero(), true);
+        guards.timed_updates(&later, &circgen, &mut observer);
+        assert_eq!(
+            guards.get_guard_for_circuit(&later, &circgen, &mut observer),
+            Some(primary_guards[0].clone())
+        );
+        assert_eq!(guards.primary_guards, primary_guards);
+    }
+
+    #[test]
+    fn guard_confirmed_on_first_use() {
+        let circgen = network();
+        let (mut guards, mut observer) = new_client();
+        guards.recompute_primary_guards(&now(), &circgen);
+        let sampled = sampled(&guards);
+
+        let guard = guards
+            .get_guard_for_circuit(&now(), &circgen, &mut observer)
+            .unwrap();
+        assert!(guards.confirmed_guards.is_empty());
+
+        guards.mark_as_confirmed(&guard, &now());
+        guards.mark_as_confirmed(&guard, &now());
+        assert_eq!(
+            guards.get_confirmed_on(&guard).map(|time| time <= now()),
+            Some(true)
+        );
+        assert_eq!(guards.confirmed_guards.len(), 1);
+
+        // a confirmed guard that was not primary yet comes right after the
+        // previously confirmed ones
+        let other = sampled[*N_PRIMARY_GUARDS].clone();
+        guards.mark_as_confirmed(&other, &now());
+        assert!(guards.primary_guards_outdated);
+        guards.recompute_primary_guards(&now(), &circgen);
+        assert_eq!(guards.primary_guards[..2], [guard, other]);
+
+        // guards that were never sampled cannot be confirmed
+        let unsampled = Fingerprint::from_str_hex("F".repeat(40)).unwrap();
+        guards.mark_as_confirmed(&unsampled, &now());
+        assert_eq!(guards.confirmed_guards.len(), 2);
+    }
+}
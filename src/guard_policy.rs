@@ -0,0 +1,461 @@
+//! Pluggable guard selection policies
+//!
+//! Clients choose the first hop of their circuits according to a
+//! `GuardPolicy`. Besides Tor's current algorithm (see `crate::guard`), which
+//! can also be restricted to guards above a bandwidth threshold, this allows
+//! to compare alternatives from the literature: a single long-lived guard and
+//! guard sets that are shared by groups of clients.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow;
+use chrono::{DateTime, Utc};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tor_circuit_generator::CircuitGenerator;
+use tordoc::Fingerprint;
+
+use crate::config::{protocol_parameters, GuardPolicyKind};
+use crate::guard::{is_listed_guard, random_past, GuardHandling, GuardState, GUARD_LIFETIME};
+use crate::observer::ClientObserver;
+use crate::reachability::ReachabilityModel;
+use crate::rng::{get_rng, with_circgen};
+use crate::utils::{from_micros, parse_fingerprint, to_micros};
+
+/// A strategy of a client to choose the guards for its circuits
+pub(crate) trait GuardPolicy: Send {
+    /// Update the guards when time advances, e.g. with a new consensus
+    fn timed_updates(
+        &mut self,
+        now: &DateTime<Utc>,
+        circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+    );
+
+    /// Select the guard for a new circuit and connect to it.
+    ///
+    /// Returns `None` if no guard is reachable.
+    fn get_guard_for_circuit(
+        &mut self,
+        now: &DateTime<Utc>,
+        circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+    ) -> Option<Fingerprint>;
+
+    /// Notify the policy that a circuit through this guard was used successfully
+    fn mark_as_confirmed(&mut self, _guard: &Fingerprint, _now: &DateTime<Utc>) {}
+
+    /// Notify the policy that a circuit through this guard failed before
+    /// its first hop was completed, so the guard is not reachable
+    fn mark_as_failed(
+        &mut self,
+        _guard: &Fingerprint,
+        _now: &DateTime<Utc>,
+        _observer: &mut ClientObserver,
+    ) {
+    }
+
+    /// Export the state of the policy, so a checkpoint can continue with it
+    fn to_state(&self) -> GuardPolicyState;
+
+    /// Continue with an exported state of the same policy
+    fn restore_state(&mut self, state: &GuardPolicyState) -> anyhow::Result<()>;
+}
+
+/// The state of a client's guard policy, as stored in checkpoints. Times are
+/// in microseconds since the UNIX epoch.
+///
+/// Only the state of Tor's algorithm can also be saved to a guard state file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum GuardPolicyState {
+    Tor(GuardState),
+    Single {
+        /// The guard and when it was added
+        guard: Option<(String, i64)>,
+    },
+    SetMember {
+        /// The index of the client's guard set
+        set: usize,
+    },
+}
+
+impl GuardPolicyState {
+    /// The state of Tor's algorithm, if the policy is based on it
+    pub(crate) fn into_guard_state(self) -> Option<GuardState> {
+        match self {
+            GuardPolicyState::Tor(state) => Some(state),
+            _ => None,
+        }
+    }
+}
+
+/// The error when restoring the state of a different guard policy
+fn wrong_policy() -> anyhow::Error {
+    anyhow::anyhow!("The guard state belongs to a different guard policy")
+}
+
+impl GuardPolicy for GuardHandling {
+    fn timed_updates(
+        &mut self,
+        now: &DateTime<Utc>,
+        circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+    ) {
+        GuardHandling::timed_updates(self, now, circgen, observer)
+    }
+
+    fn get_guard_for_circuit(
+        &mut self,
+        now: &DateTime<Utc>,
+        circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+    ) -> Option<Fingerprint> {
+        GuardHandling::get_guard_for_circuit(self, now, circgen, observer)
+    }
+
+    fn mark_as_confirmed(&mut self, guard: &Fingerprint, now: &DateTime<Utc>) {
+        GuardHandling::mark_as_confirmed(self, guard, now)
+    }
+
+    fn mark_as_failed(
+        &mut self,
+        guard: &Fingerprint,
+        now: &DateTime<Utc>,
+        observer: &mut ClientObserver,
+    ) {
+        GuardHandling::mark_as_failed(self, guard, now, observer)
+    }
+
+    fn to_state(&self) -> GuardPolicyState {
+        GuardPolicyState::Tor(GuardHandling::to_state(self))
+    }
+
+    fn restore_state(&mut self, state: &GuardPolicyState) -> anyhow::Result<()> {
+        match state {
+            GuardPolicyState::Tor(state) => GuardHandling::restore_state(self, state),
+            _ => Err(wrong_policy()),
+        }
+    }
+}
+
+/// Constructs the guard policies of new clients, as configured
+pub(crate) struct GuardPolicyFactory {
+    kind: GuardPolicyKind,
+    reachability: Arc<ReachabilityModel>,
+    /// The guard sets shared by all clients (sets policy only)
+    guard_sets: Option<Arc<GuardSetRegistry>>,
+}
+
+impl GuardPolicyFactory {
+    pub(crate) fn new(reachability: Arc<ReachabilityModel>) -> anyhow::Result<GuardPolicyFactory> {
+        let config = &protocol_parameters().guards;
+        let guard_sets = match config.policy {
+            GuardPolicyKind::Sets => {
+                if config.num_sets == 0 {
+                    anyhow::bail!("The guard sets policy needs at least one set (guards.num_sets)");
+                }
+                Some(Arc::new(GuardSetRegistry::new(
+                    config.num_sets,
+                    config.set_min_bandwidth,
+                )))
+            }
+            _ => None,
+        };
+
+        Ok(GuardPolicyFactory {
+            kind: config.policy,
+            reachability,
+            guard_sets,
+        })
+    }
+
+    /// Update the guard state that is shared by all clients. This is done at
+    /// the beginning of every epoch, before the clients are simulated, so it
+    /// does not depend on the order in which they are.
+    pub(crate) fn timed_updates(&self, circgen: &CircuitGenerator) {
+        if let Some(ref guard_sets) = self.guard_sets {
+            guard_sets.refresh(circgen);
+        }
+    }
+
+    /// Export the guard sets shared by the clients, if any
+    pub(crate) fn to_state(&self) -> Option<Vec<Vec<String>>> {
+        self.guard_sets
+            .as_ref()
+            .map(|guard_sets| guard_sets.to_state())
+    }
+
+    /// Continue with exported guard sets
+    pub(crate) fn restore_state(&self, state: &Option<Vec<Vec<String>>>) -> anyhow::Result<()> {
+        match (&self.guard_sets, state) {
+            (Some(guard_sets), Some(state)) => guard_sets.restore_state(state),
+            (None, None) => Ok(()),
+            _ => anyhow::bail!("The checkpoint was written with a different guard policy"),
+        }
+    }
+
+    pub(crate) fn make(&self) -> Box<dyn GuardPolicy> {
+        match self.kind {
+            // the bandwidth threshold is applied within Tor's algorithm
+            GuardPolicyKind::Tor | GuardPolicyKind::Bandwidth => {
+                Box::new(GuardHandling::new(self.reachability.clone()))
+            }
+            GuardPolicyKind::Single => Box::new(SingleGuard::new(self.reachability.clone())),
+            GuardPolicyKind::Sets => Box::new(GuardSetMember::new(
+                self.guard_sets.clone().unwrap(), // always set for this policy
+                self.reachability.clone(),
+            )),
+        }
+    }
+}
+
+/// A single guard that is kept until it leaves the network or its lifetime
+/// is over. There is no fallback if it cannot be reached.
+struct SingleGuard {
+    guard: Option<(Fingerprint, DateTime<Utc>)>,
+    reachability: Arc<ReachabilityModel>,
+    client_failure_probability: f64,
+}
+
+impl SingleGuard {
+    fn new(reachability: Arc<ReachabilityModel>) -> SingleGuard {
+        SingleGuard {
+            guard: None,
+            client_failure_probability: reachability.sample_client_failure_probability(),
+            reachability,
+        }
+    }
+}
+
+impl GuardPolicy for SingleGuard {
+    fn timed_updates(
+        &mut self,
+        now: &DateTime<Utc>,
+        circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+    ) {
+        if let Some((ref guard, added_on)) = self.guard {
+            if !circgen
+                .lookup_relay(guard)
+                .map_or(false, |relay| is_listed_guard(&relay))
+            {
+                observer.notify_guard_removed_offline(now, guard);
+                self.guard = None;
+            } else if *now - added_on >= *GUARD_LIFETIME {
+                observer.notify_guard_removed_too_old(now, guard);
+                self.guard = None;
+            }
+        }
+    }
+
+    fn get_guard_for_circuit(
+        &mut self,
+        now: &DateTime<Utc>,
+        circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+    ) -> Option<Fingerprint> {
+        if self.guard.is_none() {
+            let guard = with_circgen(|| circgen.sample_new_guard(&Vec::new())).ok()?;
+            self.guard = Some((
+                guard.fingerprint.clone(),
+                random_past(now, *GUARD_LIFETIME / 10),
+            ));
+        }
+
+        let (guard, _) = self.guard.as_ref().unwrap();
+        if self
+            .reachability
+            .try_connect(guard, now, self.client_failure_probability)
+        {
+            Some(guard.clone())
+        } else {
+            observer.notify_guard_unreachable(now, guard);
+            None
+        }
+    }
+
+    fn to_state(&self) -> GuardPolicyState {
+        GuardPolicyState::Single {
+            guard: self
+                .guard
+                .as_ref()
+                .map(|(guard, added_on)| (guard.to_string(), to_micros(added_on))),
+        }
+    }
+
+    fn restore_state(&mut self, state: &GuardPolicyState) -> anyhow::Result<()> {
+        match state {
+            GuardPolicyState::Single { guard } => {
+                self.guard = guard
+                    .as_ref()
+                    .map(|(guard, added_on)| -> anyhow::Result<_> {
+                        Ok((parse_fingerprint(guard)?, from_micros(*added_on)?))
+                    })
+                    .transpose()?;
+                Ok(())
+            }
+            _ => Err(wrong_policy()),
+        }
+    }
+}
+
+/// The guard sets, shared by all clients.
+///
+/// The sets are disjoint. At the beginning of every epoch, members that left
+/// the network are removed and every set is topped up with new guards until
+/// its total consensus weight is above the threshold again.
+pub(crate) struct GuardSetRegistry {
+    sets: Mutex<Vec<Vec<Fingerprint>>>,
+    min_bandwidth: u64,
+}
+
+impl GuardSetRegistry {
+    fn new(num_sets: usize, min_bandwidth: u64) -> GuardSetRegistry {
+        GuardSetRegistry {
+            sets: Mutex::new(vec![Vec::new(); num_sets]),
+            min_bandwidth,
+        }
+    }
+
+    fn num_sets(&self) -> usize {
+        self.sets.lock().unwrap().len()
+    }
+
+    /// Remove the members that left the network and top up all the sets, in order
+    fn refresh(&self, circgen: &CircuitGenerator) {
+        let mut sets = self.sets.lock().unwrap();
+
+        for set in 0..sets.len() {
+            // drop members that left the network
+            sets[set].retain(|guard| {
+                circgen
+                    .lookup_relay(guard)
+                    .map_or(false, |relay| is_listed_guard(&relay))
+            });
+
+            let mut bandwidth: u64 = sets[set]
+                .iter()
+                .filter_map(|guard| circgen.lookup_relay(guard))
+                .map(|relay| relay.bandwidth_weight)
+                .sum();
+            while bandwidth < self.min_bandwidth {
+                let new_guard = {
+                    let all_members: Vec<_> = sets.iter().flatten().collect();
+                    match with_circgen(|| circgen.sample_new_guard(&all_members)) {
+                        Ok(new_guard) => new_guard,
+                        // no guards left, go on with what we have
+                        Err(_) => break,
+                    }
+                };
+                bandwidth += new_guard.bandwidth_weight;
+                sets[set].push(new_guard.fingerprint.clone());
+            }
+        }
+    }
+
+    /// Get the current members of a guard set
+    fn members(&self, set: usize) -> Vec<Fingerprint> {
+        self.sets.lock().unwrap()[set].clone()
+    }
+
+    /// Export the members of all sets
+    fn to_state(&self) -> Vec<Vec<String>> {
+        self.sets
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|set| set.iter().map(|guard| guard.to_string()).collect())
+            .collect()
+    }
+
+    /// Replace the members of all sets with exported ones
+    fn restore_state(&self, state: &[Vec<String>]) -> anyhow::Result<()> {
+        let mut sets = self.sets.lock().unwrap();
+        if state.len() != sets.len() {
+            anyhow::bail!(
+                "The checkpoint contains {} guard sets instead of {}",
+                state.len(),
+                sets.len()
+            );
+        }
+        *sets = state
+            .iter()
+            .map(|set| set.iter().map(|guard| parse_fingerprint(guard)).collect())
+            .collect::<anyhow::Result<_>>()?;
+        Ok(())
+    }
+}
+
+/// A client that uses the guards of its (randomly assigned) guard set
+struct GuardSetMember {
+    registry: Arc<GuardSetRegistry>,
+    set: usize,
+    reachability: Arc<ReachabilityModel>,
+    client_failure_probability: f64,
+}
+
+impl GuardSetMember {
+    fn new(
+        registry: Arc<GuardSetRegistry>,
+        reachability: Arc<ReachabilityModel>,
+    ) -> GuardSetMember {
+        GuardSetMember {
+            set: get_rng().gen_range(0..registry.num_sets()),
+            registry,
+            client_failure_probability: reachability.sample_client_failure_probability(),
+            reachability,
+        }
+    }
+}
+
+impl GuardPolicy for GuardSetMember {
+    fn timed_updates(
+        &mut self,
+        _now: &DateTime<Utc>,
+        _circgen: &CircuitGenerator,
+        _observer: &mut ClientObserver,
+    ) {
+        // the sets are maintained by the registry at the beginning of each epoch
+    }
+
+    /// Use a random member of the guard set, falling back to the other
+    /// members if it cannot be reached
+    fn get_guard_for_circuit(
+        &mut self,
+        now: &DateTime<Utc>,
+        _circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+    ) -> Option<Fingerprint> {
+        let mut members = self.registry.members(self.set);
+        members.shuffle(&mut get_rng());
+
+        for guard in members {
+            if self
+                .reachability
+                .try_connect(&guard, now, self.client_failure_probability)
+            {
+                return Some(guard);
+            }
+            observer.notify_guard_unreachable(now, &guard);
+        }
+        None
+    }
+
+    fn to_state(&self) -> GuardPolicyState {
+        GuardPolicyState::SetMember { set: self.set }
+    }
+
+    fn restore_state(&mut self, state: &GuardPolicyState) -> anyhow::Result<()> {
+        match *state {
+            GuardPolicyState::SetMember { set } if set < self.registry.num_sets() => {
+                self.set = set;
+                Ok(())
+            }
+            GuardPolicyState::SetMember { set } => {
+                anyhow::bail!("Guard set {} does not exist", set)
+            }
+            _ => Err(wrong_policy()),
+        }
+    }
+}
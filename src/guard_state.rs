@@ -0,0 +1,113 @@
+//! Persisting the clients' guard state across simulation runs
+//!
+//! At the end of a run, the sampled, confirmed and primary guards of every
+//! client can be written to a state file. A later run can load this file so
+//! its clients do not all bootstrap at once, e.g. to chain long timelines or
+//! to reuse a warmed-up client population.
+//!
+//! The file is JSON, compressed with zstd if its name ends with `.zst`.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{self, Context};
+use chrono::{DateTime, TimeZone, Utc};
+use fxhash::FxHashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::guard::GuardState;
+
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct GuardStateFile {
+    format_version: u32,
+    /// Simulated time when the state was saved, as UNIX timestamp
+    time: i64,
+    clients: Vec<ClientGuardState>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ClientGuardState {
+    client_id: u64,
+    guards: GuardState,
+}
+
+impl GuardStateFile {
+    pub(crate) fn new(
+        time: &DateTime<Utc>,
+        clients: impl IntoIterator<Item = (u64, GuardState)>,
+    ) -> GuardStateFile {
+        GuardStateFile {
+            format_version: FORMAT_VERSION,
+            time: time.timestamp(),
+            clients: clients
+                .into_iter()
+                .map(|(client_id, guards)| ClientGuardState { client_id, guards })
+                .collect(),
+        }
+    }
+
+    /// Simulated time when the state was saved
+    pub(crate) fn time(&self) -> anyhow::Result<DateTime<Utc>> {
+        Utc.timestamp_opt(self.time, 0)
+            .single()
+            .context("Invalid time in guard state file")
+    }
+
+    pub(crate) fn num_clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Get the guard states by client ID
+    pub(crate) fn into_map(self) -> FxHashMap<u64, GuardState> {
+        self.clients
+            .into_iter()
+            .map(|client| (client.client_id, client.guards))
+            .collect()
+    }
+
+    pub(crate) fn read(path: impl AsRef<Path>) -> anyhow::Result<GuardStateFile> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Cannot open guard state file {}", path.display()))?;
+        let reader: Box<dyn Read> = if is_zstd(path) {
+            Box::new(zstd::Decoder::new(file)?)
+        } else {
+            Box::new(BufReader::new(file))
+        };
+
+        let state: GuardStateFile = serde_json::from_reader(reader)
+            .with_context(|| format!("Broken guard state file {}", path.display()))?;
+        if state.format_version != FORMAT_VERSION {
+            anyhow::bail!(
+                "Guard state file {} has unsupported format version {}",
+                path.display(),
+                state.format_version
+            );
+        }
+
+        Ok(state)
+    }
+
+    pub(crate) fn write(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_context(|| format!("Cannot write guard state file {}", path.display()))?;
+        let mut writer: Box<dyn Write> = if is_zstd(path) {
+            Box::new(zstd::Encoder::new(file, 5)?.auto_finish())
+        } else {
+            Box::new(BufWriter::new(file))
+        };
+
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+fn is_zstd(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "zst")
+}
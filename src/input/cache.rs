@@ -0,0 +1,254 @@
+//! Binary cache of the documents needed for each consensus
+//!
+//! Locating the descriptors of a consensus (especially in tarballs) dominates
+//! the start-up time of a simulation. The cache therefore stores, for every
+//! consensus, a single compressed file containing the consensus and exactly
+//! the descriptors it references. tordoc's document types cannot be
+//! serialized, so the documents are kept in their raw form and parsed from
+//! memory when loading.
+//!
+//! Each cache entry starts with a header that identifies the sources the
+//! consensus and its descriptors were taken from. An entry is only used if
+//! the consensus file and the descriptor archives of the consensus' month and
+//! the month before are still the same, i.e., none of them was added, removed,
+//! or changed in size or modification time.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{self, Context};
+use chrono::{DateTime, Utc};
+#[allow(unused_imports)]
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    combine_documents, descriptor_months, parse_consensus, referenced_digests, ConsensusHandle,
+    ConsensusSource, DescriptorArchive, DescriptorIndex, TorArchive,
+};
+use crate::cli::SimulationRangeEdge;
+use crate::config::ConsensusFlavor;
+
+/// Version of the on-disk format. Entries with a different version are ignored.
+const FORMAT_VERSION: u32 = 3;
+
+/// A directory containing cached consensuses and their descriptors
+pub(crate) struct ConsensusCache {
+    dir: PathBuf,
+}
+
+/// Identification of the file a consensus was originally read from
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct SourceStamp {
+    path: PathBuf,
+    len: u64,
+    modified: u64,
+}
+
+impl SourceStamp {
+    fn of(path: &Path) -> anyhow::Result<SourceStamp> {
+        let metadata = fs::metadata(path)?;
+        Ok(SourceStamp {
+            path: path.to_path_buf(),
+            len: metadata.len(),
+            modified: metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct CacheHeader {
+    format_version: u32,
+    /// Time of the consensus, as UNIX timestamp
+    time: i64,
+    flavor: ConsensusFlavor,
+    /// The consensus source, followed by the descriptor archives
+    sources: Vec<SourceStamp>,
+}
+
+impl CacheHeader {
+    /// Get the header a valid cache entry for the consensus must have
+    fn expected(
+        handle: &ConsensusHandle,
+        archives: &[DescriptorArchive],
+    ) -> anyhow::Result<CacheHeader> {
+        let months = descriptor_months(&handle.time);
+        let mut sources = vec![SourceStamp::of(handle.source_path())?];
+        for archive in archives.iter().filter(|a| months.contains(&a.month)) {
+            sources.push(SourceStamp::of(&archive.path)?);
+        }
+
+        Ok(CacheHeader {
+            format_version: FORMAT_VERSION,
+            time: handle.time.timestamp(),
+            flavor: handle.flavor,
+            sources,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheBody {
+    consensus: String,
+    /// Raw descriptors together with their digests
+    descriptors: Vec<(String, Vec<u8>)>,
+}
+
+impl ConsensusCache {
+    pub(crate) fn new(dir: impl Into<PathBuf>) -> ConsensusCache {
+        ConsensusCache { dir: dir.into() }
+    }
+
+    fn entry_path(&self, time: &DateTime<Utc>, flavor: ConsensusFlavor) -> PathBuf {
+        let suffix = match flavor {
+            ConsensusFlavor::Full => "consensus",
+            ConsensusFlavor::Microdesc => "consensus-microdesc",
+        };
+        self.dir.join(format!(
+            "{}-{}.bin.zst",
+            time.format("%Y-%m-%d-%H-%M-%S"),
+            suffix
+        ))
+    }
+
+    /// Make the consensus handle use the cache if a valid entry exists.
+    /// `archives` are the descriptor archives available for the simulation.
+    ///
+    /// Returns `true` if the cache is used.
+    pub(super) fn try_attach(
+        &self,
+        handle: &mut ConsensusHandle,
+        archives: &[DescriptorArchive],
+    ) -> anyhow::Result<bool> {
+        let path = self.entry_path(&handle.time, handle.flavor);
+        if !path.exists() {
+            return Ok(false);
+        }
+
+        let expected = CacheHeader::expected(handle, archives)?;
+
+        match read_header(&path) {
+            Ok(header) if header == expected => {
+                handle.source = ConsensusSource::Cache(path);
+                handle.descriptors = None;
+                Ok(true)
+            }
+            Ok(_) => {
+                debug!("Cache entry {} is outdated", path.display());
+                Ok(false)
+            }
+            Err(e) => {
+                warn!("Ignoring broken cache entry {}: {}", path.display(), e);
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl TorArchive {
+    /// Parse all the consensuses in a date range and store them, together with
+    /// their descriptors, in the cache. Entries that are still valid are kept.
+    ///
+    /// Returns the number of newly written cache entries.
+    pub(crate) fn build_cache(
+        &self,
+        from: &SimulationRangeEdge,
+        to: &SimulationRangeEdge,
+    ) -> anyhow::Result<usize> {
+        let cache = self
+            .cache
+            .as_ref()
+            .context("No cache directory configured")?;
+        fs::create_dir_all(&cache.dir)?;
+
+        let mut handles = self.find_consensus_sources(from, to)?;
+        let archives = self.descriptor_archives(from, to)?;
+        handles.retain_mut(|handle| !cache.try_attach(handle, &archives).unwrap_or(false));
+        info!("{} consensuses need to be cached", handles.len());
+        if handles.is_empty() {
+            return Ok(0);
+        }
+
+        let index = self.index_descriptors(from, to)?;
+
+        for (i, handle) in handles.iter().enumerate() {
+            info!(
+                "Caching consensus {} ({}/{})",
+                handle.time,
+                i + 1,
+                handles.len()
+            );
+
+            let raw_consensus = handle.load_raw()?;
+            let consensus = parse_consensus(&raw_consensus)?;
+            let descriptors = index
+                .raw_documents(referenced_digests(
+                    handle.flavor,
+                    &consensus,
+                    &raw_consensus,
+                )?)?
+                .into_iter()
+                .map(|(digest, raw)| (digest, raw.into_owned()))
+                .collect();
+
+            let header = CacheHeader::expected(handle, &archives)?;
+            let body = CacheBody {
+                consensus: raw_consensus,
+                descriptors,
+            };
+
+            write_entry(
+                &cache.entry_path(&handle.time, handle.flavor),
+                &header,
+                &body,
+            )?;
+        }
+
+        Ok(handles.len())
+    }
+}
+
+fn read_header(path: &Path) -> anyhow::Result<CacheHeader> {
+    let mut decoder = zstd::Decoder::new(BufReader::new(File::open(path)?))?;
+    Ok(bincode::deserialize_from(&mut decoder)?)
+}
+
+fn write_entry(path: &Path, header: &CacheHeader, body: &CacheBody) -> anyhow::Result<()> {
+    // write to a temporary file first so we never leave half-written entries
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut encoder = zstd::Encoder::new(BufWriter::new(File::create(&tmp_path)?), 3)?;
+        bincode::serialize_into(&mut encoder, header)?;
+        bincode::serialize_into(&mut encoder, body)?;
+        encoder.finish()?;
+    }
+    fs::rename(tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Load the consensus and descriptors from a cache entry
+pub(super) fn load_entry(
+    path: &Path,
+    flavor: ConsensusFlavor,
+) -> anyhow::Result<(tordoc::Consensus, Vec<tordoc::Descriptor>)> {
+    let mut decoder = zstd::Decoder::new(BufReader::new(File::open(path)?))?;
+    let _header: CacheHeader = bincode::deserialize_from(&mut decoder)?;
+    let body: CacheBody = bincode::deserialize_from(&mut decoder)
+        .with_context(|| format!("Broken cache entry {}", path.display()))?;
+
+    let mut consensus = parse_consensus(&body.consensus)?;
+
+    // combine the documents the same way as when reading them from the archive
+    let mut index = DescriptorIndex::new();
+    index.in_memory.extend(
+        body.descriptors
+            .into_iter()
+            .map(|(digest, raw)| (digest, raw.into_boxed_slice())),
+    );
+    let descriptors = combine_documents(flavor, &mut consensus, &body.consensus, &index)?;
+
+    Ok((consensus, descriptors))
+}
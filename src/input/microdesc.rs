@@ -0,0 +1,198 @@
+//! Support for microdescriptor-flavored consensuses
+//!
+//! Modern Tor clients do not use the full consensus and server descriptors,
+//! but the microdesc consensus and microdescriptors. The information is split
+//! differently between these documents: Relays in the microdesc consensus refer
+//! to their microdescriptor by an `m` line instead of a descriptor digest, and
+//! the exit policy summaries (`p`, `p6`), family declarations and IPv6
+//! addresses are taken from the microdescriptor.
+//!
+//! We combine these documents into the same `(Consensus, Vec<Descriptor>)`
+//! representation as used for the full flavor, so path selection sees the
+//! same information a current Tor client would see.
+//!
+//! Like a Tor client, we do not learn the relays' full exit policies this way.
+//! When destinations are modelled, exits are therefore only checked against
+//! the ports in their policy summaries, not against the destination address.
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use anyhow;
+use fxhash::FxHashMap;
+#[allow(unused_imports)]
+use log::{debug, info, trace, warn};
+use tordoc::{
+    consensus::CondensedExitPolicy, consensus::Relay, descriptor::OrAddress, Consensus, Descriptor,
+};
+
+use super::DescriptorIndex;
+
+/// Get the microdescriptor digests (lowercase hex) of all relays in a raw
+/// microdesc consensus, keyed by the relays' fingerprints (uppercase hex).
+pub(super) fn microdesc_digests(raw_consensus: &str) -> anyhow::Result<FxHashMap<String, String>> {
+    let mut digests = FxHashMap::default();
+    let mut current_fingerprint = None;
+
+    for line in raw_consensus.lines() {
+        let mut parts = line.split(' ');
+        match parts.next() {
+            Some("r") => {
+                // r nickname identity published-date published-time IP ORPort DirPort
+                let identity = parts
+                    .nth(1)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid r line: {}", line))?;
+                current_fingerprint = Some(base64_to_hex(identity)?.to_uppercase());
+            }
+            Some("m") => {
+                let digest = parts
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("Invalid m line: {}", line))?;
+                if let Some(fingerprint) = current_fingerprint.take() {
+                    digests.insert(fingerprint, base64_to_hex(digest)?);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(digests)
+}
+
+/// Look up the microdescriptors of all relays in a microdesc consensus and
+/// turn them into descriptors.
+///
+/// This also sets the relays' exit policy summaries, as these are part of
+/// the microdescriptors in this flavor.
+pub(super) fn combine(
+    consensus: &mut Consensus,
+    raw_consensus: &str,
+    index: &DescriptorIndex,
+) -> anyhow::Result<Vec<Descriptor>> {
+    let digests = microdesc_digests(raw_consensus)?;
+    let mut descriptors = Vec::with_capacity(consensus.relays.len());
+    let mut missing = 0;
+
+    for relay in consensus.relays.iter_mut() {
+        let digest = relay
+            .fingerprint
+            .as_ref()
+            .and_then(|fingerprint| digests.get(&fingerprint.to_string().to_uppercase()));
+
+        let raw = match digest {
+            Some(digest) => index.get(digest)?,
+            None => None,
+        };
+
+        match raw {
+            Some(raw) => {
+                let microdesc = Microdescriptor::from_str(&String::from_utf8_lossy(&raw))?;
+                relay.exit_policy = Some(
+                    microdesc
+                        .policy
+                        .clone()
+                        .unwrap_or_else(CondensedExitPolicy::reject_all),
+                );
+                descriptors.push(microdesc.into_descriptor(relay));
+            }
+            None => {
+                trace!(
+                    "Microdescriptor for relay {:?} is missing",
+                    relay.fingerprint
+                );
+                missing += 1;
+            }
+        }
+    }
+
+    if missing > 0 {
+        debug!(
+            "{} of {} microdescriptors referenced by the consensus are missing",
+            missing,
+            consensus.relays.len()
+        );
+    }
+
+    Ok(descriptors)
+}
+
+/// The information from a microdescriptor that is relevant to path selection
+#[derive(Debug, Default)]
+struct Microdescriptor {
+    /// Additional (IPv6) addresses from `a` lines
+    or_addresses: Vec<OrAddress>,
+    /// Declared family members
+    family: Option<Vec<String>>,
+    /// IPv4 exit policy summary
+    policy: Option<CondensedExitPolicy>,
+    /// IPv6 exit policy summary
+    policy_ipv6: Option<CondensedExitPolicy>,
+}
+
+impl Microdescriptor {
+    fn from_str(raw: &str) -> anyhow::Result<Microdescriptor> {
+        let mut microdesc = Microdescriptor::default();
+
+        for line in raw.lines() {
+            let (keyword, args) = line.split_once(' ').unwrap_or((line, ""));
+            match keyword {
+                "a" => {
+                    let addr = SocketAddr::from_str(args)
+                        .map_err(|e| anyhow::anyhow!("Invalid a line \"{}\": {}", line, e))?;
+                    microdesc.or_addresses.push(OrAddress {
+                        ip: addr.ip(),
+                        port: addr.port(),
+                    });
+                }
+                "family" => {
+                    microdesc.family = Some(args.split(' ').map(|x| x.to_string()).collect());
+                }
+                "p" => {
+                    microdesc.policy = Some(parse_policy_summary(args)?);
+                }
+                "p6" => {
+                    microdesc.policy_ipv6 = Some(parse_policy_summary(args)?);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(microdesc)
+    }
+
+    /// Combine this microdescriptor with the relay's consensus entry
+    fn into_descriptor(self, relay: &Relay) -> Descriptor {
+        // the IPv4 address is only given in the consensus
+        let mut or_addresses = Vec::new();
+        if let (Some(ip), Some(port)) = (relay.address, relay.or_port) {
+            or_addresses.push(OrAddress { ip, port });
+        }
+        or_addresses.extend(self.or_addresses);
+
+        Descriptor {
+            nickname: relay.nickname.clone(),
+            fingerprint: relay.fingerprint.clone(),
+            digest: relay.digest.clone(),
+            published: relay.published,
+            or_addresses: Some(or_addresses),
+            family_members: self.family,
+            bandwidth_avg: None,
+            bandwidth_burst: None,
+            bandwidth_observed: None,
+            // microdescriptors only contain the policy summaries
+            exit_policy: None,
+            exit_policies_ipv6: self.policy_ipv6,
+        }
+    }
+}
+
+fn parse_policy_summary(raw: &str) -> anyhow::Result<CondensedExitPolicy> {
+    CondensedExitPolicy::from_str(raw)
+        .map_err(|e| anyhow::anyhow!("Invalid exit policy summary \"{}\": {:?}", raw, e))
+}
+
+/// Convert an unpadded base64 value (as used by Tor) to lowercase hex
+fn base64_to_hex(raw: &str) -> anyhow::Result<String> {
+    let bytes = base64::decode_config(raw.trim_end_matches('='), base64::STANDARD_NO_PAD)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
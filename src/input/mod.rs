@@ -0,0 +1,789 @@
+//! Wrappers and helpers for loading consensus and descriptor data
+//!
+//! Documents can either be read from an extracted CollecTor directory tree
+//! (`consensuses-YYYY-MM/DD/...`) or straight from the original CollecTor
+//! tarballs (`consensuses-YYYY-MM.tar.xz`, `server-descriptors-YYYY-MM.tar.xz`,
+//! or their `.tar.zst` counterparts). The needed entries of tarballs are
+//! unpacked once into a temporary file (see [UnpackedTarball]).
+//!
+//! Instead of the full consensus and server descriptors, the microdesc
+//! consensus and microdescriptors (`microdescs-YYYY-MM`) can be used, which is
+//! what current Tor clients do (see [microdesc]).
+//!
+//! To speed up repeated runs over the same time range, the documents needed
+//! for each consensus can additionally be stored in a binary cache (see
+//! [cache]).
+
+pub(crate) mod cache;
+mod microdesc;
+
+use std::borrow::Cow;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{self, Context};
+use chrono::prelude::*;
+use fxhash::FxHashMap;
+#[allow(unused_imports)]
+use log::{debug, info, trace, warn};
+use regex::Regex;
+use tordoc;
+
+use crate::cli::SimulationRangeEdge;
+use crate::config::{ConsensusFlavor, ExperimentConfig};
+use cache::ConsensusCache;
+
+/// Fill the binary cache for the configured simulation timespan
+pub(crate) fn build_cache(config: &ExperimentConfig) -> anyhow::Result<()> {
+    let from = config
+        .time
+        .from
+        .as_ref()
+        .context("No simulation start time given (--from or time.from)")?;
+    let to = config
+        .time
+        .to
+        .as_ref()
+        .context("No simulation end time given (--to or time.to)")?;
+
+    let archive = TorArchive::new(
+        config
+            .input
+            .tor_data
+            .as_ref()
+            .context("No Tor data location given (--tor-data or input.tor_data)")?,
+    )?
+    .with_flavor(config.input.flavor)
+    .with_unpack_dir(config.input.unpack_dir())
+    .with_cache(config.input.cache_dir().unwrap()); // cannot fail as tor_data is set
+
+    let num_written = archive.build_cache(from, to)?;
+    info!("Wrote {} new cache entries", num_written);
+
+    Ok(())
+}
+
+/// Loader for data (consensus or descriptors) from an on-disk Tor data archive
+pub(crate) struct TorArchive {
+    dir: PathBuf,
+    flavor: ConsensusFlavor,
+    cache: Option<ConsensusCache>,
+    unpack_dir: PathBuf,
+}
+
+impl TorArchive {
+    /// Construct a new loader
+    pub(crate) fn new(dir: impl Into<PathBuf>) -> anyhow::Result<TorArchive> {
+        let dir = dir.into();
+
+        if !dir.exists() {
+            anyhow::bail!("Data archive path {} does not exist", dir.to_string_lossy())
+        }
+
+        if !dir.is_dir() {
+            anyhow::bail!(
+                "Data archive path {} is not a directory",
+                dir.to_string_lossy()
+            )
+        }
+
+        Ok(TorArchive {
+            dir: dir,
+            flavor: ConsensusFlavor::Full,
+            cache: None,
+            unpack_dir: std::env::temp_dir(),
+        })
+    }
+
+    /// Use the given consensus flavor (and the matching kind of descriptors)
+    pub(crate) fn with_flavor(mut self, flavor: ConsensusFlavor) -> TorArchive {
+        self.flavor = flavor;
+        self
+    }
+
+    /// Unpack the needed entries of tarballs to a temporary file in `unpack_dir`
+    pub(crate) fn with_unpack_dir(mut self, unpack_dir: impl Into<PathBuf>) -> TorArchive {
+        self.unpack_dir = unpack_dir.into();
+        self
+    }
+
+    /// Use the binary cache in `cache_dir`, if it exists and is valid
+    pub(crate) fn with_cache(mut self, cache_dir: impl Into<PathBuf>) -> TorArchive {
+        self.cache = Some(ConsensusCache::new(cache_dir));
+        self
+    }
+
+    /// Find all the consensuses in a given date range.
+    ///
+    /// If a cache is used, consensuses with a valid cache entry are loaded from there.
+    pub(crate) fn find_consensuses(
+        &self,
+        from: &SimulationRangeEdge,
+        to: &SimulationRangeEdge,
+    ) -> anyhow::Result<Vec<ConsensusHandle>> {
+        let mut handles = self.find_consensus_sources(from, to)?;
+
+        if let Some(ref cache) = self.cache {
+            let archives = self.descriptor_archives(from, to)?;
+            let mut num_cached = 0;
+            for handle in handles.iter_mut() {
+                if cache.try_attach(handle, &archives)? {
+                    num_cached += 1;
+                }
+            }
+            info!(
+                "Using cached documents for {} of {} consensuses",
+                num_cached,
+                handles.len()
+            );
+        }
+
+        // Consensuses taken from tarballs (and microdesc consensuses in general)
+        // cannot use tordoc's descriptor lookup, so we provide them with an
+        // index of the matching descriptors.
+        let needs_index = |source: &ConsensusSource| match source {
+            ConsensusSource::Tarball { .. } => true,
+            ConsensusSource::File(_) => self.flavor == ConsensusFlavor::Microdesc,
+            ConsensusSource::Cache(_) => false,
+        };
+        if handles.iter().any(|h| needs_index(&h.source)) {
+            let descriptors = Arc::new(self.index_descriptors(from, to)?);
+            for handle in handles.iter_mut() {
+                if needs_index(&handle.source) {
+                    handle.descriptors = Some(descriptors.clone());
+                }
+            }
+        }
+
+        Ok(handles)
+    }
+
+    /// Find the original documents of all the consensuses in a given date range
+    fn find_consensus_sources(
+        &self,
+        from: &SimulationRangeEdge,
+        to: &SimulationRangeEdge,
+    ) -> anyhow::Result<Vec<ConsensusHandle>> {
+        // iterate through available consensuses
+        let prefix = match self.flavor {
+            ConsensusFlavor::Full => "consensuses",
+            ConsensusFlavor::Microdesc => "microdescs",
+        };
+        let re_consdir = Regex::new(&format!(r"^{}-(\d{{4}})-(\d{{2}})$", prefix)).unwrap();
+        let re_constarball =
+            Regex::new(&format!(r"^{}-(\d{{4}})-(\d{{2}})\.tar\.(xz|zst)$", prefix)).unwrap();
+        let re_subdir = Regex::new(r"^\d{2}$").unwrap();
+
+        // check if a month is (partially) covered by the simulation range
+        let month_in_range = |year: u16, month: u8| {
+            !(year < from.year() || (year == from.year() && month < from.month()))
+                && !(year > to.year() || (year == to.year() && month > to.month()))
+        };
+
+        // parse a consensus file name and return its time, if it is in the simulation range
+        let consensus_time = |file_name: &str| -> anyhow::Result<Option<DateTime<Utc>>> {
+            match parse_consensus_file_name(file_name, self.flavor)? {
+                Some(time) if time >= from.first_datetime() && time <= to.last_datetime() => {
+                    Ok(Some(time))
+                }
+                _ => Ok(None),
+            }
+        };
+
+        let mut handles = Vec::new();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let file_name = fname_as_string(&entry)?;
+
+            if let Some(captures) = re_constarball.captures(file_name.as_str()) {
+                let year = captures.get(1).unwrap().as_str().parse::<u16>().unwrap();
+                let month = captures.get(2).unwrap().as_str().parse::<u8>().unwrap();
+                if !month_in_range(year, month) {
+                    continue;
+                }
+
+                // unpack the consensuses we need from the tarball
+                info!("Unpacking consensus archive {}", entry.path().display());
+                let mut wanted = Vec::new();
+                let unpacked = Arc::new(UnpackedTarball::new(
+                    &entry.path(),
+                    &self.unpack_dir,
+                    |path| {
+                        Ok(consensus_time(base_name(path))?.map(|time| {
+                            wanted.push((path.to_string(), time));
+                            path.to_string()
+                        }))
+                    },
+                )?);
+
+                for (path, time) in wanted {
+                    handles.push(ConsensusHandle {
+                        time,
+                        source: ConsensusSource::Tarball {
+                            unpacked: unpacked.clone(),
+                            entry: path,
+                        },
+                        flavor: self.flavor,
+                        descriptors: None,
+                    });
+                }
+                continue;
+            }
+
+            match re_consdir.captures(file_name.as_str()) {
+                None => continue,
+                Some(captures) => {
+                    let dir_year = captures.get(1).unwrap().as_str().parse::<u16>().unwrap();
+                    let dir_month = captures.get(2).unwrap().as_str().parse::<u8>().unwrap();
+
+                    if !month_in_range(dir_year, dir_month) {
+                        continue;
+                    }
+                }
+            }
+
+            // find all matching consensuses in this folder
+            let consensus_dir = match self.flavor {
+                ConsensusFlavor::Full => entry.path(),
+                ConsensusFlavor::Microdesc => entry.path().join("consensus-microdesc"),
+            };
+            for subentry in fs::read_dir(consensus_dir)? {
+                let subentry = subentry?;
+                if !re_subdir.is_match(fname_as_string(&subentry)?.as_str()) {
+                    continue;
+                }
+
+                for file in fs::read_dir(subentry.path())? {
+                    let file = file?;
+                    // ignore consensuses that do not match specified days
+                    if let Some(time) = consensus_time(fname_as_string(&file)?.as_str())? {
+                        handles.push(ConsensusHandle {
+                            time,
+                            source: ConsensusSource::File(file.path()),
+                            flavor: self.flavor,
+                            descriptors: None,
+                        });
+                    }
+                }
+            }
+        }
+
+        handles.sort_unstable_by_key(|h| h.time);
+
+        Ok(handles)
+    }
+
+    /// Find the archives of server descriptors (or microdescriptors) that may
+    /// be referenced by consensuses in the given date range
+    fn descriptor_archives(
+        &self,
+        from: &SimulationRangeEdge,
+        to: &SimulationRangeEdge,
+    ) -> anyhow::Result<Vec<DescriptorArchive>> {
+        let prefix = match self.flavor {
+            ConsensusFlavor::Full => "server-descriptors",
+            ConsensusFlavor::Microdesc => "microdescs",
+        };
+        let re_descdir = Regex::new(&format!(r"^{}-(\d{{4}})-(\d{{2}})$", prefix)).unwrap();
+        let re_desctarball =
+            Regex::new(&format!(r"^{}-(\d{{4}})-(\d{{2}})\.tar\.(xz|zst)$", prefix)).unwrap();
+
+        let first_month = descriptor_months(&from.first_datetime())[0];
+        let last_month = (to.year(), to.month());
+
+        let mut archives = Vec::new();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let file_name = fname_as_string(&entry)?;
+
+            let (captures, is_tarball) = match re_desctarball.captures(file_name.as_str()) {
+                Some(captures) => (captures, true),
+                None => match re_descdir.captures(file_name.as_str()) {
+                    Some(captures) => (captures, false),
+                    None => continue,
+                },
+            };
+
+            let month = (
+                captures.get(1).unwrap().as_str().parse::<u16>().unwrap(),
+                captures.get(2).unwrap().as_str().parse::<u8>().unwrap(),
+            );
+            if month < first_month || month > last_month {
+                continue;
+            }
+
+            archives.push(DescriptorArchive {
+                path: entry.path(),
+                month,
+                is_tarball,
+            });
+        }
+
+        archives.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(archives)
+    }
+
+    /// Collect the server descriptors (or microdescriptors) that may be
+    /// referenced by consensuses in the given date range.
+    ///
+    /// The descriptors in tarballs are unpacked to disk and indexed by their
+    /// offset. Extracted descriptor directories are only remembered and looked
+    /// up on demand.
+    fn index_descriptors(
+        &self,
+        from: &SimulationRangeEdge,
+        to: &SimulationRangeEdge,
+    ) -> anyhow::Result<DescriptorIndex> {
+        // Microdescriptors are stored next to the microdesc consensuses
+        let is_descriptor = |path: &str| match self.flavor {
+            ConsensusFlavor::Full => true,
+            ConsensusFlavor::Microdesc => path.contains("/micro/"),
+        };
+
+        let mut index = DescriptorIndex::new();
+
+        for archive in self.descriptor_archives(from, to)? {
+            if archive.is_tarball {
+                info!("Unpacking descriptor archive {}", archive.path.display());
+                let unpacked = UnpackedTarball::new(&archive.path, &self.unpack_dir, |path| {
+                    Ok(is_descriptor(path).then(|| base_name(path).to_lowercase()))
+                })?;
+                index.tarballs.push(unpacked);
+            } else {
+                index.directories.push(match self.flavor {
+                    ConsensusFlavor::Full => archive.path,
+                    ConsensusFlavor::Microdesc => archive.path.join("micro"),
+                });
+            }
+        }
+
+        info!(
+            "Indexed {} descriptors from archives and {} descriptor directories",
+            index.tarballs.iter().map(|t| t.len()).sum::<usize>(),
+            index.directories.len()
+        );
+
+        Ok(index)
+    }
+}
+
+/// A monthly archive of server descriptors or microdescriptors, either a
+/// tarball or an extracted directory
+#[derive(Debug)]
+struct DescriptorArchive {
+    path: PathBuf,
+    /// Year and month the descriptors were published in
+    month: (u16, u8),
+    is_tarball: bool,
+}
+
+/// Get the months whose descriptors a consensus from the given time may
+/// reference. Consensuses at the beginning of a month reference descriptors
+/// published in the month before.
+fn descriptor_months(time: &DateTime<Utc>) -> [(u16, u8); 2] {
+    let before = *time - chrono::Months::new(1);
+    [
+        (before.year() as u16, before.month() as u8),
+        (time.year() as u16, time.month() as u8),
+    ]
+}
+
+/// A reference to a consensus that is known to exist in the data archive
+#[derive(Debug)]
+pub(crate) struct ConsensusHandle {
+    time: DateTime<Utc>,
+    source: ConsensusSource,
+    flavor: ConsensusFlavor,
+    /// Where to take the descriptors from. If this is `None`, tordoc looks
+    /// them up relative to the consensus file.
+    descriptors: Option<Arc<DescriptorIndex>>,
+}
+
+/// The location of a consensus document
+#[derive(Debug)]
+enum ConsensusSource {
+    /// A plain file in an extracted directory tree
+    File(PathBuf),
+    /// An entry within a (compressed) tarball
+    Tarball {
+        unpacked: Arc<UnpackedTarball>,
+        entry: String,
+    },
+    /// An entry of the binary cache, containing the consensus and its descriptors
+    Cache(PathBuf),
+}
+
+impl ConsensusHandle {
+    /// The publication time of the consensus
+    pub fn time(&self) -> &DateTime<Utc> {
+        &self.time
+    }
+
+    pub fn load(self) -> anyhow::Result<(tordoc::Consensus, Vec<tordoc::Descriptor>)> {
+        if let ConsensusSource::Cache(ref path) = self.source {
+            return cache::load_entry(path, self.flavor);
+        }
+
+        let raw_consensus = self.load_raw()?;
+        let mut consensus = parse_consensus(&raw_consensus)?;
+
+        let descriptors = match (&self.source, &self.descriptors) {
+            (_, Some(ref index)) => {
+                combine_documents(self.flavor, &mut consensus, &raw_consensus, index)?
+            }
+            (ConsensusSource::File(ref path), None) if self.flavor == ConsensusFlavor::Full => {
+                consensus
+                    .retrieve_descriptors(path)
+                    .map_err(|_| anyhow::anyhow!("Error combining documents"))? // TODO
+            }
+            (_, None) => {
+                anyhow::bail!("No descriptors available for consensus from archive")
+            }
+        };
+
+        Ok((consensus, descriptors))
+    }
+
+    /// Read the raw consensus document
+    fn load_raw(&self) -> anyhow::Result<String> {
+        Ok(match self.source {
+            ConsensusSource::File(ref path) => {
+                let mut raw = String::new();
+                let mut file = File::open(path)?;
+                file.read_to_string(&mut raw).unwrap();
+                raw
+            }
+            ConsensusSource::Tarball {
+                ref unpacked,
+                ref entry,
+            } => String::from_utf8(unpacked.get(entry)?.ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Entry {} not found in archive {}",
+                    entry,
+                    unpacked.path.display()
+                )
+            })?)?,
+            ConsensusSource::Cache(_) => {
+                anyhow::bail!("Raw consensus is not available from the cache")
+            }
+        })
+    }
+
+    /// Get the file the consensus is read from, as a whole
+    fn source_path(&self) -> &Path {
+        match self.source {
+            ConsensusSource::File(ref path) => path,
+            ConsensusSource::Tarball { ref unpacked, .. } => &unpacked.path,
+            ConsensusSource::Cache(ref path) => path,
+        }
+    }
+}
+
+fn parse_consensus(raw: &str) -> anyhow::Result<tordoc::Consensus> {
+    tordoc::Consensus::from_str(raw)
+        .map_err(|e| anyhow::anyhow!("Error parsing consensus: {:?}", e))
+}
+
+fn parse_descriptor(digest: &str, raw: &[u8]) -> anyhow::Result<tordoc::Descriptor> {
+    let raw = String::from_utf8_lossy(raw);
+    tordoc::Descriptor::from_str(&raw)
+        .map_err(|e| anyhow::anyhow!("Error parsing descriptor {}: {:?}", digest, e))
+}
+
+/// Get the (lowercase hex) digests of the descriptors referenced by a consensus
+fn referenced_digests(
+    flavor: ConsensusFlavor,
+    consensus: &tordoc::Consensus,
+    raw_consensus: &str,
+) -> anyhow::Result<Vec<String>> {
+    Ok(match flavor {
+        ConsensusFlavor::Full => consensus
+            .relays
+            .iter()
+            .filter_map(|relay| relay.digest.as_ref())
+            .map(|digest| digest.to_string().to_lowercase())
+            .collect(),
+        ConsensusFlavor::Microdesc => microdesc::microdesc_digests(raw_consensus)?
+            .into_values()
+            .collect(),
+    })
+}
+
+/// Look up and parse the descriptors referenced by a consensus.
+///
+/// For microdesc consensuses, this also completes the relays' entries with
+/// information that is only contained in the microdescriptors.
+fn combine_documents(
+    flavor: ConsensusFlavor,
+    consensus: &mut tordoc::Consensus,
+    raw_consensus: &str,
+    index: &DescriptorIndex,
+) -> anyhow::Result<Vec<tordoc::Descriptor>> {
+    match flavor {
+        ConsensusFlavor::Full => index
+            .raw_documents(referenced_digests(flavor, consensus, raw_consensus)?)?
+            .iter()
+            .map(|(digest, raw)| parse_descriptor(digest, raw))
+            .collect(),
+        ConsensusFlavor::Microdesc => microdesc::combine(consensus, raw_consensus, index),
+    }
+}
+
+/// Server descriptors, indexed by their (lowercase hex) digest
+#[derive(Debug)]
+struct DescriptorIndex {
+    /// Raw descriptors kept in memory (as loaded from a cache entry)
+    in_memory: FxHashMap<String, Box<[u8]>>,
+    /// Descriptors unpacked from tarballs
+    tarballs: Vec<UnpackedTarball>,
+    /// Extracted `server-descriptors-YYYY-MM` directories
+    directories: Vec<PathBuf>,
+}
+
+impl DescriptorIndex {
+    fn new() -> DescriptorIndex {
+        DescriptorIndex {
+            in_memory: FxHashMap::default(),
+            tarballs: Vec::new(),
+            directories: Vec::new(),
+        }
+    }
+
+    /// Get the raw descriptor with a given digest
+    fn get(&self, digest: &str) -> anyhow::Result<Option<Cow<'_, [u8]>>> {
+        if let Some(raw) = self.in_memory.get(digest) {
+            return Ok(Some(Cow::Borrowed(raw)));
+        }
+
+        for tarball in self.tarballs.iter() {
+            if let Some(raw) = tarball.get(digest)? {
+                return Ok(Some(Cow::Owned(raw)));
+            }
+        }
+
+        // CollecTor stores descriptors as `<dir>/<d[0]>/<d[1]>/<digest>`
+        for dir in self.directories.iter() {
+            let path = dir.join(&digest[0..1]).join(&digest[1..2]).join(digest);
+            if path.exists() {
+                return Ok(Some(Cow::Owned(fs::read(path)?)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Look up the raw descriptors with the given digests, together with their digests
+    fn raw_documents(&self, digests: Vec<String>) -> anyhow::Result<Vec<(String, Cow<'_, [u8]>)>> {
+        let total = digests.len();
+        let mut descriptors = Vec::with_capacity(total);
+        let mut missing = 0;
+
+        for digest in digests {
+            match self.get(&digest)? {
+                Some(raw) => {
+                    descriptors.push((digest, raw));
+                }
+                None => {
+                    trace!("Descriptor {} is missing", digest);
+                    missing += 1;
+                }
+            }
+        }
+
+        if missing > 0 {
+            debug!(
+                "{} of {} descriptors referenced by the consensus are missing",
+                missing, total
+            );
+        }
+
+        Ok(descriptors)
+    }
+}
+
+/// Selected entries of a (compressed) tarball, unpacked to a temporary file.
+///
+/// Compressed tarballs cannot be accessed randomly, and CollecTor orders
+/// descriptors by digest rather than by publication time, so the descriptors
+/// of a single consensus are spread over the whole archive. Instead of keeping
+/// them in memory, the wanted entries are decompressed once into an
+/// uncompressed spill file and read back by their offset when needed.
+#[derive(Debug)]
+struct UnpackedTarball {
+    /// The original tarball
+    path: PathBuf,
+    spill_path: PathBuf,
+    spill: Mutex<File>,
+    /// Offset and length of the unpacked entries within the spill file
+    entries: FxHashMap<String, (u64, u64)>,
+}
+
+impl UnpackedTarball {
+    /// Unpack the entries of the tarball at `path` into a new file in `unpack_dir`.
+    ///
+    /// `key` is called with the path of every entry within the archive and
+    /// returns the key to store the entry under, or `None` to skip it.
+    fn new(
+        path: &Path,
+        unpack_dir: &Path,
+        mut key: impl FnMut(&str) -> anyhow::Result<Option<String>>,
+    ) -> anyhow::Result<UnpackedTarball> {
+        static NUM_SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+        let spill_path = unpack_dir.join(format!(
+            "torfs-{}-{}.unpacked",
+            std::process::id(),
+            NUM_SPILL_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let spill = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&spill_path)
+            .with_context(|| format!("Could not create {}", spill_path.display()))?;
+
+        // construct early so that the spill file is removed on errors, too
+        let mut unpacked = UnpackedTarball {
+            path: path.to_path_buf(),
+            spill_path,
+            spill: Mutex::new(spill),
+            entries: FxHashMap::default(),
+        };
+
+        let mut entries = FxHashMap::default();
+        let mut writer = BufWriter::new(unpacked.spill.get_mut().unwrap());
+        let mut offset = 0;
+        for_each_tarball_entry(path, |name, entry| {
+            if let Some(key) = key(name)? {
+                let len = std::io::copy(entry, &mut writer)?;
+                entries.insert(key, (offset, len));
+                offset += len;
+            }
+            Ok(false)
+        })
+        .with_context(|| format!("Could not unpack {}", path.display()))?;
+        writer.flush()?;
+        drop(writer);
+
+        unpacked.entries = entries;
+        Ok(unpacked)
+    }
+
+    /// The number of unpacked entries
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Get the contents of the entry stored under `key`
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let (offset, len) = match self.entries.get(key) {
+            Some(&location) => location,
+            None => return Ok(None),
+        };
+
+        let mut data = vec![0; len as usize];
+        let mut spill = self.spill.lock().unwrap();
+        spill.seek(SeekFrom::Start(offset))?;
+        spill.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+}
+
+impl Drop for UnpackedTarball {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.spill_path) {
+            warn!("Could not remove {}: {}", self.spill_path.display(), e);
+        }
+    }
+}
+
+/// Open a tarball, decompressing it according to its file extension
+fn open_tarball(path: &Path) -> anyhow::Result<tar::Archive<Box<dyn Read>>> {
+    let file = File::open(path)?;
+    let file_name = path.to_string_lossy();
+
+    let reader: Box<dyn Read> = if file_name.ends_with(".xz") {
+        Box::new(xz2::read::XzDecoder::new(file))
+    } else if file_name.ends_with(".zst") {
+        Box::new(zstd::Decoder::new(file)?)
+    } else {
+        Box::new(file)
+    };
+
+    Ok(tar::Archive::new(reader))
+}
+
+/// Call `f` for every regular file in a tarball, with the entry's path within
+/// the archive. Iteration stops early if `f` returns `true`.
+fn for_each_tarball_entry(
+    path: &Path,
+    mut f: impl FnMut(&str, &mut tar::Entry<'_, Box<dyn Read>>) -> anyhow::Result<bool>,
+) -> anyhow::Result<()> {
+    let mut archive = open_tarball(path)?;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let name = entry
+            .path()?
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("invalid UTF-8 in path"))?
+            .to_string();
+        if f(&name, &mut entry)? {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the time encoded in the file name of a consensus of the given flavor, if it is one
+fn parse_consensus_file_name(
+    file_name: &str,
+    flavor: ConsensusFlavor,
+) -> anyhow::Result<Option<DateTime<Utc>>> {
+    lazy_static::lazy_static! {
+        static ref RE_CONSFILE: Regex =
+            Regex::new(r"^(\d{4}-\d{2}-\d{2}-\d{2}-\d{2}-\d{2})-consensus$").unwrap();
+        static ref RE_MICRODESC_CONSFILE: Regex =
+            Regex::new(r"^(\d{4}-\d{2}-\d{2}-\d{2}-\d{2}-\d{2})-consensus-microdesc$").unwrap();
+    }
+
+    let re = match flavor {
+        ConsensusFlavor::Full => &*RE_CONSFILE,
+        ConsensusFlavor::Microdesc => &*RE_MICRODESC_CONSFILE,
+    };
+    match re.captures(file_name) {
+        None => Ok(None),
+        Some(captures) => {
+            let raw_date = captures.get(1).unwrap().as_str();
+            Ok(Some(Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(
+                raw_date,
+                "%Y-%m-%d-%H-%M-%S",
+            )?)))
+        }
+    }
+}
+
+/// Get the last component of a path within a tarball
+fn base_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// helper to get utf-8 file name
+fn fname_as_string(entry: &fs::DirEntry) -> anyhow::Result<String> {
+    Ok(entry
+        .file_name()
+        .into_string()
+        .map_err(|_| anyhow::anyhow!("invalid UTF-8 in path"))?)
+}
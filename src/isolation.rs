@@ -0,0 +1,47 @@
+//! Stream isolation
+//!
+//! Like in Tor (`connection_edge_compatible_with_circuit()` in circuituse.c),
+//! a circuit is isolated to the properties of the first stream it carries.
+//! Later streams only share the circuit if they agree with it on every
+//! property that the isolation flags (IsolateDestAddr, IsolateDestPort,
+//! IsolateSOCKSAuth) are set for.
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::config::protocol_parameters;
+use crate::user::Request;
+
+lazy_static! {
+    static ref ISOLATE_DEST_ADDR: bool = protocol_parameters().circuits.isolate_dest_addr;
+    static ref ISOLATE_DEST_PORT: bool = protocol_parameters().circuits.isolate_dest_port;
+    static ref ISOLATE_SOCKS_AUTH: bool = protocol_parameters().circuits.isolate_socks_auth;
+}
+
+/// The properties of a circuit's first stream that later streams are
+/// compared with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct StreamIsolation {
+    destination: Option<u64>,
+    port: u16,
+    token: Option<u64>,
+}
+
+impl StreamIsolation {
+    /// Isolate a circuit to the properties of a stream request
+    pub(crate) fn of(request: &Request) -> StreamIsolation {
+        StreamIsolation {
+            destination: request.destination.map(|destination| destination.id),
+            port: request.port,
+            token: request.isolation_token,
+        }
+    }
+
+    /// Check if a stream may share a circuit that is isolated like this
+    pub(crate) fn allows(&self, request: &Request) -> bool {
+        let other = StreamIsolation::of(request);
+        !(*ISOLATE_DEST_ADDR && self.destination != other.destination
+            || *ISOLATE_DEST_PORT && self.port != other.port
+            || *ISOLATE_SOCKS_AUTH && self.token != other.token)
+    }
+}
@@ -5,39 +5,83 @@ use env_logger;
 use log::{debug, info, trace, warn};
 
 mod cli;
-use cli::Cli;
+use cli::{Cli, Command};
 mod client;
+mod compromise;
+mod config;
+use config::ExperimentConfig;
 mod input;
 mod observer;
 mod sim;
 mod user;
 use sim::Simulator;
 mod adversaries;
+mod bridges;
+mod checkpoint;
+mod circuit_build;
+mod destinations;
 mod guard;
+mod guard_policy;
+mod guard_state;
+mod isolation;
 mod needs;
 mod packet_model;
+mod prefetch;
+mod reachability;
+mod rng;
+mod sweep;
 mod trace;
+mod trials;
 mod utils;
+mod vanguards;
 
 fn main() -> anyhow::Result<()> {
     // Initialize logging system
     env_logger::init();
 
     let cli = Cli::parse();
+    let mut config = ExperimentConfig::from_cli(&cli)?;
 
-    let seed = if cli.seed == 0 {
-        let new_seed = seeded_rand::generate_random_seed();
-        info!(
-            "No seed was given. Call with \"--seed {}\" to reproduce this run.",
+    if let Some(Command::Cache) = cli.command {
+        return input::build_cache(&config);
+    }
+
+    let seed = match config.seed {
+        None | Some(0) => {
+            let new_seed = seeded_rand::generate_random_seed();
+            info!(
+                "No seed was given. Call with \"--seed {}\" to reproduce this run.",
+                new_seed
+            );
             new_seed
-        );
-        new_seed
-    } else {
-        cli.seed
+        }
+        Some(seed) => seed,
     };
-    seeded_rand::set_seed(seed);
+    rng::set_seed(seed);
+    config.seed = Some(seed);
+
+    config.check()?;
+    config::set_protocol_parameters(config::ProtocolParameters {
+        guards: config.guards.clone(),
+        circuits: config.circuits.clone(),
+        circuit_build: config.circuit_build.clone(),
+        vanguards: config.vanguards.clone(),
+    })?;
 
-    let simulator = Simulator::new(cli);
+    // Store the resolved configuration so this run can be reproduced exactly
+    if let Some(path) = config.resolved_config_path() {
+        info!("Writing resolved configuration to {}", path.display());
+        config.write(path)?;
+    }
+
+    let simulator = match cli.command {
+        Some(Command::Sweep { ref file }) => {
+            let scenarios = sweep::SweepSpec::from_file(file)?.scenarios(&config)?;
+            info!("Sweeping over {} configurations", scenarios.len());
+            Simulator::new_sweep(config, scenarios)
+        }
+        _ => Simulator::new(config),
+    };
     simulator.run()?;
 
     Ok(())
@@ -2,13 +2,18 @@
 //!
 //! Port needs are collected from past user behavior to predict likely upcoming
 //! user requests. For predicted ports, suitable circuits are built proactively,
-//! before they are actually needed.
+//! before they are actually needed. Ports used for IPv6 destinations are
+//! needs of their own, since only exits with an IPv6 policy can cover them.
+//! Likewise, after onion services were used, clean internal circuits are kept
+//! available (the "internal" need).
 //!
 //! - interior mut
 //! - weak
 //! - handle
 
 use crate::client::ShallowCircuit;
+use crate::config::protocol_parameters;
+use crate::destinations::ExitPolicies;
 use crate::utils::*;
 
 use std::collections::hash_map::Entry::{Occupied, Vacant};
@@ -20,21 +25,33 @@ use chrono::prelude::*;
 use chrono::Duration;
 use lazy_static::lazy_static;
 use seeded_rand::RHashMap;
+use serde::{Deserialize, Serialize};
 use tor_circuit_generator::CircuitGenerator;
 
 lazy_static! {
-    // min coverage given with "#define MIN_CIRCUITS_HANDLING_STREAM 2" in or.h
-    static ref PORT_NEED_COVER_NUM: usize = 2;
-    // #define PREDICTED_CIRCS_RELEVANCE_TIME 60*60" in rephist.c
-    // need expires after an hour
-    static ref PORT_NEED_LIFETIME: Duration = Duration::minutes(60);
+    static ref PORT_NEED_COVER_NUM: usize = protocol_parameters().circuits.port_need_cover_num;
+    static ref INTERNAL_NEED_COVER_NUM: usize =
+        protocol_parameters().circuits.internal_need_cover_num;
+    static ref PORT_NEED_LIFETIME: Duration =
+        Duration::seconds(protocol_parameters().circuits.port_need_lifetime_secs);
+}
+
+/// What kind of circuit is needed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub(crate) enum NeedTarget {
+    /// An exit circuit that allows this port
+    Port(u16),
+    /// An exit circuit that allows this port for IPv6 destinations
+    Ipv6Port(u16),
+    /// An internal circuit, e.g. for onion services
+    Internal,
 }
 
 /// A container for all the current port needs of the client
 ///
 /// This container _owns_ the port needs.
 pub(crate) struct NeedsContainer {
-    needs: RHashMap<u16, Arc<Need>>,
+    needs: RHashMap<NeedTarget, Arc<Need>>,
 }
 
 impl NeedsContainer {
@@ -45,14 +62,14 @@ impl NeedsContainer {
         }
     }
 
-    /// If a need for port `port` exists **and needs cover**, return a [NeedHandle] for it.
+    /// If a need for `target` exists **and needs cover**, return a [NeedHandle] for it.
     ///
     /// This triggers the book-keeping, so the Need knows that it is covered.
     /// If, however, the handle is dropped at some point, the need will be
     /// "uncovered" automatically.
-    pub fn cover_need_if_necessary(&mut self, port: u16) -> Option<NeedHandle> {
+    pub fn cover_need_if_necessary(&mut self, target: NeedTarget) -> Option<NeedHandle> {
         self.needs
-            .get(&port)
+            .get(&target)
             .filter(|need| need.needs_cover())
             .map(|need| NeedHandle::from_need(need))
     }
@@ -66,7 +83,7 @@ impl NeedsContainer {
     /// If this function returns `Some(x)`, then `x` is guaranteed to be a need
     /// that exists at the moment ([NeedHandle::exists] returns true).
     pub fn get_uncovered_need(&self) -> Option<NeedHandle> {
-        for (_port, need) in self.needs.iter() {
+        for (_target, need) in self.needs.iter() {
             if need.needs_cover() {
                 return Some(NeedHandle::from_need(need));
             }
@@ -80,8 +97,8 @@ impl NeedsContainer {
         let mut handler = handler;
 
         self.needs.retain_or_else(
-            |_port, need| !need.has_expired(now),
-            |_port, need| {
+            |_target, need| !need.has_expired(now),
+            |_target, need| {
                 handler(need.to_string());
             },
         );
@@ -89,12 +106,19 @@ impl NeedsContainer {
 
     /// Add a need to be covered by circuits and return a `String` representation of it.
     ///
-    /// There can only be one need per port. If one already exists for `port`,
-    /// then it isn't re-inserted. In particular, the `fast` and `stable` flags aren't
-    /// updated. If the need has expired, though, the expiration date is updated.
-    /// This is in line with TorPS's `stream_update_port_needs` behavior.
-    pub fn add_need(&mut self, port: u16, now: &DateTime<Utc>, fast: bool, stable: bool) -> String {
-        match self.needs.entry(port) {
+    /// There can only be one need per port (and one internal need). If one
+    /// already exists for `target`, then it isn't re-inserted. In particular,
+    /// the `fast` and `stable` flags aren't updated. If the need has expired,
+    /// though, the expiration date is updated. This is in line with TorPS's
+    /// `stream_update_port_needs` behavior.
+    pub fn add_need(
+        &mut self,
+        target: NeedTarget,
+        now: &DateTime<Utc>,
+        fast: bool,
+        stable: bool,
+    ) -> String {
+        match self.needs.entry(target) {
             Occupied(mut entry) => {
                 let need = entry.get_mut();
                 if need.has_expired(now) {
@@ -103,11 +127,63 @@ impl NeedsContainer {
                 need.to_string()
             }
             Vacant(entry) => {
-                let need = Arc::new(Need::new(port, now, fast, stable));
+                let need = Arc::new(Need::new(target, now, fast, stable));
                 entry.insert(need).to_string()
             }
         }
     }
+
+    /// Get a handle for the need for `target`, whether it needs cover or not.
+    ///
+    /// This is only used when restoring the circuits that covered the need.
+    pub fn handle_for(&self, target: NeedTarget) -> Option<NeedHandle> {
+        self.needs.get(&target).map(NeedHandle::from_need)
+    }
+
+    /// Export the current needs, for checkpoints. How often they are covered
+    /// is not part of the state, but follows from the restored circuits.
+    pub fn to_state(&self) -> Vec<NeedState> {
+        let mut needs: Vec<_> = self
+            .needs
+            .values()
+            .map(|need| NeedState {
+                target: need.target,
+                expires: to_micros(&need.expires.read().unwrap()),
+                fast: need.fast,
+                stable: need.stable,
+            })
+            .collect();
+        needs.sort_unstable_by_key(|need| need.target);
+        needs
+    }
+
+    /// Continue with exported needs, none of which are covered yet
+    pub fn from_state(state: &[NeedState]) -> anyhow::Result<NeedsContainer> {
+        let mut needs = RHashMap::default();
+        for need in state {
+            needs.insert(
+                need.target,
+                Arc::new(Need {
+                    target: need.target,
+                    expires: RwLock::new(from_micros(need.expires)?),
+                    fast: need.fast,
+                    stable: need.stable,
+                    covered: RwLock::new(0),
+                }),
+            );
+        }
+
+        Ok(NeedsContainer { needs })
+    }
+}
+
+/// A port need, as stored in checkpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct NeedState {
+    target: NeedTarget,
+    expires: i64,
+    fast: bool,
+    stable: bool,
 }
 
 /// TODO
@@ -149,9 +225,9 @@ impl NeedHandle {
         }
     }
 
-    /// Get the needed port, if the need still exists.
-    pub fn get_port(&self) -> Option<u16> {
-        self.need.upgrade().map(|need| need.port)
+    /// Get what kind of circuit is needed, if the need still exists.
+    pub fn get_target(&self) -> Option<NeedTarget> {
+        self.need.upgrade().map(|need| need.target)
     }
 
     /// Get the needed fast flag, if the need still exists.
@@ -169,6 +245,7 @@ impl NeedHandle {
         &self,
         circuit: &ShallowCircuit,
         circgen: &CircuitGenerator,
+        exit_policies: &ExitPolicies,
     ) -> bool {
         match self.need.upgrade() {
             None => {
@@ -183,9 +260,29 @@ impl NeedHandle {
                     return false;
                 }
 
-                let exit = circgen.lookup_relay(&circuit.exit).unwrap();
-                if !(*exit).exit_policy.allows_port(need.port) {
-                    return false;
+                match need.target {
+                    NeedTarget::Port(port) => {
+                        if circuit.is_internal {
+                            return false;
+                        }
+                        let exit = circgen.lookup_relay(&circuit.exit).unwrap();
+                        if !(*exit).exit_policy.allows_port(port) {
+                            return false;
+                        }
+                    }
+                    NeedTarget::Ipv6Port(port) => {
+                        if circuit.is_internal {
+                            return false;
+                        }
+                        if !exit_policies.allows_ipv6_port(&circuit.exit, port) {
+                            return false;
+                        }
+                    }
+                    NeedTarget::Internal => {
+                        if !circuit.is_internal {
+                            return false;
+                        }
+                    }
                 }
 
                 return true;
@@ -219,7 +316,7 @@ impl Display for NeedHandle {
 
 #[derive(Debug)]
 struct Need {
-    port: u16,
+    target: NeedTarget,
     expires: RwLock<DateTime<Utc>>,
     fast: bool,
     stable: bool,
@@ -234,9 +331,9 @@ impl Display for Need {
 }
 
 impl Need {
-    fn new(port: u16, now: &DateTime<Utc>, fast: bool, stable: bool) -> Need {
+    fn new(target: NeedTarget, now: &DateTime<Utc>, fast: bool, stable: bool) -> Need {
         Need {
-            port,
+            target,
             expires: RwLock::new(*now + *PORT_NEED_LIFETIME),
             fast,
             stable,
@@ -265,7 +362,11 @@ impl Need {
 
     /// Returns `true` if the need is _not_ sufficiently covered by circuits at the moment
     fn needs_cover(&self) -> bool {
-        *(self.covered.read().unwrap()) < *PORT_NEED_COVER_NUM
+        let cover_num = match self.target {
+            NeedTarget::Port(_) | NeedTarget::Ipv6Port(_) => *PORT_NEED_COVER_NUM,
+            NeedTarget::Internal => *INTERNAL_NEED_COVER_NUM,
+        };
+        *(self.covered.read().unwrap()) < cover_num
     }
 
     /// TODO
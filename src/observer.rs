@@ -7,37 +7,88 @@
 //! which data is really useful and needed. Until then, it is a bit messy.
 
 use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
 
 use anyhow;
 use chrono::{DateTime, Utc};
 use fxhash::FxHashMap;
+use seeded_rand::RHashSet;
+use serde::{Deserialize, Serialize};
 use tor_circuit_generator::TorCircuit;
 use tordoc::{consensus::Flag, Consensus, Fingerprint};
 
 use crate::adversaries::Adversary;
 use crate::client;
-use crate::trace::{make_trace_entries, MemoryCsvWriter};
+use crate::compromise::{ClientCompromise, ClientCompromiseState, CompromiseAnalysis};
+use crate::trace::{make_trace_entries, MemoryCsvWriter, TraceIds};
+use crate::trials::TrialMetrics;
 use crate::user::Request;
+use crate::utils::parse_fingerprint;
 
 #[allow(unused_imports)]
 use log::{debug, info, trace, warn};
 
+/// Onion services are the sources of their messages in the trace. Their IDs
+/// start here so they never collide with the IDs of exits.
+const ONION_SERVICE_SOURCE_IDS: u64 = 1 << 32;
+
 pub(crate) struct SimulationObserver {
     circuit_events: Vec<CircuitUsedEvent>,
-    adversary: Adversary,
+    compromise_counts: CompromiseCounts,
+    isolation_counts: IsolationCounts,
+    exit_usage: ExitUsage,
+    build_counts: CircuitBuildCounts,
+    /// The learned circuit build timeouts (in milliseconds) of the clients
+    /// that have one
+    build_timeouts: Vec<f64>,
+    client_compromises: Vec<ClientCompromise>,
+    /// The streams of bridge users, by their transport
+    bridge_streams: Vec<BridgeStreams>,
+    adversary: Arc<Adversary>,
+}
+
+/// The message IDs (in the trace) of a bridge user's streams
+struct BridgeStreams {
+    client_id: u64,
+    transport: String,
+    message_ids: Vec<Range<u64>>,
 }
 
 impl SimulationObserver {
     /// Construct a new `SimulationObserver` from the finished `ClientObserver`s.
     pub(crate) fn from_clients(
         client_observers: impl IntoIterator<Item = ClientObserver>,
-        adversary: Adversary,
+        adversary: Arc<Adversary>,
     ) -> SimulationObserver {
+        let mut compromise_counts = CompromiseCounts::default();
+        let mut isolation_counts = IsolationCounts::default();
+        let mut exit_usage = ExitUsage::default();
+        let mut build_counts = CircuitBuildCounts::default();
+        let mut build_timeouts = Vec::new();
+        let mut client_compromises = Vec::new();
+        let mut bridge_streams = Vec::new();
+
         // merge the sorted event vectors into a single one
         use itertools::Itertools;
         let merged_iterator = client_observers
             .into_iter()
             .map(|mut co| {
+                compromise_counts.merge(&co.compromise_counts);
+                isolation_counts.merge(&co.isolation_counts);
+                exit_usage.merge(&co.exit_usage);
+                build_counts.merge(&co.build_counts);
+                build_timeouts.extend(co.build_timeout_ms);
+                client_compromises.push(std::mem::take(&mut co.compromise));
+                if let Some(transport) = co.bridge_transport.take() {
+                    bridge_streams.push(BridgeStreams {
+                        client_id: co.client_id,
+                        transport,
+                        message_ids: std::mem::take(&mut co.bridge_message_ids),
+                    });
+                }
                 co.events_circuit_used.sort_unstable();
                 co.events_circuit_used.into_iter()
             })
@@ -45,10 +96,55 @@ impl SimulationObserver {
 
         SimulationObserver {
             circuit_events: merged_iterator.collect(),
+            compromise_counts,
+            isolation_counts,
+            exit_usage,
+            build_counts,
+            build_timeouts,
+            client_compromises,
+            bridge_streams,
             adversary,
         }
     }
 
+    /// Write the message IDs of the bridge users' streams to a CSV file, so
+    /// their traffic can be told apart in the trace. Every stream has a row
+    /// with its first message ID and its number of messages, whose IDs are
+    /// consecutive.
+    ///
+    /// Nothing is written if there are no bridge users.
+    pub(crate) fn write_bridge_streams(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        if self.bridge_streams.is_empty() {
+            return Ok(());
+        }
+        info!(
+            "Writing streams of {} bridge users to {}",
+            self.bridge_streams.len(),
+            path.as_ref().display()
+        );
+
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record(["first_message_id", "messages", "client_id", "transport"])?;
+        for client in self.bridge_streams.iter() {
+            for message_ids in client.message_ids.iter() {
+                writer.write_record([
+                    message_ids.start.to_string(),
+                    (message_ids.end - message_ids.start).to_string(),
+                    client.client_id.to_string(),
+                    client.transport.clone(),
+                ])?;
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Evaluate the clients' compromise, relative to the simulation start
+    pub(crate) fn compromise_analysis(&self, start_time: &DateTime<Utc>) -> CompromiseAnalysis {
+        CompromiseAnalysis::new(start_time, self.client_compromises.iter())
+    }
+
     pub(crate) fn print(&self) {
         let format_with_adv = |fp: &Fingerprint| {
             format!(
@@ -68,10 +164,494 @@ impl SimulationObserver {
                 &circuit_event.time,
                 &circuit_event.client_id,
                 format_with_adv(&circuit_event.circuit.guard),
-                format_with_adv(&circuit_event.circuit.middle),
+                circuit_event
+                    .circuit
+                    .middle
+                    .iter()
+                    .map(format_with_adv)
+                    .collect::<Vec<_>>()
+                    .join(" "),
                 format_with_adv(&circuit_event.circuit.exit),
             );
         }
+
+        self.compromise_counts.print();
+        self.isolation_counts.print(&self.compromise_counts);
+        self.exit_usage.print();
+        self.build_counts.print(&self.build_timeouts);
+    }
+
+    /// The metrics of this run that are compared across trials
+    pub(crate) fn metrics(&self) -> TrialMetrics {
+        let mut metrics = self.compromise_counts.metrics();
+        metrics.extend(self.isolation_counts.metrics(&self.compromise_counts));
+        metrics.extend(self.exit_usage.metrics());
+        metrics.extend(self.build_counts.metrics(&self.build_timeouts));
+        metrics
+    }
+}
+
+/// The positions of a circuit that are controlled by the adversary.
+///
+/// Only exit circuits have an exit. The last hop of an internal circuit (the
+/// rendezvous point of streams to onion services) sees neither the
+/// destination nor the exit traffic, so it counts as a middle relay. Streams
+/// to onion services are thus never compromised at the exit or end-to-end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CompromisedPositions {
+    pub guard: bool,
+    pub middle: bool,
+    pub exit: bool,
+}
+
+impl CompromisedPositions {
+    /// Classify a circuit by the adversary's relays in it, including the
+    /// relays compromised by nickname in the current consensus
+    pub(crate) fn of(
+        circuit: &client::ShallowCircuit,
+        adversary: &Adversary,
+        compromised_by_nickname: &RHashSet<Fingerprint>,
+    ) -> Self {
+        let is_adversarial = |relay: &Fingerprint| {
+            adversary.is_adversarial(relay) || compromised_by_nickname.contains(relay)
+        };
+        let last_hop = is_adversarial(&circuit.exit);
+        CompromisedPositions {
+            guard: is_adversarial(&circuit.guard),
+            // internal circuits have more than one middle relay
+            middle: circuit.middle.iter().any(is_adversarial) || (circuit.is_internal && last_hop),
+            exit: !circuit.is_internal && last_hop,
+        }
+    }
+
+    /// All the possible combinations, in the order of their index
+    fn all() -> impl Iterator<Item = CompromisedPositions> {
+        (0..8).map(|i| CompromisedPositions {
+            guard: i & 0b100 != 0,
+            middle: i & 0b010 != 0,
+            exit: i & 0b001 != 0,
+        })
+    }
+
+    fn index(&self) -> usize {
+        (self.guard as usize) << 2 | (self.middle as usize) << 1 | (self.exit as usize)
+    }
+}
+
+impl fmt::Display for CompromisedPositions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let positions: Vec<_> = [
+            (self.guard, "guard"),
+            (self.middle, "middle"),
+            (self.exit, "exit"),
+        ]
+        .into_iter()
+        .filter(|(compromised, _)| *compromised)
+        .map(|(_, name)| name)
+        .collect();
+
+        if positions.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", positions.join("+"))
+        }
+    }
+}
+
+/// Number of used circuits (and the streams they carried) per combination
+/// of adversarial positions
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct CompromiseCounts {
+    circuits: [u64; 8],
+    streams: [u64; 8],
+}
+
+impl CompromiseCounts {
+    fn add(&mut self, positions: CompromisedPositions, first_use: bool) {
+        if first_use {
+            self.circuits[positions.index()] += 1;
+        }
+        self.streams[positions.index()] += 1;
+    }
+
+    fn merge(&mut self, other: &CompromiseCounts) {
+        for i in 0..8 {
+            self.circuits[i] += other.circuits[i];
+            self.streams[i] += other.streams[i];
+        }
+    }
+
+    fn print(&self) {
+        let total_circuits: u64 = self.circuits.iter().sum();
+        let total_streams: u64 = self.streams.iter().sum();
+        let percent = |x: u64, total: u64| {
+            if total == 0 {
+                0.0
+            } else {
+                x as f64 / total as f64 * 100.0
+            }
+        };
+
+        println!("Used circuits by adversary-controlled positions:");
+        println!(
+            "{:<20} {:>12} {:>8} {:>12} {:>8}",
+            "positions", "circuits", "%", "streams", "%"
+        );
+        for positions in CompromisedPositions::all() {
+            let i = positions.index();
+            println!(
+                "{:<20} {:>12} {:>8.3} {:>12} {:>8.3}",
+                positions.to_string(),
+                self.circuits[i],
+                percent(self.circuits[i], total_circuits),
+                self.streams[i],
+                percent(self.streams[i], total_streams),
+            );
+        }
+    }
+
+    /// The fractions of used circuits and streams per combination of
+    /// adversarial positions
+    fn metrics(&self) -> TrialMetrics {
+        let total_circuits: u64 = self.circuits.iter().sum();
+        let total_streams: u64 = self.streams.iter().sum();
+        let fraction = |x: u64, total: u64| {
+            if total == 0 {
+                0.0
+            } else {
+                x as f64 / total as f64
+            }
+        };
+
+        let mut metrics = Vec::new();
+        for positions in CompromisedPositions::all() {
+            let i = positions.index();
+            metrics.push((
+                format!("circuit_fraction_{}", positions),
+                fraction(self.circuits[i], total_circuits),
+            ));
+            metrics.push((
+                format!("stream_fraction_{}", positions),
+                fraction(self.streams[i], total_streams),
+            ));
+        }
+        metrics
+    }
+}
+
+/// How stream isolation changed the circuits that streams used. Without
+/// isolation, a stream that was isolated would have used the dirty circuit
+/// it was isolated from.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct IsolationCounts {
+    /// Streams that were kept from using a dirty circuit by isolation
+    isolated_streams: u64,
+    /// Circuits that were only put into use because of isolation
+    extra_circuits: u64,
+    /// All streams, by the adversary-controlled positions of the circuit they
+    /// would have used without isolation
+    streams_without_isolation: [u64; 8],
+}
+
+impl IsolationCounts {
+    fn add(
+        &mut self,
+        positions: CompromisedPositions,
+        first_use: bool,
+        isolated_from: Option<CompromisedPositions>,
+    ) {
+        if isolated_from.is_some() {
+            self.isolated_streams += 1;
+            if first_use {
+                self.extra_circuits += 1;
+            }
+        }
+        self.streams_without_isolation[isolated_from.unwrap_or(positions).index()] += 1;
+    }
+
+    fn merge(&mut self, other: &IsolationCounts) {
+        self.isolated_streams += other.isolated_streams;
+        self.extra_circuits += other.extra_circuits;
+        for i in 0..8 {
+            self.streams_without_isolation[i] += other.streams_without_isolation[i];
+        }
+    }
+
+    fn print(&self, compromise_counts: &CompromiseCounts) {
+        let total_circuits: u64 = compromise_counts.circuits.iter().sum();
+        let total_streams: u64 = compromise_counts.streams.iter().sum();
+        println!(
+            "Stream isolation: {} of {} streams isolated, {} of {} used circuits because of isolation",
+            self.isolated_streams, total_streams, self.extra_circuits, total_circuits,
+        );
+        if self.isolated_streams == 0 {
+            return;
+        }
+
+        println!("Streams by adversary-controlled positions, with and without isolation:");
+        println!(
+            "{:<20} {:>12} {:>12}",
+            "positions", "isolated", "unisolated"
+        );
+        for positions in CompromisedPositions::all() {
+            let i = positions.index();
+            println!(
+                "{:<20} {:>12} {:>12}",
+                positions.to_string(),
+                compromise_counts.streams[i],
+                self.streams_without_isolation[i],
+            );
+        }
+    }
+
+    /// The fraction of isolated streams, the used circuits per stream with
+    /// and without isolation, and the fractions of streams per combination of
+    /// adversarial positions without isolation
+    fn metrics(&self, compromise_counts: &CompromiseCounts) -> TrialMetrics {
+        let total_circuits: u64 = compromise_counts.circuits.iter().sum();
+        let total_streams: u64 = compromise_counts.streams.iter().sum();
+        let fraction = |x: u64, total: u64| {
+            if total == 0 {
+                0.0
+            } else {
+                x as f64 / total as f64
+            }
+        };
+
+        let mut metrics = vec![
+            (
+                "isolated_stream_fraction".to_string(),
+                fraction(self.isolated_streams, total_streams),
+            ),
+            (
+                "circuits_per_stream".to_string(),
+                fraction(total_circuits, total_streams),
+            ),
+            (
+                "circuits_per_stream_without_isolation".to_string(),
+                fraction(total_circuits - self.extra_circuits, total_streams),
+            ),
+        ];
+        for positions in CompromisedPositions::all() {
+            metrics.push((
+                format!("stream_fraction_{}_without_isolation", positions),
+                fraction(
+                    self.streams_without_isolation[positions.index()],
+                    total_streams,
+                ),
+            ));
+        }
+        metrics
+    }
+}
+
+/// Number of streams through each exit (by ID), separately for IPv4 and IPv6
+/// destinations. Far fewer exits allow IPv6, so IPv6 streams concentrate on
+/// fewer of them. Streams without a destination count as IPv4.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct ExitUsage {
+    ipv4: FxHashMap<u64, u64>,
+    ipv6: FxHashMap<u64, u64>,
+    /// Streams through adversarial exits, for IPv4 and IPv6
+    adversarial: [u64; 2],
+}
+
+impl ExitUsage {
+    fn add(&mut self, exit_id: u64, ipv6: bool, adversarial: bool) {
+        let streams = if ipv6 { &mut self.ipv6 } else { &mut self.ipv4 };
+        *streams.entry(exit_id).or_default() += 1;
+        if adversarial {
+            self.adversarial[ipv6 as usize] += 1;
+        }
+    }
+
+    fn merge(&mut self, other: &ExitUsage) {
+        for (exit_id, count) in other.ipv4.iter() {
+            *self.ipv4.entry(*exit_id).or_default() += count;
+        }
+        for (exit_id, count) in other.ipv6.iter() {
+            *self.ipv6.entry(*exit_id).or_default() += count;
+        }
+        self.adversarial[0] += other.adversarial[0];
+        self.adversarial[1] += other.adversarial[1];
+    }
+
+    /// The streams of an address family, the exits they used, their
+    /// concentration on these exits (Herfindahl-Hirschman index: the sum of
+    /// the squared shares of the exits), and the fraction of them through
+    /// adversarial exits
+    fn summary(streams: &FxHashMap<u64, u64>, adversarial: u64) -> (u64, usize, f64, f64) {
+        let total: u64 = streams.values().sum();
+        if total == 0 {
+            return (0, 0, 0.0, 0.0);
+        }
+        let concentration = streams
+            .values()
+            .map(|count| (*count as f64 / total as f64).powi(2))
+            .sum();
+        (
+            total,
+            streams.len(),
+            concentration,
+            adversarial as f64 / total as f64,
+        )
+    }
+
+    fn print(&self) {
+        println!("Exit usage by address family of the destination:");
+        println!(
+            "{:<8} {:>12} {:>8} {:>14} {:>14}",
+            "family", "streams", "exits", "concentration", "adversarial %"
+        );
+        for (name, streams, adversarial) in [
+            ("IPv4", &self.ipv4, self.adversarial[0]),
+            ("IPv6", &self.ipv6, self.adversarial[1]),
+        ] {
+            let (total, exits, concentration, adversarial) = Self::summary(streams, adversarial);
+            println!(
+                "{:<8} {:>12} {:>8} {:>14.4} {:>14.3}",
+                name,
+                total,
+                exits,
+                concentration,
+                adversarial * 100.0
+            );
+        }
+    }
+
+    /// The fraction of IPv6 streams, and per address family the number of
+    /// used exits, the streams' concentration on them and the fraction of
+    /// streams through adversarial exits
+    fn metrics(&self) -> TrialMetrics {
+        let (ipv4_streams, ..) = Self::summary(&self.ipv4, self.adversarial[0]);
+        let (ipv6_streams, ..) = Self::summary(&self.ipv6, self.adversarial[1]);
+        let total_streams = ipv4_streams + ipv6_streams;
+        let mut metrics = vec![(
+            "ipv6_stream_fraction".to_string(),
+            if total_streams == 0 {
+                0.0
+            } else {
+                ipv6_streams as f64 / total_streams as f64
+            },
+        )];
+        for (name, streams, adversarial) in [
+            ("ipv4", &self.ipv4, self.adversarial[0]),
+            ("ipv6", &self.ipv6, self.adversarial[1]),
+        ] {
+            let (_, exits, concentration, adversarial) = Self::summary(streams, adversarial);
+            metrics.push((format!("exits_used_{}", name), exits as f64));
+            metrics.push((format!("exit_concentration_{}", name), concentration));
+            metrics.push((
+                format!("stream_fraction_adversarial_exit_{}", name),
+                adversarial,
+            ));
+        }
+        metrics
+    }
+}
+
+/// Why an attempt to build a circuit failed
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub(crate) enum CircuitFailureReason {
+    /// Extending the circuit to the next relay failed
+    ExtendFailed,
+    /// The circuit was not completed within the circuit build timeout
+    Timeout,
+}
+
+/// Number of attempts to build a circuit, by their outcome
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct CircuitBuildCounts {
+    built: u64,
+    extend_failed: u64,
+    timed_out: u64,
+    /// Failed attempts (of both kinds) that did not complete the first hop
+    first_hop_failed: u64,
+    /// Attempts that were not made because no suitable path was found
+    /// (e.g. no usable vanguard)
+    no_path: u64,
+    /// Circuits that could not be built, even after retrying
+    given_up: u64,
+}
+
+impl CircuitBuildCounts {
+    fn merge(&mut self, other: &CircuitBuildCounts) {
+        self.built += other.built;
+        self.extend_failed += other.extend_failed;
+        self.timed_out += other.timed_out;
+        self.first_hop_failed += other.first_hop_failed;
+        self.no_path += other.no_path;
+        self.given_up += other.given_up;
+    }
+
+    fn attempts(&self) -> u64 {
+        self.built + self.extend_failed + self.timed_out
+    }
+
+    fn print(&self, build_timeouts: &[f64]) {
+        println!(
+            "Circuit build attempts: {} built, {} failed to extend, {} timed out \
+            ({} at the first hop), {} without a path, {} circuits given up",
+            self.built,
+            self.extend_failed,
+            self.timed_out,
+            self.first_hop_failed,
+            self.no_path,
+            self.given_up,
+        );
+        if let Some(mean) = mean(build_timeouts) {
+            println!(
+                "Mean circuit build timeout: {:.0} ms ({} clients)",
+                mean,
+                build_timeouts.len()
+            );
+        }
+    }
+
+    /// The fractions of failed build attempts, and the mean learned timeout
+    fn metrics(&self, build_timeouts: &[f64]) -> TrialMetrics {
+        let fraction = |x: u64, total: u64| {
+            if total == 0 {
+                0.0
+            } else {
+                x as f64 / total as f64
+            }
+        };
+
+        let mut metrics = vec![
+            (
+                "circuit_build_failure_fraction".to_string(),
+                fraction(self.extend_failed + self.timed_out, self.attempts()),
+            ),
+            (
+                "circuit_build_timeout_fraction".to_string(),
+                fraction(self.timed_out, self.attempts()),
+            ),
+            (
+                "circuit_given_up_fraction".to_string(),
+                fraction(self.given_up, self.built + self.given_up),
+            ),
+        ];
+        if let Some(mean) = mean(build_timeouts) {
+            metrics.push(("circuit_build_timeout_mean_ms".to_string(), mean));
+        }
+        metrics
+    }
+}
+
+fn format_middle(middle: &[Fingerprint]) -> String {
+    middle
+        .iter()
+        .map(|relay| relay.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.iter().sum::<f64>() / values.len() as f64)
     }
 }
 
@@ -113,11 +693,12 @@ impl Eq for NewCircuitEvent {}
 #[allow(unused)]
 struct ShallowCircuitSnapshot {
     pub guard: Fingerprint,
-    pub middle: Fingerprint,
+    pub middle: Vec<Fingerprint>,
     pub exit: Fingerprint,
     time: DateTime<Utc>,
     dirty_time: Option<DateTime<Utc>>,
     is_internal: bool,
+    onion_service: Option<u64>,
     is_stable: bool,
     is_fast: bool,
     covered_needs: Vec<String>,
@@ -132,6 +713,7 @@ impl From<&client::ShallowCircuit> for ShallowCircuitSnapshot {
             time: circuit.time.clone(),
             dirty_time: circuit.dirty_time.clone(),
             is_internal: circuit.is_internal.clone(),
+            onion_service: circuit.onion_service,
             is_stable: circuit.is_stable.clone(),
             is_fast: circuit.is_fast.clone(),
             covered_needs: circuit
@@ -186,6 +768,8 @@ pub(crate) enum CircuitCloseReason {
     OldDirty,
     OldClean,
     Down,
+    /// The introduction to an onion service is done
+    Introduced,
 }
 
 impl Ord for CircuitClosedEvent {
@@ -214,6 +798,22 @@ impl Eq for CircuitClosedEvent {}
 /// An observer object used by a single client to collect their events (locally).
 pub(crate) struct ClientObserver {
     client_id: u64,
+    adversary: Arc<Adversary>,
+    /// The relays the adversary controls by nickname in the current consensus
+    compromised_by_nickname: Arc<RHashSet<Fingerprint>>,
+    compromise_counts: CompromiseCounts,
+    isolation_counts: IsolationCounts,
+    exit_usage: ExitUsage,
+    compromise: ClientCompromise,
+    build_counts: CircuitBuildCounts,
+    /// The circuit build timeout this client learned, in milliseconds
+    build_timeout_ms: Option<f64>,
+    /// The IDs of this client's streams and messages in the trace
+    trace_ids: TraceIds,
+    /// For bridge users, the pluggable transport of their (first) bridge
+    bridge_transport: Option<String>,
+    /// For bridge users, the message IDs of their streams in the trace
+    bridge_message_ids: Vec<Range<u64>>,
     #[allow(unused)]
     events_new_circuit: Vec<NewCircuitEvent>,
     events_circuit_used: Vec<CircuitUsedEvent>,
@@ -223,32 +823,86 @@ pub(crate) struct ClientObserver {
 
 impl ClientObserver {
     /// Create a new `ClientObserver` with no events.
-    pub(crate) fn new(client_id: u64) -> ClientObserver {
+    pub(crate) fn new(
+        client_id: u64,
+        adversary: Arc<Adversary>,
+        bridge_transport: Option<String>,
+    ) -> ClientObserver {
         ClientObserver {
             client_id,
+            adversary,
+            compromised_by_nickname: Arc::new(RHashSet::default()),
+            compromise_counts: CompromiseCounts::default(),
+            isolation_counts: IsolationCounts::default(),
+            exit_usage: ExitUsage::default(),
+            compromise: ClientCompromise::default(),
+            build_counts: CircuitBuildCounts::default(),
+            build_timeout_ms: None,
+            trace_ids: TraceIds::new(client_id),
+            bridge_transport,
+            bridge_message_ids: Vec::new(),
             events_new_circuit: Vec::new(),
             events_circuit_used: Vec::new(),
             events_circuit_closed: Vec::new(),
         }
     }
 
+    /// Export what the observer collected so far, for checkpoints.
+    ///
+    /// The event lists are not part of the state as they are not collected
+    /// at the moment.
+    pub(crate) fn to_state(&self) -> ClientObserverState {
+        ClientObserverState {
+            compromise_counts: self.compromise_counts.clone(),
+            isolation_counts: self.isolation_counts.clone(),
+            exit_usage: self.exit_usage.clone(),
+            compromise: self.compromise.to_state(),
+            build_counts: self.build_counts.clone(),
+            build_timeout_ms: self.build_timeout_ms,
+            trace_ids: self.trace_ids.clone(),
+            bridge_transport: self.bridge_transport.clone(),
+            bridge_message_ids: self.bridge_message_ids.clone(),
+        }
+    }
+
+    /// Continue with what an earlier observer collected
+    pub(crate) fn from_state(
+        client_id: u64,
+        adversary: Arc<Adversary>,
+        state: &ClientObserverState,
+    ) -> anyhow::Result<ClientObserver> {
+        let mut observer =
+            ClientObserver::new(client_id, adversary, state.bridge_transport.clone());
+        observer.compromise_counts = state.compromise_counts.clone();
+        observer.isolation_counts = state.isolation_counts.clone();
+        observer.exit_usage = state.exit_usage.clone();
+        observer.compromise = ClientCompromise::from_state(&state.compromise)?;
+        observer.build_counts = state.build_counts.clone();
+        observer.build_timeout_ms = state.build_timeout_ms;
+        observer.trace_ids = state.trace_ids.clone();
+        observer.bridge_message_ids = state.bridge_message_ids.clone();
+        Ok(observer)
+    }
+
     /// Notify the observer that a new circuit was created
     pub(crate) fn notify_new_circuit(
         &mut self,
         time: DateTime<Utc>,
-        circuit: &TorCircuit,
+        circuit: &client::ShallowCircuit,
         _port: u16,
         reason: String,
     ) {
         trace!(
-            "[{}] Client {} built circuit: {} {} {} [reason: {}]",
+            "[{}] Client {} built circuit: {}{} {} {} [reason: {}]",
             &time,
             self.client_id,
-            circuit.guard.fingerprint,
-            circuit.middle[0].fingerprint,
-            circuit.exit.fingerprint,
+            circuit.guard,
+            if circuit.via_bridge { " (bridge)" } else { "" },
+            format_middle(&circuit.middle),
+            circuit.exit,
             reason,
         );
+        self.build_counts.built += 1;
 
         // self.events_new_circuit.push(NewCircuitEvent {
         //     time,
@@ -258,39 +912,89 @@ impl ClientObserver {
         // });
     }
 
-    /// Notify the observer that a circuit was used to carry a new stream
+    /// Update the relays the adversary controls by nickname, when entering a
+    /// new epoch
+    pub(crate) fn set_compromised_by_nickname(
+        &mut self,
+        compromised_by_nickname: Arc<RHashSet<Fingerprint>>,
+    ) {
+        self.compromised_by_nickname = compromised_by_nickname;
+    }
+
+    /// The positions of a circuit that are controlled by the adversary
+    pub(crate) fn compromised_positions(
+        &self,
+        circuit: &client::ShallowCircuit,
+    ) -> CompromisedPositions {
+        CompromisedPositions::of(circuit, &self.adversary, &self.compromised_by_nickname)
+    }
+
+    /// Notify the observer that a circuit was used to carry a new stream.
+    ///
+    /// `first_use` tells whether this is the first stream on this circuit.
+    /// `isolated_from` gives the positions of the dirty circuit the stream
+    /// would have used if it was not isolated from it.
     pub(crate) fn notify_circuit_used(
         &mut self,
         circuit: &client::ShallowCircuit,
+        first_use: bool,
+        isolated_from: Option<CompromisedPositions>,
         request: &Request,
         timestamps: Vec<DateTime<Utc>>,
         csv_writer: &mut MemoryCsvWriter,
         exit_ids: &ExitFingerprintSerializer,
     ) -> anyhow::Result<()> {
         trace!(
-            "[{}] Client {} uses the following circuit for a stream request: {} {} {}",
+            "[{}] Client {} uses the following circuit for a stream request: {}{} {} {}",
             &request.time,
             self.client_id,
             circuit.guard,
-            circuit.middle,
+            if circuit.via_bridge { " (bridge)" } else { "" },
+            format_middle(&circuit.middle),
             circuit.exit,
         );
 
+        let positions = self.compromised_positions(circuit);
+        self.compromise_counts.add(positions, first_use);
+        self.isolation_counts
+            .add(positions, first_use, isolated_from);
+        self.compromise.record_stream(&request.time, positions);
+
         // self.events_circuit_used.push(CircuitUsedEvent {
         //     time: request.time.clone(),
         //     client_id: self.client_id,
         //     circuit: circuit.into(),
         //     request: request.clone(),
         // });
-        let exit_id = exit_ids.get(&circuit.exit).expect(
-            format!(
-                "Observer got an exit fingerprint that has no ID assigned: {}",
-                &circuit.exit
-            )
-            .as_str(),
-        );
+        // the messages of onion services do not come from an exit
+        let source_id = match circuit.onion_service {
+            Some(service) => ONION_SERVICE_SOURCE_IDS + service,
+            None => {
+                let exit_id = exit_ids.get(&circuit.exit).expect(
+                    format!(
+                        "Observer got an exit fingerprint that has no ID assigned: {}",
+                        &circuit.exit
+                    )
+                    .as_str(),
+                );
+                let ipv6 = request
+                    .destination
+                    .is_some_and(|destination| destination.address.is_ipv6());
+                self.exit_usage.add(exit_id, ipv6, positions.exit);
+                exit_id
+            }
+        };
 
-        csv_writer.write_entries(make_trace_entries(timestamps, exit_id))?;
+        let (message_ids, entries) = make_trace_entries(
+            timestamps,
+            source_id,
+            request.destination.map(|destination| destination.id),
+            &mut self.trace_ids,
+        )?;
+        if self.bridge_transport.is_some() {
+            self.bridge_message_ids.push(message_ids);
+        }
+        csv_writer.write_entries(entries)?;
 
         Ok(())
     }
@@ -308,7 +1012,7 @@ impl ClientObserver {
             self.client_id,
             reason,
             circuit.guard,
-            circuit.middle,
+            format_middle(&circuit.middle),
             circuit.exit,
         );
 
@@ -320,6 +1024,66 @@ impl ClientObserver {
         // });
     }
 
+    /// Notify the observer that an attempt to build a circuit failed at the
+    /// given hop (0 is the first hop)
+    pub(crate) fn notify_circuit_failed(
+        &mut self,
+        time: &DateTime<Utc>,
+        relay: &Fingerprint,
+        hop: usize,
+        reason: CircuitFailureReason,
+    ) {
+        trace!(
+            "[{}] Client {}: Circuit failed at hop {} ({}) because of \"{:?}\".",
+            &time,
+            self.client_id,
+            hop,
+            relay,
+            reason,
+        );
+
+        match reason {
+            CircuitFailureReason::ExtendFailed => self.build_counts.extend_failed += 1,
+            CircuitFailureReason::Timeout => self.build_counts.timed_out += 1,
+        }
+        if hop == 0 {
+            self.build_counts.first_hop_failed += 1;
+        }
+    }
+
+    /// Notify the observer that an attempt to build a circuit was abandoned
+    /// because no suitable path was found
+    pub(crate) fn notify_no_path(&mut self, time: &DateTime<Utc>) {
+        trace!(
+            "[{}] Client {}: Found no suitable path for a circuit.",
+            &time,
+            self.client_id,
+        );
+        self.build_counts.no_path += 1;
+    }
+
+    /// Notify the observer that a circuit could not be built in any attempt
+    pub(crate) fn notify_circuit_given_up(&mut self, time: &DateTime<Utc>, attempts: usize) {
+        trace!(
+            "[{}] Client {}: Giving up on building a circuit after {} attempts.",
+            &time,
+            self.client_id,
+            attempts,
+        );
+        self.build_counts.given_up += 1;
+    }
+
+    /// Notify the observer that the client learned a new circuit build timeout
+    pub(crate) fn notify_build_timeout(&mut self, time: &DateTime<Utc>, timeout_ms: f64) {
+        trace!(
+            "[{}] Client {}: Set circuit build timeout to {:.0} ms.",
+            &time,
+            self.client_id,
+            timeout_ms,
+        );
+        self.build_timeout_ms = Some(timeout_ms);
+    }
+
     pub(crate) fn notify_new_need(&mut self, time: &DateTime<Utc>, need: String) {
         trace!("[{}] Client {}: new {}.", &time, self.client_id, need);
     }
@@ -345,6 +1109,63 @@ impl ClientObserver {
             fp,
         );
     }
+
+    pub(crate) fn notify_guard_unreachable(&mut self, time: &DateTime<Utc>, fp: &Fingerprint) {
+        trace!(
+            "[{}] Client {}: Could not connect to guard {}, marking it as unreachable.",
+            &time,
+            self.client_id,
+            fp,
+        );
+    }
+
+    pub(crate) fn notify_bridge_unreachable(&mut self, time: &DateTime<Utc>, fp: &Fingerprint) {
+        trace!(
+            "[{}] Client {}: Could not connect to bridge {}, marking it as unreachable.",
+            &time,
+            self.client_id,
+            fp,
+        );
+    }
+
+    pub(crate) fn notify_vanguard_removed(
+        &mut self,
+        time: &DateTime<Utc>,
+        layer: u8,
+        fp: &Fingerprint,
+    ) {
+        trace!(
+            "[{}] Client {}: Rotated layer-{} guard {}.",
+            &time,
+            self.client_id,
+            layer,
+            fp,
+        );
+    }
+
+    pub(crate) fn notify_request_failed(&mut self, request: &Request, reason: &str) {
+        trace!(
+            "[{}] Client {}: Failed to handle stream request to port {}: {}.",
+            &request.time,
+            self.client_id,
+            request.port,
+            reason,
+        );
+    }
+}
+
+/// What a `ClientObserver` collected, as stored in checkpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ClientObserverState {
+    compromise_counts: CompromiseCounts,
+    isolation_counts: IsolationCounts,
+    exit_usage: ExitUsage,
+    compromise: ClientCompromiseState,
+    build_counts: CircuitBuildCounts,
+    build_timeout_ms: Option<f64>,
+    trace_ids: TraceIds,
+    bridge_transport: Option<String>,
+    bridge_message_ids: Vec<Range<u64>>,
 }
 
 /// A helper struct to assemble a mapping from exit relay fingerprints to plain
@@ -381,4 +1202,38 @@ impl ExitFingerprintSerializer {
     pub(crate) fn get(&self, fingerprint: &Fingerprint) -> Option<u64> {
         self.assigned_ids.get(fingerprint).copied()
     }
+
+    /// Export the assigned IDs, so a resumed simulation keeps using them
+    pub(crate) fn to_state(&self) -> ExitIdsState {
+        let mut ids: Vec<_> = self
+            .assigned_ids
+            .iter()
+            .map(|(fingerprint, id)| (fingerprint.to_string(), *id))
+            .collect();
+        ids.sort_unstable_by_key(|(_, id)| *id);
+
+        ExitIdsState {
+            ids,
+            next_id: self.next_id,
+        }
+    }
+
+    pub(crate) fn from_state(state: &ExitIdsState) -> anyhow::Result<ExitFingerprintSerializer> {
+        let mut assigned_ids = FxHashMap::default();
+        for (fingerprint, id) in state.ids.iter() {
+            assigned_ids.insert(parse_fingerprint(fingerprint)?, *id);
+        }
+
+        Ok(ExitFingerprintSerializer {
+            assigned_ids,
+            next_id: state.next_id,
+        })
+    }
+}
+
+/// The IDs assigned to exit relays, as stored in checkpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ExitIdsState {
+    ids: Vec<(String, u64)>,
+    next_id: u64,
 }
@@ -2,12 +2,12 @@ use super::parse::StreamEdge;
 use super::parse::StreamEdgeEmission;
 use super::parse::StreamNode;
 use super::parse::StreamPacketModel;
+use crate::rng::get_rng;
 
 use chrono::{DateTime, Duration, Utc};
 use core::panic;
 use rand::distributions::WeightedIndex;
 use rand_distr::{Distribution, Exp, LogNormal};
-use seeded_rand::get_rng;
 use seeded_rand::RHashMap as HashMap;
 use std::fmt;
 use std::fmt::Display;
@@ -11,6 +11,9 @@ use std::sync::Arc;
 
 use anyhow;
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{from_micros, to_micros};
 
 /// A model to generate a sequence of packets that are exchanged once a client
 /// starts a request through the network. This currently only generates traffic
@@ -91,6 +94,25 @@ impl FlowOfStreams {
     pub fn advance_to(&mut self, new_time: DateTime<Utc>) {
         self.chain.advance_to(new_time)
     }
+
+    /// Export the progress of this flow, for checkpoints
+    pub fn to_state(&self) -> FlowState {
+        FlowState {
+            current_state: self.chain.current_state.clone(),
+            current_time: to_micros(&self.chain.current_time),
+            stopped: self.chain.stopped,
+            not_after: to_micros(&self.not_after),
+        }
+    }
+}
+
+/// The progress of a `FlowOfStreams` within the stream model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FlowState {
+    current_state: String,
+    current_time: i64,
+    stopped: bool,
+    not_after: i64,
 }
 
 impl Iterator for FlowOfStreams {
@@ -138,4 +160,22 @@ impl StreamModelParameters {
             not_after,
         }
     }
+
+    /// Continue a flow from its exported state
+    pub fn restore_flow(&self, state: &FlowState) -> anyhow::Result<FlowOfStreams> {
+        let mut flow = self.make_flow(
+            from_micros(state.current_time)?,
+            from_micros(state.not_after)?,
+        );
+        if !flow.chain.states.contains_key(&state.current_state) {
+            anyhow::bail!(
+                "The stream model has no state {}. Was the checkpoint made with a different model?",
+                state.current_state
+            );
+        }
+        flow.chain.current_state = state.current_state.clone();
+        flow.chain.stopped = state.stopped;
+
+        Ok(flow)
+    }
 }
@@ -0,0 +1,167 @@
+//! Background loading of upcoming simulation epochs
+//!
+//! Parsing a consensus, combining it with its descriptors and constructing the
+//! `CircuitGenerator` takes considerable time. A loader thread therefore
+//! prepares the next epochs while the clients are busy with the current one.
+//!
+//! If several adversaries are simulated at once (in a sweep), every consensus
+//! is loaded only once and then modified separately for each adversary.
+
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::{self, Context};
+use crossbeam::channel::Receiver;
+#[allow(unused_imports)]
+use log::{debug, info, trace, warn};
+use seeded_rand::RHashSet;
+use tor_circuit_generator::CircuitGenerator;
+use tordoc::{Consensus, Descriptor, Fingerprint};
+
+use crate::adversaries::Adversary;
+use crate::config::PrefetchConfig;
+use crate::destinations::ExitPolicies;
+use crate::input::ConsensusHandle;
+use crate::utils::resident_memory;
+
+/// A consensus that is ready to be simulated
+pub(crate) struct PreparedEpoch {
+    /// The network as seen with each of the adversaries, in the order the
+    /// adversaries were given
+    pub networks: Vec<PreparedNetwork>,
+}
+
+/// A consensus, including the modifications of one adversary
+pub(crate) struct PreparedNetwork {
+    pub consensus: Consensus,
+    pub circgen: CircuitGenerator,
+    /// The full exit policies from the descriptors
+    pub exit_policies: Arc<ExitPolicies>,
+    /// The relays in this consensus that the adversary controls because of
+    /// their nickname
+    pub compromised_by_nickname: Arc<RHashSet<Fingerprint>>,
+}
+
+impl PreparedEpoch {
+    /// The consensus of the first adversary. Its validity times are the same
+    /// for all adversaries.
+    pub(crate) fn consensus(&self) -> &Consensus {
+        &self.networks[0].consensus
+    }
+}
+
+/// Iterator over the prepared epochs, which are loaded by a background thread
+pub(crate) struct EpochLoader {
+    receiver: Receiver<anyhow::Result<PreparedEpoch>>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl EpochLoader {
+    /// Start loading the given consensuses in the background.
+    ///
+    /// Up to `config.depth` epochs are prepared in advance. While the process
+    /// uses more than `config.max_memory_mb` of memory, no further epochs are
+    /// prepared unless none is waiting to be used.
+    pub(crate) fn spawn(
+        handles: Vec<ConsensusHandle>,
+        adversaries: Vec<Arc<Adversary>>,
+        config: &PrefetchConfig,
+    ) -> EpochLoader {
+        assert!(!adversaries.is_empty());
+
+        let (sender, receiver) = crossbeam::channel::bounded(config.depth);
+        let max_memory = config.max_memory_mb.map(|mb| mb * 1024 * 1024);
+
+        let join_handle = std::thread::spawn(move || {
+            for handle in handles {
+                // respect the memory bound
+                if let Some(max_memory) = max_memory {
+                    while !sender.is_empty()
+                        && resident_memory().map_or(false, |used| used > max_memory)
+                    {
+                        std::thread::sleep(Duration::from_millis(100));
+                    }
+                }
+
+                let epoch = prepare_epoch(handle, &adversaries);
+                let failed = epoch.is_err();
+
+                // stop if the simulation is not interested anymore
+                if sender.send(epoch).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        EpochLoader {
+            receiver,
+            join_handle: Some(join_handle),
+        }
+    }
+}
+
+impl Iterator for EpochLoader {
+    type Item = anyhow::Result<PreparedEpoch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.receiver.recv() {
+            Ok(epoch) => Some(epoch),
+            Err(_) => {
+                // the loader has finished
+                if let Some(join_handle) = self.join_handle.take() {
+                    join_handle.join().unwrap();
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Load a consensus and construct the circuit generator for it, for each
+/// of the adversaries
+fn prepare_epoch(
+    handle: ConsensusHandle,
+    adversaries: &[Arc<Adversary>],
+) -> anyhow::Result<PreparedEpoch> {
+    let (consensus, descriptors) = handle.load()?;
+
+    // the last adversary can take the original instead of a copy
+    let (last, others) = adversaries.split_last().unwrap(); // checked when spawning
+    let mut networks = Vec::with_capacity(adversaries.len());
+    for adversary in others {
+        networks.push(prepare_network(
+            consensus.clone(),
+            descriptors.clone(),
+            adversary,
+        )?);
+    }
+    networks.push(prepare_network(consensus, descriptors, last)?);
+    trace!("Prepared epoch {:?}", networks[0].consensus.valid_after);
+
+    Ok(PreparedEpoch { networks })
+}
+
+/// Apply the adversary's modifications to a consensus and construct the
+/// circuit generator for it
+fn prepare_network(
+    mut consensus: Consensus,
+    mut descriptors: Vec<Descriptor>,
+    adversary: &Adversary,
+) -> anyhow::Result<PreparedNetwork> {
+    // Apply adversarial changes
+    let compromised_by_nickname = Arc::new(adversary.compromised_by_nickname(&consensus));
+    adversary.modify_consensus(&mut consensus, &mut descriptors);
+
+    let exit_policies = Arc::new(ExitPolicies::new(&consensus, &descriptors));
+    let circgen = CircuitGenerator::new(&consensus, descriptors, vec![443, 80, 22])
+        .map_err(|e| anyhow::anyhow!(e))
+        .context("Failed to construct circuit generator")?;
+
+    Ok(PreparedNetwork {
+        consensus,
+        circgen,
+        exit_policies,
+        compromised_by_nickname,
+    })
+}
@@ -0,0 +1,103 @@
+//! Model of failing connections from clients to relays
+//!
+//! Connections to a relay can fail at random, with a probability that is
+//! either global, specific to the relay, or specific to the client (e.g.
+//! clients on unreliable networks). Additionally, relays can be completely
+//! unreachable during configured outage windows, even if they are listed
+//! in the consensus.
+
+use chrono::{DateTime, Utc};
+use fxhash::FxHashMap;
+use rand::Rng;
+use tordoc::Fingerprint;
+
+use crate::config::ReachabilityConfig;
+use crate::rng::get_rng;
+use crate::utils::parse_fingerprint;
+
+/// The reachability of all relays
+#[derive(Debug, Default)]
+pub(crate) struct ReachabilityModel {
+    /// Probability that a connection attempt fails, for relays without an
+    /// individual probability
+    failure_probability: f64,
+    /// Upper bound of the client-specific failure probabilities
+    client_failure_max: f64,
+    relays: FxHashMap<Fingerprint, RelayReachability>,
+}
+
+/// Reachability of a relay with individual settings
+#[derive(Debug)]
+struct RelayReachability {
+    failure_probability: Option<f64>,
+    outages: Vec<(DateTime<Utc>, DateTime<Utc>)>,
+}
+
+impl ReachabilityModel {
+    /// Construct the model from the experiment configuration
+    pub(crate) fn new(config: &ReachabilityConfig) -> anyhow::Result<ReachabilityModel> {
+        let mut relays = FxHashMap::default();
+        for relay in config.relays.iter() {
+            relays.insert(
+                parse_fingerprint(&relay.fingerprint)?,
+                RelayReachability {
+                    failure_probability: relay.failure_probability,
+                    outages: relay
+                        .outages
+                        .iter()
+                        .map(|outage| (outage.from.first_datetime(), outage.until.last_datetime()))
+                        .collect(),
+                },
+            );
+        }
+
+        Ok(ReachabilityModel {
+            failure_probability: config.failure_probability,
+            client_failure_max: config.client_failure_max,
+            relays,
+        })
+    }
+
+    /// Draw the individual failure probability of a new client
+    pub(crate) fn sample_client_failure_probability(&self) -> f64 {
+        if self.client_failure_max > 0.0 {
+            get_rng().gen_range(0.0..=self.client_failure_max)
+        } else {
+            0.0
+        }
+    }
+
+    /// Simulate a connection attempt of a client to a relay.
+    ///
+    /// Returns `true` if the connection succeeds.
+    pub(crate) fn try_connect(
+        &self,
+        relay: &Fingerprint,
+        time: &DateTime<Utc>,
+        client_failure_probability: f64,
+    ) -> bool {
+        let relay_failure_probability = match self.relays.get(relay) {
+            Some(reachability) => {
+                if reachability
+                    .outages
+                    .iter()
+                    .any(|(from, until)| time >= from && time <= until)
+                {
+                    return false;
+                }
+                reachability
+                    .failure_probability
+                    .unwrap_or(self.failure_probability)
+            }
+            None => self.failure_probability,
+        };
+
+        if relay_failure_probability <= 0.0 && client_failure_probability <= 0.0 {
+            return true;
+        }
+
+        let mut rng = get_rng();
+        !rng.gen_bool(relay_failure_probability.min(1.0))
+            && !rng.gen_bool(client_failure_probability.min(1.0))
+    }
+}
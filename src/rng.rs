@@ -0,0 +1,165 @@
+//! Random numbers that do not depend on the number of threads
+//!
+//! Every trial has its own seed that is derived from the configured one (see
+//! `trial_seed`), and every client owns a random number generator that is
+//! derived from the trial's seed and the client's ID. While a client is
+//! simulated, its generator is installed for the current thread, and all
+//! random draws (through `get_rng`) use it. Random draws outside of clients,
+//! e.g. when setting up the simulation, use seeded_rand's global generator and
+//! must happen in a fixed order.
+//!
+//! The circuit generator draws from seeded_rand's generator itself. Calls to
+//! it that involve randomness therefore go through `with_circgen`, which seeds
+//! that generator from the current generator first. seeded_rand keeps a
+//! generator per thread, so threads do not interfere with each other. As this
+//! is crucial for reproducible results, it is checked once at startup (see
+//! `set_seed`). Should the generator be shared between threads, the calls are
+//! serialized instead.
+
+use std::cell::RefCell;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+#[allow(unused_imports)]
+use log::{debug, info, trace, warn};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// The random number generator of a client
+pub(crate) type ClientRng = ChaCha20Rng;
+
+thread_local! {
+    /// The generator of the client that is currently simulated by this thread
+    static CURRENT: RefCell<Option<ClientRng>> = const { RefCell::new(None) };
+}
+
+lazy_static! {
+    /// Serializes the calls to the circuit generator, only if seeded_rand's
+    /// generator is shared between threads
+    static ref CIRCGEN_LOCK: Option<Mutex<()>> = if generator_is_per_thread() {
+        None
+    } else {
+        warn!("seeded_rand's generator is shared between threads. Path selection is serialized.");
+        Some(Mutex::new(()))
+    };
+}
+
+/// What a derived generator is used for
+#[derive(Debug, Clone, Copy)]
+enum Purpose {
+    Client = 1,
+    Epoch = 2,
+    Trial = 3,
+}
+
+/// Derive an independent generator from the seed. Generators with a different
+/// purpose or stream do not overlap.
+fn derive(seed: u64, purpose: Purpose, stream: u64) -> ClientRng {
+    let mut key = [0u8; 32];
+    key[..8].copy_from_slice(&seed.to_le_bytes());
+    key[8..16].copy_from_slice(&(purpose as u64).to_le_bytes());
+
+    let mut rng = ChaCha20Rng::from_seed(key);
+    rng.set_stream(stream);
+    rng
+}
+
+/// The seed of a trial. The first trial uses the configured seed itself, so
+/// any trial can be reproduced on its own by simulating a single trial with
+/// the trial's seed.
+pub(crate) fn trial_seed(seed: u64, trial: usize) -> u64 {
+    match trial {
+        0 => seed,
+        _ => derive(seed, Purpose::Trial, trial as u64).next_u64(),
+    }
+}
+
+/// The generator of a client in the trial with the given seed
+pub(crate) fn client_rng(trial_seed: u64, client_id: u64) -> ClientRng {
+    derive(trial_seed, Purpose::Client, client_id)
+}
+
+/// A generator for the state that is shared by the clients of a trial and
+/// updated at the beginning of an epoch (e.g. the guard sets)
+pub(crate) fn epoch_rng(trial_seed: u64, epoch_start: &DateTime<Utc>) -> ClientRng {
+    derive(trial_seed, Purpose::Epoch, epoch_start.timestamp() as u64)
+}
+
+/// Run `f` with `rng` as the generator of the current thread
+pub(crate) fn with_rng<T>(rng: &mut ClientRng, f: impl FnOnce() -> T) -> T {
+    let previous = CURRENT.with(|current| current.replace(Some(rng.clone())));
+    let result = f();
+    *rng = CURRENT.with(|current| current.replace(previous)).unwrap(); // cannot fail as we installed it above
+    result
+}
+
+/// Call the circuit generator (or anything else that draws from seeded_rand's
+/// global generator) so its result only depends on the current generator
+pub(crate) fn with_circgen<T>(f: impl FnOnce() -> T) -> T {
+    let seed = get_rng().next_u64();
+    let _lock = CIRCGEN_LOCK.as_ref().map(|lock| lock.lock().unwrap());
+    seeded_rand::set_seed(seed);
+    f()
+}
+
+/// Seed seeded_rand's generator of the current thread, which is used for
+/// random draws outside of clients.
+///
+/// This must be called before any other threads draw random numbers, as it
+/// first checks how seeded_rand's generator behaves (see `CIRCGEN_LOCK`).
+pub(crate) fn set_seed(seed: u64) {
+    lazy_static::initialize(&CIRCGEN_LOCK);
+    seeded_rand::set_seed(seed);
+}
+
+/// Check if seeding seeded_rand's generator in another thread leaves the
+/// generator of the current thread untouched
+fn generator_is_per_thread() -> bool {
+    let draw_after_seeding = |seed_other_thread: bool| {
+        seeded_rand::set_seed(0);
+        if seed_other_thread {
+            std::thread::spawn(|| seeded_rand::set_seed(1))
+                .join()
+                .unwrap();
+        }
+        seeded_rand::get_rng().next_u64()
+    };
+    draw_after_seeding(false) == draw_after_seeding(true)
+}
+
+/// Get the generator of the current thread: the one of the client that is
+/// currently simulated, or seeded_rand's global generator otherwise
+pub(crate) fn get_rng() -> CurrentRng {
+    CurrentRng
+}
+
+/// A handle to the generator of the current thread
+pub(crate) struct CurrentRng;
+
+impl CurrentRng {
+    fn with<T>(&mut self, f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+        CURRENT.with(|current| match current.borrow_mut().as_mut() {
+            Some(rng) => f(rng),
+            None => f(&mut seeded_rand::get_rng()),
+        })
+    }
+}
+
+impl RngCore for CurrentRng {
+    fn next_u32(&mut self) -> u32 {
+        self.with(|rng| rng.next_u32())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.with(|rng| rng.next_u64())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.with(|rng| rng.fill_bytes(dest))
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.with(|rng| rng.try_fill_bytes(dest))
+    }
+}
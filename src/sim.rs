@@ -1,45 +1,107 @@
 //! The (abstract) simulator and simulation environment
 
+use std::sync::Arc;
+
 use anyhow;
 use anyhow::Context;
+use chrono::{DateTime, Duration, Utc};
+use fxhash::FxHashMap;
 #[allow(unused_imports)]
 use log::{debug, info, trace, warn};
+use rand::Rng;
 use rayon::prelude::*;
-
+use seeded_rand::RHashSet;
 use tor_circuit_generator::CircuitGenerator;
+use tordoc::Fingerprint;
 
 use crate::adversaries::Adversary;
-use crate::cli::Cli;
+use crate::bridges::BridgeSet;
+use crate::checkpoint::{Checkpoint, CoverageState, ScenarioCheckpoint, TrialCheckpoint};
 use crate::client::Client;
+use crate::config::{ConsensusFlavor, ExperimentConfig, GapPolicy, OutputConfig};
+use crate::destinations::ExitPolicies;
+use crate::guard_policy::GuardPolicyFactory;
+use crate::guard_state::GuardStateFile;
 use crate::input::TorArchive;
 use crate::observer::{ExitFingerprintSerializer, SimulationObserver};
 use crate::packet_model::{PacketModelParameters, StreamModelParameters};
-use crate::trace::TraceHandle;
-use crate::user::{get_privcount_circuits_10min, get_privcount_users, PrivcountUser};
+use crate::prefetch::EpochLoader;
+use crate::reachability::ReachabilityModel;
+use crate::rng::{client_rng, epoch_rng, get_rng, trial_seed, with_rng};
+use crate::sweep::{self, Scenario};
+use crate::trace::{MemoryCsvWriter, TraceHandle};
+use crate::trials::{TrialMetrics, TrialsSummary};
+use crate::user::{get_privcount_circuits_10min, get_privcount_users, PrivcountUser, RequestMix};
+
+/// Number of clients whose traces are collected in memory before they are
+/// written, in the order of the clients
+const ORDERED_CHUNK_SIZE: usize = 1024;
 
 pub(crate) struct Simulator {
-    cli: Cli,
+    config: ExperimentConfig,
+    /// The configurations that are simulated side by side
+    scenarios: Vec<Scenario>,
+    /// Whether this is a parameter sweep over several scenarios
+    is_sweep: bool,
 }
 
 impl Simulator {
     /// Construct a new simulator environment
-    pub(crate) fn new(cli: Cli) -> Simulator {
-        Simulator { cli }
+    pub(crate) fn new(config: ExperimentConfig) -> Simulator {
+        Simulator {
+            scenarios: vec![Scenario::from_config(&config)],
+            config,
+            is_sweep: false,
+        }
+    }
+
+    /// Construct a new simulator environment for a parameter sweep
+    pub(crate) fn new_sweep(config: ExperimentConfig, scenarios: Vec<Scenario>) -> Simulator {
+        Simulator {
+            config,
+            scenarios,
+            is_sweep: true,
+        }
     }
 
     /// Run the simulation
     pub(crate) fn run(self) -> anyhow::Result<()> {
-        // configure adversary
-        let adversary = Adversary::new(&self.cli);
+        // configure the failure model for connections to guards
+        let reachability = Arc::new(ReachabilityModel::new(&self.config.reachability)?);
+
+        // configure the bridges used by bridge users
+        let bridge_set = BridgeSet::new(&self.config.bridges)?;
+
+        let from = self
+            .config
+            .time
+            .from
+            .as_ref()
+            .context("No simulation start time given (--from or time.from)")?;
+        let to = self
+            .config
+            .time
+            .to
+            .as_ref()
+            .context("No simulation end time given (--to or time.to)")?;
 
         info!("Finding consensuses");
-        let archive = TorArchive::new(self.cli.tor_data)?;
-        let consensus_handles = archive.find_consensuses(&self.cli.from, &self.cli.to)?;
+        let archive = TorArchive::new(
+            self.config
+                .input
+                .tor_data
+                .as_ref()
+                .context("No Tor data location given (--tor-data or input.tor_data)")?,
+        )?
+        .with_flavor(self.config.input.flavor)
+        .with_unpack_dir(self.config.input.unpack_dir())
+        .with_cache(self.config.input.cache_dir().unwrap()); // cannot fail as tor_data is set
+        let mut consensus_handles = archive.find_consensuses(from, to)?;
         info!("Found {} consensuses.", consensus_handles.len());
 
         // parse simulation time range into DateTime objects
-        let start_time = self.cli.from.first_datetime();
-        let end_time = self.cli.to.last_datetime();
+        let start_time = from.first_datetime();
+        let end_time = to.last_datetime();
 
         if end_time <= start_time {
             anyhow::bail!(
@@ -50,155 +112,807 @@ impl Simulator {
         }
 
         info!("Parsing stream model");
-        let stream_model = StreamModelParameters::new(&self.cli.stream_model)?;
+        let stream_model = StreamModelParameters::new(
+            self.config
+                .models
+                .stream_model
+                .as_ref()
+                .context("No stream model given (--stream-model or models.stream_model)")?,
+        )?;
 
         info!("Parsing packet model");
-        let packet_model = PacketModelParameters::new(&self.cli.packet_model)?;
+        let packet_model = PacketModelParameters::new(
+            self.config
+                .models
+                .packet_model
+                .as_ref()
+                .context("No packet model given (--packet-model or models.packet_model)")?,
+        )?;
+
+        let num_trials = self.config.trials.num;
+        if num_trials == 0 {
+            anyhow::bail!("At least one trial must be simulated (--trials or trials.num)");
+        }
 
-        let trace_handle = TraceHandle::new(self.cli.output_trace)?;
+        // the seeds of the trials (and of their clients) are derived from the seed
+        let seed = self.config.seed.unwrap_or(0);
+
+        // the checkpoint of an interrupted run to resume, if given
+        let mut resume_from = match self.config.input.resume {
+            Some(ref path) => {
+                let checkpoint = Checkpoint::read(path)?;
+                checkpoint.check_time_range(&start_time, &end_time)?;
+                if checkpoint.scenarios.len() != self.scenarios.len()
+                    || checkpoint
+                        .scenarios
+                        .iter()
+                        .any(|scenario| scenario.trials.len() != num_trials)
+                {
+                    anyhow::bail!(
+                        "The checkpoint {} was written with a different number of configurations or trials",
+                        path.display()
+                    );
+                }
+                if checkpoint.seed != seed {
+                    anyhow::bail!(
+                        "The checkpoint {} was written with seed {} instead of {}",
+                        path.display(),
+                        checkpoint.seed,
+                        seed
+                    );
+                }
+                info!(
+                    "Resuming from checkpoint {} after the consensus of {}",
+                    path.display(),
+                    checkpoint.last_consensus()?
+                );
+                Some(checkpoint)
+            }
+            None => None,
+        };
+
+        // the guard state of an earlier run to continue with, if given
+        let initial_guard_states = match self.config.input.guard_state {
+            Some(_) if resume_from.is_some() => {
+                warn!(
+                    "Ignoring the guard state file, as the guard state is part of the checkpoint"
+                );
+                None
+            }
+            Some(ref path) => {
+                let state = GuardStateFile::read(path)?;
+                let saved_at = state.time()?;
+                if saved_at > start_time {
+                    warn!(
+                        "Guard state was saved at {}, after the simulation start {}",
+                        saved_at, start_time
+                    );
+                }
+                info!(
+                    "Loaded guard state of {} clients from {} (saved at {})",
+                    state.num_clients(),
+                    path.display(),
+                    saved_at
+                );
+                Some(state.into_map())
+            }
+            None => None,
+        };
+
+        // Set up the scenarios and their trials. Each trial has its own,
+        // independently drawn client population and its own outputs, but all
+        // trials share the consensuses and circuit generators of their scenario.
+        let mut scenario_runs = Vec::with_capacity(self.scenarios.len());
+        let scenario_checkpoints: Vec<Option<ScenarioCheckpoint>> = match resume_from {
+            Some(ref mut checkpoint) => std::mem::take(&mut checkpoint.scenarios)
+                .into_iter()
+                .map(Some)
+                .collect(),
+            None => self.scenarios.iter().map(|_| None).collect(),
+        };
+        for ((scenario_index, scenario), scenario_checkpoint) in
+            self.scenarios.iter().enumerate().zip(scenario_checkpoints)
+        {
+            if self.is_sweep {
+                info!(
+                    "Setting up configuration {} of {}: {}",
+                    scenario_index + 1,
+                    self.scenarios.len(),
+                    scenario.name
+                );
+            }
+
+            // Collect, over time, a mapping from exit fingerprints to unique u64 values
+            // so we can output these instead of the full fingerprints to traces.
+            let (exit_ids, trial_checkpoints) = match scenario_checkpoint {
+                Some(checkpoint) => (
+                    ExitFingerprintSerializer::from_state(&checkpoint.exit_ids)?,
+                    checkpoint.trials.into_iter().map(Some).collect(),
+                ),
+                None => (
+                    ExitFingerprintSerializer::new(),
+                    (0..num_trials).map(|_| None).collect::<Vec<_>>(),
+                ),
+            };
 
-        let num_clients = (self.cli.clients.unwrap_or_else(|| get_privcount_users()) as f64
-            * self.cli.load_scale) as u64;
-        // the total number of circuits/flows that are created every 10 minutes
-        let num_circuits_10min = get_privcount_circuits_10min() * self.cli.load_scale;
+            // configure adversary
+            let adversary = Arc::new(Adversary::new(&scenario.adversary)?);
 
-        info!(
-            "Creating {} clients that build {:.1} circuits every 10 minutes in total",
-            num_clients, num_circuits_10min
-        );
-        let mut clients: Vec<_> = (0..num_clients)
-            .map(|id| {
-                Client::new(
-                    id,
-                    PrivcountUser::new(
-                        start_time.clone(),
-                        num_circuits_10min as f64 / num_clients as f64,
-                        stream_model.clone(),
-                        packet_model.clone(),
-                        end_time,
-                    ),
-                )
-            })
-            .collect();
+            let scenario_output = if self.is_sweep {
+                self.config.output.for_scenario(scenario_index)
+            } else {
+                self.config.output.clone()
+            };
+
+            let load = &scenario.load;
+            if load.models_destinations() && self.config.input.flavor == ConsensusFlavor::Microdesc
+            {
+                warn!(
+                    "Microdescriptors do not contain full exit policies. Exits are only \
+                     checked against their port summaries, not the destination addresses."
+                );
+            }
+            let num_clients = (load.clients.unwrap_or_else(|| get_privcount_users()) as f64
+                * load.load_scale) as u64;
+            let clients_factory = ClientFactory {
+                start_time,
+                end_time,
+                stream_model: &stream_model,
+                packet_model: &packet_model,
+                adversary: adversary.clone(),
+                reachability: reachability.clone(),
+                bridge_set: &bridge_set,
+                num_clients,
+                // the total number of circuits/flows that are created every 10 minutes
+                num_circuits_10min: get_privcount_circuits_10min() * load.load_scale,
+                request_mix: RequestMix::new(load)?,
+            };
 
-        // Collect, over time, a mapping from exit fingerprints to unique u64 values
-        // so we can output these instead of the full fingerprints to traces.
-        let mut exit_ids = ExitFingerprintSerializer::new();
+            let mut trials = Vec::with_capacity(num_trials);
+            for (index, trial_checkpoint) in trial_checkpoints.into_iter().enumerate() {
+                let output = if num_trials > 1 {
+                    scenario_output.for_trial(index)
+                } else {
+                    scenario_output.clone()
+                };
+
+                let trace_path = output
+                    .trace
+                    .as_ref()
+                    .context("No output trace given (--output-trace or output.trace)")?;
+                let trace_handle = match trial_checkpoint {
+                    // continue the trace of the interrupted run
+                    Some(ref checkpoint) => {
+                        TraceHandle::resume(trace_path, checkpoint.trace_offset)?
+                    }
+                    None => TraceHandle::new(trace_path)?,
+                };
+
+                let seed = trial_seed(seed, index);
+                if num_trials > 1 {
+                    info!(
+                        "Setting up trial {} of {} with seed {}",
+                        index + 1,
+                        num_trials,
+                        seed
+                    );
+                }
+                let (mut clients, guard_policies) = clients_factory.make_clients(seed)?;
+
+                // continue with the clients of the interrupted run, if resuming
+                if let Some(checkpoint) = trial_checkpoint {
+                    guard_policies.restore_state(&checkpoint.guard_sets)?;
+                    if checkpoint.clients.len() != clients.len() {
+                        anyhow::bail!(
+                            "The checkpoint contains {} clients instead of {}",
+                            checkpoint.clients.len(),
+                            clients.len()
+                        );
+                    }
+                    clients
+                        .par_iter_mut()
+                        .zip(checkpoint.clients.par_iter())
+                        .try_for_each(|(client, state)| {
+                            client.restore_state(state, adversary.clone(), reachability.clone())
+                        })?;
+                }
+
+                // continue with the guard state of an earlier run, if given
+                if let Some(ref states) = initial_guard_states {
+                    for client in clients.iter_mut() {
+                        if let Some(guard_state) = states.get(&client.get_id()) {
+                            client.restore_guard_state(guard_state)?;
+                        }
+                    }
+                    let num_ignored = states.keys().filter(|id| **id >= num_clients).count();
+                    if num_ignored > 0 {
+                        warn!(
+                            "Ignoring the guard state of {} clients that are not simulated",
+                            num_ignored
+                        );
+                    }
+                }
+
+                trials.push(Trial {
+                    index,
+                    seed,
+                    output,
+                    clients,
+                    guard_policies,
+                    trace_handle,
+                });
+            }
+
+            scenario_runs.push(ScenarioRun {
+                adversary,
+                trials,
+                exit_ids,
+            });
+        }
 
         // Iterate over the consensus handles for the simulation duration.
+        // The consensuses are loaded (and their circuit generators constructed)
+        // in the background while the clients handle the current epoch.
         // We make this peekable so we can see when the next consensus period starts.
         // Each item of this iterator is of type anyhow::Result<...>, so we keep
         // any errors that occured.
-        let mut consensus_iterator = consensus_handles
-            .into_iter()
-            .map(|handle| -> anyhow::Result<_> {
-                let (consensus, descriptors) = handle.load()?;
-                anyhow::Ok((consensus, descriptors))
-            })
-            .peekable();
-
-        while let Some(consensus_result) = consensus_iterator.next() {
+        // Keep track of which parts of the simulation time could be covered by consensuses
+        let mut coverage = TimeCoverage::new();
+        let mut simulated_until = start_time;
+
+        // When resuming, continue after the last consensus of the interrupted run
+        if let Some(ref checkpoint) = resume_from {
+            let last_consensus = checkpoint.last_consensus()?;
+            consensus_handles.retain(|handle| handle.time() > &last_consensus);
+            coverage = TimeCoverage::from_state(&checkpoint.coverage);
+            simulated_until = checkpoint.simulated_until()?;
+        }
+        let mut epochs_since_checkpoint = 0;
+
+        let mut consensus_iterator = EpochLoader::spawn(
+            consensus_handles,
+            scenario_runs
+                .iter()
+                .map(|run| run.adversary.clone())
+                .collect(),
+            &self.config.prefetch,
+        )
+        .peekable();
+
+        while let Some(epoch_result) = consensus_iterator.next() {
             // we cannot use a for loop here because then we couldn't call .peek() on the iterator
 
-            let (mut consensus, mut descriptors) = consensus_result?;
+            let epoch = epoch_result?;
+            let consensus = epoch.consensus();
 
             let range_start = &consensus
                 .valid_after
                 .context("consensus missing valid_after")?;
+            let fresh_until = consensus
+                .fresh_until
+                .context("consensus missing fresh_until")?;
+            let valid_until = consensus
+                .valid_until
+                .context("consensus missing valid_until")?;
             info!(
                 "Entering simulation epoch with consensus from {}",
                 &range_start
             );
 
-            let range_end = match consensus_iterator.peek() {
-                Some(Ok((next_consensus, _))) => {
+            // If there was no consensus for some time before this one, the
+            // clients must not be active for that time.
+            if *range_start > simulated_until && simulated_until < end_time {
+                let skip_until = std::cmp::min(*range_start, end_time);
+                if self.config.time.gap_policy == GapPolicy::Abort {
+                    anyhow::bail!(
+                        "No consensus available from {} until {}",
+                        simulated_until,
+                        skip_until
+                    );
+                }
+                warn!(
+                    "No consensus available from {} until {}. Skipping this time.",
+                    simulated_until, skip_until
+                );
+                coverage.skipped = coverage.skipped + (skip_until - simulated_until);
+                coverage.num_gaps += 1;
+                for trial in scenario_runs
+                    .iter_mut()
+                    .flat_map(|run| run.trials.iter_mut())
+                {
+                    trial
+                        .clients
+                        .par_iter_mut()
+                        .for_each(|client| client.skip_requests_until(&skip_until));
+                }
+            }
+
+            let next_start = match consensus_iterator.peek() {
+                Some(Ok(next_epoch)) => {
                     // If there is a next consensus, use its start time as our end time.
                     // This will ignore errors in the next consensus for now (we only
                     // have a reference, so cannot return them easily), but these
                     // will be handled in the next iteration
-                    next_consensus
-                        .valid_after
-                        .context("consensus missing valid_after")?
+                    Some(
+                        next_epoch
+                            .consensus()
+                            .valid_after
+                            .context("consensus missing valid_after")?,
+                    )
+                }
+                _ => None,
+            };
+
+            let range_end = match next_start {
+                Some(next_start) if next_start > fresh_until => {
+                    // There is a gap until the next consensus
+                    match self.config.time.gap_policy {
+                        GapPolicy::Reuse => std::cmp::min(next_start, valid_until),
+                        GapPolicy::Skip => fresh_until,
+                        GapPolicy::Abort => anyhow::bail!(
+                            "No consensus available from {} until {}",
+                            fresh_until,
+                            next_start
+                        ),
+                    }
                 }
-                _ => {
+                Some(next_start) => next_start,
+                None if fresh_until < end_time => {
+                    // There is a gap until the end of the simulation
+                    match self.config.time.gap_policy {
+                        GapPolicy::Reuse => valid_until,
+                        GapPolicy::Skip => fresh_until,
+                        GapPolicy::Abort => anyhow::bail!(
+                            "No consensus available from {} until the end of the simulation",
+                            fresh_until
+                        ),
+                    }
+                }
+                None => {
                     // Otherwise, use this consensus's valid_until
-                    // TODO
-                    *range_start + chrono::Duration::hours(3)
+                    valid_until
                 }
             };
             let range_end = std::cmp::min(range_end, end_time);
 
-            // Apply adversarial changes
-            adversary.modify_consensus(&mut consensus, &mut descriptors);
-
-            let circgen = CircuitGenerator::new(&consensus, descriptors, vec![443, 80, 22])
-                .map_err(|e| anyhow::anyhow!(e))
-                .context("Failed to construct circuit generator")?;
-            exit_ids.add_consensus(&consensus);
-
-            // Progress printer. Takes progress info via a channel from the processing
-            // threads and prints status info to stdout. This thread finishes as soon
-            // a false value is sent to the channel, or the channel is closed.
-            let (progress_thread_handle, progress_s) = {
-                let total = clients.len();
-                let print_every = std::cmp::max(total / 1000, 1);
-                let (progress_s, progress_r) = crossbeam::channel::unbounded::<bool>();
-
-                (
-                    std::thread::spawn(move || {
-                        let mut seen: usize = 0;
-                        while let Ok(value) = progress_r.recv() {
-                            if value == false {
-                                break;
-                            }
-                            seen += 1;
-                            if seen % print_every == 0 && seen > 0 {
-                                info!("completed {:.1} %", (seen as f64 / total as f64) * 100.0);
-                            }
-                        }
-                    }),
-                    progress_s,
+            coverage.add_epoch(range_start, &fresh_until, &range_end);
+            simulated_until = std::cmp::max(simulated_until, range_end);
+
+            for (scenario_index, (run, network)) in scenario_runs
+                .iter_mut()
+                .zip(epoch.networks.iter())
+                .enumerate()
+            {
+                run.exit_ids.add_consensus(&network.consensus);
+
+                if self.is_sweep {
+                    info!(
+                        "Simulating configuration {} of {}",
+                        scenario_index + 1,
+                        self.scenarios.len()
+                    );
+                }
+                for trial in run.trials.iter_mut() {
+                    if num_trials > 1 {
+                        info!("Simulating trial {} of {}", trial.index + 1, num_trials);
+                    }
+                    trial.handle_epoch(
+                        range_start,
+                        &range_end,
+                        &network.circgen,
+                        &network.exit_policies,
+                        &network.compromised_by_nickname,
+                        &run.exit_ids,
+                    )?;
+                }
+            }
+
+            // Periodically store the state, unless the simulation is over anyway
+            epochs_since_checkpoint += 1;
+            if let Some(ref path) = self.config.checkpoint.path {
+                if epochs_since_checkpoint >= self.config.checkpoint.every_epochs
+                    && range_end < end_time
+                {
+                    info!("Writing checkpoint to {}", path.display());
+                    let scenarios = scenario_runs
+                        .iter()
+                        .map(|run| run.checkpoint())
+                        .collect::<anyhow::Result<_>>()?;
+                    Checkpoint::new(
+                        seed,
+                        &start_time,
+                        &end_time,
+                        range_start,
+                        &simulated_until,
+                        coverage.to_state(),
+                        scenarios,
+                    )
+                    .write(path)?;
+
+                    epochs_since_checkpoint = 0;
+                }
+            }
+        }
+
+        if simulated_until < end_time {
+            if self.config.time.gap_policy == GapPolicy::Abort {
+                anyhow::bail!(
+                    "No consensus available from {} until the end of the simulation",
+                    simulated_until
+                );
+            }
+            warn!(
+                "No consensus available from {} until the end of the simulation",
+                simulated_until
+            );
+            coverage.skipped = coverage.skipped + (end_time - simulated_until);
+            coverage.num_gaps += 1;
+        }
+        coverage.print_summary(&self.config.time.gap_policy);
+
+        // Wrap up the simulation
+        let mut results = Vec::with_capacity(self.scenarios.len());
+        for (scenario, run) in self.scenarios.into_iter().zip(scenario_runs) {
+            if self.is_sweep {
+                info!("Results of configuration {}", scenario.name);
+            }
+
+            let mut trial_metrics = Vec::with_capacity(num_trials);
+            for trial in run.trials {
+                if num_trials > 1 {
+                    info!("Results of trial {} of {}", trial.index + 1, num_trials);
+                }
+                trial_metrics.push(trial.finish(run.adversary.clone(), &start_time, &end_time)?);
+            }
+
+            let summary = TrialsSummary::new(&trial_metrics);
+            if num_trials > 1 {
+                summary.print();
+            }
+            results.push((scenario, summary));
+        }
+
+        if self.is_sweep {
+            if let Some(path) = self.config.output.sweep_path() {
+                info!(
+                    "Writing results of {} configurations to {}",
+                    results.len(),
+                    path.display()
+                );
+                sweep::write_results(path, &results)?;
+            }
+        } else if num_trials > 1 {
+            if let Some(path) = self.config.output.trials_summary_path() {
+                info!("Writing summary of all trials to {}", path.display());
+                results[0].1.write_csv(path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates the clients of a scenario
+struct ClientFactory<'a> {
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    stream_model: &'a StreamModelParameters,
+    packet_model: &'a PacketModelParameters,
+    adversary: Arc<Adversary>,
+    reachability: Arc<ReachabilityModel>,
+    bridge_set: &'a BridgeSet,
+    num_clients: u64,
+    /// The total number of circuits/flows that are created every 10 minutes
+    num_circuits_10min: f64,
+    request_mix: RequestMix,
+}
+
+impl ClientFactory<'_> {
+    /// Create a new, independent client population for the trial with the
+    /// given seed, together with the guard state shared by the clients
+    fn make_clients(
+        &self,
+        seed: u64,
+    ) -> anyhow::Result<(Vec<Client<PrivcountUser>>, GuardPolicyFactory)> {
+        // configure how clients choose their guards
+        let guard_policies = GuardPolicyFactory::new(self.reachability.clone())?;
+
+        info!(
+            "Creating {} clients that build {:.1} circuits every 10 minutes in total",
+            self.num_clients, self.num_circuits_10min
+        );
+        let mut transport_counts: FxHashMap<String, u64> = FxHashMap::default();
+        let clients: Vec<_> = (0..self.num_clients)
+            .map(|id| {
+                // everything about the client is drawn from its own generator
+                let mut rng = client_rng(seed, id);
+                let (user_model, guards, bridges) = with_rng(&mut rng, || {
+                    let is_bridge_user = self.bridge_set.users_fraction() > 0.0
+                        && get_rng().gen_bool(self.bridge_set.users_fraction());
+                    let bridges = if is_bridge_user {
+                        let bridges = self.bridge_set.choose_for_client();
+                        let transport = bridges[0].transport.as_deref().unwrap_or("vanilla");
+                        *transport_counts.entry(transport.to_string()).or_insert(0) += 1;
+                        Some(bridges)
+                    } else {
+                        None
+                    };
+
+                    let user_model = PrivcountUser::new(
+                        self.start_time,
+                        self.num_circuits_10min / self.num_clients as f64,
+                        self.request_mix.clone(),
+                        self.stream_model.clone(),
+                        self.packet_model.clone(),
+                        self.end_time,
+                    );
+                    (user_model, guard_policies.make(), bridges)
+                });
+
+                Client::new(
+                    id,
+                    rng,
+                    user_model,
+                    self.adversary.clone(),
+                    guards,
+                    self.reachability.clone(),
+                    bridges,
                 )
-            };
+            })
+            .collect();
+        for (transport, count) in transport_counts.iter() {
+            info!("{} clients use bridges with transport {}", count, transport);
+        }
+
+        Ok((clients, guard_policies))
+    }
+}
+
+/// A scenario during the simulation, with all its trials
+struct ScenarioRun {
+    adversary: Arc<Adversary>,
+    trials: Vec<Trial>,
+    exit_ids: ExitFingerprintSerializer,
+}
 
-            // Trigger clients
-            clients
+impl ScenarioRun {
+    /// Export the state of this scenario. All traces are written to disk
+    /// up to this point.
+    fn checkpoint(&self) -> anyhow::Result<ScenarioCheckpoint> {
+        let mut trials = Vec::with_capacity(self.trials.len());
+        for trial in self.trials.iter() {
+            trials.push(TrialCheckpoint {
+                trace_offset: trial.trace_handle.checkpoint()?,
+                guard_sets: trial.guard_policies.to_state(),
+                clients: trial
+                    .clients
+                    .iter()
+                    .map(|client| client.to_state())
+                    .collect(),
+            });
+        }
+
+        Ok(ScenarioCheckpoint {
+            exit_ids: self.exit_ids.to_state(),
+            trials,
+        })
+    }
+}
+
+/// A single Monte-Carlo trial: an independent population of clients, with
+/// its own outputs
+struct Trial {
+    index: usize,
+    /// The seed the random number generators of the trial are derived from
+    seed: u64,
+    output: OutputConfig,
+    clients: Vec<Client<PrivcountUser>>,
+    /// Maintains the guard state that is shared by the clients
+    guard_policies: GuardPolicyFactory,
+    trace_handle: TraceHandle,
+}
+
+impl Trial {
+    /// Let the clients handle a simulation epoch
+    fn handle_epoch(
+        &mut self,
+        range_start: &DateTime<Utc>,
+        range_end: &DateTime<Utc>,
+        circgen: &CircuitGenerator,
+        exit_policies: &Arc<ExitPolicies>,
+        compromised_by_nickname: &Arc<RHashSet<Fingerprint>>,
+        exit_ids: &ExitFingerprintSerializer,
+    ) -> anyhow::Result<()> {
+        // Progress printer. Takes progress info via a channel from the processing
+        // threads and prints status info to stdout. This thread finishes as soon
+        // a false value is sent to the channel, or the channel is closed.
+        let (progress_thread_handle, progress_s) = {
+            let total = self.clients.len();
+            let print_every = std::cmp::max(total / 1000, 1);
+            let (progress_s, progress_r) = crossbeam::channel::unbounded::<bool>();
+
+            (
+                std::thread::spawn(move || {
+                    let mut seen: usize = 0;
+                    while let Ok(value) = progress_r.recv() {
+                        if value == false {
+                            break;
+                        }
+                        seen += 1;
+                        if seen % print_every == 0 && seen > 0 {
+                            info!("completed {:.1} %", (seen as f64 / total as f64) * 100.0);
+                        }
+                    }
+                }),
+                progress_s,
+            )
+        };
+
+        // Update the guard state shared by all clients before simulating them
+        with_rng(&mut epoch_rng(self.seed, range_start), || {
+            self.guard_policies.timed_updates(circgen)
+        });
+
+        // Trigger clients. They are simulated in parallel, but in chunks so
+        // their traces can be written in the order of the clients.
+        for chunk in self.clients.chunks_mut(ORDERED_CHUNK_SIZE) {
+            let traces = chunk
                 .par_iter_mut()
                 // .progress_count(num_clients as u64)
-                .map_init(
-                    || trace_handle.get_writer(),
-                    |csv_writer, client| -> anyhow::Result<()> {
-                        client.handle_new_epoch(
-                            range_start,
-                            &range_end,
-                            &circgen,
-                            csv_writer,
-                            &exit_ids,
-                        )?;
-
-                        csv_writer.flush()?;
-                        progress_s.send(true).unwrap();
-                        Ok(())
-                    },
-                )
-                .collect::<anyhow::Result<()>>()?;
+                .map(|client| -> anyhow::Result<Vec<u8>> {
+                    let mut csv_writer = MemoryCsvWriter::new();
+                    client.handle_new_epoch(
+                        range_start,
+                        range_end,
+                        circgen,
+                        exit_policies,
+                        compromised_by_nickname,
+                        &mut csv_writer,
+                        exit_ids,
+                    )?;
+
+                    progress_s.send(true).unwrap();
+                    csv_writer.take_data()
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
+            for trace in traces {
+                self.trace_handle.write_data(trace)?;
+            }
+        }
+
+        progress_s.send(false).unwrap();
+        progress_thread_handle.join().unwrap();
 
-            progress_s.send(false).unwrap();
-            progress_thread_handle.join().unwrap();
+        // test_send::<Client<PrivcountUser>>();
+        Ok(())
+    }
 
-            // test_send::<Client<PrivcountUser>>();
+    /// Write the outputs of this trial and return its metrics
+    fn finish(
+        self,
+        adversary: Arc<Adversary>,
+        start_time: &DateTime<Utc>,
+        end_time: &DateTime<Utc>,
+    ) -> anyhow::Result<TrialMetrics> {
+        if let Some(ref path) = self.output.guard_state {
+            let states: Vec<_> = self
+                .clients
+                .iter()
+                .filter_map(|client| Some((client.get_id(), client.guard_state()?)))
+                .collect();
+            if states.len() < self.clients.len() {
+                warn!(
+                    "The guard policy of {} clients does not support saving the guard state",
+                    self.clients.len() - states.len()
+                );
+            }
+            info!("Writing guard state to {}", path.display());
+            GuardStateFile::new(end_time, states).write(path)?;
         }
 
-        // Wrap up the simulation
         let observer = SimulationObserver::from_clients(
-            clients.into_iter().map(|c| c.into_observer()),
+            self.clients.into_iter().map(|c| c.into_observer()),
             adversary,
         );
         observer.print();
 
-        trace_handle.stop_and_join()?;
+        if let Some(path) = self.output.bridge_streams_path() {
+            observer.write_bridge_streams(path)?;
+        }
 
-        Ok(())
+        let compromise = observer.compromise_analysis(start_time);
+        compromise.print_summary();
+        if let Some(path) = self.output.compromise_path() {
+            info!("Writing compromise CDFs to {}", path.display());
+            compromise.write_csv(path)?;
+        }
+
+        self.trace_handle.stop_and_join()?;
+
+        let mut metrics = observer.metrics();
+        metrics.extend(compromise.metrics());
+        Ok(metrics)
+    }
+}
+
+/// Book-keeping of how the simulated time was covered by consensuses
+struct TimeCoverage {
+    /// Time covered by consensuses that were fresh
+    fresh: Duration,
+    /// Time covered by consensuses that were not fresh anymore, but still valid
+    stale: Duration,
+    /// Time without any consensus, and therefore without client activity
+    skipped: Duration,
+    /// Number of gaps where no consensus was available
+    num_gaps: usize,
+}
+
+impl TimeCoverage {
+    fn new() -> TimeCoverage {
+        TimeCoverage {
+            fresh: Duration::zero(),
+            stale: Duration::zero(),
+            skipped: Duration::zero(),
+            num_gaps: 0,
+        }
+    }
+
+    fn from_state(state: &CoverageState) -> TimeCoverage {
+        TimeCoverage {
+            fresh: Duration::seconds(state.fresh),
+            stale: Duration::seconds(state.stale),
+            skipped: Duration::seconds(state.skipped),
+            num_gaps: state.num_gaps,
+        }
+    }
+
+    fn to_state(&self) -> CoverageState {
+        CoverageState {
+            fresh: self.fresh.num_seconds(),
+            stale: self.stale.num_seconds(),
+            skipped: self.skipped.num_seconds(),
+            num_gaps: self.num_gaps,
+        }
+    }
+
+    /// Account for an epoch that was simulated with a consensus
+    fn add_epoch(
+        &mut self,
+        start: &DateTime<Utc>,
+        fresh_until: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) {
+        if end <= start {
+            return;
+        }
+
+        let fresh_end = std::cmp::min(end, fresh_until);
+        if fresh_end > start {
+            self.fresh = self.fresh + (*fresh_end - *start);
+        }
+        if end > fresh_until {
+            self.stale = self.stale + (*end - *std::cmp::max(start, fresh_until));
+        }
+    }
+
+    fn print_summary(&self, gap_policy: &GapPolicy) {
+        let hours = |d: &Duration| d.num_seconds() as f64 / 3600.0;
+
+        info!(
+            "Simulated time covered by fresh consensuses: {:.1} hours",
+            hours(&self.fresh)
+        );
+        info!(
+            "Simulated time covered by stale, but valid consensuses: {:.1} hours",
+            hours(&self.stale)
+        );
+        info!(
+            "Simulated time skipped without client activity: {:.1} hours in {} gaps (gap policy: {:?})",
+            hours(&self.skipped),
+            self.num_gaps,
+            gap_policy
+        );
     }
 }
 
@@ -0,0 +1,342 @@
+//! Parameter sweeps over adversary and load configurations
+//!
+//! A sweep file (TOML or JSON) describes the configurations to compare,
+//! either as a grid, i.e. all combinations of the given values, or as an
+//! explicit list. Parameters that are not given are taken from the experiment
+//! configuration. All configurations are simulated in a single run that loads
+//! every consensus only once, and their metrics are collected into one table
+//! with a row per configuration.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{self, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{is_json, AdversaryConfig, ExperimentConfig, LoadConfig};
+use crate::trials::TrialsSummary;
+
+/// The contents of a sweep file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SweepSpec {
+    pub grid: SweepGrid,
+    pub configs: Vec<SweepPoint>,
+}
+
+/// All combinations of the given values. Empty lists keep the value of the
+/// experiment configuration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SweepGrid {
+    pub adv_guards_num: Vec<u64>,
+    pub adv_guards_bw: Vec<u64>,
+    pub adv_exits_num: Vec<u64>,
+    pub adv_exits_bw: Vec<u64>,
+    pub adv_middles_num: Vec<u64>,
+    pub adv_middles_bw: Vec<u64>,
+    pub adv_spec: Vec<PathBuf>,
+    pub clients: Vec<u64>,
+    pub load_scale: Vec<f64>,
+}
+
+/// A single configuration, overriding the experiment configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SweepPoint {
+    /// Name of the configuration in the results. If missing, it is derived
+    /// from the overridden parameters.
+    pub name: Option<String>,
+    pub adv_guards_num: Option<u64>,
+    pub adv_guards_bw: Option<u64>,
+    pub adv_exits_num: Option<u64>,
+    pub adv_exits_bw: Option<u64>,
+    pub adv_middles_num: Option<u64>,
+    pub adv_middles_bw: Option<u64>,
+    pub adv_spec: Option<PathBuf>,
+    pub clients: Option<u64>,
+    pub load_scale: Option<f64>,
+}
+
+/// A configuration that is simulated as part of a run
+#[derive(Debug, Clone)]
+pub(crate) struct Scenario {
+    pub name: String,
+    pub adversary: AdversaryConfig,
+    pub load: LoadConfig,
+}
+
+impl Scenario {
+    /// The scenario of a plain run, as given by the experiment configuration
+    pub(crate) fn from_config(config: &ExperimentConfig) -> Scenario {
+        Scenario {
+            name: "default".to_string(),
+            adversary: config.adversary.clone(),
+            load: config.load.clone(),
+        }
+    }
+}
+
+impl SweepSpec {
+    /// Load a sweep from a TOML or JSON file.
+    ///
+    /// The format is determined by the file extension (`.json` for JSON,
+    /// TOML otherwise).
+    pub(crate) fn from_file(path: impl AsRef<Path>) -> anyhow::Result<SweepSpec> {
+        let path = path.as_ref();
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read sweep file {}", path.display()))?;
+
+        let spec = if is_json(path) {
+            serde_json::from_str(&raw)?
+        } else {
+            toml::from_str(&raw)?
+        };
+
+        Ok(spec)
+    }
+
+    /// All configurations of this sweep: the grid points first, followed by
+    /// the explicitly listed configurations
+    pub(crate) fn points(&self) -> Vec<SweepPoint> {
+        let mut points = Vec::new();
+        if !self.grid.is_empty() {
+            points.push(SweepPoint::default());
+        }
+
+        // extend the grid by one dimension at a time
+        fn extend<T: Clone>(
+            points: Vec<SweepPoint>,
+            values: &[T],
+            set: impl Fn(&mut SweepPoint, T),
+        ) -> Vec<SweepPoint> {
+            if values.is_empty() {
+                return points;
+            }
+            let set = &set;
+            points
+                .into_iter()
+                .flat_map(|point| {
+                    values.iter().map(move |value| {
+                        let mut point = point.clone();
+                        set(&mut point, value.clone());
+                        point
+                    })
+                })
+                .collect()
+        }
+
+        let grid = &self.grid;
+        points = extend(points, &grid.adv_guards_num, |p, v| {
+            p.adv_guards_num = Some(v)
+        });
+        points = extend(points, &grid.adv_guards_bw, |p, v| {
+            p.adv_guards_bw = Some(v)
+        });
+        points = extend(points, &grid.adv_exits_num, |p, v| {
+            p.adv_exits_num = Some(v)
+        });
+        points = extend(points, &grid.adv_exits_bw, |p, v| p.adv_exits_bw = Some(v));
+        points = extend(points, &grid.adv_middles_num, |p, v| {
+            p.adv_middles_num = Some(v)
+        });
+        points = extend(points, &grid.adv_middles_bw, |p, v| {
+            p.adv_middles_bw = Some(v)
+        });
+        points = extend(points, &grid.adv_spec, |p, v| p.adv_spec = Some(v));
+        points = extend(points, &grid.clients, |p, v| p.clients = Some(v));
+        points = extend(points, &grid.load_scale, |p, v| p.load_scale = Some(v));
+
+        points.extend(self.configs.iter().cloned());
+        points
+    }
+
+    /// The scenarios to simulate, based on the experiment configuration
+    pub(crate) fn scenarios(&self, config: &ExperimentConfig) -> anyhow::Result<Vec<Scenario>> {
+        let scenarios: Vec<_> = self
+            .points()
+            .iter()
+            .map(|point| point.apply(config))
+            .collect();
+        for scenario in scenarios.iter() {
+            scenario
+                .load
+                .check()
+                .and_then(|_| scenario.adversary.check())
+                .with_context(|| format!("Invalid sweep configuration {}", scenario.name))?;
+        }
+
+        if scenarios.is_empty() {
+            anyhow::bail!("The sweep does not contain any configurations");
+        }
+        Ok(scenarios)
+    }
+}
+
+impl SweepGrid {
+    fn is_empty(&self) -> bool {
+        self.adv_guards_num.is_empty()
+            && self.adv_guards_bw.is_empty()
+            && self.adv_exits_num.is_empty()
+            && self.adv_exits_bw.is_empty()
+            && self.adv_middles_num.is_empty()
+            && self.adv_middles_bw.is_empty()
+            && self.adv_spec.is_empty()
+            && self.clients.is_empty()
+            && self.load_scale.is_empty()
+    }
+}
+
+impl SweepPoint {
+    /// Override the experiment configuration with the values of this point
+    fn apply(&self, config: &ExperimentConfig) -> Scenario {
+        fn set<T: Clone>(target: &mut Option<T>, value: &Option<T>) {
+            if value.is_some() {
+                *target = value.clone();
+            }
+        }
+
+        let mut adversary = config.adversary.clone();
+        set(&mut adversary.guards_num, &self.adv_guards_num);
+        set(&mut adversary.guards_bw, &self.adv_guards_bw);
+        set(&mut adversary.exits_num, &self.adv_exits_num);
+        set(&mut adversary.exits_bw, &self.adv_exits_bw);
+        set(&mut adversary.middles_num, &self.adv_middles_num);
+        set(&mut adversary.middles_bw, &self.adv_middles_bw);
+        set(&mut adversary.spec, &self.adv_spec);
+
+        let mut load = config.load.clone();
+        if self.clients.is_some() {
+            // an explicit number of clients is not scaled by the load from the base configuration
+            load.clients = self.clients;
+            load.load_scale = 1.0;
+        }
+        if let Some(load_scale) = self.load_scale {
+            load.load_scale = load_scale;
+        }
+
+        Scenario {
+            name: self.name.clone().unwrap_or_else(|| self.describe()),
+            adversary,
+            load,
+        }
+    }
+
+    /// Describe the overridden parameters, e.g. `adv_guards_bw=1000,load_scale=0.1`
+    fn describe(&self) -> String {
+        let values = [
+            ("adv_guards_num", self.adv_guards_num.map(|v| v.to_string())),
+            ("adv_guards_bw", self.adv_guards_bw.map(|v| v.to_string())),
+            ("adv_exits_num", self.adv_exits_num.map(|v| v.to_string())),
+            ("adv_exits_bw", self.adv_exits_bw.map(|v| v.to_string())),
+            (
+                "adv_middles_num",
+                self.adv_middles_num.map(|v| v.to_string()),
+            ),
+            ("adv_middles_bw", self.adv_middles_bw.map(|v| v.to_string())),
+            (
+                "adv_spec",
+                self.adv_spec.as_ref().map(|v| v.display().to_string()),
+            ),
+            ("clients", self.clients.map(|v| v.to_string())),
+            ("load_scale", self.load_scale.map(|v| v.to_string())),
+        ];
+
+        let parts: Vec<_> = values
+            .into_iter()
+            .filter_map(|(name, value)| Some(format!("{}={}", name, value?)))
+            .collect();
+        if parts.is_empty() {
+            "default".to_string()
+        } else {
+            parts.join(",")
+        }
+    }
+}
+
+/// Write the results of a sweep to a CSV file, with one row per scenario.
+///
+/// Besides the scenario's parameters, each row contains the mean of every
+/// metric over the scenario's trials, followed by the number of trials that
+/// reported the metric (`<metric>_trials`). Metrics without a value in a
+/// scenario are left empty.
+pub(crate) fn write_results(
+    path: impl AsRef<Path>,
+    results: &[(Scenario, TrialsSummary)],
+) -> anyhow::Result<()> {
+    // all metrics, in the order they first appear
+    let mut metric_names: Vec<&str> = Vec::new();
+    for (_, summary) in results.iter() {
+        for (name, _, _) in summary.means() {
+            if !metric_names.contains(&name) {
+                metric_names.push(name);
+            }
+        }
+    }
+
+    let mut writer = csv::Writer::from_path(path)?;
+    let mut header: Vec<String> = [
+        "config",
+        "name",
+        "adv_guards_num",
+        "adv_guards_bw",
+        "adv_exits_num",
+        "adv_exits_bw",
+        "adv_middles_num",
+        "adv_middles_bw",
+        "adv_spec",
+        "clients",
+        "load_scale",
+        "trials",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    for name in metric_names.iter() {
+        header.push(name.to_string());
+        header.push(format!("{}_trials", name));
+    }
+    writer.write_record(&header)?;
+
+    let or_empty = |value: Option<u64>| value.map(|v| v.to_string()).unwrap_or_default();
+    for (index, (scenario, summary)) in results.iter().enumerate() {
+        let adversary = &scenario.adversary;
+        let mut row = vec![
+            index.to_string(),
+            scenario.name.clone(),
+            or_empty(adversary.guards_num),
+            or_empty(adversary.guards_bw),
+            or_empty(adversary.exits_num),
+            or_empty(adversary.exits_bw),
+            or_empty(adversary.middles_num),
+            or_empty(adversary.middles_bw),
+            adversary
+                .spec
+                .as_ref()
+                .map(|spec| spec.display().to_string())
+                .unwrap_or_default(),
+            or_empty(scenario.load.clients),
+            scenario.load.load_scale.to_string(),
+            summary.num_trials().to_string(),
+        ];
+
+        let means: Vec<_> = summary.means().collect();
+        for name in metric_names.iter() {
+            let metric = means.iter().find(|(metric, _, _)| metric == name);
+            row.push(
+                metric
+                    .map(|(_, mean, _)| mean.to_string())
+                    .unwrap_or_default(),
+            );
+            row.push(
+                metric
+                    .map(|(_, _, trials)| trials.to_string())
+                    .unwrap_or_else(|| "0".to_string()),
+            );
+        }
+        writer.write_record(&row)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
@@ -1,10 +1,11 @@
 //! Generation of network traces for use in ppcalc
 
 use num_cpus;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::ops::Range;
 use std::path::Path;
-use std::sync::Mutex;
 use std::thread::JoinHandle;
 use zstd;
 
@@ -12,40 +13,99 @@ use anyhow;
 use chrono::{DateTime, Utc};
 use crossbeam::channel::{Receiver, Sender};
 // use indicatif::ProgressIterator;
-use lazy_static::lazy_static;
 #[allow(unused_imports)]
 use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
 
 use ppcalc_metric;
 use ppcalc_metric::{DestinationId, MessageId, SourceId, TraceEntry};
 
-lazy_static! {
-    static ref NEXT_RECEIVER: GlobalCounter = GlobalCounter::new(0);
-    static ref NEXT_MESSAGE: GlobalCounter = GlobalCounter::new(0);
+/// Destination hosts (if they are modelled) have the destination IDs from
+/// this offset on. The IDs of streams stay below it.
+const DESTINATION_HOST_IDS: u64 = 1 << 63;
+
+/// Assigns the destination and message IDs of a client's streams.
+///
+/// The IDs must not depend on the order in which the clients are simulated,
+/// so every client counts its own streams and messages. The upper 32 bits of
+/// an ID are the client ID, the lower 32 bits are the client's counter.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceIds {
+    client_id: u64,
+    next_destination: u64,
+    next_message: u64,
 }
 
+impl TraceIds {
+    pub fn new(client_id: u64) -> TraceIds {
+        TraceIds {
+            client_id,
+            next_destination: 0,
+            next_message: 0,
+        }
+    }
+
+    fn make_id(&self, counter: u64) -> anyhow::Result<u64> {
+        if self.client_id >= DESTINATION_HOST_IDS >> 32 || counter > u32::MAX as u64 {
+            anyhow::bail!(
+                "Cannot assign trace ID {} of client {}: out of range",
+                counter,
+                self.client_id
+            );
+        }
+        Ok(self.client_id << 32 | counter)
+    }
+
+    fn next_destination(&mut self) -> anyhow::Result<u64> {
+        let id = self.make_id(self.next_destination)?;
+        self.next_destination += 1;
+        Ok(id)
+    }
+
+    fn next_messages(&mut self, n: u64) -> anyhow::Result<Range<u64>> {
+        let first = self.make_id(self.next_message)?;
+        if n > 0 {
+            // make sure the last ID is in range as well
+            self.make_id(self.next_message + n - 1)?;
+        }
+        self.next_message += n;
+        Ok(first..(first + n))
+    }
+}
+
+/// Construct the trace entries of a stream, returning them together with the
+/// stream's message IDs. The messages' destination ID is the ID of the
+/// destination host, if it is known, or a unique ID of the stream otherwise.
 pub fn make_trace_entries(
     timestamps: Vec<DateTime<Utc>>,
     exit_id: u64,
-) -> impl Iterator<Item = TraceEntry> {
-    let receiver = NEXT_RECEIVER.get_next();
-    let message_ids = NEXT_MESSAGE.get_next_n(timestamps.len() as u64);
-
-    timestamps
-        .into_iter()
-        .zip(message_ids.into_iter())
-        .map(move |(timestamp, message_id)| {
-            let source_timestamp = convert_time(timestamp);
-            let destination_timestamp = source_timestamp + time::Duration::milliseconds(210); // TODO
-
-            TraceEntry {
-                m_id: MessageId::new(message_id),
-                source_id: SourceId::new(exit_id),
-                source_timestamp,
-                destination_id: DestinationId::new(receiver),
-                destination_timestamp,
-            }
-        })
+    destination_host: Option<u64>,
+    ids: &mut TraceIds,
+) -> anyhow::Result<(Range<u64>, impl Iterator<Item = TraceEntry>)> {
+    let receiver = match destination_host {
+        Some(host) => DESTINATION_HOST_IDS + host,
+        None => ids.next_destination()?,
+    };
+    let message_ids = ids.next_messages(timestamps.len() as u64)?;
+
+    let entries =
+        timestamps
+            .into_iter()
+            .zip(message_ids.clone())
+            .map(move |(timestamp, message_id)| {
+                let source_timestamp = convert_time(timestamp);
+                let destination_timestamp = source_timestamp + time::Duration::milliseconds(210); // TODO
+
+                TraceEntry {
+                    m_id: MessageId::new(message_id),
+                    source_id: SourceId::new(exit_id),
+                    source_timestamp,
+                    destination_id: DestinationId::new(receiver),
+                    destination_timestamp,
+                }
+            });
+
+    Ok((message_ids, entries))
 }
 
 fn convert_time(timestamp: DateTime<Utc>) -> time::PrimitiveDateTime {
@@ -58,53 +118,55 @@ fn convert_time(timestamp: DateTime<Utc>) -> time::PrimitiveDateTime {
     time::PrimitiveDateTime::new(date_part, time_part)
 }
 
-/// A global counter to assign unique values
-struct GlobalCounter {
-    inner: Mutex<GlobalCounterInner>,
-}
-struct GlobalCounterInner {
-    next_value: u64,
-}
-
-impl GlobalCounter {
-    fn new(start: u64) -> GlobalCounter {
-        GlobalCounter {
-            inner: Mutex::new(GlobalCounterInner { next_value: start }),
-        }
-    }
-
-    fn get_next(&self) -> u64 {
-        let mut inner = self.inner.lock().unwrap();
-
-        let res = inner.next_value;
-        inner.next_value += 1;
-
-        return res;
-    }
-
-    fn get_next_n(&self, n: u64) -> Vec<u64> {
-        let first_value = {
-            let mut inner = self.inner.lock().unwrap();
-            let first_value = inner.next_value;
-            inner.next_value += n;
-            first_value
-        };
-
-        return (first_value..(first_value + n)).collect();
-    }
+/// A message to the thread writing the trace file
+pub enum TraceMessage {
+    /// Serialized trace entries
+    Data(Vec<u8>),
+    /// Write everything received so far to disk and report the file's size
+    Checkpoint(Sender<anyhow::Result<u64>>),
+    Stop,
 }
 
 pub struct TraceHandle {
-    sender: Sender<Option<Vec<u8>>>,
+    sender: Sender<TraceMessage>,
     join_handle: JoinHandle<anyhow::Result<()>>,
 }
 
 impl TraceHandle {
     pub fn new(path: impl AsRef<Path>) -> anyhow::Result<TraceHandle> {
+        let file = File::create(path.as_ref())?;
+        TraceHandle::spawn(path, file, true)
+    }
+
+    /// Continue a trace that was written until a checkpoint.
+    ///
+    /// Everything written after the checkpoint (at byte `offset`) is discarded.
+    pub fn resume(path: impl AsRef<Path>, offset: u64) -> anyhow::Result<TraceHandle> {
+        let mut file = OpenOptions::new().write(true).open(path.as_ref())?;
+        let size = file.metadata()?.len();
+        if size < offset {
+            anyhow::bail!(
+                "The trace {} is shorter ({} bytes) than at the checkpoint ({} bytes)",
+                path.as_ref().display(),
+                size,
+                offset
+            );
+        }
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        TraceHandle::spawn(path, file, false)
+    }
+
+    fn spawn(
+        path: impl AsRef<Path>,
+        file: File,
+        write_header: bool,
+    ) -> anyhow::Result<TraceHandle> {
         let (sender, receiver) = crossbeam::channel::bounded(1024);
 
-        let worker = TraceWorker::new(path, receiver)?;
-        let join_handle = std::thread::spawn(move || worker.run());
+        let worker = TraceWorker::new(path, file, receiver)?;
+        let join_handle = std::thread::spawn(move || worker.run(write_header));
 
         Ok(TraceHandle {
             sender,
@@ -112,81 +174,156 @@ impl TraceHandle {
         })
     }
 
-    pub fn get_writer(&self) -> MemoryCsvWriter {
-        MemoryCsvWriter::new(self.sender.clone())
+    /// Append serialized entries (see `MemoryCsvWriter`) to the trace
+    pub fn write_data(&self, data: Vec<u8>) -> anyhow::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.sender
+            .send(TraceMessage::Data(data))
+            .map_err(|e| anyhow::anyhow!(e))?;
+        Ok(())
+    }
+
+    /// Make sure all the entries sent so far are written to disk, and return
+    /// the size of the trace file.
+    ///
+    /// For compressed traces, this ends the current zstd frame and starts a
+    /// new one, so the file can be truncated to this size and continued.
+    pub fn checkpoint(&self) -> anyhow::Result<u64> {
+        let (reply_sender, reply_receiver) = crossbeam::channel::bounded(1);
+        self.sender
+            .send(TraceMessage::Checkpoint(reply_sender))
+            .map_err(|e| anyhow::anyhow!(e))?;
+        reply_receiver.recv()?
     }
 
     pub fn stop_and_join(self) -> anyhow::Result<()> {
-        self.sender.send(None)?;
+        self.sender
+            .send(TraceMessage::Stop)
+            .map_err(|e| anyhow::anyhow!(e))?;
         self.join_handle.join().unwrap()?;
         Ok(())
     }
 }
 
+/// The trace file, either plain or zstd-compressed
+enum TraceOutput {
+    Plain(File),
+    Compressed(zstd::Encoder<'static, File>),
+}
+
+impl TraceOutput {
+    fn new(file: File, compress: bool) -> anyhow::Result<TraceOutput> {
+        if compress {
+            let nproc = num_cpus::get_physical();
+            let mut encoder = zstd::Encoder::new(file, 5)?;
+            encoder.multithread(nproc as u32)?;
+            Ok(TraceOutput::Compressed(encoder))
+        } else {
+            Ok(TraceOutput::Plain(file))
+        }
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            TraceOutput::Plain(file) => file.write_all(data),
+            TraceOutput::Compressed(encoder) => encoder.write_all(data),
+        }
+    }
+
+    /// Flush everything to the file and return it
+    fn finish(self) -> anyhow::Result<File> {
+        let mut file = match self {
+            TraceOutput::Plain(file) => file,
+            TraceOutput::Compressed(encoder) => encoder.finish()?,
+        };
+        file.flush()?;
+        Ok(file)
+    }
+}
+
 struct TraceWorker {
-    receiver: Receiver<Option<Vec<u8>>>,
-    file_writer: Box<dyn std::io::Write + Send>,
+    receiver: Receiver<TraceMessage>,
+    output: Option<TraceOutput>,
+    compress: bool,
 }
 
 impl TraceWorker {
     fn new(
         path: impl AsRef<Path>,
-        receiver: Receiver<Option<Vec<u8>>>,
+        file: File,
+        receiver: Receiver<TraceMessage>,
     ) -> anyhow::Result<TraceWorker> {
-        let file_writer: Box<dyn Write + Send> = {
-            let path = path.as_ref();
-
-            let file = File::create(path)?;
-
-            if path
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .ends_with(".zst")
-            {
-                let nproc = num_cpus::get_physical();
-                Box::new({
-                    let mut encoder = zstd::Encoder::new(file, 5)?;
-                    encoder.multithread(nproc as u32)?;
-                    encoder.auto_finish()
-                })
-            } else {
-                Box::new(file)
-            }
-        };
+        let compress = path
+            .as_ref()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .ends_with(".zst");
 
         Ok(TraceWorker {
             receiver,
-            file_writer,
+            output: Some(TraceOutput::new(file, compress)?),
+            compress,
         })
     }
 
-    fn run(mut self) -> anyhow::Result<()> {
-        self.file_writer
-            .write_all(b"m_id,source_id,source_timestamp,destination_id,destination_timestamp\n")?;
+    /// The current output. It is only missing while a checkpoint is made.
+    fn output(&mut self) -> &mut TraceOutput {
+        self.output.as_mut().unwrap()
+    }
 
-        while let Some(data) = self.receiver.recv()? {
-            assert!(&data.iter().filter(|x| x == &&b',').count() % 4 == 0);
+    fn checkpoint(&mut self) -> anyhow::Result<u64> {
+        let mut file = self.output.take().unwrap().finish()?;
+        let offset = file.stream_position()?;
+        self.output = Some(TraceOutput::new(file, self.compress)?);
+        Ok(offset)
+    }
 
-            // let s = String::from_utf8_lossy(&data[..]);
-            // info!("Got: \"{}\"", s);
+    fn run(mut self, write_header: bool) -> anyhow::Result<()> {
+        if write_header {
+            self.output().write_all(
+                b"m_id,source_id,source_timestamp,destination_id,destination_timestamp\n",
+            )?;
+        }
 
-            self.file_writer.write_all(&data[..])?;
+        loop {
+            match self.receiver.recv()? {
+                TraceMessage::Data(data) => {
+                    assert!(&data.iter().filter(|x| x == &&b',').count() % 4 == 0);
+
+                    // let s = String::from_utf8_lossy(&data[..]);
+                    // info!("Got: \"{}\"", s);
+
+                    self.output().write_all(&data[..])?;
+                }
+                TraceMessage::Checkpoint(reply) => {
+                    let result = self.checkpoint();
+                    let failed = result.is_err();
+                    reply.send(result)?;
+                    if failed {
+                        anyhow::bail!("Writing the trace failed at a checkpoint");
+                    }
+                }
+                TraceMessage::Stop => break,
+            }
         }
 
+        self.output.take().unwrap().finish()?;
         Ok(())
     }
 }
 
+/// Serializes trace entries in memory. The data is then written to the trace
+/// with `TraceHandle::write_data`, so that it ends up in a fixed order.
 pub struct MemoryCsvWriter {
-    sender: Sender<Option<Vec<u8>>>,
     csv_writer: csv::Writer<Vec<u8>>,
 }
 
 impl MemoryCsvWriter {
-    pub fn new(sender: Sender<Option<Vec<u8>>>) -> MemoryCsvWriter {
+    pub fn new() -> MemoryCsvWriter {
         MemoryCsvWriter {
-            sender,
             csv_writer: csv::WriterBuilder::new()
                 .has_headers(false)
                 .from_writer(Vec::with_capacity(65536)),
@@ -201,37 +338,16 @@ impl MemoryCsvWriter {
             self.csv_writer.serialize(entry)?;
         }
 
-        if self.csv_writer.get_ref().len() > 49152 {
-            self.flush()?;
-        }
-
         Ok(())
     }
 
-    pub fn flush(&mut self) -> anyhow::Result<()> {
+    /// Take the serialized entries written so far
+    pub fn take_data(&mut self) -> anyhow::Result<Vec<u8>> {
         let new_writer = csv::WriterBuilder::new()
             .has_headers(false)
             .from_writer(Vec::with_capacity(65536));
         let old_writer = std::mem::replace(&mut self.csv_writer, new_writer);
-        self.sender
-            .send(Some(old_writer.into_inner()?))
-            .map_err(|e| anyhow::anyhow!(e))?;
-
-        Ok(())
-    }
-}
 
-// impl Clone for MemoryCsvWriter {
-//     fn clone(&self) -> Self {
-//         Self {
-//             sender: self.sender.clone(),
-//             csv_writer: csv::Writer::from_writer(Vec::with_capacity(65536)),
-//         }
-//     }
-// }
-
-impl Drop for MemoryCsvWriter {
-    fn drop(&mut self) {
-        self.flush().unwrap();
+        Ok(old_writer.into_inner()?)
     }
 }
@@ -0,0 +1,146 @@
+//! Aggregation of metrics over multiple Monte-Carlo trials
+//!
+//! Every trial simulates an independent client population on the same
+//! consensuses. For each metric, we report the mean over the trials together
+//! with a 95 % confidence interval, based on Student's t-distribution.
+//!
+//! Some metrics are not reported by every trial (e.g. the median time to
+//! compromise is undefined if fewer than half of the clients get compromised).
+//! Those are aggregated over the trials that report them only, so the number
+//! of these trials is reported with every metric.
+
+use std::path::Path;
+
+use anyhow;
+use fxhash::FxHashMap;
+#[allow(unused_imports)]
+use log::{debug, info, trace, warn};
+
+/// The metrics of a single trial, as (name, value) pairs
+pub(crate) type TrialMetrics = Vec<(String, f64)>;
+
+/// Summary of one metric over all trials
+struct MetricSummary {
+    name: String,
+    /// Number of trials that reported this metric
+    trials: usize,
+    mean: f64,
+    std_dev: f64,
+    ci_low: f64,
+    ci_high: f64,
+}
+
+/// Summary of all metrics over all trials
+pub(crate) struct TrialsSummary {
+    num_trials: usize,
+    metrics: Vec<MetricSummary>,
+}
+
+impl TrialsSummary {
+    pub(crate) fn new(trials: &[TrialMetrics]) -> TrialsSummary {
+        // collect the values by metric, in the order the metrics first appear
+        let mut names: Vec<&String> = Vec::new();
+        let mut values: FxHashMap<&String, Vec<f64>> = FxHashMap::default();
+        for (name, value) in trials.iter().flatten() {
+            values
+                .entry(name)
+                .or_insert_with(|| {
+                    names.push(name);
+                    Vec::new()
+                })
+                .push(*value);
+        }
+
+        let metrics = names
+            .into_iter()
+            .map(|name| {
+                let values = &values[name];
+                let n = values.len();
+                let mean = values.iter().sum::<f64>() / n as f64;
+                let std_dev = if n > 1 {
+                    (values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1) as f64).sqrt()
+                } else {
+                    0.0
+                };
+                let half_width = if n > 1 {
+                    t_quantile_975(n - 1) * std_dev / (n as f64).sqrt()
+                } else {
+                    f64::NAN
+                };
+
+                MetricSummary {
+                    name: name.clone(),
+                    trials: n,
+                    mean,
+                    std_dev,
+                    ci_low: mean - half_width,
+                    ci_high: mean + half_width,
+                }
+            })
+            .collect();
+
+        TrialsSummary {
+            num_trials: trials.len(),
+            metrics,
+        }
+    }
+
+    pub(crate) fn num_trials(&self) -> usize {
+        self.num_trials
+    }
+
+    /// The mean of every metric, by name, together with the number of trials
+    /// that reported the metric
+    pub(crate) fn means(&self) -> impl Iterator<Item = (&str, f64, usize)> {
+        self.metrics
+            .iter()
+            .map(|metric| (metric.name.as_str(), metric.mean, metric.trials))
+    }
+
+    pub(crate) fn print(&self) {
+        info!("Summary over all trials (mean and 95 % confidence interval):");
+        for metric in self.metrics.iter() {
+            info!(
+                "{}: {:.6} [{:.6}, {:.6}] ({} trials)",
+                metric.name, metric.mean, metric.ci_low, metric.ci_high, metric.trials
+            );
+        }
+    }
+
+    pub(crate) fn write_csv(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record(["metric", "trials", "mean", "std_dev", "ci_low", "ci_high"])?;
+        for metric in self.metrics.iter() {
+            writer.write_record([
+                metric.name.clone(),
+                metric.trials.to_string(),
+                metric.mean.to_string(),
+                metric.std_dev.to_string(),
+                metric.ci_low.to_string(),
+                metric.ci_high.to_string(),
+            ])?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The 97.5 % quantile of Student's t-distribution with the given degrees of
+/// freedom, as used for two-sided 95 % confidence intervals
+fn t_quantile_975(df: usize) -> f64 {
+    const TABLE: [f64; 30] = [
+        12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+        2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+        2.052, 2.048, 2.045, 2.042,
+    ];
+
+    match df {
+        0 => f64::NAN,
+        1..=30 => TABLE[df - 1],
+        31..=40 => 2.021,
+        41..=60 => 2.000,
+        61..=120 => 1.980,
+        _ => 1.960,
+    }
+}
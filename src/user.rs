@@ -3,19 +3,36 @@
 //! These models currently also generate the response packet traces so they can
 //! delay further requests until the previous one is finished.
 
+use std::sync::Arc;
+
 use chrono::{DateTime, Duration, Utc};
 use rand::Rng;
 use rand_distr::{Distribution, Exp};
-use seeded_rand::get_rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 
-use crate::packet_model::{FlowOfStreams, PacketModelParameters, StreamModelParameters};
+use crate::config::LoadConfig;
+use crate::destinations::{Destination, Destinations};
+use crate::packet_model::{FlowOfStreams, FlowState, PacketModelParameters, StreamModelParameters};
+use crate::rng::get_rng;
+use crate::utils::{from_micros, to_micros};
 
 /// A user behavior model that determines when to initiate which kind of
 /// traffic through the Tor network.
 ///
 /// This is essentially just an iterator of `Request`s which will be carried out
 /// by the Tor client.
-pub(crate) trait UserModel: Iterator<Item = Request> {}
+pub(crate) trait UserModel: Iterator<Item = Request> {
+    /// The progress of the user model, as stored in checkpoints
+    type State: Serialize + DeserializeOwned;
+
+    /// Export the progress of the user model
+    fn to_state(&self) -> Self::State;
+
+    /// Continue from an exported progress. The model must have been
+    /// constructed with the same parameters as the one that was exported.
+    fn restore_state(&mut self, state: &Self::State) -> anyhow::Result<()>;
+}
 
 /// A traffic request by the user, to be carried out by the Tor client
 ///
@@ -28,10 +45,144 @@ pub(crate) struct Request {
     pub time: DateTime<Utc>,
     /// Remote port to connect to
     pub port: u16,
+    pub kind: RequestKind,
+    /// The destination host, if destinations are modelled
+    pub destination: Option<Destination>,
+    /// The SOCKS credentials the stream is opened with, if any
+    pub isolation_token: Option<u64>,
     /// Response packets the server will send
     pub packet_timestamps: Vec<DateTime<Utc>>,
 }
 
+/// What the client is asked to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum RequestKind {
+    /// Open a stream to a destination on the internet, through an exit
+    Exit,
+    /// Resolve a hostname through an exit (RELAY_RESOLVE). The port is not used.
+    Resolve,
+    /// Open a stream to the onion service with the given number, through a
+    /// rendezvous circuit
+    OnionService(u64),
+}
+
+/// A request that was generated, but not handled yet, as stored in checkpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RequestState {
+    time: i64,
+    port: u16,
+    kind: RequestKind,
+    destination: Option<Destination>,
+    isolation_token: Option<u64>,
+    packet_timestamps: Vec<i64>,
+}
+
+impl Request {
+    pub(crate) fn to_state(&self) -> RequestState {
+        RequestState {
+            time: to_micros(&self.time),
+            port: self.port,
+            kind: self.kind,
+            destination: self.destination,
+            isolation_token: self.isolation_token,
+            packet_timestamps: self.packet_timestamps.iter().map(to_micros).collect(),
+        }
+    }
+
+    pub(crate) fn from_state(state: &RequestState) -> anyhow::Result<Request> {
+        Ok(Request {
+            time: from_micros(state.time)?,
+            port: state.port,
+            kind: state.kind,
+            destination: state.destination,
+            isolation_token: state.isolation_token,
+            packet_timestamps: state
+                .packet_timestamps
+                .iter()
+                .map(|time| from_micros(*time))
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+}
+
+/// Which kinds of requests users make, besides streams through exits, and
+/// where they go
+#[derive(Debug, Clone)]
+pub(crate) struct RequestMix {
+    /// Fraction of requests that are hostname lookups
+    resolve_fraction: f64,
+    /// Fraction of flows that go to an onion service
+    onion_fraction: f64,
+    /// Number of onion services the users choose from (uniformly)
+    onion_services: u64,
+    /// The destination hosts, if they are modelled
+    destinations: Option<Arc<Destinations>>,
+    /// Isolate the streams of a flow by the flow's first destination
+    first_party_isolation: bool,
+}
+
+impl RequestMix {
+    pub(crate) fn new(config: &LoadConfig) -> anyhow::Result<RequestMix> {
+        if !(0.0..=1.0).contains(&config.resolve_fraction) {
+            anyhow::bail!(
+                "Invalid fraction of resolve requests {}",
+                config.resolve_fraction
+            );
+        }
+        if !(0.0..=1.0).contains(&config.onion_fraction) {
+            anyhow::bail!(
+                "Invalid fraction of onion service flows {}",
+                config.onion_fraction
+            );
+        }
+        if config.onion_fraction > 0.0 && config.onion_services == 0 {
+            anyhow::bail!(
+                "Onion service flows configured, but no onion services (load.onion_services)"
+            );
+        }
+        let destinations = Destinations::new(config)?.map(Arc::new);
+        if config.first_party_isolation && destinations.is_none() {
+            anyhow::bail!(
+                "First-party isolation configured, but no destinations (load.destinations)"
+            );
+        }
+
+        Ok(RequestMix {
+            resolve_fraction: config.resolve_fraction,
+            onion_fraction: config.onion_fraction,
+            onion_services: config.onion_services,
+            destinations,
+            first_party_isolation: config.first_party_isolation,
+        })
+    }
+
+    /// Choose the destination host of a new stream, if destinations are modelled
+    fn sample_destination(&self) -> Option<Destination> {
+        self.destinations
+            .as_ref()
+            .map(|destinations| destinations.sample())
+    }
+
+    /// Choose the onion service a new flow goes to, if any
+    fn sample_onion_service(&self) -> Option<u64> {
+        if self.onion_fraction <= 0.0 {
+            return None;
+        }
+        let mut rng = get_rng();
+        if rng.gen_bool(self.onion_fraction) {
+            Some(rng.gen_range(0..self.onion_services))
+        } else {
+            None
+        }
+    }
+
+    /// Check if a new request (that does not go to an onion service) is a
+    /// hostname lookup
+    fn sample_resolve(&self) -> bool {
+        self.resolve_fraction > 0.0 && get_rng().gen_bool(self.resolve_fraction)
+    }
+}
+
 /// A dummy client that connects to HTTPS randomly every 0-3 days
 #[allow(unused)]
 pub(crate) struct DummyUser {
@@ -85,12 +236,27 @@ impl Iterator for DummyUser {
         Some(Request {
             time: request_time,
             port: 443,
+            kind: RequestKind::Exit,
+            destination: None,
+            isolation_token: None,
             packet_timestamps,
         })
     }
 }
 
-impl UserModel for DummyUser {}
+impl UserModel for DummyUser {
+    /// The time of the last request
+    type State = i64;
+
+    fn to_state(&self) -> i64 {
+        to_micros(&self.current_time)
+    }
+
+    fn restore_state(&mut self, state: &i64) -> anyhow::Result<()> {
+        self.current_time = from_micros(*state)?;
+        Ok(())
+    }
+}
 
 /// A user model that behaves much like the one modelled by the PrivCount paper
 /// and implemented in tornettools.
@@ -102,6 +268,14 @@ impl UserModel for DummyUser {}
 pub(crate) struct PrivcountUser {
     flow_model: ExponentialFlowModel,
     current_flow: Option<FlowOfStreams>,
+    /// The onion service the current flow goes to, if any
+    current_onion_service: Option<u64>,
+    /// The destination of the current flow's first stream (the site the
+    /// user visits), if destinations are modelled
+    current_first_party: Option<Destination>,
+    /// Has the current flow opened a stream yet?
+    flow_started: bool,
+    request_mix: RequestMix,
     stream_model_parameters: StreamModelParameters,
     // packet model to generate the response timestamps
     packet_model: PacketModelParameters,
@@ -115,6 +289,7 @@ impl PrivcountUser {
     pub fn new(
         start_time: DateTime<Utc>,
         flows_every_10min: f64,
+        request_mix: RequestMix,
         stream_model: StreamModelParameters,
         packet_model: PacketModelParameters,
         not_after: DateTime<Utc>,
@@ -122,6 +297,10 @@ impl PrivcountUser {
         PrivcountUser {
             flow_model: ExponentialFlowModel::new(start_time, flows_every_10min),
             current_flow: None,
+            current_onion_service: None,
+            current_first_party: None,
+            flow_started: false,
+            request_mix,
             stream_model_parameters: stream_model,
             packet_model,
             not_after,
@@ -145,12 +324,36 @@ impl Iterator for PrivcountUser {
                             // make sure future flows do not overlap with this one
                             self.flow_model.advance_to(request_time);
 
+                            let kind = match self.current_onion_service {
+                                Some(service) => RequestKind::OnionService(service),
+                                None if self.request_mix.sample_resolve() => RequestKind::Resolve,
+                                None => RequestKind::Exit,
+                            };
+
+                            // the first stream goes to the site itself, the
+                            // following ones also to other hosts
+                            let destination = match kind {
+                                RequestKind::OnionService(_) => None,
+                                _ if !self.flow_started => self.current_first_party,
+                                _ => self.request_mix.sample_destination(),
+                            };
+                            self.flow_started = true;
+                            let isolation_token = if self.request_mix.first_party_isolation {
+                                self.current_first_party.map(|site| site.id)
+                            } else {
+                                None
+                            };
+
                             // generate the stream of packets
-                            let packet_timestamps = self
-                                .packet_model
-                                .make_packetstream(request_time)
-                                .generate_timestamps(self.not_after)
-                                .unwrap();
+                            let packet_timestamps = match kind {
+                                // the answer fits into a single cell
+                                RequestKind::Resolve => vec![request_time],
+                                _ => self
+                                    .packet_model
+                                    .make_packetstream(request_time)
+                                    .generate_timestamps(self.not_after)
+                                    .unwrap(),
+                            };
 
                             // wait with further requests until this request is over
                             // TODO: network latency?
@@ -162,12 +365,16 @@ impl Iterator for PrivcountUser {
                             return Some(Request {
                                 time: request_time,
                                 port: 443,
+                                kind,
+                                destination,
+                                isolation_token,
                                 packet_timestamps,
                             });
                         }
                         None => {
                             // this flow has finished, no more streams
                             self.current_flow = None;
+                            self.current_onion_service = None;
                         }
                     }
                 }
@@ -178,13 +385,55 @@ impl Iterator for PrivcountUser {
                         self.stream_model_parameters
                             .make_flow(flow_time, self.not_after),
                     );
+                    self.current_onion_service = self.request_mix.sample_onion_service();
+                    self.current_first_party = match self.current_onion_service {
+                        Some(_) => None,
+                        None => self.request_mix.sample_destination(),
+                    };
+                    self.flow_started = false;
                 }
             }
         }
     }
 }
 
-impl UserModel for PrivcountUser {}
+/// The progress of a `PrivcountUser`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PrivcountUserState {
+    flow_model_time: i64,
+    current_flow: Option<FlowState>,
+    current_onion_service: Option<u64>,
+    current_first_party: Option<Destination>,
+    flow_started: bool,
+}
+
+impl UserModel for PrivcountUser {
+    type State = PrivcountUserState;
+
+    fn to_state(&self) -> PrivcountUserState {
+        PrivcountUserState {
+            flow_model_time: to_micros(&self.flow_model.current_time),
+            current_flow: self.current_flow.as_ref().map(|flow| flow.to_state()),
+            current_onion_service: self.current_onion_service,
+            current_first_party: self.current_first_party,
+            flow_started: self.flow_started,
+        }
+    }
+
+    fn restore_state(&mut self, state: &PrivcountUserState) -> anyhow::Result<()> {
+        self.flow_model
+            .advance_to(from_micros(state.flow_model_time)?);
+        self.current_flow = state
+            .current_flow
+            .as_ref()
+            .map(|flow| self.stream_model_parameters.restore_flow(flow))
+            .transpose()?;
+        self.current_onion_service = state.current_onion_service;
+        self.current_first_party = state.current_first_party;
+        self.flow_started = state.flow_started;
+        Ok(())
+    }
+}
 
 /// A flow model that emits new flows based on an expontential distribution
 struct ExponentialFlowModel {
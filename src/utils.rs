@@ -1,6 +1,10 @@
 //! Collection of useful helper code.
 
 use std::collections::HashMap;
+use std::fs;
+
+use chrono::{DateTime, TimeZone, Utc};
+use tordoc::Fingerprint;
 
 pub trait RetainOrElseVec {
     type Item;
@@ -78,3 +82,37 @@ impl<K, V, H> RetainOrElseHashMap for HashMap<K, V, H> {
         })
     }
 }
+
+/// Parse a relay fingerprint given in hex notation
+pub fn parse_fingerprint(raw: &str) -> anyhow::Result<Fingerprint> {
+    if raw.len() != 40 || !raw.chars().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid relay fingerprint {}", raw);
+    }
+    Fingerprint::from_str_hex(raw.to_uppercase())
+        .map_err(|e| anyhow::anyhow!("Invalid relay fingerprint {}: {:?}", raw, e))
+}
+
+/// Get the resident memory of this process in bytes, if available (Linux only)
+pub fn resident_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+
+    // the line has the format "VmRSS:     1234 kB"
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kb * 1024)
+}
+
+/// Represent a point in time as microseconds since the UNIX epoch, e.g. for
+/// storing it in a checkpoint
+pub fn to_micros(time: &DateTime<Utc>) -> i64 {
+    time.timestamp() * 1_000_000 + time.timestamp_subsec_micros() as i64
+}
+
+/// Restore a point in time from microseconds since the UNIX epoch
+pub fn from_micros(micros: i64) -> anyhow::Result<DateTime<Utc>> {
+    let secs = micros.div_euclid(1_000_000);
+    let nanos = (micros.rem_euclid(1_000_000) * 1000) as u32;
+    Utc.timestamp_opt(secs, nanos)
+        .single()
+        .ok_or_else(|| anyhow::anyhow!("Invalid timestamp {} us", micros))
+}
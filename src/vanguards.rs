@@ -0,0 +1,244 @@
+//! Handling of vanguards, i.e., pinned middle relays for internal circuits
+//! (see vanguards-spec.txt)
+//!
+//! With vanguards-lite, the second hop of internal circuits is chosen from a
+//! small set of layer-2 guards. Full vanguards additionally pin the third hop
+//! to a set of layer-3 guards. Each vanguard is replaced when its (randomly
+//! chosen) lifetime is over, or when it is not listed as a suitable relay in
+//! the consensus anymore.
+//!
+//! Deviations from spec: We do not model family or subnet restrictions
+//! between the hops, only that every relay appears once in a path.
+
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use lazy_static::lazy_static;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tor_circuit_generator::{CircuitGenerator, Relay, TorCircuit};
+use tordoc::{consensus::Flag, Fingerprint};
+
+use crate::config::{protocol_parameters, VanguardsMode};
+use crate::observer::ClientObserver;
+use crate::rng::{get_rng, with_circgen};
+use crate::utils::{from_micros, parse_fingerprint, to_micros};
+
+lazy_static! {
+    static ref MODE: VanguardsMode = protocol_parameters().vanguards.mode;
+    static ref NUM_LAYER2_GUARDS: usize = protocol_parameters().vanguards.layer2_num;
+    static ref NUM_LAYER3_GUARDS: usize = protocol_parameters().vanguards.layer3_num;
+    static ref LAYER2_LIFETIME: (Duration, Duration) = (
+        Duration::hours(protocol_parameters().vanguards.layer2_lifetime_min_hours),
+        Duration::hours(protocol_parameters().vanguards.layer2_lifetime_max_hours),
+    );
+    static ref LAYER3_LIFETIME: (Duration, Duration) = (
+        Duration::hours(protocol_parameters().vanguards.layer3_lifetime_min_hours),
+        Duration::hours(protocol_parameters().vanguards.layer3_lifetime_max_hours),
+    );
+}
+
+/// How often we try to sample a relay that is not excluded before giving up
+const MAX_SAMPLING_ATTEMPTS: usize = 100;
+
+#[derive(Debug)]
+struct Vanguard {
+    fingerprint: Fingerprint,
+    expires_at: DateTime<Utc>,
+}
+
+/// The layer-2 and layer-3 guards of a client
+#[derive(Debug)]
+pub(crate) struct Vanguards {
+    layer2: Vec<Vanguard>,
+    layer3: Vec<Vanguard>,
+}
+
+/// The vanguards of a client, as stored in checkpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct VanguardsState {
+    layer2: Vec<VanguardState>,
+    layer3: Vec<VanguardState>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct VanguardState {
+    fingerprint: String,
+    expires_at: i64,
+}
+
+impl Vanguards {
+    pub fn new() -> Vanguards {
+        Vanguards {
+            layer2: Vec::new(),
+            layer3: Vec::new(),
+        }
+    }
+
+    /// Export the current vanguards
+    pub fn to_state(&self) -> VanguardsState {
+        let export = |layer: &Vec<Vanguard>| {
+            layer
+                .iter()
+                .map(|vanguard| VanguardState {
+                    fingerprint: vanguard.fingerprint.to_string(),
+                    expires_at: to_micros(&vanguard.expires_at),
+                })
+                .collect()
+        };
+
+        VanguardsState {
+            layer2: export(&self.layer2),
+            layer3: export(&self.layer3),
+        }
+    }
+
+    /// Continue with exported vanguards
+    pub fn from_state(state: &VanguardsState) -> anyhow::Result<Vanguards> {
+        let restore = |layer: &Vec<VanguardState>| {
+            layer
+                .iter()
+                .map(|vanguard| -> anyhow::Result<_> {
+                    Ok(Vanguard {
+                        fingerprint: parse_fingerprint(&vanguard.fingerprint)?,
+                        expires_at: from_micros(vanguard.expires_at)?,
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+
+        Ok(Vanguards {
+            layer2: restore(&state.layer2)?,
+            layer3: restore(&state.layer3)?,
+        })
+    }
+
+    /// Rotate expired and unsuitable vanguards, and fill up the sets
+    pub fn timed_updates(
+        &mut self,
+        now: &DateTime<Utc>,
+        circgen: &CircuitGenerator,
+        observer: &mut ClientObserver,
+    ) {
+        let (num_layer2, num_layer3) = match *MODE {
+            VanguardsMode::None => (0, 0),
+            VanguardsMode::Lite => (*NUM_LAYER2_GUARDS, 0),
+            VanguardsMode::Full => (*NUM_LAYER2_GUARDS, *NUM_LAYER3_GUARDS),
+        };
+
+        for (layer, vanguards, num, lifetime) in [
+            (2, &mut self.layer2, num_layer2, *LAYER2_LIFETIME),
+            (3, &mut self.layer3, num_layer3, *LAYER3_LIFETIME),
+        ] {
+            vanguards.retain(|vanguard| {
+                let keep = vanguard.expires_at > *now
+                    && circgen
+                        .lookup_relay(&vanguard.fingerprint)
+                        .map_or(false, |relay| is_suitable(&relay));
+                if !keep {
+                    observer.notify_vanguard_removed(now, layer, &vanguard.fingerprint);
+                }
+                keep
+            });
+
+            while vanguards.len() < num {
+                let existing: Vec<_> = vanguards.iter().map(|v| &v.fingerprint).collect();
+                let relay = match sample_middle(circgen, &existing) {
+                    Some(relay) => relay,
+                    // not enough suitable relays, try again later
+                    None => break,
+                };
+                vanguards.push(Vanguard {
+                    fingerprint: relay.fingerprint.clone(),
+                    expires_at: *now + sample_lifetime(lifetime),
+                });
+            }
+        }
+    }
+
+    /// Construct the path of a four-hop internal circuit through the given guard.
+    ///
+    /// Depending on the vanguards mode, the path is G-M-M-M (no vanguards),
+    /// G-L2-M-M (lite) or G-L2-L3-M (full). Without a guard (for bridge
+    /// users), the first hop is a placeholder middle relay.
+    ///
+    /// Returns `None` if no suitable path exists at the moment, e.g. because
+    /// the only layer-2 vanguard is the guard itself.
+    pub fn build_internal_circuit(
+        &self,
+        guard: Option<&Fingerprint>,
+        circgen: &CircuitGenerator,
+    ) -> Option<TorCircuit> {
+        let guard = match guard {
+            Some(guard) => circgen.lookup_relay(guard)?,
+            None => sample_middle(circgen, &[])?,
+        };
+        let mut path: Vec<Arc<Relay>> = vec![guard];
+
+        for layer in [&self.layer2, &self.layer3] {
+            if layer.is_empty() {
+                continue;
+            }
+            let candidates: Vec<_> = layer
+                .iter()
+                .filter(|vanguard| {
+                    !path
+                        .iter()
+                        .any(|relay| relay.fingerprint == vanguard.fingerprint)
+                })
+                .collect();
+            let vanguard = candidates.choose(&mut get_rng())?;
+            path.push(circgen.lookup_relay(&vanguard.fingerprint)?);
+        }
+
+        while path.len() < 4 {
+            let existing: Vec<_> = path.iter().map(|relay| &relay.fingerprint).collect();
+            path.push(sample_middle(circgen, &existing)?);
+        }
+
+        let exit = path.pop().unwrap();
+        let guard = path.remove(0);
+        Some(TorCircuit {
+            guard,
+            middle: path,
+            exit,
+        })
+    }
+}
+
+/// Check if a relay can be used as a vanguard
+fn is_suitable(relay: &Relay) -> bool {
+    [Flag::Fast, Flag::Stable, Flag::Valid, Flag::Running]
+        .iter()
+        .all(|flag| relay.flags.contains(flag))
+}
+
+/// Sample a (fast and stable) relay, weighted as a middle relay, that is not
+/// already excluded.
+///
+/// The circuit generator has no direct way to sample a middle relay, so we take
+/// the middle hop of a freshly generated circuit.
+fn sample_middle(circgen: &CircuitGenerator, exclude: &[&Fingerprint]) -> Option<Arc<Relay>> {
+    for _ in 0..MAX_SAMPLING_ATTEMPTS {
+        let circuit =
+            with_circgen(|| circgen.build_circuit_with_flags_and_guard(3, 443, None, true, true))
+                .ok()?;
+        let relay = circuit.middle[0].clone();
+        if !exclude.contains(&&relay.fingerprint) {
+            return Some(relay);
+        }
+    }
+    None
+}
+
+/// Sample a vanguard lifetime as MAX(X, X), with X uniform in the given range
+fn sample_lifetime((min, max): (Duration, Duration)) -> Duration {
+    let mut rng = get_rng();
+    let range = min.num_seconds()..=max.num_seconds();
+    let x1 = rng.gen_range(range.clone());
+    let x2 = rng.gen_range(range);
+
+    Duration::seconds(std::cmp::max(x1, x2))
+}